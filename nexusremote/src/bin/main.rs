@@ -1,7 +1,11 @@
 //! NexusRemote - Decentralized P2P Remote Control System
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use nexusremote::*;
+use nexusremote::core::keystore::{Keystore, PASSPHRASE_ENV};
+use nexusremote::daemon::{shutdown_signal, Daemon, DaemonConfig, RpcClient, CONFIG_FILE};
+use serde::Serialize;
+use std::path::PathBuf;
 use tracing::{info, Level};
 
 /// NexusRemote CLI
@@ -9,6 +13,18 @@ use tracing::{info, Level};
 #[command(name = "nexusremote")]
 #[command(about = "Decentralized P2P remote control system with token incentives", long_about = None)]
 struct Cli {
+    /// Data directory (defaults to $NEXUSREMOTE_DATA_DIR or ~/.nexusremote)
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    
+    /// Config file (defaults to config.toml in the data directory, if present)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    
+    /// Print replies from the node as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    
     /// Subcommand
     #[command(subcommand)]
     command: Commands,
@@ -19,23 +35,54 @@ struct Cli {
 enum Commands {
     /// Start the node
     Start {
-        /// Enable relay mode
+        /// Enable relay mode (overrides the config file)
         #[arg(long)]
         relay: bool,
     },
     
-    /// Mine initial tokens
+    /// Show the running node's status
+    Status,
+    
+    /// List the running node's peers
+    Peers,
+    
+    /// Manage the node identity
+    Identity {
+        /// Identity action
+        #[command(subcommand)]
+        action: IdentityCommand,
+    },
+    
+    /// Mine initial tokens into the running node's wallet
     Mine,
     
     /// Check wallet status
-    Wallet,
+    Wallet {
+        /// List transactions instead of the balance
+        #[arg(long)]
+        transactions: bool,
+    },
     
-    /// Find a peer
+    /// Find a peer through the running node
     Find {
         /// Target device ID (hex)
         target: String,
     },
     
+    /// Manage payment channels
+    Channel {
+        /// Channel action
+        #[command(subcommand)]
+        action: ChannelCommand,
+    },
+    
+    /// Control relaying
+    Relay {
+        /// Relay action
+        #[command(subcommand)]
+        action: RelayCommand,
+    },
+    
     /// Run network simulation
     Simulate {
         /// Number of nodes
@@ -45,6 +92,14 @@ enum Commands {
         /// Number of lookups
         #[arg(long, default_value = "1000")]
         lookups: usize,
+        
+        /// Distance policy: raw-xor, hyperbolic, bucket-preserving or bandwidth-aware
+        #[arg(long, default_value = "hyperbolic")]
+        policy: DistancePolicyKind,
+        
+        /// Run the lookups under every policy and compare them
+        #[arg(long)]
+        compare_policies: bool,
     },
     
     /// Test weighted routing
@@ -54,6 +109,113 @@ enum Commands {
     Version,
 }
 
+/// Identity subcommands
+#[derive(Debug, Subcommand)]
+enum IdentityCommand {
+    /// Generate and store a new identity
+    Create,
+    
+    /// Show the stored identity
+    Show,
+    
+    /// Print the public identity as JSON
+    ExportPublic,
+    
+    /// Replace the identity with a new one, archiving the old key
+    Rotate,
+}
+
+/// Payment channel subcommands
+#[derive(Debug, Subcommand)]
+enum ChannelCommand {
+    /// Propose a channel, locking tokens from the balance; prints the proposal for the peer
+    Open {
+        /// Peer ID
+        peer: String,
+        
+        /// Peer public key (hex)
+        public_key: String,
+        
+        /// Tokens to lock
+        capacity: u128,
+    },
+    
+    /// Accept a channel proposal from a peer
+    Accept {
+        /// Peer ID
+        peer: String,
+        
+        /// Proposal printed by the peer's `channel open`, as JSON
+        proposal: String,
+    },
+    
+    /// Apply a channel update from the peer, printing our countersignature if one is due
+    Update {
+        /// Update as JSON
+        update: String,
+    },
+    
+    /// Propose closing a channel; prints the closing update for the peer
+    Close {
+        /// Peer ID
+        peer: String,
+    },
+}
+
+/// Relay subcommands
+#[derive(Debug, Subcommand)]
+enum RelayCommand {
+    /// Accept relay sessions
+    Start,
+    
+    /// Stop accepting relay sessions; active ones finish
+    Stop,
+    
+    /// List active relay sessions
+    Sessions,
+    
+    /// List relay offers from other nodes, cheapest first
+    Offers,
+}
+
+/// Read the daemon config from `--config`, or the data directory if present
+fn load_config(cli: &Cli, keystore: &Keystore) -> Result<DaemonConfig> {
+    let default_config = keystore.data_dir().join(CONFIG_FILE);
+    let mut config = match &cli.config {
+        Some(path) => DaemonConfig::load(path)?,
+        None if default_config.exists() => DaemonConfig::load(&default_config)?,
+        None => DaemonConfig::default(),
+    };
+    if cli.data_dir.is_some() || config.data_dir.is_none() {
+        config.data_dir = Some(keystore.data_dir().to_path_buf());
+    }
+    Ok(config)
+}
+
+/// Print `value` as JSON when `--json` is given, otherwise run `human`
+fn print_reply<T: Serialize>(json: bool, value: &T, human: impl FnOnce(&T)) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        human(value);
+    }
+    Ok(())
+}
+
+fn print_peer(peer: &PeerInfo) {
+    println!("{}  {}  {:?}  reputation {}", peer.device_id.to_hex(), peer.peer_id, peer.role, peer.reputation);
+}
+
+/// Read the keystore passphrase from the environment or stdin
+fn read_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    
+    // Read from the terminal with echo off so the passphrase never shows
+    Ok(rpassword::prompt_password("Keystore passphrase: ")?)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
@@ -62,52 +224,227 @@ async fn main() -> Result<()> {
         .init();
     
     let cli = Cli::parse();
+    let keystore = match &cli.data_dir {
+        Some(dir) => Keystore::new(dir),
+        None => Keystore::open_default(),
+    };
+    
+    let client = || -> Result<RpcClient> { Ok(RpcClient::new(load_config(&cli, &keystore)?.rpc_socket_path())) };
     
     match &cli.command {
         Commands::Start { relay } => {
             info!("Starting NexusRemote node...");
-            if *relay {
+            let mut config = load_config(&cli, &keystore)?;
+            config.relay_enabled |= *relay;
+            if config.relay_enabled {
                 info!("Relay mode enabled");
             }
+            
+            let keystore = Keystore::new(config.data_dir());
+            let keypair = keystore.load_or_create(&read_passphrase()?)?;
+            let daemon = Daemon::start(config, keypair).await?;
             info!("Node started. Press Ctrl+C to stop.");
+            daemon.run(shutdown_signal()).await?;
         }
         
-        Commands::Mine => {
-            info!("Starting PoW mining for initial tokens...");
-            let keypair = core::crypto::NodeKeypair::generate();
-            let miner = wallet::mining::PowMiner::new();
+        Commands::Status => {
+            let status = client()?.status().await?;
+            print_reply(cli.json, &status, |status| {
+                println!("Device ID:  {}", status.device_id.to_hex());
+                println!("Role:       {:?}", status.role);
+                println!("Reputation: {}", status.reputation);
+                println!("Peers:      {}", status.peers);
+                println!("Relaying:   {} ({} sessions)", status.relaying, status.relay_sessions);
+                println!("Balance:    {}", status.balance);
+                for addr in &status.listen_addrs {
+                    println!("Listening:  {}", addr);
+                }
+                for addr in &status.relayed_addrs {
+                    println!("Relayed:    {}", addr);
+                }
+                for addr in &status.relay_addrs {
+                    println!("Relay:      {}", addr);
+                }
+            })?;
+        }
+        
+        Commands::Peers => {
+            let peers = client()?.peers().await?;
+            print_reply(cli.json, &peers, |peers| peers.iter().for_each(print_peer))?;
+        }
+        
+        Commands::Identity { action } => match action {
+            IdentityCommand::Create => {
+                if keystore.exists() {
+                    return Err(Error::Other(format!(
+                        "Identity already exists at {} (use 'identity rotate' to replace it)",
+                        keystore.path().display()
+                    )));
+                }
+                let keypair = keystore.create(&read_passphrase()?)?;
+                info!("Identity created at {}", keystore.path().display());
+                println!("{}", keypair.node_id().to_hex());
+            }
             
-            info!("This may take a few minutes...");
-            let result = miner.mine_initial_tokens(keypair.node_id().as_bytes()).await?;
+            IdentityCommand::Show => {
+                let identity = keystore.public_identity()?;
+                println!("Device ID:  {}", identity.device_id);
+                println!("Public key: {}", identity.public_key);
+                println!("Created:    {}", identity.created_at);
+                println!("Keystore:   {}", keystore.path().display());
+            }
             
-            if result.success {
-                info!("🎉 Mining successful!");
-                info!("Reward: {}", result.reward);
-                info!("Time taken: {:.2}s", result.time_taken);
-                info!("Attempts: {}", result.attempts);
+            IdentityCommand::ExportPublic => {
+                let identity = keystore.public_identity()?;
+                println!("{}", serde_json::to_string_pretty(&identity)?);
             }
+            
+            IdentityCommand::Rotate => {
+                let keypair = keystore.rotate(&read_passphrase()?)?;
+                info!("Identity rotated");
+                println!("{}", keypair.node_id().to_hex());
+            }
+        },
+        
+        Commands::Mine => {
+            info!("Mining initial tokens, this may take a few minutes...");
+            let result = client()?.mine().await?;
+            print_reply(cli.json, &result, |result| {
+                println!("Reward:   {}", result.reward);
+                println!("Time:     {:.2}s", result.time_taken);
+                println!("Attempts: {}", result.attempts);
+            })?;
+        }
+        
+        Commands::Wallet { transactions: true } => {
+            let transactions = client()?.transactions().await?;
+            print_reply(cli.json, &transactions, |transactions| {
+                for tx in transactions {
+                    println!("{}  {:?}  {}  {}", tx.timestamp, tx.tx_type, tx.amount, tx.description);
+                }
+            })?;
         }
         
-        Commands::Wallet => {
-            info!("Wallet status:");
-            info!("Balance: 0 NEXUS");
-            info!("Reputation: 100");
-            info!("(Wallet functionality will be implemented)");
+        Commands::Wallet { transactions: false } => {
+            let balance = client()?.balance().await?;
+            print_reply(cli.json, &balance, |balance| {
+                println!("Balance:    {}", balance.balance);
+                println!("Available:  {} (with overdraft)", balance.effective_balance);
+                println!("Reputation: {}", balance.reputation);
+                println!("Earned:     {}", balance.total_earned);
+                println!("Spent:      {}", balance.total_spent);
+                println!("Channels:   {}", balance.open_channels);
+            })?;
         }
         
         Commands::Find { target } => {
-            info!("Looking for peer: {}", target);
-            info!("(Peer discovery will be implemented)");
+            let device_id = DeviceID::from_hex(target)
+                .map_err(|e| Error::Other(format!("Invalid device ID {}: {}", target, e)))?;
+            info!("Looking for peer: {}", device_id);
+            let result = client()?.find_peer(&device_id).await?;
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+                return Ok(());
+            }
+            match result.presence {
+                Some(peer) => {
+                    println!("Device ID: {}", peer.device_id.to_hex());
+                    println!("Peer ID:   {}", peer.peer_id);
+                    println!("Role:      {:?}", peer.role);
+                    println!("Bandwidth: {} bps", peer.available_bandwidth);
+                    for addr in &peer.addresses {
+                        println!("Address:   {}", addr);
+                    }
+                }
+                None => {
+                    return Err(Error::Network(format!("No fresh presence record for {}", device_id)));
+                }
+            }
         }
         
-        Commands::Simulate { nodes, lookups } => {
+        Commands::Channel { action } => match action {
+            ChannelCommand::Open { peer, public_key, capacity } => {
+                let mut key = [0u8; 32];
+                hex::decode_to_slice(public_key, &mut key)
+                    .map_err(|e| Error::Other(format!("Invalid public key {}: {}", public_key, e)))?;
+                let open = client()?
+                    .open_channel(&PeerID::new(peer.clone()), &key, TokenAmount::new(*capacity))
+                    .await?;
+                println!("{}", serde_json::to_string(&open)?);
+            }
+            
+            ChannelCommand::Accept { peer, proposal } => {
+                let open = serde_json::from_str(proposal)?;
+                let accepted = client()?.accept_channel(&PeerID::new(peer.clone()), &open).await?;
+                println!("{}", serde_json::to_string(&accepted)?);
+            }
+            
+            ChannelCommand::Update { update } => {
+                let update = serde_json::from_str(update)?;
+                match client()?.apply_channel_update(&update).await? {
+                    Some(countersigned) => println!("{}", serde_json::to_string(&countersigned)?),
+                    None => println!("Channel update confirmed"),
+                }
+            }
+            
+            ChannelCommand::Close { peer } => {
+                let close = client()?.close_channel(&PeerID::new(peer.clone())).await?;
+                println!("{}", serde_json::to_string(&close)?);
+            }
+        },
+        
+        Commands::Relay { action } => match action {
+            RelayCommand::Start | RelayCommand::Stop => {
+                let enabled = matches!(action, RelayCommand::Start);
+                client()?.set_relaying(enabled).await?;
+                println!("Relaying {}", if enabled { "started" } else { "stopped" });
+            }
+            
+            RelayCommand::Sessions => {
+                let sessions = client()?.relay_sessions().await?;
+                print_reply(cli.json, &sessions, |sessions| {
+                    for session in sessions {
+                        println!("{}  {} -> {}  {} bytes",
+                            hex::encode(&session.session_id[..8]), session.client, session.target, session.data_relayed);
+                    }
+                })?;
+            }
+            
+            RelayCommand::Offers => {
+                let offers = client()?.relay_offers().await?;
+                print_reply(cli.json, &offers, |offers| {
+                    for offer in offers {
+                        println!("{}  {}/MB  {} sessions free  min reputation {}",
+                            offer.device_id.to_hex(), offer.tokens_per_mb, offer.available_sessions, offer.min_reputation);
+                    }
+                })?;
+            }
+        },
+        
+        Commands::Simulate { nodes, lookups, policy, compare_policies } => {
             info!("Starting network simulation...");
             info!("Nodes: {}", nodes);
             info!("Lookups: {}", lookups);
             
             let mut sim = simulator::network::NetworkSimulator::new();
+            sim.set_distance_policy(policy.policy());
             sim.create_random_nodes(*nodes);
             sim.connect_mesh(10);
+            sim.simulate_relay_sessions(*nodes * 10);
+            
+            if *compare_policies {
+                let policies: Vec<_> = DistancePolicyKind::ALL.iter().map(|kind| kind.policy()).collect();
+                info!("\n=== Policy Comparison ===");
+                for results in sim.compare_distance_policies(&policies, *lookups) {
+                    info!("{:<18} high rep {:>6.2}%  fairness {:.3}  success {:>6.2}%  hops {:.2}",
+                        results.policy,
+                        results.high_rep_selection_rate * 100.0,
+                        results.fairness,
+                        results.lookup_success_rate * 100.0,
+                        results.average_path_length);
+                }
+                return Ok(());
+            }
             
             let results = sim.run_routing_simulation(*lookups);
             
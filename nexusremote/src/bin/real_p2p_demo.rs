@@ -4,18 +4,17 @@
 use std::error::Error;
 use std::time::Duration;
 use libp2p::{
-    kad::{Kademlia, KademliaConfig, KademliaEvent},
-    noise, tcp, yamux, Multiaddr, PeerId, Transport,
+    kad::{self, store::MemoryStore},
+    noise, tcp, yamux, Multiaddr, PeerId, SwarmBuilder,
 };
 use libp2p::swarm::{Swarm, SwarmEvent};
 use libp2p::identity::Keypair;
-use libp2p::core::upgrade;
 use futures::StreamExt;
 use tokio::time::sleep;
 
 /// 真实的P2P网络节点
 pub struct RealP2PNode {
-    swarm: Swarm<Kademlia<libp2p::kad::store::MemoryStore>>,
+    swarm: Swarm<kad::Behaviour<MemoryStore>>,
     local_peer_id: PeerId,
     node_name: String,
 }
@@ -29,24 +28,21 @@ impl RealP2PNode {
         
         println!("[{}] 节点ID: {}", node_name, local_peer_id);
         
-        // 创建传输层
-        let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&local_key).expect("签名配置应该成功"))
-            .multiplex(yamux::Config::default())
-            .timeout(Duration::from_secs(20))
-            .boxed();
-        
         // 创建Kademlia DHT
-        let store = libp2p::kad::store::MemoryStore::new(local_peer_id);
-        let mut kademlia_config = KademliaConfig::default();
+        let store = MemoryStore::new(local_peer_id);
+        let mut kademlia_config = kad::Config::default();
         kademlia_config.set_query_timeout(Duration::from_secs(60));
         
-        let mut kademlia = Kademlia::new(local_peer_id, store);
-        kademlia.set_mode(Some(libp2p::kad::Mode::Server));
+        let mut kademlia = kad::Behaviour::with_config(local_peer_id, store, kademlia_config);
+        kademlia.set_mode(Some(kad::Mode::Server));
         
-        // 创建Swarm
-        let swarm = Swarm::new(transport, kademlia, local_peer_id, libp2p::swarm::Config::default());
+        // 创建传输层和Swarm
+        let swarm = SwarmBuilder::with_existing_identity(local_key)
+            .with_tokio()
+            .with_tcp(tcp::Config::default().nodelay(true), noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|_| kademlia)?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(20)))
+            .build();
         
         Ok(Self {
             swarm,
@@ -57,14 +53,14 @@ impl RealP2PNode {
     
     /// 监听地址
     pub async fn listen_on(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
-        self.swarm.listen_on(addr)?;
+        self.swarm.listen_on(addr.clone())?;
         println!("[{}] 监听地址: {}", self.node_name, addr);
         Ok(())
     }
     
     /// 连接到对等节点
     pub async fn dial(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
-        match self.swarm.dial(addr) {
+        match self.swarm.dial(addr.clone()) {
             Ok(_) => {
                 println!("[{}] 连接到: {}", self.node_name, addr);
                 Ok(())
@@ -100,7 +96,7 @@ impl RealP2PNode {
                         SwarmEvent::ConnectionClosed { peer_id, .. } => {
                             println!("[{}] 连接关闭: {}", self.node_name, peer_id);
                         }
-                        SwarmEvent::Behaviour(KademliaEvent::RoutingUpdated { peer, .. }) => {
+                        SwarmEvent::Behaviour(kad::Event::RoutingUpdated { peer, .. }) => {
                             println!("[{}] 路由表更新: {}", self.node_name, peer);
                         }
                         _ => {}
@@ -119,9 +115,9 @@ impl RealP2PNode {
 /// 演示真实的P2P网络
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("=".repeat(60));
+    println!("{}", "=".repeat(60));
     println!("NexusRemote 真实P2P网络演示");
-    println!("=".repeat(60));
+    println!("{}", "=".repeat(60));
     
     // 创建3个P2P节点
     println!("\n🚀 创建P2P节点...");
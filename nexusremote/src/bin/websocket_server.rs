@@ -13,6 +13,7 @@ use tokio::sync::Mutex;
 use tracing::{info, warn, error};
 
 /// WebSocket server state
+#[allow(dead_code)]
 struct WebSocketServer {
     /// Local node keypair
     keypair: NodeKeypair,
@@ -23,6 +24,7 @@ struct WebSocketServer {
 }
 
 /// Client information
+#[allow(dead_code)]
 struct ClientInfo {
     /// Client ID
     id: String,
@@ -45,6 +47,7 @@ impl WebSocketServer {
             role: NodeRole::Relay,
             addresses: vec!["localhost:8081".to_string()],
             available_bandwidth: 100_000_000,
+            identity_proof: None,
         };
         
         let dht = InMemoryDht::new(peer_info.clone());
@@ -135,8 +138,8 @@ impl WebSocketServer {
         json: &serde_json::Value,
         ws_sender: &mut futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, Message>,
         dht: &Arc<Mutex<InMemoryDht>>,
-        clients: &Arc<Mutex<HashMap<String, ClientInfo>>>,
-        client_id: &str,
+        _clients: &Arc<Mutex<HashMap<String, ClientInfo>>>,
+        _client_id: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let msg_type = json.get("type").and_then(|t| t.as_str()).unwrap_or("unknown");
         
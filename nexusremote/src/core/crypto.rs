@@ -6,12 +6,12 @@ use ed25519_dalek::{
 use ed25519_dalek::SigningKey as SecretKey;
 use ed25519_dalek::VerifyingKey as PublicKey;
 use ed25519_dalek::Signature;
-use ed25519_dalek::SigningKey;
 use rand::RngCore;
 use rand::rngs::OsRng;
 use sha2::{Sha256, Digest};
 use blake3::Hasher;
 use crate::core::types::*;
+use serde::{Deserialize, Serialize};
 use crate::Error;
 
 /// Cryptographic keypair for node identity
@@ -60,10 +60,7 @@ impl NodeKeypair {
     
     /// Get the NodeID (hash of public key)
     pub fn node_id(&self) -> DeviceID {
-        let mut hasher = Sha256::new();
-        hasher.update(self.public.as_bytes());
-        let hash = hasher.finalize();
-        DeviceID::new(hash.into())
+        node_id_from_public_key(self.public.as_bytes())
     }
     
     /// Sign a message
@@ -89,6 +86,27 @@ impl NodeKeypair {
     }
 }
 
+/// Derive the NodeID for a raw Ed25519 public key
+pub fn node_id_from_public_key(public_key: &[u8; 32]) -> DeviceID {
+    let mut hasher = Sha256::new();
+    hasher.update(public_key);
+    let hash = hasher.finalize();
+    DeviceID::new(hash.into())
+}
+
+/// Verify a signature against a raw Ed25519 public key
+pub fn verify_signature(public_key: &[u8; 32], message: &[u8], signature: &[u8]) -> bool {
+    let public = match PublicKey::from_bytes(public_key) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let sig = match Signature::from_slice(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    public.verify(message, &sig).is_ok()
+}
+
 /// Hash utility functions
 pub mod hash {
     use super::*;
@@ -113,10 +131,51 @@ pub mod hash {
     }
 }
 
+/// Domain separator for identity proofs
+const IDENTITY_DOMAIN: &[u8] = b"nexusremote-identity-v1";
+
+/// Proof of work binding a node identity, required to enter routing tables
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityProof {
+    /// Ed25519 public key the device ID derives from
+    pub public_key: [u8; 32],
+    /// Nonce solving the PoW over the public key
+    pub nonce: u64,
+}
+
+impl IdentityProof {
+    fn seed(public_key: &[u8; 32]) -> Vec<u8> {
+        [IDENTITY_DOMAIN, public_key.as_slice()].concat()
+    }
+    
+    /// Mine a proof for `keypair`
+    pub async fn mine(keypair: &NodeKeypair, difficulty: u32) -> Result<Self, Error> {
+        let public_key = keypair.public_key().to_bytes();
+        let nonce = pow::mine(&Self::seed(&public_key), difficulty).await?;
+        Ok(Self { public_key, nonce })
+    }
+    
+    /// Mine a proof on the current thread (simulations and tests)
+    pub fn solve(keypair: &NodeKeypair, difficulty: u32) -> Self {
+        let public_key = keypair.public_key().to_bytes();
+        let nonce = pow::solve(&Self::seed(&public_key), difficulty);
+        Self { public_key, nonce }
+    }
+    
+    /// Check the proof belongs to `device_id` and meets `difficulty`
+    pub fn verify(&self, device_id: &DeviceID, difficulty: u32) -> bool {
+        node_id_from_public_key(&self.public_key) == *device_id
+            && pow::verify(&Self::seed(&self.public_key), self.nonce, difficulty)
+    }
+}
+
 /// Proof of Work for anti-Sybil protection
 pub mod pow {
     use super::*;
     
+    /// Network-wide difficulty of identity proofs
+    pub const IDENTITY_DIFFICULTY: u32 = 16;
+    
     /// Mine a PoW with given difficulty
     pub async fn mine(seed: &[u8], difficulty: u32) -> Result<u64, Error> {
         let seed_hash = hash::sha256(seed);
@@ -125,38 +184,37 @@ pub mod pow {
         let mut nonce = 0u64;
         
         loop {
-            let mut input = Vec::with_capacity(40);
-            input.extend_from_slice(&seed_hash);
-            input.extend_from_slice(&nonce.to_be_bytes());
-            
-            let hash = hash::sha256(&input);
-            
-            // Check leading zeros in bits
-            let leading_zeros = count_leading_zeros(&hash);
-            
-            if leading_zeros >= difficulty {
+            if solves(&seed_hash, nonce, difficulty) {
                 return Ok(nonce);
             }
             
             nonce += 1;
             
             // Yield occasionally to not block the event loop
-            if nonce % 100_000 == 0 {
+            if nonce.is_multiple_of(100_000) {
                 tokio::task::yield_now().await;
             }
         }
     }
     
+    /// Mine a PoW without yielding
+    pub fn solve(seed: &[u8], difficulty: u32) -> u64 {
+        let seed_hash = hash::sha256(seed);
+        (0u64..).find(|nonce| solves(&seed_hash, *nonce, difficulty)).unwrap()
+    }
+    
     /// Verify a PoW solution
     pub fn verify(seed: &[u8], nonce: u64, difficulty: u32) -> bool {
-        let seed_hash = hash::sha256(seed);
-        
+        solves(&hash::sha256(seed), nonce, difficulty)
+    }
+    
+    fn solves(seed_hash: &[u8; 32], nonce: u64, difficulty: u32) -> bool {
         let mut input = Vec::with_capacity(40);
-        input.extend_from_slice(&seed_hash);
+        input.extend_from_slice(seed_hash);
         input.extend_from_slice(&nonce.to_be_bytes());
         
-        let hash = hash::sha256(&input);
-        count_leading_zeros(&hash) >= difficulty
+        // Check leading zeros in bits
+        count_leading_zeros(&hash::sha256(&input)) >= difficulty
     }
     
     /// Count leading zero bits in a hash
@@ -167,7 +225,7 @@ pub mod pow {
             if byte == 0 {
                 count += 8;
             } else {
-                count += byte.leading_zeros() as u32;
+                count += byte.leading_zeros();
                 break;
             }
         }
@@ -197,6 +255,18 @@ mod tests {
         assert!(!keypair.verify(b"Wrong message", &signature));
     }
     
+    #[test]
+    fn test_verify_with_public_key() {
+        let keypair = NodeKeypair::generate();
+        let public = keypair.public_key().to_bytes();
+        let signature = keypair.sign(b"message");
+        
+        assert!(verify_signature(&public, b"message", &signature));
+        assert!(!verify_signature(&public, b"other", &signature));
+        assert!(!verify_signature(&public, b"message", &[0u8; 10]));
+        assert_eq!(node_id_from_public_key(&public), keypair.node_id());
+    }
+    
     #[test]
     fn test_hash_consistency() {
         let data = b"test data";
@@ -205,6 +275,21 @@ mod tests {
         assert_eq!(hash1, hash2);
     }
     
+    #[test]
+    fn test_identity_proof() {
+        let keypair = NodeKeypair::from_secret_key(&[1u8; 32]).unwrap();
+        let other = NodeKeypair::from_secret_key(&[2u8; 32]).unwrap();
+        let proof = IdentityProof::solve(&keypair, 8);
+        
+        assert!(proof.verify(&keypair.node_id(), 8));
+        assert!(!proof.verify(&other.node_id(), 8));
+        
+        // A proof cannot be moved to another key
+        let mut stolen = proof.clone();
+        stolen.public_key = other.public_key().to_bytes();
+        assert!(!stolen.verify(&node_id_from_public_key(&stolen.public_key), 8));
+    }
+    
     #[tokio::test]
     async fn test_pow() {
         let seed = b"test seed";
@@ -1,7 +1,83 @@
 //! Weighted distance calculation for DHT routing
 
 use crate::core::types::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// 64-bit limbs of a weighted distance, least significant first
+///
+/// The XOR distance is below 2^256 and both weight terms fit in 64 bits, so
+/// cross-multiplied fractions stay below 2^384.
+const LIMBS: usize = 6;
+
+/// Reputation-weighted distance, kept as the exact fraction
+/// `xor * 3000 / (reputation + 500)`
+///
+/// Ordering compares fractions by cross-multiplication, so no bits of the
+/// XOR distance are lost and equal weights order exactly like raw XOR.
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedDistance {
+    numerator: [u64; LIMBS],
+    denominator: u64,
+}
+
+impl WeightedDistance {
+    /// Weight a raw XOR distance by `weight_numerator / weight_denominator`
+    pub fn new(xor_distance: &[u8; 32], weight_numerator: u64, weight_denominator: u64) -> Self {
+        assert!(weight_denominator > 0, "distance weight denominator must be positive");
+        let mut limbs = [0u64; LIMBS];
+        for (i, chunk) in xor_distance.rchunks(8).enumerate() {
+            limbs[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        Self {
+            numerator: mul_small(&limbs, weight_numerator),
+            denominator: weight_denominator,
+        }
+    }
+
+    /// Whether the distance is zero (the node is the target)
+    pub fn is_zero(&self) -> bool {
+        self.numerator.iter().all(|&limb| limb == 0)
+    }
+}
+
+impl Ord for WeightedDistance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lhs = mul_small(&self.numerator, other.denominator);
+        let rhs = mul_small(&other.numerator, self.denominator);
+        lhs.iter().rev().cmp(rhs.iter().rev())
+    }
+}
+
+impl PartialOrd for WeightedDistance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for WeightedDistance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for WeightedDistance {}
+
+/// Multiply little-endian limbs by a 64-bit factor
+fn mul_small(limbs: &[u64; LIMBS], factor: u64) -> [u64; LIMBS] {
+    let mut product = [0u64; LIMBS];
+    let mut carry = 0u128;
+    for (out, &limb) in product.iter_mut().zip(limbs) {
+        let wide = limb as u128 * factor as u128 + carry;
+        *out = wide as u64;
+        carry = wide >> 64;
+    }
+    debug_assert_eq!(carry, 0, "weighted distance overflow");
+    product
+}
 
 /// Calculate the logical distance between two node IDs, weighted by reputation
 /// 
@@ -11,38 +87,8 @@ pub fn calculate_logical_distance(
     node_id: &DeviceID,
     target_id: &DeviceID,
     reputation: ReputationScore,
-) -> [u8; 32] {
-    // Calculate XOR distance
-    let mut xor_distance = [0u8; 32];
-    for i in 0..32 {
-        xor_distance[i] = node_id.as_bytes()[i] ^ target_id.as_bytes()[i];
-    }
-    
-    // Calculate reputation weight: 3000 / (reputation + 500)
-    // This gives high reputation nodes lower effective distance
-    // High reputation (900): 3000/(900+500) = 2.143
-    // Low reputation (100): 3000/(100+500) = 5.0
-    // Advantage: 5.0/2.143 = 2.33x
-    let reputation_weight = 3000.0 / (reputation.value() as f64 + 500.0);
-    
-    // Apply reputation weight to the XOR distance
-    // We use the first 8 bytes as a u64 for weighting to keep it simple
-    let mut weighted_distance = xor_distance;
-    
-    // Get the high 64 bits for weighting
-    let high_bits = u64::from_be_bytes([
-        xor_distance[0], xor_distance[1], xor_distance[2], xor_distance[3],
-        xor_distance[4], xor_distance[5], xor_distance[6], xor_distance[7],
-    ]);
-    
-    // Apply the weight (this is a simplification - in production you'd want more precision)
-    let weighted_high_bits = (high_bits as f64 * reputation_weight) as u64;
-    let weighted_bytes = weighted_high_bits.to_be_bytes();
-    
-    // Update the first 8 bytes with the weighted value
-    weighted_distance[0..8].copy_from_slice(&weighted_bytes);
-    
-    weighted_distance
+) -> WeightedDistance {
+    HyperbolicPolicy::default().distance(node_id, target_id, reputation)
 }
 
 /// Compare two nodes by their logical distance to a target
@@ -53,16 +99,7 @@ pub fn compare_by_distance(
 ) -> Ordering {
     let dist_a = calculate_logical_distance(&a.device_id, target, a.reputation);
     let dist_b = calculate_logical_distance(&b.device_id, target, b.reputation);
-    
-    // Compare as big-endian
-    for i in 0..32 {
-        match dist_a[i].cmp(&dist_b[i]) {
-            Ordering::Equal => continue,
-            ord => return ord,
-        }
-    }
-    
-    Ordering::Equal
+    dist_a.cmp(&dist_b)
 }
 
 /// Sort peers by their weighted distance to a target
@@ -73,8 +110,8 @@ pub fn sort_peers_by_distance(peers: &mut [PeerInfo], target: &DeviceID) {
 /// Calculate the raw XOR distance (without reputation weighting)
 pub fn calculate_raw_xor_distance(a: &DeviceID, b: &DeviceID) -> [u8; 32] {
     let mut distance = [0u8; 32];
-    for i in 0..32 {
-        distance[i] = a.as_bytes()[i] ^ b.as_bytes()[i];
+    for (d, (x, y)) in distance.iter_mut().zip(a.as_bytes().iter().zip(b.as_bytes())) {
+        *d = x ^ y;
     }
     distance
 }
@@ -102,12 +139,222 @@ pub fn is_closer(
 /// Estimate routing improvement for high reputation nodes
 /// Returns the factor by which a node's effective distance is reduced
 pub fn reputation_distance_factor(reputation: ReputationScore) -> f64 {
-    3000.0 / (reputation.value() as f64 + 500.0)
+    HyperbolicPolicy::default().factor(reputation)
+}
+
+/// Ordering of peers by distance to a target, for routing tables and lookups
+pub trait DistancePolicy: fmt::Debug + Send + Sync {
+    /// Short name for logs and reports
+    fn name(&self) -> &'static str;
+    
+    /// Compare two peers by distance to `target`
+    fn compare(&self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering;
+    
+    /// Sort peers by distance to `target`, closest first
+    fn sort(&self, peers: &mut [PeerInfo], target: &DeviceID) {
+        peers.sort_by(|a, b| self.compare(a, b, target));
+    }
+}
+
+/// Plain Kademlia XOR distance; reputation is ignored
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawXorPolicy;
+
+impl DistancePolicy for RawXorPolicy {
+    fn name(&self) -> &'static str {
+        "raw-xor"
+    }
+    
+    fn compare(&self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering {
+        compare_raw_distances(
+            &calculate_raw_xor_distance(&a.device_id, target),
+            &calculate_raw_xor_distance(&b.device_id, target),
+        )
+    }
+}
+
+/// XOR distance scaled by `numerator / (reputation + offset)`
+#[derive(Debug, Clone, Copy)]
+pub struct HyperbolicPolicy {
+    /// Numerator of the weight
+    pub numerator: u64,
+    /// Offset added to the reputation
+    pub offset: u64,
+}
+
+impl Default for HyperbolicPolicy {
+    fn default() -> Self {
+        Self { numerator: 3000, offset: 500 }
+    }
+}
+
+impl HyperbolicPolicy {
+    /// Weighted distance of `node_id` to `target_id`
+    pub fn distance(&self, node_id: &DeviceID, target_id: &DeviceID, reputation: ReputationScore) -> WeightedDistance {
+        let xor_distance = calculate_raw_xor_distance(node_id, target_id);
+        WeightedDistance::new(&xor_distance, self.numerator, reputation.value() + self.offset)
+    }
+    
+    /// Factor the XOR distance is multiplied by
+    pub fn factor(&self, reputation: ReputationScore) -> f64 {
+        self.numerator as f64 / (reputation.value() + self.offset) as f64
+    }
+}
+
+impl DistancePolicy for HyperbolicPolicy {
+    fn name(&self) -> &'static str {
+        "hyperbolic"
+    }
+    
+    fn compare(&self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering {
+        self.distance(&a.device_id, target, a.reputation)
+            .cmp(&self.distance(&b.device_id, target, b.reputation))
+    }
+}
+
+/// Raw XOR order between k-buckets, hyperbolic weighting only within one
+///
+/// A peer sharing a longer prefix with the target always sorts first, so
+/// reputation cannot pull a lookup away from the target's region.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BucketPreservingPolicy {
+    /// Ordering within a bucket
+    pub within_bucket: HyperbolicPolicy,
+}
+
+impl DistancePolicy for BucketPreservingPolicy {
+    fn name(&self) -> &'static str {
+        "bucket-preserving"
+    }
+    
+    fn compare(&self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering {
+        let prefix = |peer: &PeerInfo| common_prefix_len(&calculate_raw_xor_distance(&peer.device_id, target));
+        prefix(b).cmp(&prefix(a))
+            .then_with(|| self.within_bucket.compare(a, b, target))
+    }
+}
+
+/// Hyperbolic weighting with available bandwidth counted as extra reputation
+///
+/// The weight is `numerator / (reputation + offset + bonus)`, where `bonus`
+/// grows linearly to `bandwidth_bonus` at `reference_bandwidth`.
+#[derive(Debug, Clone, Copy)]
+pub struct BandwidthAwarePolicy {
+    /// Numerator of the weight
+    pub numerator: u64,
+    /// Offset added to the reputation
+    pub offset: u64,
+    /// Bandwidth earning the full bonus (bits per second)
+    pub reference_bandwidth: u64,
+    /// Bonus at or above the reference bandwidth, in reputation points
+    pub bandwidth_bonus: u64,
+}
+
+impl Default for BandwidthAwarePolicy {
+    fn default() -> Self {
+        Self {
+            numerator: 3000,
+            offset: 500,
+            reference_bandwidth: 100_000_000, // 100 Mbps
+            bandwidth_bonus: 500,
+        }
+    }
+}
+
+impl BandwidthAwarePolicy {
+    /// Weighted distance of `peer` to `target`
+    pub fn distance(&self, peer: &PeerInfo, target: &DeviceID) -> WeightedDistance {
+        // Scale by the reference bandwidth so the bonus stays an exact integer
+        let reference = self.reference_bandwidth.max(1);
+        let bandwidth = peer.available_bandwidth.min(reference);
+        let denominator = (peer.reputation.value() + self.offset)
+            .saturating_mul(reference)
+            .saturating_add(self.bandwidth_bonus.saturating_mul(bandwidth));
+        WeightedDistance::new(
+            &calculate_raw_xor_distance(&peer.device_id, target),
+            self.numerator.saturating_mul(reference),
+            denominator,
+        )
+    }
+}
+
+impl DistancePolicy for BandwidthAwarePolicy {
+    fn name(&self) -> &'static str {
+        "bandwidth-aware"
+    }
+    
+    fn compare(&self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering {
+        self.distance(a, target).cmp(&self.distance(b, target))
+    }
+}
+
+/// Built-in distance policies, with default parameters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DistancePolicyKind {
+    /// [`RawXorPolicy`]
+    RawXor,
+    /// [`HyperbolicPolicy`]
+    #[default]
+    Hyperbolic,
+    /// [`BucketPreservingPolicy`]
+    BucketPreserving,
+    /// [`BandwidthAwarePolicy`]
+    BandwidthAware,
+}
+
+impl DistancePolicyKind {
+    /// Every built-in policy
+    pub const ALL: [DistancePolicyKind; 4] = [
+        DistancePolicyKind::RawXor,
+        DistancePolicyKind::Hyperbolic,
+        DistancePolicyKind::BucketPreserving,
+        DistancePolicyKind::BandwidthAware,
+    ];
+    
+    /// Instantiate the policy
+    pub fn policy(self) -> Arc<dyn DistancePolicy> {
+        match self {
+            DistancePolicyKind::RawXor => Arc::new(RawXorPolicy),
+            DistancePolicyKind::Hyperbolic => Arc::new(HyperbolicPolicy::default()),
+            DistancePolicyKind::BucketPreserving => Arc::new(BucketPreservingPolicy::default()),
+            DistancePolicyKind::BandwidthAware => Arc::new(BandwidthAwarePolicy::default()),
+        }
+    }
+}
+
+impl fmt::Display for DistancePolicyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.policy().name())
+    }
+}
+
+impl FromStr for DistancePolicyKind {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|kind| kind.policy().name() == s)
+            .ok_or_else(|| format!("Unknown distance policy: {}", s))
+    }
+}
+
+/// Number of leading zero bits in an XOR distance
+fn common_prefix_len(distance: &[u8; 32]) -> u32 {
+    let mut len = 0;
+    for byte in distance {
+        len += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    len
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use quickcheck_macros::quickcheck;
     
     #[test]
     fn test_logical_distance_calculation() {
@@ -116,7 +363,8 @@ mod tests {
         let reputation = ReputationScore::new(500);
         
         let distance = calculate_logical_distance(&node_id, &target_id, reputation);
-        assert_ne!(distance, [0u8; 32]);
+        assert!(!distance.is_zero());
+        assert!(calculate_logical_distance(&node_id, &node_id, reputation).is_zero());
     }
     
     #[test]
@@ -131,7 +379,7 @@ mod tests {
         let high_dist = calculate_logical_distance(&node_id, &target_id, high_rep);
         
         // High reputation should have shorter effective distance
-        assert!(compare_raw_distances(&high_dist, &low_dist) == Ordering::Less);
+        assert!(high_dist < low_dist);
     }
     
     #[test]
@@ -145,6 +393,7 @@ mod tests {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 0,
+            identity_proof: None,
         };
         
         let peer2 = PeerInfo {
@@ -154,6 +403,7 @@ mod tests {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 0,
+            identity_proof: None,
         };
         
         let mut peers = vec![peer1.clone(), peer2.clone()];
@@ -187,4 +437,116 @@ mod tests {
         let distance = calculate_raw_xor_distance(&a, &b);
         assert_eq!(distance, [0xffu8; 32]);
     }
+
+    /// Device ID built from four quickcheck-generated limbs
+    fn device_id((a, b, c, d): (u64, u64, u64, u64)) -> DeviceID {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_mut(8).zip([a, b, c, d]) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        DeviceID::new(bytes)
+    }
+
+    fn reputation(value: u16) -> ReputationScore {
+        ReputationScore::new(value as u64 % (ReputationScore::MAX.value() + 1))
+    }
+
+    fn peer(id: DeviceID, rep: ReputationScore) -> PeerInfo {
+        PeerInfo {
+            peer_id: PeerID::new(id.to_hex()),
+            device_id: id,
+            reputation: rep,
+            role: NodeRole::Idle,
+            addresses: vec![],
+            available_bandwidth: 0,
+            identity_proof: None,
+        }
+    }
+
+    #[test]
+    fn test_low_order_bits_are_kept() {
+        // Ids differing only in their last byte used to weigh the same
+        let target = DeviceID::new([0u8; 32]);
+        let mut near = [0u8; 32];
+        near[31] = 1;
+        let mut far = [0u8; 32];
+        far[31] = 2;
+        let rep = ReputationScore::DEFAULT;
+        assert!(
+            calculate_logical_distance(&DeviceID::new(near), &target, rep)
+                < calculate_logical_distance(&DeviceID::new(far), &target, rep)
+        );
+    }
+
+    #[quickcheck]
+    fn prop_monotonic_in_reputation(node: (u64, u64, u64, u64), target: (u64, u64, u64, u64), a: u16, b: u16) -> bool {
+        let (node, target) = (device_id(node), device_id(target));
+        let (low, high) = (reputation(a).min(reputation(b)), reputation(a).max(reputation(b)));
+        calculate_logical_distance(&node, &target, high) <= calculate_logical_distance(&node, &target, low)
+    }
+
+    #[quickcheck]
+    fn prop_equal_reputation_matches_raw_xor(a: (u64, u64, u64, u64), b: (u64, u64, u64, u64), target: (u64, u64, u64, u64), rep: u16) -> bool {
+        let (a, b, target, rep) = (device_id(a), device_id(b), device_id(target), reputation(rep));
+        let weighted = calculate_logical_distance(&a, &target, rep).cmp(&calculate_logical_distance(&b, &target, rep));
+        let raw = compare_raw_distances(&calculate_raw_xor_distance(&a, &target), &calculate_raw_xor_distance(&b, &target));
+        weighted == raw
+    }
+
+    #[quickcheck]
+    fn prop_total_order_consistent_with_compare_by_distance(peers: Vec<((u64, u64, u64, u64), u16)>, target: (u64, u64, u64, u64)) -> bool {
+        let target = device_id(target);
+        let peers: Vec<PeerInfo> = peers.into_iter().take(16).map(|(id, rep)| peer(device_id(id), reputation(rep))).collect();
+        let distance = |p: &PeerInfo| calculate_logical_distance(&p.device_id, &target, p.reputation);
+        peers.iter().all(|a| {
+            peers.iter().all(|b| {
+                let ord = compare_by_distance(a, b, &target);
+                ord == distance(a).cmp(&distance(b))
+                    && ord == compare_by_distance(b, a, &target).reverse()
+                    && peers.iter().all(|c| {
+                        ord == Ordering::Greater
+                            || compare_by_distance(b, c, &target) == Ordering::Greater
+                            || compare_by_distance(a, c, &target) != Ordering::Greater
+                    })
+            })
+        })
+    }
+    
+    #[quickcheck]
+    fn prop_bucket_preserving_keeps_bucket_order(a: ((u64, u64, u64, u64), u16), b: ((u64, u64, u64, u64), u16), target: (u64, u64, u64, u64)) -> bool {
+        let target = device_id(target);
+        let (a, b) = (peer(device_id(a.0), reputation(a.1)), peer(device_id(b.0), reputation(b.1)));
+        let prefix = |p: &PeerInfo| common_prefix_len(&calculate_raw_xor_distance(&p.device_id, &target));
+        let ord = BucketPreservingPolicy::default().compare(&a, &b, &target);
+        if prefix(&a) == prefix(&b) {
+            ord == HyperbolicPolicy::default().compare(&a, &b, &target)
+        } else {
+            ord == RawXorPolicy.compare(&a, &b, &target)
+        }
+    }
+    
+    #[test]
+    fn test_bandwidth_counts_as_reputation() {
+        let policy = BandwidthAwarePolicy::default();
+        let target = DeviceID::new([0u8; 32]);
+        let id = DeviceID::new([1u8; 32]);
+        
+        let mut fast = peer(id, ReputationScore::new(100));
+        fast.available_bandwidth = policy.reference_bandwidth * 2;
+        let slow = peer(id, ReputationScore::new(100 + policy.bandwidth_bonus));
+        assert_eq!(policy.compare(&fast, &slow, &target), Ordering::Equal);
+        
+        let mut idle = fast.clone();
+        idle.available_bandwidth = policy.reference_bandwidth / 2;
+        assert_eq!(policy.compare(&fast, &idle, &target), Ordering::Less);
+    }
+    
+    #[test]
+    fn test_policy_kind_names() {
+        for kind in DistancePolicyKind::ALL {
+            assert_eq!(kind.to_string().parse::<DistancePolicyKind>(), Ok(kind));
+        }
+        assert_eq!(DistancePolicyKind::default().policy().name(), "hyperbolic");
+        assert!("manhattan".parse::<DistancePolicyKind>().is_err());
+    }
 }
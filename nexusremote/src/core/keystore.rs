@@ -0,0 +1,511 @@
+//! Persistent, passphrase-encrypted storage for the node identity
+
+use crate::core::crypto::{node_id_from_public_key, NodeKeypair};
+use crate::Error;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Current keystore file format version
+pub const KEYSTORE_VERSION: u32 = 1;
+
+/// Identity file name inside the data directory
+pub const IDENTITY_FILE: &str = "identity.json";
+
+/// Environment variable overriding the default data directory
+pub const DATA_DIR_ENV: &str = "NEXUSREMOTE_DATA_DIR";
+
+/// Environment variable holding the keystore passphrase for non-interactive use
+pub const PASSPHRASE_ENV: &str = "NEXUSREMOTE_PASSPHRASE";
+
+/// Argon2id parameters used to derive the encryption key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory cost (KiB)
+    pub memory_kib: u32,
+    /// Number of iterations
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Largest memory cost accepted from a keystore file (256 MiB)
+    pub const MAX_MEMORY_KIB: u32 = 256 * 1024;
+    /// Largest iteration count accepted from a keystore file
+    pub const MAX_ITERATIONS: u32 = 16;
+    /// Largest degree of parallelism accepted from a keystore file
+    pub const MAX_PARALLELISM: u32 = 16;
+
+    /// Reject parameters that would make key derivation unreasonably expensive
+    ///
+    /// The parameters come from the file itself, so they are checked before
+    /// any memory is allocated for Argon2.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.memory_kib > Self::MAX_MEMORY_KIB
+            || self.iterations > Self::MAX_ITERATIONS
+            || self.parallelism > Self::MAX_PARALLELISM
+        {
+            return Err(Error::Crypto(format!(
+                "KDF parameters exceed the supported maximum (memory {} KiB, {} iterations, parallelism {})",
+                self.memory_kib, self.iterations, self.parallelism
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// On-disk keystore format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeystoreFile {
+    /// File format version
+    pub version: u32,
+    /// Key derivation function name
+    pub kdf: String,
+    /// Key derivation parameters
+    pub kdf_params: KdfParams,
+    /// KDF salt (hex)
+    pub salt: String,
+    /// Cipher name
+    pub cipher: String,
+    /// AEAD nonce (hex)
+    pub nonce: String,
+    /// Encrypted secret key (hex)
+    pub ciphertext: String,
+    /// Public key (hex), also bound as associated data
+    pub public_key: String,
+    /// Device ID (hex)
+    pub device_id: String,
+    /// Creation timestamp
+    pub created_at: u64,
+}
+
+/// Public part of a stored identity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicIdentity {
+    /// Public key (hex)
+    pub public_key: String,
+    /// Device ID (hex)
+    pub device_id: String,
+    /// Creation timestamp
+    pub created_at: u64,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Error> {
+    params.check()?;
+    let argon_params = Params::new(params.memory_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(|e| Error::Crypto(format!("Invalid KDF parameters: {}", e)))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn decode_hex<const N: usize>(field: &str, value: &str) -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    hex::decode_to_slice(value, &mut bytes)
+        .map_err(|e| Error::Serialization(format!("Invalid {} in keystore: {}", field, e)))?;
+    Ok(bytes)
+}
+
+/// Check that the file's device ID is the one its public key derives
+fn check_identity(file: &KeystoreFile) -> Result<[u8; 32], Error> {
+    let public_key: [u8; 32] = decode_hex("public key", &file.public_key)?;
+    if node_id_from_public_key(&public_key).to_hex() != file.device_id {
+        return Err(Error::Crypto("Keystore device ID does not match its public key".to_string()));
+    }
+    Ok(public_key)
+}
+
+/// Encrypt a keypair with a passphrase
+pub fn encrypt_keypair(keypair: &NodeKeypair, passphrase: &str, params: KdfParams) -> Result<KeystoreFile, Error> {
+    let salt: [u8; 16] = rand::random();
+    let nonce: [u8; 12] = rand::random();
+    let key = derive_key(passphrase, &salt, &params)?;
+    let public_key = keypair.public_key().to_bytes();
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: keypair.secret_key().as_bytes(), aad: &public_key })
+        .map_err(|_| Error::Crypto("Keystore encryption failed".to_string()))?;
+
+    Ok(KeystoreFile {
+        version: KEYSTORE_VERSION,
+        kdf: "argon2id".to_string(),
+        kdf_params: params,
+        salt: hex::encode(salt),
+        cipher: "chacha20poly1305".to_string(),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+        public_key: hex::encode(public_key),
+        device_id: keypair.node_id().to_hex(),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    })
+}
+
+/// Decrypt a keypair with a passphrase
+pub fn decrypt_keypair(file: &KeystoreFile, passphrase: &str) -> Result<NodeKeypair, Error> {
+    if !(1..=KEYSTORE_VERSION).contains(&file.version) {
+        return Err(Error::Crypto(format!(
+            "Unsupported keystore version {} (this build supports 1 to {})",
+            file.version, KEYSTORE_VERSION
+        )));
+    }
+    if file.kdf != "argon2id" || file.cipher != "chacha20poly1305" {
+        return Err(Error::Crypto(format!("Unsupported keystore scheme {}/{}", file.kdf, file.cipher)));
+    }
+
+    let salt: [u8; 16] = decode_hex("salt", &file.salt)?;
+    let nonce: [u8; 12] = decode_hex("nonce", &file.nonce)?;
+    let public_key = check_identity(file)?;
+    let ciphertext = hex::decode(&file.ciphertext)
+        .map_err(|e| Error::Serialization(format!("Invalid ciphertext in keystore: {}", e)))?;
+
+    let key = derive_key(passphrase, &salt, &file.kdf_params)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let secret = cipher
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: &public_key })
+        .map_err(|_| Error::Crypto("Wrong passphrase or corrupted keystore".to_string()))?;
+
+    let secret: [u8; 32] = secret.try_into()
+        .map_err(|_| Error::Crypto("Invalid secret key length".to_string()))?;
+    let keypair = NodeKeypair::from_secret_key(&secret)?;
+    if keypair.public_key().to_bytes() != public_key {
+        return Err(Error::Crypto("Keystore public key does not match secret key".to_string()));
+    }
+
+    Ok(keypair)
+}
+
+/// Keystore rooted at a data directory
+#[derive(Debug, Clone)]
+pub struct Keystore {
+    data_dir: PathBuf,
+    kdf_params: KdfParams,
+}
+
+impl Keystore {
+    /// Create a keystore in the given data directory
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            kdf_params: KdfParams::default(),
+        }
+    }
+
+    /// Create a keystore in the default data directory
+    pub fn open_default() -> Self {
+        Self::new(Self::default_data_dir())
+    }
+
+    /// Default data directory (`$NEXUSREMOTE_DATA_DIR` or `~/.nexusremote`)
+    pub fn default_data_dir() -> PathBuf {
+        if let Ok(dir) = std::env::var(DATA_DIR_ENV) {
+            return PathBuf::from(dir);
+        }
+        let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        Path::new(&home).join(".nexusremote")
+    }
+
+    /// Use custom KDF parameters for newly written files
+    pub fn with_kdf_params(mut self, params: KdfParams) -> Self {
+        self.kdf_params = params;
+        self
+    }
+
+    /// Data directory
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Path of the identity file
+    pub fn path(&self) -> PathBuf {
+        self.data_dir.join(IDENTITY_FILE)
+    }
+
+    /// Whether an identity has been stored
+    pub fn exists(&self) -> bool {
+        self.path().exists()
+    }
+
+    /// Generate and store a new identity; fails if one already exists
+    pub fn create(&self, passphrase: &str) -> Result<NodeKeypair, Error> {
+        if self.exists() {
+            return Err(Error::Other(format!("Identity already exists at {}", self.path().display())));
+        }
+        let keypair = NodeKeypair::generate();
+        self.save(&keypair, passphrase)?;
+        info!("Created identity {}", keypair.node_id());
+        Ok(keypair)
+    }
+
+    /// Encrypt and write a keypair, replacing any stored identity
+    pub fn save(&self, keypair: &NodeKeypair, passphrase: &str) -> Result<(), Error> {
+        let file = encrypt_keypair(keypair, passphrase, self.kdf_params)?;
+        self.write_file(&file)
+    }
+
+    /// Load and decrypt the stored identity
+    pub fn load(&self, passphrase: &str) -> Result<NodeKeypair, Error> {
+        decrypt_keypair(&self.read_file()?, passphrase)
+    }
+
+    /// Load the stored identity, creating one on first use
+    pub fn load_or_create(&self, passphrase: &str) -> Result<NodeKeypair, Error> {
+        if self.exists() {
+            self.load(passphrase)
+        } else {
+            self.create(passphrase)
+        }
+    }
+
+    /// Read the public part of the stored identity (no passphrase needed)
+    pub fn public_identity(&self) -> Result<PublicIdentity, Error> {
+        let file = self.read_file()?;
+        check_identity(&file)?;
+        Ok(PublicIdentity {
+            public_key: file.public_key,
+            device_id: file.device_id,
+            created_at: file.created_at,
+        })
+    }
+
+    /// Export the encrypted identity file contents
+    pub fn export(&self) -> Result<String, Error> {
+        let file = self.read_file()?;
+        Ok(serde_json::to_string_pretty(&file)?)
+    }
+
+    /// Import an exported identity after checking it decrypts with `passphrase`
+    ///
+    /// Any existing identity is archived first.
+    pub fn import(&self, exported: &str, passphrase: &str) -> Result<NodeKeypair, Error> {
+        let file: KeystoreFile = serde_json::from_str(exported)?;
+        let keypair = decrypt_keypair(&file, passphrase)?;
+        self.replace_file(&file)?;
+        Ok(keypair)
+    }
+
+    /// Replace the stored identity with a fresh one, archiving the old file
+    ///
+    /// The current passphrase must be correct.
+    pub fn rotate(&self, passphrase: &str) -> Result<NodeKeypair, Error> {
+        let old = self.load(passphrase)?;
+        let keypair = NodeKeypair::generate();
+        let archived = self.replace_file(&encrypt_keypair(&keypair, passphrase, self.kdf_params)?)?;
+        if let Some(archived) = archived {
+            info!("Rotated identity {} -> {} (old key archived at {})", old.node_id(), keypair.node_id(), archived.display());
+        }
+        Ok(keypair)
+    }
+
+    /// Swap in a new identity file, archiving any current one
+    ///
+    /// The new file is written before the old one is archived, and the final
+    /// rename means there is never a moment without an identity on disk.
+    fn replace_file(&self, file: &KeystoreFile) -> Result<Option<PathBuf>, Error> {
+        let staged = self.stage_file(file)?;
+        let archived = match self.exists().then(|| self.archive()).transpose() {
+            Ok(archived) => archived,
+            Err(e) => {
+                let _ = fs::remove_file(&staged);
+                return Err(e);
+            }
+        };
+        fs::rename(&staged, self.path())?;
+        Ok(archived)
+    }
+
+    /// Copy the current identity file aside, returning the copy's path
+    fn archive(&self) -> Result<PathBuf, Error> {
+        let file = self.read_file()?;
+        let archived = self.data_dir.join(format!(
+            "identity-{}-{}.json.bak",
+            &file.device_id[..16.min(file.device_id.len())],
+            file.created_at
+        ));
+        fs::copy(self.path(), &archived)?;
+        Ok(archived)
+    }
+
+    fn read_file(&self) -> Result<KeystoreFile, Error> {
+        let contents = fs::read_to_string(self.path())?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn write_file(&self, file: &KeystoreFile) -> Result<(), Error> {
+        let staged = self.stage_file(file)?;
+        fs::rename(&staged, self.path())?;
+        Ok(())
+    }
+
+    /// Write `file` next to the identity without replacing it yet
+    fn stage_file(&self, file: &KeystoreFile) -> Result<PathBuf, Error> {
+        fs::create_dir_all(&self.data_dir)?;
+        let contents = serde_json::to_vec_pretty(file)?;
+
+        // Write to a temporary file first so a crash never leaves a truncated identity;
+        // its name is our own, so concurrent writers never share one
+        let tmp = self.data_dir.join(format!(
+            "{}.{}.{}.tmp",
+            IDENTITY_FILE,
+            std::process::id(),
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut out = options.open(&tmp)?;
+        if let Err(e) = out.write_all(&contents).and_then(|()| out.sync_all()) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(tmp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_keystore() -> Keystore {
+        let dir = std::env::temp_dir().join(format!("nexusremote-keystore-{}", hex::encode(rand::random::<[u8; 8]>())));
+        Keystore::new(dir).with_kdf_params(KdfParams {
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+    }
+
+    #[test]
+    fn test_create_and_load() {
+        let keystore = test_keystore();
+        let created = keystore.create("correct horse").unwrap();
+        let loaded = keystore.load("correct horse").unwrap();
+
+        assert_eq!(created.node_id(), loaded.node_id());
+        assert!(keystore.create("correct horse").is_err());
+        assert_eq!(keystore.public_identity().unwrap().device_id, created.node_id().to_hex());
+
+        fs::remove_dir_all(keystore.data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let keystore = test_keystore();
+        keystore.create("right").unwrap();
+
+        assert!(matches!(keystore.load("wrong"), Err(Error::Crypto(_))));
+
+        fs::remove_dir_all(keystore.data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_versions_rejected() {
+        let keypair = NodeKeypair::generate();
+        let mut file = encrypt_keypair(&keypair, "pass", KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 }).unwrap();
+        assert!(decrypt_keypair(&file, "pass").is_ok());
+
+        file.version = KEYSTORE_VERSION + 1;
+        assert!(decrypt_keypair(&file, "pass").is_err());
+        file.version = 0;
+        assert!(decrypt_keypair(&file, "pass").is_err());
+    }
+
+    #[test]
+    fn test_oversized_kdf_params_rejected() {
+        let keypair = NodeKeypair::generate();
+        let mut file = encrypt_keypair(&keypair, "pass", KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 }).unwrap();
+
+        file.kdf_params.memory_kib = KdfParams::MAX_MEMORY_KIB + 1;
+        assert!(matches!(decrypt_keypair(&file, "pass"), Err(Error::Crypto(_))));
+
+        file.kdf_params.memory_kib = 64;
+        file.kdf_params.iterations = u32::MAX;
+        let exported = serde_json::to_string(&file).unwrap();
+        let keystore = test_keystore();
+        assert!(keystore.import(&exported, "pass").is_err());
+        assert!(!keystore.exists());
+    }
+
+    #[test]
+    fn test_mismatched_identity_rejected() {
+        let keystore = test_keystore();
+        keystore.create("pass").unwrap();
+        let mut file = keystore.read_file().unwrap();
+        file.device_id = NodeKeypair::generate().node_id().to_hex();
+        keystore.write_file(&file).unwrap();
+
+        assert!(keystore.public_identity().is_err());
+        assert!(keystore.load("pass").is_err());
+
+        fs::remove_dir_all(keystore.data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_export_import_and_rotate() {
+        let source = test_keystore();
+        let original = source.create("pass").unwrap();
+        let exported = source.export().unwrap();
+
+        let target = test_keystore();
+        assert!(target.import(&exported, "wrong").is_err());
+        let imported = target.import(&exported, "pass").unwrap();
+        assert_eq!(imported.node_id(), original.node_id());
+
+        let rotated = target.rotate("pass").unwrap();
+        assert_ne!(rotated.node_id(), original.node_id());
+        assert_eq!(target.load("pass").unwrap().node_id(), rotated.node_id());
+
+        // The old identity is kept as an archive
+        let archives = fs::read_dir(target.data_dir()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(archives, 1);
+
+        fs::remove_dir_all(source.data_dir()).unwrap();
+        fs::remove_dir_all(target.data_dir()).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_archives_the_old_identity_intact() {
+        let keystore = test_keystore();
+        let original = keystore.create("pass").unwrap();
+        let rotated = keystore.rotate("pass").unwrap();
+
+        let names: Vec<String> = fs::read_dir(keystore.data_dir()).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(!names.iter().any(|n| n.ends_with(".tmp")));
+        let archive = names.iter().find(|n| n.ends_with(".bak")).unwrap();
+        let archived: KeystoreFile = serde_json::from_str(&fs::read_to_string(keystore.data_dir().join(archive)).unwrap()).unwrap();
+        assert_eq!(decrypt_keypair(&archived, "pass").unwrap().node_id(), original.node_id());
+        assert_eq!(keystore.load("pass").unwrap().node_id(), rotated.node_id());
+
+        fs::remove_dir_all(keystore.data_dir()).unwrap();
+    }
+}
@@ -4,8 +4,14 @@ pub mod types;
 pub mod crypto;
 pub mod distance;
 pub mod state;
+pub mod keystore;
+pub mod receipt;
+pub mod reputation;
 
 pub use types::*;
 pub use crypto::*;
 pub use distance::*;
 pub use state::*;
+pub use keystore::*;
+pub use receipt::*;
+pub use reputation::*;
@@ -0,0 +1,213 @@
+//! Canonical encoding, co-signing and verification of relay receipts
+
+use crate::core::crypto::{verify_signature, NodeKeypair};
+use crate::core::types::*;
+
+/// Domain separator for receipt signatures
+const RECEIPT_DOMAIN: &[u8] = b"nexusremote-receipt-v1";
+
+/// Bytes per megabyte used for relay pricing
+const BYTES_PER_MB: u128 = 1024 * 1024;
+
+/// Reasons a relay receipt is rejected
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ReceiptError {
+    /// One or both signatures are missing
+    #[error("receipt is not signed by both relay and client")]
+    Unsigned,
+
+    /// The relay signature does not verify
+    #[error("invalid relay signature")]
+    InvalidRelaySignature,
+
+    /// The client signature does not verify
+    #[error("invalid client signature")]
+    InvalidClientSignature,
+
+    /// The relay co-signed its own receipt as client
+    #[error("relay and client keys are identical")]
+    SelfSigned,
+
+    /// The receipt was issued to a different relay
+    #[error("receipt was issued to a different relay")]
+    WrongRelay,
+
+    /// The relay has no open payment channel with the receipt's client
+    #[error("no open payment channel with the receipt's client")]
+    NoChannel,
+
+    /// The client's channel payments do not cover the receipt
+    #[error("claimed {claimed} but the client's channel only covers {available}")]
+    Unfunded {
+        /// Amount claimed in the receipt
+        claimed: TokenAmount,
+        /// Channel payments not yet claimed by other receipts
+        available: TokenAmount,
+    },
+
+    /// The session was already redeemed
+    #[error("receipt for session {0} was already redeemed")]
+    Replayed(String),
+
+    /// The claimed amount exceeds what the relayed data is worth
+    #[error("claimed {claimed} but relayed data is worth at most {max}")]
+    AmountInflated {
+        /// Amount claimed in the receipt
+        claimed: TokenAmount,
+        /// Maximum amount for the relayed data
+        max: TokenAmount,
+    },
+}
+
+/// Amount owed for relaying `data_relayed` bytes at `tokens_per_mb`
+pub fn receipt_amount(data_relayed: u64, tokens_per_mb: TokenAmount) -> TokenAmount {
+    TokenAmount::new((data_relayed as u128).saturating_mul(tokens_per_mb.value()) / BYTES_PER_MB)
+}
+
+impl SignedReceipt {
+    /// Canonical byte encoding covered by both signatures
+    ///
+    /// Fixed-width big-endian fields behind a domain tag, so the encoding is
+    /// stable across serializers. Both keys are included: the client's
+    /// signature commits to which relay it is paying, and the relay's to
+    /// which client owes it.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RECEIPT_DOMAIN.len() + 32 + 8 + 8 + 16 + 8 + 32 + 32);
+        bytes.extend_from_slice(RECEIPT_DOMAIN);
+        bytes.extend_from_slice(&self.session_id);
+        bytes.extend_from_slice(&self.data_relayed.to_be_bytes());
+        bytes.extend_from_slice(&self.duration.to_be_bytes());
+        bytes.extend_from_slice(&self.amount.value().to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.relay_public_key);
+        bytes.extend_from_slice(&self.client_public_key);
+        bytes
+    }
+
+    /// Sign the receipt as the relay
+    pub fn sign_as_relay(&mut self, keypair: &NodeKeypair) {
+        self.relay_public_key = keypair.public_key().to_bytes();
+        self.relay_signature = keypair.sign(&self.signing_bytes());
+    }
+
+    /// Co-sign the receipt as the client
+    ///
+    /// The client key is the one the relay recorded when it admitted the
+    /// session, so only that client's signature verifies.
+    pub fn sign_as_client(&mut self, keypair: &NodeKeypair) {
+        self.client_signature = keypair.sign(&self.signing_bytes());
+    }
+
+    /// Whether both parties have signed
+    pub fn is_signed(&self) -> bool {
+        !self.relay_signature.is_empty() && !self.client_signature.is_empty()
+    }
+
+    /// Verify both signatures
+    pub fn verify_signatures(&self) -> Result<(), ReceiptError> {
+        if !self.is_signed() {
+            return Err(ReceiptError::Unsigned);
+        }
+        if self.relay_public_key == self.client_public_key {
+            return Err(ReceiptError::SelfSigned);
+        }
+
+        let message = self.signing_bytes();
+        if !verify_signature(&self.relay_public_key, &message, &self.relay_signature) {
+            return Err(ReceiptError::InvalidRelaySignature);
+        }
+        if !verify_signature(&self.client_public_key, &message, &self.client_signature) {
+            return Err(ReceiptError::InvalidClientSignature);
+        }
+        Ok(())
+    }
+
+    /// Check the claimed amount against the relayed data
+    pub fn verify_amount(&self, tokens_per_mb: TokenAmount) -> Result<(), ReceiptError> {
+        let max = receipt_amount(self.data_relayed, tokens_per_mb);
+        if self.amount > max {
+            return Err(ReceiptError::AmountInflated { claimed: self.amount, max });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsigned_receipt(client: &NodeKeypair) -> SignedReceipt {
+        SignedReceipt {
+            session_id: [7u8; 32],
+            data_relayed: 5 * 1024 * 1024,
+            duration: 60,
+            amount: TokenAmount::new(5),
+            relay_public_key: [0u8; 32],
+            client_public_key: client.public_key().to_bytes(),
+            relay_signature: vec![],
+            client_signature: vec![],
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn test_co_signed_receipt_verifies() {
+        let relay = NodeKeypair::generate();
+        let client = NodeKeypair::generate();
+
+        let mut receipt = unsigned_receipt(&client);
+        assert_eq!(receipt.verify_signatures(), Err(ReceiptError::Unsigned));
+
+        receipt.sign_as_relay(&relay);
+        assert_eq!(receipt.verify_signatures(), Err(ReceiptError::Unsigned));
+
+        receipt.sign_as_client(&client);
+        assert_eq!(receipt.verify_signatures(), Ok(()));
+    }
+
+    #[test]
+    fn test_tampering_breaks_signatures() {
+        let relay = NodeKeypair::generate();
+        let client = NodeKeypair::generate();
+
+        let mut receipt = unsigned_receipt(&client);
+        receipt.sign_as_relay(&relay);
+        receipt.sign_as_client(&client);
+
+        receipt.data_relayed += 1;
+        assert_eq!(receipt.verify_signatures(), Err(ReceiptError::InvalidRelaySignature));
+    }
+
+    #[test]
+    fn test_client_key_is_bound_by_both_signatures() {
+        let relay = NodeKeypair::generate();
+        let client = NodeKeypair::generate();
+        let throwaway = NodeKeypair::generate();
+
+        // Only the client recorded for the session can co-sign
+        let mut receipt = unsigned_receipt(&client);
+        receipt.sign_as_relay(&relay);
+        receipt.sign_as_client(&throwaway);
+        assert_eq!(receipt.verify_signatures(), Err(ReceiptError::InvalidClientSignature));
+
+        // Swapping in another client key afterwards breaks the relay signature
+        receipt.sign_as_client(&client);
+        receipt.client_public_key = throwaway.public_key().to_bytes();
+        receipt.client_signature = throwaway.sign(&receipt.signing_bytes());
+        assert_eq!(receipt.verify_signatures(), Err(ReceiptError::InvalidRelaySignature));
+    }
+
+    #[test]
+    fn test_amount_check() {
+        let client = NodeKeypair::generate();
+        let receipt = unsigned_receipt(&client);
+        assert!(receipt.verify_amount(TokenAmount::new(1)).is_ok());
+
+        let mut inflated = unsigned_receipt(&client);
+        inflated.amount = TokenAmount::new(6);
+        assert_eq!(
+            inflated.verify_amount(TokenAmount::new(1)),
+            Err(ReceiptError::AmountInflated { claimed: TokenAmount::new(6), max: TokenAmount::new(5) })
+        );
+    }
+}
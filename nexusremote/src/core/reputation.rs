@@ -0,0 +1,240 @@
+//! Reputation model with decay, penalties and capped gains
+//!
+//! A node's own reputation drifts exponentially back toward a baseline, so
+//! a long-idle node loses its routing advantage and a penalised node slowly
+//! recovers. Completed relay sessions raise the score, but only by a bounded
+//! amount per time window; dropped sessions and rejected receipts lower it.
+//! Every change is kept in a bounded, serializable history.
+
+use crate::core::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Reputation model parameters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReputationConfig {
+    /// Score the reputation decays toward
+    pub baseline: ReputationScore,
+    /// Time for the distance to the baseline to halve (seconds)
+    pub half_life: u64,
+    /// Gain for a completed relay session
+    pub relay_reward: u64,
+    /// Penalty for a session we dropped
+    pub dropped_session_penalty: u64,
+    /// Penalty for a receipt that failed verification
+    pub failed_receipt_penalty: u64,
+    /// Length of the window gains are capped over (seconds)
+    pub gain_window: u64,
+    /// Maximum gain per window
+    pub max_gain_per_window: u64,
+    /// Changes kept in the history
+    pub max_history: usize,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            baseline: ReputationScore::DEFAULT,
+            half_life: 7 * 24 * 60 * 60, // 1 week
+            relay_reward: 1,
+            dropped_session_penalty: 10,
+            failed_receipt_penalty: 25,
+            gain_window: 60 * 60, // 1 hour
+            max_gain_per_window: 20,
+            max_history: 256,
+        }
+    }
+}
+
+/// Something that changes a node's reputation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReputationEvent {
+    /// A relay session completed and its receipt was redeemed
+    RelayCompleted {
+        /// Session ID
+        session_id: [u8; 32],
+    },
+    /// A relay session ended before completing
+    SessionDropped {
+        /// Session ID
+        session_id: [u8; 32],
+    },
+    /// A submitted receipt failed verification
+    ReceiptRejected {
+        /// Session ID
+        session_id: [u8; 32],
+    },
+}
+
+/// One entry of the reputation history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReputationChange {
+    /// When the event happened (unix seconds)
+    pub timestamp: u64,
+    /// What happened
+    pub event: ReputationEvent,
+    /// Score after decay, before the event
+    pub before: ReputationScore,
+    /// Score after the event
+    pub after: ReputationScore,
+}
+
+/// A node's own reputation over time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReputationModel {
+    config: ReputationConfig,
+    /// Exact score; `ReputationScore` is the rounded view
+    score: f64,
+    /// When decay was last applied
+    updated_at: u64,
+    window_start: u64,
+    window_gain: u64,
+    history: VecDeque<ReputationChange>,
+}
+
+impl Default for ReputationModel {
+    fn default() -> Self {
+        Self::new(ReputationConfig::default(), 0)
+    }
+}
+
+impl ReputationModel {
+    /// Start at the baseline
+    pub fn new(config: ReputationConfig, now: u64) -> Self {
+        Self {
+            score: config.baseline.value() as f64,
+            config,
+            updated_at: now,
+            window_start: now,
+            window_gain: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Model parameters
+    pub fn config(&self) -> &ReputationConfig {
+        &self.config
+    }
+
+    fn decayed(&self, now: u64) -> f64 {
+        let baseline = self.config.baseline.value() as f64;
+        if self.config.half_life == 0 {
+            return baseline;
+        }
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        baseline + (self.score - baseline) * 0.5f64.powf(elapsed / self.config.half_life as f64)
+    }
+
+    /// Current score, including decay up to `now`
+    pub fn score(&self, now: u64) -> ReputationScore {
+        to_score(self.decayed(now))
+    }
+
+    /// Apply decay up to `now`
+    pub fn decay(&mut self, now: u64) -> ReputationScore {
+        if now > self.updated_at {
+            self.score = self.decayed(now);
+            self.updated_at = now;
+        }
+        to_score(self.score)
+    }
+
+    /// Apply an event and return the new score
+    pub fn record(&mut self, event: ReputationEvent, now: u64) -> ReputationScore {
+        let before = self.decay(now);
+        match &event {
+            ReputationEvent::RelayCompleted { .. } => {
+                if now >= self.window_start + self.config.gain_window {
+                    self.window_start = now;
+                    self.window_gain = 0;
+                }
+                let gain = self.config.relay_reward
+                    .min(self.config.max_gain_per_window.saturating_sub(self.window_gain));
+                self.window_gain += gain;
+                self.score += gain as f64;
+            }
+            ReputationEvent::SessionDropped { .. } => self.score -= self.config.dropped_session_penalty as f64,
+            ReputationEvent::ReceiptRejected { .. } => self.score -= self.config.failed_receipt_penalty as f64,
+        }
+        self.score = self.score.clamp(ReputationScore::MIN.value() as f64, ReputationScore::MAX.value() as f64);
+        let after = to_score(self.score);
+
+        self.history.push_back(ReputationChange { timestamp: now, event, before, after });
+        while self.history.len() > self.config.max_history {
+            self.history.pop_front();
+        }
+        after
+    }
+
+    /// Recorded changes, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &ReputationChange> {
+        self.history.iter()
+    }
+}
+
+fn to_score(score: f64) -> ReputationScore {
+    ReputationScore::new(score.round().clamp(ReputationScore::MIN.value() as f64, ReputationScore::MAX.value() as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn completed(i: u8) -> ReputationEvent {
+        ReputationEvent::RelayCompleted { session_id: [i; 32] }
+    }
+
+    #[test]
+    fn test_decays_toward_baseline() {
+        let config = ReputationConfig { relay_reward: 50, max_gain_per_window: 1000, ..Default::default() };
+        let half_life = config.half_life;
+        let mut model = ReputationModel::new(config, 0);
+        for i in 0..8 {
+            model.record(completed(i), 0);
+        }
+        assert_eq!(model.score(0), ReputationScore::new(500));
+        // Idle nodes lose half their advantage every half-life
+        assert_eq!(model.score(half_life), ReputationScore::new(300));
+        assert_eq!(model.score(2 * half_life), ReputationScore::new(200));
+        assert_eq!(model.score(50 * half_life), ReputationScore::DEFAULT);
+
+        // Penalised nodes recover the same way
+        let mut model = ReputationModel::new(ReputationConfig::default(), 0);
+        model.record(ReputationEvent::ReceiptRejected { session_id: [0; 32] }, 0);
+        model.record(ReputationEvent::SessionDropped { session_id: [1; 32] }, 0);
+        assert_eq!(model.score(0), ReputationScore::new(65));
+        assert_eq!(model.decay(half_life), ReputationScore::new(83));
+    }
+
+    #[test]
+    fn test_gains_are_capped_per_window() {
+        let config = ReputationConfig { relay_reward: 5, max_gain_per_window: 12, ..Default::default() };
+        let mut model = ReputationModel::new(config, 0);
+        let scores: Vec<u64> = (0..4).map(|i| model.record(completed(i), 10).value()).collect();
+        assert_eq!(scores, vec![105, 110, 112, 112]);
+        // A new window allows gains again
+        assert_eq!(model.record(completed(4), HOUR + 10), ReputationScore::new(117));
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let config = ReputationConfig { max_history: 2, ..Default::default() };
+        let mut model = ReputationModel::new(config, 0);
+        model.record(completed(0), 1);
+        model.record(ReputationEvent::SessionDropped { session_id: [1; 32] }, 2);
+        model.record(completed(2), 3);
+
+        let history: Vec<_> = model.history().collect();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].event, ReputationEvent::SessionDropped { session_id: [1; 32] });
+        assert_eq!(history[0].before, ReputationScore::new(101));
+        assert_eq!(history[0].after, ReputationScore::new(91));
+
+        let json = serde_json::to_string(&model).unwrap();
+        let restored: ReputationModel = serde_json::from_str(&json).unwrap();
+        assert!(restored.history().eq(model.history()));
+        assert_eq!(restored.score(100), model.score(100));
+    }
+}
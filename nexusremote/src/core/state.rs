@@ -1,9 +1,12 @@
 //! Node state management
 
 use crate::core::types::*;
-use crate::core::crypto::NodeKeypair;
+use crate::core::crypto::{pow, NodeKeypair};
+use crate::core::reputation::{ReputationConfig, ReputationEvent, ReputationModel};
+use crate::Error;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 
 /// Node state
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,8 +18,11 @@ pub struct NodeState {
     pub keypair: Option<NodeKeypair>,
     /// Device ID
     pub device_id: DeviceID,
-    /// Reputation score
+    /// Reputation score, kept in step with `reputation_model`
     pub reputation: ReputationScore,
+    /// Decay, penalties and history behind `reputation`
+    #[serde(default)]
+    pub reputation_model: ReputationModel,
     /// Known peers
     pub known_peers: HashMap<PeerID, PeerInfo>,
     /// Active sessions
@@ -31,12 +37,17 @@ impl NodeState {
     /// Create a new node state
     pub fn new(keypair: NodeKeypair) -> Self {
         let device_id = keypair.node_id();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         
         Self {
             role: NodeRole::Idle,
             keypair: Some(keypair),
             device_id,
             reputation: ReputationScore::DEFAULT,
+            reputation_model: ReputationModel::new(ReputationConfig::default(), now),
             known_peers: HashMap::new(),
             active_sessions: Vec::new(),
             last_heartbeat: 0,
@@ -44,14 +55,47 @@ impl NodeState {
         }
     }
     
+    /// Load the state saved at `path` for `keypair`, or start fresh if there is none
+    pub fn load_or_new(path: &Path, keypair: NodeKeypair) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Self::new(keypair));
+        }
+        let mut state: NodeState = serde_json::from_slice(&std::fs::read(path)?)?;
+        if state.device_id != keypair.node_id() {
+            return Err(Error::Other(format!(
+                "State at {} belongs to {}, not {}",
+                path.display(), state.device_id, keypair.node_id()
+            )));
+        }
+        state.keypair = Some(keypair);
+        Ok(state)
+    }
+    
+    /// Write the state to `path`, replacing it atomically
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+    
     /// Get the keypair
     pub fn keypair(&self) -> Option<&NodeKeypair> {
         self.keypair.as_ref()
     }
     
-    /// Add a known peer
-    pub fn add_peer(&mut self, peer: PeerInfo) {
+    /// Add a known peer; its identity proof must meet the network difficulty
+    pub fn add_peer(&mut self, peer: PeerInfo) -> Result<(), Error> {
+        let proven = peer.identity_proof.as_ref()
+            .is_some_and(|proof| proof.verify(&peer.device_id, pow::IDENTITY_DIFFICULTY));
+        if !proven {
+            return Err(Error::Crypto(format!("Peer {} has no valid identity proof", peer.peer_id)));
+        }
         self.known_peers.insert(peer.peer_id.clone(), peer);
+        Ok(())
     }
     
     /// Remove a peer
@@ -62,7 +106,7 @@ impl NodeState {
     /// Get peers sorted by reputation (highest first)
     pub fn get_peers_by_reputation(&self) -> Vec<&PeerInfo> {
         let mut peers: Vec<_> = self.known_peers.values().collect();
-        peers.sort_by(|a, b| b.reputation.cmp(&a.reputation));
+        peers.sort_by_key(|p| std::cmp::Reverse(p.reputation));
         peers
     }
     
@@ -92,6 +136,26 @@ impl NodeState {
         self.reputation.decrease(delta);
     }
     
+    /// Apply a reputation event from the relay or wallet
+    pub fn record_reputation_event(&mut self, event: ReputationEvent, now: u64) -> ReputationScore {
+        self.reputation = self.reputation_model.record(event, now);
+        self.reputation
+    }
+    
+    /// Apply timestamped events, e.g. from `RelayManager::take_reputation_events`
+    pub fn apply_reputation_events(&mut self, events: impl IntoIterator<Item = (u64, ReputationEvent)>) -> ReputationScore {
+        for (now, event) in events {
+            self.record_reputation_event(event, now);
+        }
+        self.reputation
+    }
+    
+    /// Let the reputation decay toward its baseline
+    pub fn decay_reputation(&mut self, now: u64) -> ReputationScore {
+        self.reputation = self.reputation_model.decay(now);
+        self.reputation
+    }
+    
     /// Add an active session
     pub fn add_session(&mut self, session: SessionInfo) {
         self.active_sessions.push(session);
@@ -161,6 +225,35 @@ pub struct NetworkStats {
     pub successful_connections: u64,
     /// Number of failed connections
     pub failed_connections: u64,
+    /// Connections made straight to the peer
+    #[serde(default)]
+    pub direct_connections: u64,
+    /// Connections made through a circuit relay
+    #[serde(default)]
+    pub relayed_connections: u64,
+    /// Relayed connections upgraded to direct by hole punching
+    #[serde(default)]
+    pub hole_punch_successes: u64,
+    /// Hole punching attempts that left the connection on the relay
+    #[serde(default)]
+    pub hole_punch_failures: u64,
+}
+
+impl NetworkStats {
+    /// Add another set of counters to these
+    pub fn merge(&mut self, other: &NetworkStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.relay_sessions += other.relay_sessions;
+        self.total_relay_duration += other.total_relay_duration;
+        self.total_data_relayed += other.total_data_relayed;
+        self.successful_connections += other.successful_connections;
+        self.failed_connections += other.failed_connections;
+        self.direct_connections += other.direct_connections;
+        self.relayed_connections += other.relayed_connections;
+        self.hole_punch_successes += other.hole_punch_successes;
+        self.hole_punch_failures += other.hole_punch_failures;
+    }
 }
 
 /// Connection strategy when funds are insufficient
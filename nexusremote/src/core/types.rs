@@ -1,5 +1,6 @@
 //! Core type definitions for NexusRemote
 
+use crate::core::crypto::IdentityProof;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -55,7 +56,7 @@ impl fmt::Display for PeerID {
 }
 
 /// Token amount - represents NEXUS tokens
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct TokenAmount(pub u128);
 
 impl TokenAmount {
@@ -73,11 +74,13 @@ impl TokenAmount {
     }
     
     /// Add two token amounts
+    #[allow(clippy::should_implement_trait)]
     pub fn add(self, other: Self) -> Self {
         Self(self.0.saturating_add(other.0))
     }
     
     /// Subtract two token amounts
+    #[allow(clippy::should_implement_trait)]
     pub fn sub(self, other: Self) -> Option<Self> {
         self.0.checked_sub(other.0).map(Self)
     }
@@ -118,7 +121,7 @@ impl ReputationScore {
     
     /// Decrease reputation
     pub fn decrease(&mut self, delta: u64) {
-        self.0 = self.0.saturating_sub(delta).max(Self::MIN.0);
+        self.0 = self.0.saturating_sub(delta);
     }
 }
 
@@ -181,13 +184,33 @@ impl QualityPreset {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum InputEvent {
     /// Mouse movement
-    MouseMove { x: i32, y: i32 },
+    MouseMove {
+        /// Horizontal position
+        x: i32,
+        /// Vertical position
+        y: i32,
+    },
     /// Mouse button press/release
-    MouseButton { button: u8, pressed: bool },
+    MouseButton {
+        /// Button index
+        button: u8,
+        /// Whether the button is down
+        pressed: bool,
+    },
     /// Mouse wheel
-    MouseWheel { delta_x: i32, delta_y: i32 },
+    MouseWheel {
+        /// Horizontal scroll
+        delta_x: i32,
+        /// Vertical scroll
+        delta_y: i32,
+    },
     /// Key press/release
-    Key { key_code: u32, pressed: bool },
+    Key {
+        /// Platform key code
+        key_code: u32,
+        /// Whether the key is down
+        pressed: bool,
+    },
     /// Unicode character input
     Char(char),
 }
@@ -199,7 +222,8 @@ pub struct PeerInfo {
     pub peer_id: PeerID,
     /// Device ID
     pub device_id: DeviceID,
-    /// Reputation score
+    /// Reputation score as computed locally from attestations; routing code
+    /// replaces whatever a remote node put here
     pub reputation: ReputationScore,
     /// Current role
     pub role: NodeRole,
@@ -207,6 +231,9 @@ pub struct PeerInfo {
     pub addresses: Vec<String>,
     /// Available bandwidth (bps)
     pub available_bandwidth: u64,
+    /// Proof of work over the node's public key
+    #[serde(default)]
+    pub identity_proof: Option<IdentityProof>,
 }
 
 /// Signed receipt for relay service
@@ -220,6 +247,10 @@ pub struct SignedReceipt {
     pub duration: u64,
     /// Token amount earned
     pub amount: TokenAmount,
+    /// Relay peer public key
+    pub relay_public_key: [u8; 32],
+    /// Client peer public key
+    pub client_public_key: [u8; 32],
     /// Relay peer signature
     pub relay_signature: Vec<u8>,
     /// Client peer signature
@@ -0,0 +1,171 @@
+//! Daemon configuration file
+
+use crate::core::keystore::Keystore;
+use crate::network::relay::RelayConfig;
+use crate::network::transport::TransportConfig;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Name of the config file looked up in the data directory
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Name of the file `NodeState` is persisted to
+pub const STATE_FILE: &str = "state.json";
+
+/// Name of the file the wallet is persisted to
+pub const WALLET_FILE: &str = "wallet.json";
+
+/// Name of the file payment channel states are persisted to
+pub const CHANNELS_FILE: &str = "channels.json";
+
+/// Name of the control socket created in the data directory
+pub const SOCKET_FILE: &str = "node.sock";
+
+/// Node daemon configuration, read from TOML
+///
+/// Every field is optional; missing ones take their default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Data directory for the identity and node state
+    /// (defaults to `Keystore::default_data_dir`)
+    pub data_dir: Option<PathBuf>,
+    /// Multiaddrs to listen on
+    pub listen: Vec<String>,
+    /// Bootstrap peer multiaddrs ending in `/p2p/<peer id>`
+    pub bootstrap: Vec<String>,
+    /// Find peers on the local network with mDNS
+    pub mdns: bool,
+    /// Relay multiaddrs ending in `/p2p/<peer id>` to reserve a slot on,
+    /// for nodes that cannot be dialed directly
+    pub relays: Vec<String>,
+    /// Reserve a slot on the cheapest relay heard over gossip when none
+    /// of `relays` can be used
+    pub auto_relay: bool,
+    /// Whether to relay traffic for other peers
+    pub relay_enabled: bool,
+    /// Relay limits
+    pub relay: RelayConfig,
+    /// QUIC multiaddrs to take relay streams from clients on
+    pub relay_listen: Vec<String>,
+    /// Transport options
+    pub transport: TransportConfig,
+    /// Seconds to wait for relay sessions to finish on shutdown
+    pub drain_timeout: u64,
+    /// Seconds between maintenance rounds (discovery, reputation upkeep)
+    pub maintenance_interval: u64,
+    /// Unix socket for the control API (defaults to `node.sock` in the data directory)
+    pub rpc_socket: Option<PathBuf>,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: None,
+            listen: vec!["/ip4/0.0.0.0/udp/4001/quic-v1".to_string()],
+            bootstrap: Vec::new(),
+            mdns: true,
+            relays: Vec::new(),
+            auto_relay: false,
+            relay_enabled: false,
+            relay: RelayConfig::default(),
+            relay_listen: vec!["/ip4/0.0.0.0/udp/4002/quic-v1".to_string()],
+            transport: TransportConfig::default(),
+            drain_timeout: 30,
+            maintenance_interval: 60,
+            rpc_socket: None,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Parse a TOML config
+    pub fn from_toml(contents: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Read the config at `path`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Other(format!("Cannot read config {}: {}", path.display(), e)))?;
+        Self::from_toml(&contents)
+    }
+
+    /// Data directory in effect
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(Keystore::default_data_dir)
+    }
+
+    /// Path `NodeState` is persisted to
+    pub fn state_path(&self) -> PathBuf {
+        self.data_dir().join(STATE_FILE)
+    }
+
+    /// Path the wallet is persisted to
+    pub fn wallet_path(&self) -> PathBuf {
+        self.data_dir().join(WALLET_FILE)
+    }
+
+    /// Path payment channel states are persisted to
+    pub fn channels_path(&self) -> PathBuf {
+        self.data_dir().join(CHANNELS_FILE)
+    }
+
+    /// Path of the control socket
+    pub fn rpc_socket_path(&self) -> PathBuf {
+        self.rpc_socket.clone().unwrap_or_else(|| self.data_dir().join(SOCKET_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = DaemonConfig::from_toml(r#"
+            data_dir = "/var/lib/nexusremote"
+            listen = ["/ip4/0.0.0.0/udp/4002/quic-v1"]
+            relay_listen = []
+            bootstrap = ["/ip4/192.0.2.1/udp/4001/quic-v1/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"]
+            relay_enabled = true
+            mdns = false
+            auto_relay = true
+            drain_timeout = 5
+
+            [relay]
+            max_sessions = 4
+            min_reputation = 300
+            tokens_per_mb = 2
+
+            [transport]
+            prefer_ipv6 = false
+        "#).unwrap();
+
+        assert_eq!(config.state_path(), Path::new("/var/lib/nexusremote/state.json"));
+        assert_eq!(config.wallet_path(), Path::new("/var/lib/nexusremote/wallet.json"));
+        assert_eq!(config.rpc_socket_path(), Path::new("/var/lib/nexusremote/node.sock"));
+        assert_eq!(config.listen, vec!["/ip4/0.0.0.0/udp/4002/quic-v1"]);
+        assert_eq!(config.bootstrap.len(), 1);
+        assert!(config.relay_enabled && config.relay_listen.is_empty());
+        assert!(!config.mdns);
+        assert!(config.auto_relay && config.relays.is_empty());
+        assert_eq!(config.drain_timeout, 5);
+        assert_eq!(config.relay.max_sessions, 4);
+        assert_eq!(config.relay.min_reputation, ReputationScore::new(300));
+        assert_eq!(config.relay.tokens_per_mb, TokenAmount::new(2));
+        // Unset fields keep their defaults
+        assert_eq!(config.relay.max_bandwidth_per_session, RelayConfig::default().max_bandwidth_per_session);
+        assert!(!config.transport.prefer_ipv6);
+        assert_eq!(config.transport.connection_timeout, TransportConfig::default().connection_timeout);
+        assert_eq!(config.maintenance_interval, 60);
+    }
+
+    #[test]
+    fn test_rejects_unknown_types() {
+        assert!(DaemonConfig::from_toml("listen = 4001").is_err());
+        assert_eq!(DaemonConfig::from_toml("").unwrap().listen, DaemonConfig::default().listen);
+    }
+}
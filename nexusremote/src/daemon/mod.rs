@@ -0,0 +1,500 @@
+//! Long-running node daemon behind `nexusremote start`
+//!
+//! The daemon loads the node state saved by its last run, joins the DHT,
+//! announces its presence, serves the control API, and keeps the relay,
+//! wallet and reputation model in step until it is told to stop. Clients
+//! open relay streams over QUIC on `relay_listen`, and the forwarder dials
+//! the targets they name; sessions are paid for through the client's
+//! payment channel with us, and the receipts it co-signs are claimed into
+//! the wallet. On shutdown it stops taking relay sessions,
+//! gives the active ones `drain_timeout` seconds to finish, and saves the
+//! node state and wallet.
+
+pub mod config;
+pub mod node;
+pub mod rpc;
+
+pub use config::*;
+pub use node::*;
+pub use rpc::*;
+
+use crate::core::crypto::NodeKeypair;
+use crate::core::state::NodeState;
+use crate::core::types::*;
+use crate::network::discovery::{DiscoveryEvent, PeerDiscovery};
+use crate::network::forwarding::{ForwardingConfig, QuicConnector, RelayForwarder};
+use crate::network::libp2p_integration::{Libp2pDht, Libp2pDhtConfig};
+use crate::network::presence::{PresenceConfig, PresenceService};
+use crate::network::relay::RelayManager;
+use crate::network::scheduler::WfqScheduler;
+use crate::network::transport::QuicTransport;
+use crate::wallet::channel::ChannelManager;
+use crate::wallet::metering::{MeteringConfig, PaymentRequest};
+use crate::wallet::wallet::InMemoryWallet;
+use crate::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How often draining checks whether relay sessions have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A running node
+pub struct Daemon {
+    config: DaemonConfig,
+    node: NodeHandle,
+    discovery: PeerDiscovery,
+    presence: JoinHandle<()>,
+    /// Takes relay streams from clients, if `relay_listen` is set
+    relay_server: Option<JoinHandle<()>>,
+    rpc: RpcServer,
+    /// Whether our version notice reached anyone yet
+    version_announced: bool,
+}
+
+impl Daemon {
+    /// Load the saved node state and start every service
+    pub async fn start(config: DaemonConfig, keypair: NodeKeypair) -> Result<Self, Error> {
+        let mut state = NodeState::load_or_new(&config.state_path(), keypair.clone())?;
+        state.decay_reputation(now_secs());
+        state.set_role(if config.relay_enabled { NodeRole::Relay } else { NodeRole::Idle });
+        info!("Starting node {} with reputation {}", state.device_id, state.reputation);
+
+        let dht = Libp2pDht::new(&keypair, Libp2pDhtConfig {
+            mdns: config.mdns,
+            relay_server: config.relay_enabled,
+            max_circuits: config.relay.max_sessions,
+            ..Default::default()
+        })?;
+        let mut discovery = PeerDiscovery::new(dht.clone(), Vec::new());
+        let mut listen_addrs = Vec::new();
+        for addr in &config.listen {
+            let bound = dht.listen(addr).await?;
+            info!("Listening on {}", bound);
+            listen_addrs.push(bound);
+        }
+        let transport = Arc::new(QuicTransport::new(config.transport.clone(), keypair.clone()));
+        let relay_addrs = if config.relay_listen.is_empty() {
+            Vec::new()
+        } else {
+            transport.listen(config.relay_listen.clone()).await?
+        };
+        for addr in &relay_addrs {
+            info!("Taking relay streams on {}", addr);
+        }
+
+        let mut relay = RelayManager::new(config.relay.clone());
+        if !config.relay_enabled {
+            relay.stop_accepting();
+        }
+        let channels = ChannelManager::with_storage(keypair.clone(), config.channels_path())?;
+        let mut wallet = InMemoryWallet::load_or_new(&config.wallet_path(), keypair.clone())?
+            .with_channels(channels)
+            .with_relay_rate(config.relay.tokens_per_mb);
+        wallet.set_reputation(state.reputation);
+
+        for addr in &config.bootstrap {
+            if let Err(e) = discovery.add_bootstrap_addr(addr) {
+                warn!("Skipping bootstrap peer {}: {}", addr, e);
+            }
+        }
+        match discovery.discover_peers().await {
+            Ok(peers) => info!("Bootstrapped with {} peers", peers.len()),
+            Err(e) => warn!("Bootstrap failed: {}", e),
+        }
+        let presence = tokio::spawn(
+            PresenceService::new(dht.clone(), keypair.clone(), PresenceConfig::default()).run(),
+        );
+
+        let node = NodeHandle {
+            transport,
+            keypair,
+            state: Arc::new(Mutex::new(state)),
+            dht,
+            relay: Arc::new(Mutex::new(relay)),
+            wallet: Arc::new(tokio::sync::Mutex::new(wallet)),
+            listen_addrs,
+            relay_addrs,
+            wallet_path: config.wallet_path(),
+        };
+        let rpc = match RpcServer::bind(&config.rpc_socket_path(), node.clone()) {
+            Ok(rpc) => rpc,
+            Err(e) => {
+                presence.abort();
+                return Err(e);
+            }
+        };
+        let forwarding = ForwardingConfig::default();
+        let scheduler = WfqScheduler::new(config.relay.total_bandwidth, forwarding.burst);
+        let (payment_requests, requests) = mpsc::unbounded_channel();
+        let forwarder = RelayForwarder::new(node.relay(), QuicConnector::new(node.transport()), forwarding)
+            .with_scheduler(scheduler)
+            .with_metering(MeteringConfig::default(), payment_requests);
+        let relay_server = (!node.relay_addrs.is_empty())
+            .then(|| tokio::spawn(serve_relay(node.clone(), Arc::new(forwarder), requests)));
+
+        Ok(Self {
+            discovery,
+            config,
+            node,
+            presence,
+            relay_server,
+            rpc,
+            version_announced: false,
+        })
+    }
+
+    /// Configuration the daemon was started with
+    pub fn config(&self) -> &DaemonConfig {
+        &self.config
+    }
+
+    /// Services of the running node
+    pub fn node(&self) -> &NodeHandle {
+        &self.node
+    }
+
+    /// Run maintenance rounds until `shutdown` resolves, then shut down
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+        tokio::pin!(shutdown);
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.maintenance_interval.max(1)));
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = ticker.tick() => self.maintain().await,
+                Some(event) = self.discovery.next_event() => self.apply_discovery(event),
+            }
+        }
+        self.shutdown().await
+    }
+
+    /// Discover peers and bring the reputation model up to date
+    pub async fn maintain(&mut self) {
+        match self.discovery.discover_peers().await {
+            Ok(peers) => debug!("Routing through {} peers", peers.len()),
+            Err(e) => warn!("Peer discovery failed: {}", e),
+        }
+        // Both fail while no peer has subscribed yet; the next round retries
+        if !self.version_announced {
+            self.version_announced = self.node.announce_version(now_secs()).await.is_ok();
+        }
+        if let Err(e) = self.node.announce_relay(now_secs()).await {
+            debug!("Relay advert not published: {}", e);
+        }
+        self.reserve_relay().await;
+        self.node.sync_reputation(now_secs()).await;
+        if let Err(e) = self.node.save_wallet(&*self.node.wallet.lock().await) {
+            warn!("Cannot save the wallet: {}", e);
+        }
+    }
+
+    /// Make sure we hold a relay reservation if we are configured to
+    ///
+    /// The configured relays are tried in order, then, with `auto_relay`,
+    /// the offers heard over gossip from cheapest up.
+    async fn reserve_relay(&self) {
+        let dht = self.node.dht();
+        if !dht.relayed_addrs().is_empty() {
+            return;
+        }
+        let mut candidates = self.config.relays.clone();
+        if self.config.auto_relay {
+            let own = self.node.keypair.node_id();
+            candidates.extend(dht.relay_offers().into_iter()
+                .filter(|offer| offer.device_id != own)
+                .flat_map(|offer| offer.addresses));
+        }
+        for relay in candidates {
+            match dht.reserve_relay(&relay).await {
+                Ok(addr) => {
+                    info!("Reachable through relay at {}", addr);
+                    return;
+                }
+                Err(e) => debug!("No reservation on {}: {}", relay, e),
+            }
+        }
+    }
+
+    /// Record a peer entering or leaving the routing table in the node state
+    fn apply_discovery(&self, event: DiscoveryEvent) {
+        debug!("{:?}", event);
+        if let Err(e) = event.apply(&mut self.node.state.lock().unwrap()) {
+            debug!("Not keeping discovered peer {}: {}", event.peer().peer_id, e);
+        }
+    }
+
+    /// Drain relay sessions and save the node state
+    pub async fn shutdown(self) -> Result<(), Error> {
+        info!("Shutting down");
+        self.rpc.close();
+        self.presence.abort();
+        if let Some(relay_server) = &self.relay_server {
+            relay_server.abort();
+        }
+
+        let relay = self.node.relay();
+        relay.lock().unwrap().stop_accepting();
+        let deadline = Instant::now() + Duration::from_secs(self.config.drain_timeout);
+        while !relay.lock().unwrap().active_sessions().is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        {
+            let mut relay = relay.lock().unwrap();
+            let unfinished: Vec<[u8; 32]> = relay.active_sessions().iter().map(|s| s.session_id).collect();
+            if !unfinished.is_empty() {
+                warn!("Dropping {} relay sessions that did not finish in time", unfinished.len());
+            }
+            for session_id in &unfinished {
+                relay.drop_session(session_id)?;
+            }
+            let mut state = self.node.state.lock().unwrap();
+            state.stats.merge(relay.stats());
+            state.stats.merge(&self.node.dht.stats());
+        }
+        self.node.sync_reputation(now_secs()).await;
+        self.node.save_wallet(&*self.node.wallet.lock().await)?;
+
+        let path = self.config.state_path();
+        let mut state = self.node.state.lock().unwrap();
+        state.active_sessions.clear();
+        state.last_heartbeat = now_secs();
+        state.save(&path)?;
+        info!("Saved node state to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Hand every inbound relay stream to the forwarder
+///
+/// The forwarder's payment `requests` are passed on to the stream of the
+/// channel they are for. Stops when the listener closes.
+async fn serve_relay(
+    node: NodeHandle,
+    forwarder: Arc<RelayForwarder<QuicConnector>>,
+    mut requests: mpsc::UnboundedReceiver<PaymentRequest>,
+) {
+    let links = PaymentLinks::default();
+    loop {
+        let channel = tokio::select! {
+            accepted = node.transport.accept() => match accepted {
+                Ok(channel) => channel,
+                Err(e) => {
+                    warn!("No longer taking relay streams: {}", e);
+                    return;
+                }
+            },
+            Some(request) = requests.recv() => {
+                // Nobody to ask once the session's stream has gone
+                if let Some(link) = links.lock().unwrap().get(&request.channel_id) {
+                    let _ = link.send(request);
+                }
+                continue;
+            }
+        };
+        let (node, forwarder, links) = (node.clone(), forwarder.clone(), links.clone());
+        tokio::spawn(async move {
+            let client = channel.peer_id.clone();
+            match node.relay_channel(&forwarder, &links, channel).await {
+                Ok(report) => debug!("Relayed {} bytes for {}", report.client_to_target + report.target_to_client, client),
+                Err(e) => debug!("Relay stream from {} failed: {}", client, e),
+            }
+        });
+    }
+}
+
+/// Resolve once the process receives SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::forwarding::pay_relay;
+    use crate::network::scheduler::session_priority;
+    use crate::network::transport::{libp2p_keypair, SecureChannel, TransportConfig};
+    use crate::wallet::channel::ChannelManager;
+    use crate::wallet::wallet::{TransactionType, WalletEngine};
+
+    fn test_config() -> DaemonConfig {
+        let dir = std::env::temp_dir().join(format!("nexusremote-daemon-{}", hex::encode(rand::random::<[u8; 8]>())));
+        DaemonConfig {
+            data_dir: Some(dir),
+            listen: vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()],
+            relay_listen: vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()],
+            relay_enabled: true,
+            drain_timeout: 0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_relay_and_saves_state() {
+        let config = test_config();
+        let keypair = NodeKeypair::generate();
+
+        let daemon = Daemon::start(config.clone(), keypair.clone()).await.unwrap();
+        assert_eq!(daemon.node().listen_addrs().len(), 1);
+        let relay = daemon.node().relay();
+        relay.lock().unwrap().start_session(
+            PeerID::new("client".to_string()),
+            [0u8; 32],
+            PeerID::new("target".to_string()),
+            ReputationScore::new(500),
+        ).unwrap();
+        daemon.node().wallet().lock().await.add_tokens(TokenAmount::new(7), "Test grant", TransactionType::Mining);
+        daemon.run(async {}).await.unwrap();
+
+        // The unfinished session was dropped, which costs reputation
+        {
+            let relay = relay.lock().unwrap();
+            assert!(relay.active_sessions().is_empty());
+            assert!(!relay.is_accepting());
+        }
+        let saved = NodeState::load_or_new(&config.state_path(), keypair.clone()).unwrap();
+        assert!(saved.reputation < ReputationScore::DEFAULT);
+        assert_eq!(saved.role, NodeRole::Relay);
+
+        // The next run picks up where this one stopped
+        let daemon = Daemon::start(config.clone(), keypair).await.unwrap();
+        assert_eq!(daemon.node().state().lock().unwrap().reputation, saved.reputation);
+        assert_eq!(daemon.node().wallet().lock().await.balance(), TokenAmount::new(7));
+        daemon.shutdown().await.unwrap();
+
+        // State belongs to one identity
+        assert!(NodeState::load_or_new(&config.state_path(), NodeKeypair::generate()).is_err());
+        std::fs::remove_dir_all(config.data_dir()).unwrap();
+    }
+
+    fn peer_for(keypair: &NodeKeypair, addresses: Vec<String>) -> PeerInfo {
+        PeerInfo {
+            peer_id: PeerID::new(libp2p_keypair(keypair).public().to_peer_id().to_string()),
+            device_id: keypair.node_id(),
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Idle,
+            addresses,
+            available_bandwidth: 0,
+            identity_proof: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relays_client_streams_to_their_target() {
+        let config = test_config();
+        let relay_keys = NodeKeypair::generate();
+        let daemon = Daemon::start(config.clone(), relay_keys.clone()).await.unwrap();
+        assert_eq!(daemon.node().relay_addrs().len(), 1);
+
+        let target_keys = NodeKeypair::generate();
+        let target = QuicTransport::with_default_config(target_keys.clone());
+        let target_addrs = target.listen(vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()]).await.unwrap();
+        let client_keys = NodeKeypair::generate();
+        let client = QuicTransport::with_default_config(client_keys.clone());
+        let relay = peer_for(&relay_keys, daemon.node().relay_addrs().to_vec());
+        let target_peer = peer_for(&target_keys, target_addrs);
+
+        // The client locks 100 in a channel with the relay, which ranks its session
+        let mut client_channels = ChannelManager::new(client_keys.clone());
+        let open = client_channels.create_channel(relay.peer_id.clone(), relay_keys.public_key().to_bytes(), TokenAmount::new(100)).unwrap();
+        let channel_id = open.params.channel_id;
+        let client_peer = peer_for(&client_keys, Vec::new()).peer_id;
+        client_channels.confirm_update(daemon.node().accept_channel(client_peer, open).await.unwrap()).unwrap();
+
+        // The target sees the relay's connection, and the client inside it
+        let echo = tokio::spawn(async move {
+            let inbound = target.accept().await.unwrap();
+            assert_eq!(inbound.device_id, relay_keys.node_id());
+            let peer_id = inbound.peer_id.clone();
+            let config = TransportConfig::default().session_config();
+            let channel = SecureChannel::respond(inbound.into_stream(), peer_id, &target_keys, config).await.unwrap();
+            while let Ok(message) = channel.receive().await {
+                channel.send(&message).await.unwrap();
+            }
+            channel.close().await.unwrap();
+            channel.device_id
+        });
+
+        // Nobody else's channel can be named
+        assert!(client.connect_relayed(&relay, &target_peer, Some([1u8; 32])).await.is_err());
+
+        let (channel, mut link) = client.connect_relayed(&relay, &target_peer, Some(channel_id)).await.unwrap();
+        let payer = tokio::spawn({
+            let (keys, tokens_per_mb) = (client_keys.clone(), config.relay.tokens_per_mb);
+            async move {
+                let receipt = pay_relay(&mut link, &mut client_channels, &keys, channel_id, tokens_per_mb).await;
+                (receipt, client_channels)
+            }
+        });
+        // Payment is due every MB both ways
+        let message = vec![7u8; 400 * 1024];
+        for _ in 0..3 {
+            channel.send(&message).await.unwrap();
+            assert_eq!(channel.receive().await.unwrap(), message);
+        }
+        let sessions = daemon.node().relay_sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].priority, session_priority(ReputationScore::DEFAULT, TokenAmount::new(100)));
+        channel.close().await.unwrap();
+        assert_eq!(echo.await.unwrap(), client_keys.node_id());
+
+        // The client paid as it went, and co-signs a receipt for what it paid
+        let (receipt, client_channels) = tokio::time::timeout(Duration::from_secs(10), payer).await.unwrap().unwrap();
+        let receipt = receipt.unwrap();
+        assert!(receipt.amount >= TokenAmount::new(2), "receipt for {}", receipt.amount);
+        assert_eq!(
+            client_channels.channel(&channel_id).unwrap().our_balance(),
+            TokenAmount::new(100).sub(receipt.amount).unwrap(),
+        );
+
+        // The session ends once both sides have closed
+        let relay = daemon.node().relay();
+        let ended = async {
+            while relay.lock().unwrap().stats().relay_sessions == 0 {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), ended).await.unwrap();
+        assert!(relay.lock().unwrap().stats().total_data_relayed > 2400 * 1024);
+
+        // The relay claims the receipt into its wallet
+        let wallet = daemon.node().wallet();
+        let claimed = async {
+            while wallet.lock().await.balance() != receipt.amount {
+                tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), claimed).await.unwrap();
+
+        daemon.shutdown().await.unwrap();
+        std::fs::remove_dir_all(config.data_dir()).unwrap();
+    }
+}
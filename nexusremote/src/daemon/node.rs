@@ -0,0 +1,433 @@
+//! Services of a running node, shared by the daemon and its control API
+
+use crate::core::crypto::NodeKeypair;
+use crate::core::state::NodeState;
+use crate::core::types::*;
+use crate::network::dht::DhtNode;
+use crate::network::forwarding::{ForwardReport, QuicConnector, RelayControl, RelayForwarder, RelayRequest};
+use crate::network::gossip::{Announcement, RelayAdvert, VersionNotice};
+use crate::network::libp2p_integration::Libp2pDht;
+use crate::network::presence::{PresenceConfig, PresenceService};
+use crate::network::relay::{RelayManager, RelaySession, SessionRequest};
+use crate::network::transport::{QuicTransport, RelayLink, SecureChannel};
+use crate::wallet::channel::{ChannelOpen, ChannelUpdate};
+use crate::wallet::metering::PaymentRequest;
+use crate::wallet::mining::{MiningResult, PowMiner};
+use crate::wallet::wallet::{ChannelStatus, InMemoryWallet, Transaction, TransactionType, WalletEngine};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio_util::compat::Compat;
+use tracing::{debug, warn};
+
+/// Where the payment requests of metered relay sessions go, by the channel paying for them
+pub(crate) type PaymentLinks = Arc<Mutex<HashMap<[u8; 32], mpsc::UnboundedSender<PaymentRequest>>>>;
+
+/// Node overview returned by `node.status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeStatus {
+    /// Device ID
+    pub device_id: DeviceID,
+    /// Current role
+    pub role: NodeRole,
+    /// Our own reputation
+    pub reputation: ReputationScore,
+    /// Addresses the DHT listens on
+    pub listen_addrs: Vec<String>,
+    /// Addresses we are reachable at through a relay
+    pub relayed_addrs: Vec<String>,
+    /// Addresses clients open relay streams to
+    #[serde(default)]
+    pub relay_addrs: Vec<String>,
+    /// Peers in the routing table
+    pub peers: usize,
+    /// Whether new relay sessions are accepted
+    pub relaying: bool,
+    /// Active relay sessions
+    pub relay_sessions: usize,
+    /// Wallet balance
+    pub balance: TokenAmount,
+}
+
+/// Wallet summary returned by `wallet.balance`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletBalance {
+    /// Current balance
+    pub balance: TokenAmount,
+    /// Balance including the overdraft
+    pub effective_balance: TokenAmount,
+    /// Reputation the overdraft is based on
+    pub reputation: ReputationScore,
+    /// Total tokens earned
+    pub total_earned: TokenAmount,
+    /// Total tokens spent
+    pub total_spent: TokenAmount,
+    /// Open payment channels
+    pub open_channels: usize,
+}
+
+/// Result of `dht.find_peer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FindPeerResult {
+    /// Closest peers the lookup found
+    pub closest: Vec<PeerInfo>,
+    /// The target's announced presence, if a fresh one was found
+    pub presence: Option<PeerInfo>,
+}
+
+/// Shared handles to the services of a running node
+#[derive(Clone)]
+pub struct NodeHandle {
+    pub(crate) keypair: NodeKeypair,
+    pub(crate) state: Arc<Mutex<NodeState>>,
+    pub(crate) dht: Libp2pDht,
+    pub(crate) transport: Arc<QuicTransport>,
+    pub(crate) relay: Arc<Mutex<RelayManager>>,
+    pub(crate) wallet: Arc<tokio::sync::Mutex<InMemoryWallet>>,
+    pub(crate) listen_addrs: Vec<String>,
+    pub(crate) relay_addrs: Vec<String>,
+    pub(crate) wallet_path: PathBuf,
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl NodeHandle {
+    /// Node state, saved on shutdown
+    pub fn state(&self) -> Arc<Mutex<NodeState>> {
+        self.state.clone()
+    }
+
+    /// DHT node
+    pub fn dht(&self) -> &Libp2pDht {
+        &self.dht
+    }
+
+    /// Transport for remote control sessions
+    pub fn transport(&self) -> Arc<QuicTransport> {
+        self.transport.clone()
+    }
+
+    /// Relay manager
+    pub fn relay(&self) -> Arc<Mutex<RelayManager>> {
+        self.relay.clone()
+    }
+
+    /// Wallet
+    pub fn wallet(&self) -> Arc<tokio::sync::Mutex<InMemoryWallet>> {
+        self.wallet.clone()
+    }
+
+    /// Addresses the DHT is listening on
+    pub fn listen_addrs(&self) -> &[String] {
+        &self.listen_addrs
+    }
+
+    /// Addresses clients open relay streams to
+    pub fn relay_addrs(&self) -> &[String] {
+        &self.relay_addrs
+    }
+
+    /// Write the wallet next to the node state
+    pub(crate) fn save_wallet(&self, wallet: &InMemoryWallet) -> Result<(), Error> {
+        wallet.save(&self.wallet_path)
+    }
+
+    /// Node overview
+    pub async fn status(&self) -> Result<NodeStatus, Error> {
+        let peers = self.dht.peers().await?.len();
+        let balance = self.wallet.lock().await.balance();
+        let (relaying, relay_sessions) = {
+            let relay = self.relay.lock().unwrap();
+            (relay.is_accepting(), relay.active_sessions().len())
+        };
+        let state = self.state.lock().unwrap();
+        Ok(NodeStatus {
+            device_id: state.device_id,
+            role: state.role,
+            reputation: state.reputation,
+            listen_addrs: self.listen_addrs.clone(),
+            relayed_addrs: self.dht.relayed_addrs(),
+            relay_addrs: self.relay_addrs.clone(),
+            peers,
+            relaying,
+            relay_sessions,
+            balance,
+        })
+    }
+
+    /// Peers in the routing table
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, Error> {
+        self.dht.peers().await
+    }
+
+    /// Wallet summary
+    pub async fn balance(&self) -> WalletBalance {
+        let wallet = self.wallet.lock().await;
+        let state = wallet.get_status();
+        WalletBalance {
+            balance: state.balance,
+            effective_balance: wallet.effective_balance(),
+            reputation: state.reputation,
+            total_earned: state.total_earned,
+            total_spent: state.total_spent,
+            open_channels: state.channels.len(),
+        }
+    }
+
+    /// Wallet transactions, oldest first
+    pub async fn transactions(&self) -> Vec<Transaction> {
+        self.wallet.lock().await.get_status().transactions
+    }
+
+    /// Mine the initial token grant into the wallet; only allowed once
+    ///
+    /// The saved wallet is checked as well as the live one, and the grant
+    /// is saved as soon as it is credited, so a restart does not allow
+    /// mining again.
+    pub async fn mine(&self) -> Result<MiningResult, Error> {
+        let mined = |wallet: &InMemoryWallet| {
+            wallet.get_status().transactions.iter().any(|tx| tx.tx_type == TransactionType::Mining)
+        };
+        let already_mined = |wallet: &InMemoryWallet| -> Result<bool, Error> {
+            Ok(mined(wallet) || mined(&InMemoryWallet::load_or_new(&self.wallet_path, self.keypair.clone())?))
+        };
+        if already_mined(&*self.wallet.lock().await)? {
+            return Err(Error::Wallet("Initial tokens were already mined".to_string()));
+        }
+        let result = PowMiner::new().mine_initial_tokens(self.keypair.node_id().as_bytes()).await?;
+
+        let mut wallet = self.wallet.lock().await;
+        if already_mined(&wallet)? {
+            return Err(Error::Wallet("Initial tokens were already mined".to_string()));
+        }
+        wallet.add_tokens(result.reward, "Initial PoW mining", TransactionType::Mining);
+        self.save_wallet(&wallet)?;
+        Ok(result)
+    }
+
+    /// Look up a device and resolve its presence announcement
+    pub async fn find_peer(&self, target: DeviceID) -> Result<FindPeerResult, Error> {
+        let closest = self.dht.find_peer(target).await?;
+        let presence = PresenceService::new(self.dht.clone(), self.keypair.clone(), PresenceConfig::default())
+            .resolve(target, now_secs())
+            .await?;
+        Ok(FindPeerResult { closest, presence })
+    }
+
+    /// Propose a payment channel to the node with `public_key`, locking `capacity` from the balance
+    pub async fn open_channel(&self, peer_id: PeerID, public_key: [u8; 32], capacity: TokenAmount) -> Result<ChannelOpen, Error> {
+        let mut wallet = self.wallet.lock().await;
+        let result = wallet.open_channel(peer_id, public_key, capacity).await?;
+        self.save_wallet(&wallet)?;
+        Ok(result)
+    }
+
+    /// Accept a channel proposed by `peer_id`
+    pub async fn accept_channel(&self, peer_id: PeerID, open: ChannelOpen) -> Result<ChannelUpdate, Error> {
+        let mut wallet = self.wallet.lock().await;
+        let result = wallet.accept_channel(peer_id, open).await?;
+        self.save_wallet(&wallet)?;
+        Ok(result)
+    }
+
+    /// Apply a channel update from the counterparty, returning our countersignature of a proposal
+    pub async fn apply_channel_update(&self, update: ChannelUpdate) -> Result<Option<ChannelUpdate>, Error> {
+        let mut wallet = self.wallet.lock().await;
+        let result = wallet.apply_channel_update(update).await?;
+        self.save_wallet(&wallet)?;
+        Ok(result)
+    }
+
+    /// Propose a cooperative close; the balance is credited once the peer countersigns
+    pub async fn close_channel(&self, peer_id: &PeerID) -> Result<ChannelUpdate, Error> {
+        let mut wallet = self.wallet.lock().await;
+        let result = wallet.close_channel(peer_id).await?;
+        self.save_wallet(&wallet)?;
+        Ok(result)
+    }
+
+    /// Start or stop accepting relay sessions; active sessions are left to finish
+    pub fn set_relaying(&self, enabled: bool) {
+        let mut relay = self.relay.lock().unwrap();
+        if enabled {
+            relay.start_accepting();
+        } else {
+            relay.stop_accepting();
+        }
+        self.state.lock().unwrap().set_role(if enabled { NodeRole::Relay } else { NodeRole::Idle });
+    }
+
+    /// Forward a client's relay stream to the target it names
+    ///
+    /// The client's first message is a `RelayRequest`; the session is
+    /// admitted on the reputation we know the client by and the balance it
+    /// holds in the payment channel it names, which must be its own. While
+    /// it runs, payment requests for that channel are taken from `links`
+    /// to the client, and once it ends, finished or preempted, the client
+    /// co-signs its receipt, which is claimed into the wallet.
+    pub(crate) async fn relay_channel(
+        &self,
+        forwarder: &RelayForwarder<QuicConnector>,
+        links: &PaymentLinks,
+        channel: SecureChannel,
+    ) -> Result<ForwardReport, Error> {
+        let timeout = Duration::from_millis(self.transport.config().connection_timeout);
+        let request: RelayRequest = match tokio::time::timeout(timeout, channel.receive()).await {
+            Ok(message) => serde_json::from_slice(&message?)?,
+            Err(_) => return Err(Error::Network(format!("{} sent no relay request", channel.peer_id))),
+        };
+        let reputation = self.state.lock().unwrap().known_peers.get(&channel.peer_id)
+            .map_or(ReputationScore::DEFAULT, |peer| peer.reputation);
+        let balance = match &request.channel_id {
+            Some(channel_id) => self.wallet.lock().await.channels().channel(channel_id)
+                .filter(|state| state.status == ChannelStatus::Open && state.their_key() == channel.remote_public_key)
+                .map(|state| state.their_balance())
+                .ok_or_else(|| Error::Token(format!("{} has no open channel {}", channel.peer_id, hex::encode(channel_id))))?,
+            None => TokenAmount::ZERO,
+        };
+        let channel_id = request.channel_id;
+        let request = SessionRequest {
+            client: channel.peer_id.clone(),
+            client_public_key: channel.remote_public_key,
+            target: request.target,
+            target_addresses: request.addresses,
+            reputation,
+            balance,
+            channel_id,
+        };
+
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+        if let Some(channel_id) = channel_id {
+            match links.lock().unwrap().entry(channel_id) {
+                Entry::Occupied(_) => {
+                    return Err(Error::Token(format!("Channel {} already pays for a session", hex::encode(channel_id))));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(requests_tx);
+                }
+            }
+        }
+        let (stream, mut link) = channel.into_relay_link();
+        let result = self.run_session(forwarder, stream, &mut link, requests, request).await;
+        if let Some(channel_id) = &channel_id {
+            links.lock().unwrap().remove(channel_id);
+        }
+        let report = result?;
+        if let Err(e) = self.redeem_receipt(&mut link, report.receipt.clone()).await {
+            warn!("Receipt of relay session {} not redeemed: {}", hex::encode(&report.session_id[..8]), e);
+        }
+        Ok(report)
+    }
+
+    /// Relay a session, passing its payment requests to the client on `link` and its payments to the forwarder
+    async fn run_session(
+        &self,
+        forwarder: &RelayForwarder<QuicConnector>,
+        stream: Compat<DuplexStream>,
+        link: &mut RelayLink,
+        mut requests: mpsc::UnboundedReceiver<PaymentRequest>,
+        request: SessionRequest,
+    ) -> Result<ForwardReport, Error> {
+        let relayed = forwarder.relay(stream, request);
+        tokio::pin!(relayed);
+        // Forwarding goes on if the client stops listening; it just cannot pay
+        let mut listening = true;
+        loop {
+            tokio::select! {
+                report = &mut relayed => return report,
+                Some(request) = requests.recv() => {
+                    if let Err(e) = link.send(&RelayControl::PaymentRequest(request)).await {
+                        debug!("Cannot ask {} for payment: {}", link.peer_id(), e);
+                    }
+                }
+                message = link.receive(), if listening => match message {
+                    Ok(RelayControl::Payment { session_id, update }) => {
+                        let countersigned = forwarder.on_payment(self.wallet.lock().await.channels_mut(), &session_id, update);
+                        match countersigned {
+                            Ok(update) => link.send(&RelayControl::PaymentAccepted(update)).await?,
+                            Err(e) => debug!("Rejected a payment from {}: {}", link.peer_id(), e),
+                        }
+                    }
+                    Ok(message) => debug!("Ignoring {:?} from {}", message, link.peer_id()),
+                    Err(_) => listening = false,
+                },
+            }
+        }
+    }
+
+    /// Have the client co-sign a session's receipt on `link`, then claim it from the client's channel
+    async fn redeem_receipt(&self, link: &mut RelayLink, mut receipt: SignedReceipt) -> Result<(), Error> {
+        receipt.sign_as_relay(&self.keypair);
+        link.send(&RelayControl::Receipt(receipt)).await?;
+        let timeout = Duration::from_millis(self.transport.config().connection_timeout);
+        let cosigned = tokio::time::timeout(timeout, async {
+            loop {
+                // Payments still in flight when the session ended are of no use now
+                if let RelayControl::CosignedReceipt(receipt) = link.receive().await? {
+                    return Ok::<_, Error>(receipt);
+                }
+            }
+        }).await.map_err(|_| Error::Network(format!("{} did not co-sign its receipt", link.peer_id())))??;
+
+        let mut wallet = self.wallet.lock().await;
+        wallet.submit_proof_of_relay(cosigned).await?;
+        self.save_wallet(&wallet)
+    }
+
+    /// Active relay sessions
+    pub fn relay_sessions(&self) -> Vec<RelaySession> {
+        self.relay.lock().unwrap().active_sessions().into_iter().cloned().collect()
+    }
+
+    /// Relay offers heard from other nodes, cheapest first
+    pub fn relay_offers(&self) -> Vec<RelayAdvert> {
+        self.dht.relay_offers()
+    }
+
+    /// Advertise our spare relay capacity, if we are relaying
+    ///
+    /// Addresses carry our peer id so clients can reserve a slot with them.
+    pub(crate) async fn announce_relay(&self, now: u64) -> Result<(), Error> {
+        let peer_id = self.dht.local_peer().peer_id;
+        let addresses = self.listen_addrs.iter().map(|addr| format!("{}/p2p/{}", addr, peer_id)).collect();
+        let advert = {
+            let relay = self.relay.lock().unwrap();
+            if !relay.is_accepting() {
+                return Ok(());
+            }
+            RelayAdvert::new(self.keypair.node_id(), addresses, &relay, now)
+        };
+        self.dht.announce(Announcement::Relay(advert)).await
+    }
+
+    /// Tell other nodes which protocol version we speak
+    pub(crate) async fn announce_version(&self, now: u64) -> Result<(), Error> {
+        self.dht.announce(Announcement::Version(VersionNotice::current(self.keypair.node_id(), now))).await
+    }
+
+    /// Feed relay and wallet events into the reputation model
+    pub(crate) async fn sync_reputation(&self, now: u64) {
+        let mut wallet = self.wallet.lock().await;
+        let mut events = wallet.take_reputation_events();
+        events.extend(self.relay.lock().unwrap().take_reputation_events());
+        events.sort_by_key(|(at, _)| *at);
+
+        let reputation = {
+            let mut state = self.state.lock().unwrap();
+            state.apply_reputation_events(events);
+            state.decay_reputation(now)
+        };
+        wallet.set_reputation(reputation);
+    }
+}
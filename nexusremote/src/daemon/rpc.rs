@@ -0,0 +1,478 @@
+//! JSON-RPC control API on a Unix socket
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line, so a
+//! script can drive the node with nothing more than `socat`:
+//!
+//! ```text
+//! {"jsonrpc":"2.0","id":1,"method":"channel.close","params":{"peer_id":"12D3Koo..."}}
+//! ```
+//!
+//! | Method | Params | Result |
+//! |---|---|---|
+//! | `node.status` | | [`NodeStatus`] |
+//! | `node.peers` | | routing table peers |
+//! | `wallet.balance` | | [`WalletBalance`] |
+//! | `wallet.transactions` | | wallet transactions |
+//! | `wallet.mine` | | `MiningResult` |
+//! | `dht.find_peer` | `target` (hex device ID) | [`FindPeerResult`] |
+//! | `channel.open` | `peer_id`, `public_key` (hex), `capacity` | `ChannelOpen` for the peer |
+//! | `channel.accept` | `peer_id`, `open` | co-signed initial `ChannelUpdate` |
+//! | `channel.update` | `update` | countersigned `ChannelUpdate`, or `null` for a confirmation |
+//! | `channel.close` | `peer_id` | closing `ChannelUpdate` for the peer |
+//! | `relay.start`, `relay.stop` | | `null` |
+//! | `relay.sessions` | | active `RelaySession`s |
+//! | `relay.offers` | | `RelayAdvert`s heard over gossip, cheapest first |
+
+use crate::core::types::*;
+use crate::daemon::node::{FindPeerResult, NodeHandle, NodeStatus, WalletBalance};
+use crate::network::gossip::RelayAdvert;
+use crate::network::relay::RelaySession;
+use crate::wallet::channel::{ChannelOpen, ChannelUpdate};
+use crate::wallet::mining::MiningResult;
+use crate::wallet::wallet::Transaction;
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Request is not valid JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// No such method
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Params missing or malformed
+pub const INVALID_PARAMS: i64 = -32602;
+/// Result could not be encoded
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The node failed to carry out the request
+pub const SERVER_ERROR: i64 = -32000;
+
+/// JSON-RPC request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcRequest {
+    /// Protocol version, always "2.0"
+    pub jsonrpc: String,
+    /// Echoed back in the response
+    #[serde(default)]
+    pub id: Value,
+    /// Method name
+    pub method: String,
+    /// Method parameters
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// JSON-RPC response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcResponse {
+    /// Protocol version, always "2.0"
+    pub jsonrpc: String,
+    /// Id of the request
+    pub id: Value,
+    /// Result on success
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// Error on failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// JSON-RPC error object
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{message} (code {code})")]
+pub struct RpcError {
+    /// Error code
+    pub code: i64,
+    /// Human-readable description
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+#[derive(Deserialize)]
+struct FindPeerParams {
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct OpenChannelParams {
+    peer_id: PeerID,
+    public_key: String,
+    capacity: TokenAmount,
+}
+
+#[derive(Deserialize)]
+struct AcceptChannelParams {
+    peer_id: PeerID,
+    open: ChannelOpen,
+}
+
+#[derive(Deserialize)]
+struct ChannelUpdateParams {
+    update: ChannelUpdate,
+}
+
+#[derive(Deserialize)]
+struct CloseChannelParams {
+    peer_id: PeerID,
+}
+
+/// Control socket server
+pub struct RpcServer {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl RpcServer {
+    /// Listen on `path`, replacing a stale socket left by a crashed node
+    pub fn bind(path: &Path, node: NodeHandle) -> Result<Self, Error> {
+        #[cfg(unix)]
+        if path.exists() {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(Error::Other(format!("Another node is already listening on {}", path.display())));
+            }
+            std::fs::remove_file(path)?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let listener = bind_private(path)?;
+        info!("Control API listening on {}", path.display());
+
+        let task = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(serve_connection(stream, node.clone()));
+                    }
+                    Err(e) => warn!("Control socket accept failed: {}", e),
+                }
+            }
+        });
+        Ok(Self { path: path.to_path_buf(), task })
+    }
+
+    /// Socket path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop accepting connections and remove the socket
+    pub fn close(self) {
+        self.task.abort();
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("Could not remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Bind a socket at `path` that only our user may connect to
+///
+/// The socket controls the wallet. It is bound in a directory only we can
+/// enter and moved into place once its own mode is 0o600, so there is no
+/// moment at which it is open to others.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> Result<UnixListener, Error> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path.file_name()
+        .ok_or_else(|| Error::Other(format!("{} is not a socket path", path.display())))?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), hex::encode(rand::random::<[u8; 4]>())));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("rpc.sock");
+    let bound = UnixListener::bind(&staged)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    if bound.is_err() {
+        let _ = std::fs::remove_file(&staged);
+    }
+    let _ = std::fs::remove_dir(&staging);
+    Ok(bound?)
+}
+
+async fn serve_connection(stream: UnixStream, node: NodeHandle) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_line(&node, &line).await;
+        let mut out = serde_json::to_vec(&response).expect("responses are valid JSON");
+        out.push(b'\n');
+        if write.write_all(&out).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle_line(node: &NodeHandle, line: &str) -> RpcResponse {
+    let (id, outcome) = match serde_json::from_str::<RpcRequest>(line) {
+        Ok(request) => {
+            debug!("Control API call {}", request.method);
+            (request.id, dispatch(node, &request.method, request.params).await)
+        }
+        Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    let (result, error) = match outcome {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    RpcResponse { jsonrpc: "2.0".to_string(), id, result, error }
+}
+
+async fn dispatch(node: &NodeHandle, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "node.status" => encode(node.status().await),
+        "node.peers" => encode(node.peers().await),
+        "wallet.balance" => encode(Ok(node.balance().await)),
+        "wallet.transactions" => encode(Ok(node.transactions().await)),
+        "wallet.mine" => encode(node.mine().await),
+        "dht.find_peer" => {
+            let params: FindPeerParams = decode(params)?;
+            let target = DeviceID::from_hex(&params.target)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid device ID {}: {}", params.target, e)))?;
+            encode(node.find_peer(target).await)
+        }
+        "channel.open" => {
+            let params: OpenChannelParams = decode(params)?;
+            let mut public_key = [0u8; 32];
+            hex::decode_to_slice(&params.public_key, &mut public_key)
+                .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid public key {}: {}", params.public_key, e)))?;
+            encode(node.open_channel(params.peer_id, public_key, params.capacity).await)
+        }
+        "channel.accept" => {
+            let params: AcceptChannelParams = decode(params)?;
+            encode(node.accept_channel(params.peer_id, params.open).await)
+        }
+        "channel.update" => {
+            let params: ChannelUpdateParams = decode(params)?;
+            encode(node.apply_channel_update(params.update).await)
+        }
+        "channel.close" => {
+            let params: CloseChannelParams = decode(params)?;
+            encode(node.close_channel(&params.peer_id).await)
+        }
+        "relay.start" | "relay.stop" => {
+            node.set_relaying(method == "relay.start");
+            Ok(Value::Null)
+        }
+        "relay.sessions" => encode(Ok(node.relay_sessions())),
+        "relay.offers" => encode(Ok(node.relay_offers())),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+    }
+}
+
+fn decode<P: DeserializeOwned>(params: Value) -> Result<P, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn encode<T: Serialize>(result: Result<T, Error>) -> Result<Value, RpcError> {
+    let value = result.map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))?;
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+/// Client for a running node's control socket
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    path: PathBuf,
+}
+
+impl RpcClient {
+    /// Client for the socket at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Call `method` and decode its result
+    pub async fn call<R: DeserializeOwned>(&self, method: &str, params: Value) -> Result<R, Error> {
+        let stream = UnixStream::connect(&self.path).await.map_err(|e| Error::Network(format!(
+            "Cannot reach the node at {} ({}); is `nexusremote start` running?",
+            self.path.display(), e
+        )))?;
+        let (read, mut write) = stream.into_split();
+
+        let request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: json!(1),
+            method: method.to_string(),
+            params,
+        };
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        write.write_all(&line).await?;
+
+        let reply = BufReader::new(read).lines().next_line().await?
+            .ok_or_else(|| Error::Network("Node closed the control connection".to_string()))?;
+        let response: RpcResponse = serde_json::from_str(&reply)?;
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+        Ok(serde_json::from_value(response.result.unwrap_or(Value::Null))?)
+    }
+
+    /// Node overview
+    pub async fn status(&self) -> Result<NodeStatus, Error> {
+        self.call("node.status", Value::Null).await
+    }
+
+    /// Peers in the node's routing table
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, Error> {
+        self.call("node.peers", Value::Null).await
+    }
+
+    /// Wallet summary
+    pub async fn balance(&self) -> Result<WalletBalance, Error> {
+        self.call("wallet.balance", Value::Null).await
+    }
+
+    /// Wallet transactions, oldest first
+    pub async fn transactions(&self) -> Result<Vec<Transaction>, Error> {
+        self.call("wallet.transactions", Value::Null).await
+    }
+
+    /// Mine the initial token grant into the node's wallet
+    pub async fn mine(&self) -> Result<MiningResult, Error> {
+        self.call("wallet.mine", Value::Null).await
+    }
+
+    /// Look up a device through the node
+    pub async fn find_peer(&self, target: &DeviceID) -> Result<FindPeerResult, Error> {
+        self.call("dht.find_peer", json!({ "target": target.to_hex() })).await
+    }
+
+    /// Propose a payment channel; the returned proposal goes to the peer
+    pub async fn open_channel(&self, peer_id: &PeerID, public_key: &[u8; 32], capacity: TokenAmount) -> Result<ChannelOpen, Error> {
+        self.call("channel.open", json!({
+            "peer_id": peer_id,
+            "public_key": hex::encode(public_key),
+            "capacity": capacity,
+        })).await
+    }
+
+    /// Accept a channel proposed by `peer_id`
+    pub async fn accept_channel(&self, peer_id: &PeerID, open: &ChannelOpen) -> Result<ChannelUpdate, Error> {
+        self.call("channel.accept", json!({ "peer_id": peer_id, "open": open })).await
+    }
+
+    /// Apply a channel update from the peer
+    pub async fn apply_channel_update(&self, update: &ChannelUpdate) -> Result<Option<ChannelUpdate>, Error> {
+        self.call("channel.update", json!({ "update": update })).await
+    }
+
+    /// Propose a cooperative close of a payment channel
+    pub async fn close_channel(&self, peer_id: &PeerID) -> Result<ChannelUpdate, Error> {
+        self.call("channel.close", json!({ "peer_id": peer_id })).await
+    }
+
+    /// Start or stop accepting relay sessions
+    pub async fn set_relaying(&self, enabled: bool) -> Result<(), Error> {
+        self.call(if enabled { "relay.start" } else { "relay.stop" }, Value::Null).await
+    }
+
+    /// Active relay sessions
+    pub async fn relay_sessions(&self) -> Result<Vec<RelaySession>, Error> {
+        self.call("relay.sessions", Value::Null).await
+    }
+
+    /// Relay offers the node has heard of
+    pub async fn relay_offers(&self) -> Result<Vec<RelayAdvert>, Error> {
+        self.call("relay.offers", Value::Null).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::daemon::{Daemon, DaemonConfig};
+    use crate::wallet::wallet::{InMemoryWallet, TransactionType, WalletEngine};
+
+    #[tokio::test]
+    async fn test_cli_calls_reach_the_running_node() {
+        let dir = std::env::temp_dir().join(format!("nexusremote-rpc-{}", hex::encode(rand::random::<[u8; 8]>())));
+        let config = DaemonConfig {
+            data_dir: Some(dir),
+            listen: vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()],
+            relay_listen: vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()],
+            relay_enabled: true,
+            drain_timeout: 0,
+            ..Default::default()
+        };
+        let keypair = NodeKeypair::generate();
+        let daemon = Daemon::start(config.clone(), keypair.clone()).await.unwrap();
+        // A second node cannot take over the socket, which only we may use
+        assert!(RpcServer::bind(&config.rpc_socket_path(), daemon.node().clone()).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(config.rpc_socket_path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let client = RpcClient::new(config.rpc_socket_path());
+
+        let status = client.status().await.unwrap();
+        assert_eq!(status.device_id, daemon.node().state().lock().unwrap().device_id);
+        assert!(status.relaying);
+        client.set_relaying(false).await.unwrap();
+        let status = client.status().await.unwrap();
+        assert!(!status.relaying);
+        assert_eq!(status.role, NodeRole::Idle);
+        assert!(client.relay_offers().await.unwrap().is_empty());
+
+        let mined = client.mine().await.unwrap();
+        assert!(client.mine().await.is_err());
+        // The counterparty is a wallet that answers in-process
+        let peer = PeerID::new("peer".to_string());
+        let peer_key = NodeKeypair::generate();
+        let mut peer_wallet = InMemoryWallet::new(peer_key.clone());
+        let open = client.open_channel(&peer, &peer_key.public_key().to_bytes(), TokenAmount::new(5)).await.unwrap();
+        assert_eq!(open.params.capacity, TokenAmount::new(5));
+        let node_peer = PeerID::new("node".to_string());
+        let accepted = peer_wallet.accept_channel(node_peer, open).await.unwrap();
+        assert!(client.apply_channel_update(&accepted).await.unwrap().is_none());
+        assert!(client.open_channel(&peer, &peer_key.public_key().to_bytes(), mined.reward).await.is_err());
+        assert_eq!(client.balance().await.unwrap().open_channels, 1);
+        let close = client.close_channel(&peer).await.unwrap();
+        let closed = peer_wallet.apply_channel_update(close).await.unwrap().unwrap();
+        assert!(client.apply_channel_update(&closed).await.unwrap().is_none());
+        assert!(client.close_channel(&peer).await.is_err());
+
+        let balance = client.balance().await.unwrap();
+        assert_eq!(balance.balance, mined.reward);
+        assert_eq!(balance.open_channels, 0);
+        let transactions = client.transactions().await.unwrap();
+        assert!(transactions.iter().any(|tx| tx.tx_type == TransactionType::Mining));
+
+        match client.call::<Value>("node.reboot", Value::Null).await {
+            Err(Error::Rpc(e)) => assert_eq!(e.code, METHOD_NOT_FOUND),
+            other => panic!("Expected an RPC error, got {:?}", other),
+        }
+        match client.call::<Value>("channel.open", json!({ "peer_id": "peer" })).await {
+            Err(Error::Rpc(e)) => assert_eq!(e.code, INVALID_PARAMS),
+            other => panic!("Expected an RPC error, got {:?}", other),
+        }
+
+        daemon.shutdown().await.unwrap();
+        assert!(!config.rpc_socket_path().exists());
+        assert!(client.status().await.is_err());
+
+        // The grant is remembered across restarts
+        let daemon = Daemon::start(config.clone(), keypair).await.unwrap();
+        assert!(client.mine().await.is_err());
+        assert_eq!(client.balance().await.unwrap().balance, mined.reward);
+        daemon.shutdown().await.unwrap();
+        std::fs::remove_dir_all(config.data_dir()).unwrap();
+    }
+}
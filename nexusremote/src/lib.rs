@@ -11,6 +11,7 @@ pub mod network;
 pub mod wallet;
 pub mod ui;
 pub mod simulator;
+pub mod daemon;
 
 // Re-export commonly used types
 pub use core::*;
@@ -39,6 +40,26 @@ pub enum Error {
     #[error("Crypto error: {0}")]
     Crypto(String),
     
+    /// Relay receipt verification errors
+    #[error("Receipt error: {0}")]
+    Receipt(#[from] crate::core::receipt::ReceiptError),
+    
+    /// DHT record verification errors
+    #[error("Record error: {0}")]
+    Record(#[from] crate::network::record::RecordError),
+    
+    /// Peer attestation verification errors
+    #[error("Attestation error: {0}")]
+    Attestation(#[from] crate::network::attestation::AttestationError),
+    
+    /// Gossip announcement validation errors
+    #[error("Gossip error: {0}")]
+    Gossip(#[from] crate::network::gossip::GossipError),
+    
+    /// Errors reported by a node's control API
+    #[error("RPC error: {0}")]
+    Rpc(#[from] crate::daemon::rpc::RpcError),
+    
     /// Input/output errors
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -63,3 +84,9 @@ impl From<bincode::Error> for Error {
         Self::Serialization(err.to_string())
     }
 }
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Self::Serialization(err.to_string())
+    }
+}
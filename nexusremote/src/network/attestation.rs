@@ -0,0 +1,442 @@
+//! Signed peer attestations and EigenTrust reputation
+//!
+//! After a relay session the client signs an `Attestation` describing how
+//! the relay behaved. Each node publishes its own attestations as a single
+//! `SignedRecord` in the "attestations" namespace, so they can be fetched
+//! from the DHT by anyone.
+//!
+//! `ReputationLedger` turns the attestations it has collected into scores
+//! with EigenTrust: a peer's trust is the sum of the local trust others
+//! place in it, weighted by their own trust, anchored on a set of
+//! pre-trusted peers. What a peer says about its own reputation is never
+//! used.
+
+use crate::core::crypto::{node_id_from_public_key, verify_signature, NodeKeypair};
+use crate::core::types::*;
+use crate::network::dht::DhtNode;
+use crate::network::record::SignedRecord;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::debug;
+
+/// Record namespace for published attestations
+pub const ATTESTATION_NAMESPACE: &str = "attestations";
+
+/// Domain separator for attestation signatures
+const ATTESTATION_DOMAIN: &[u8] = b"nexusremote-attestation-v1";
+
+/// Attestations kept per attester and subject
+pub const MAX_ATTESTATIONS_PER_PEER: usize = 32;
+
+/// Latency at which a successful session counts half (milliseconds)
+const REFERENCE_LATENCY_MS: f64 = 200.0;
+
+/// Weight of a failed session against a successful one
+const FAILURE_PENALTY: f64 = 2.0;
+
+/// Reasons an attestation is rejected
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AttestationError {
+    /// The signature does not verify
+    #[error("invalid attestation signature")]
+    InvalidSignature,
+
+    /// A node attested to itself
+    #[error("nodes cannot attest to themselves")]
+    SelfAttestation,
+
+    /// The attestation was published by someone other than its attester
+    #[error("attestation published by another node")]
+    PublisherMismatch,
+
+    /// The attestation record could not be decoded
+    #[error("malformed attestation record: {0}")]
+    Malformed(String),
+}
+
+/// What a client observed during a relay session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionOutcome {
+    /// Whether the session completed
+    pub success: bool,
+    /// Bytes relayed
+    pub bytes_relayed: u64,
+    /// Median round-trip latency (milliseconds)
+    pub latency_ms: u32,
+}
+
+/// Signed statement by one node about a session with another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attestation {
+    /// Attester public key
+    pub attester: [u8; 32],
+    /// Node the attestation is about
+    pub subject: DeviceID,
+    /// Relay session the attestation covers
+    pub session_id: [u8; 32],
+    /// Observed outcome
+    pub outcome: SessionOutcome,
+    /// When the attestation was issued (unix seconds)
+    pub timestamp: u64,
+    /// Attester signature over `signing_bytes`
+    pub signature: Vec<u8>,
+}
+
+impl Attestation {
+    /// Create and sign an attestation
+    pub fn new(keypair: &NodeKeypair, subject: DeviceID, session_id: [u8; 32], outcome: SessionOutcome, timestamp: u64) -> Self {
+        let mut attestation = Self {
+            attester: keypair.public_key().to_bytes(),
+            subject,
+            session_id,
+            outcome,
+            timestamp,
+            signature: vec![],
+        };
+        attestation.signature = keypair.sign(&attestation.signing_bytes());
+        attestation
+    }
+
+    /// Device that issued the attestation
+    pub fn attester_id(&self) -> DeviceID {
+        node_id_from_public_key(&self.attester)
+    }
+
+    /// Canonical bytes covered by the signature
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ATTESTATION_DOMAIN.len() + 32 * 3 + 1 + 8 + 4 + 8);
+        bytes.extend_from_slice(ATTESTATION_DOMAIN);
+        bytes.extend_from_slice(&self.attester);
+        bytes.extend_from_slice(self.subject.as_bytes());
+        bytes.extend_from_slice(&self.session_id);
+        bytes.push(self.outcome.success as u8);
+        bytes.extend_from_slice(&self.outcome.bytes_relayed.to_be_bytes());
+        bytes.extend_from_slice(&self.outcome.latency_ms.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Check the signature and that the attester is not its own subject
+    pub fn verify(&self) -> Result<(), AttestationError> {
+        if self.attester_id() == self.subject {
+            return Err(AttestationError::SelfAttestation);
+        }
+        if !verify_signature(&self.attester, &self.signing_bytes(), &self.signature) {
+            return Err(AttestationError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// How satisfied the attester was
+    ///
+    /// Successful sessions count more the more data they carried and less
+    /// the slower they were; failures count against the subject.
+    pub fn satisfaction(&self) -> f64 {
+        if !self.outcome.success {
+            return -FAILURE_PENALTY;
+        }
+        let megabytes = self.outcome.bytes_relayed as f64 / (1024.0 * 1024.0);
+        let speed = REFERENCE_LATENCY_MS / (REFERENCE_LATENCY_MS + self.outcome.latency_ms as f64);
+        (1.0 + megabytes.ln_1p()) * 2.0 * speed
+    }
+}
+
+/// EigenTrust parameters
+#[derive(Debug, Clone)]
+pub struct TrustConfig {
+    /// Peers trusted a priori, besides the local node (e.g. bootstrap nodes)
+    pub pre_trusted: HashSet<DeviceID>,
+    /// Weight of the pre-trusted distribution in every iteration
+    pub alpha: f64,
+    /// Convergence threshold on the L1 change between iterations
+    pub epsilon: f64,
+    /// Iteration limit
+    pub max_iterations: usize,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        Self {
+            pre_trusted: HashSet::new(),
+            alpha: 0.15,
+            epsilon: 1e-6,
+            max_iterations: 100,
+        }
+    }
+}
+
+/// Attestations known to this node and the reputations computed from them
+#[derive(Debug, Clone)]
+pub struct ReputationLedger {
+    local: DeviceID,
+    config: TrustConfig,
+    /// Attester -> subject -> attestations, oldest first
+    attestations: HashMap<DeviceID, HashMap<DeviceID, Vec<Attestation>>>,
+    /// Global trust, summing to 1 over the attestation graph
+    trust: HashMap<DeviceID, f64>,
+    scores: HashMap<DeviceID, ReputationScore>,
+}
+
+impl ReputationLedger {
+    /// Create an empty ledger for the local node
+    pub fn new(local: DeviceID, config: TrustConfig) -> Self {
+        Self {
+            local,
+            config,
+            attestations: HashMap::new(),
+            trust: HashMap::new(),
+            scores: HashMap::new(),
+        }
+    }
+
+    /// The same attestations as seen from another node
+    pub fn for_node(&self, local: DeviceID) -> Self {
+        Self { local, ..self.clone() }
+    }
+
+    /// Verify and remember an attestation; returns false for duplicates
+    pub fn add(&mut self, attestation: Attestation) -> Result<bool, AttestationError> {
+        attestation.verify()?;
+        let known = self.attestations
+            .entry(attestation.attester_id())
+            .or_default()
+            .entry(attestation.subject)
+            .or_default();
+        if known.iter().any(|a| a.session_id == attestation.session_id) {
+            return Ok(false);
+        }
+        known.push(attestation);
+        known.sort_by_key(|a| a.timestamp);
+        if known.len() > MAX_ATTESTATIONS_PER_PEER {
+            known.remove(0);
+        }
+        Ok(true)
+    }
+
+    /// Attest to a session with `subject` and remember it
+    pub fn attest(&mut self, keypair: &NodeKeypair, subject: DeviceID, session_id: [u8; 32], outcome: SessionOutcome, now: u64) -> Result<Attestation, AttestationError> {
+        let attestation = Attestation::new(keypair, subject, session_id, outcome, now);
+        self.add(attestation.clone())?;
+        Ok(attestation)
+    }
+
+    /// Attestations issued by `attester`
+    pub fn attestations_by(&self, attester: &DeviceID) -> Vec<Attestation> {
+        self.attestations.get(attester)
+            .map(|subjects| subjects.values().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Sign our own attestations into a record for the DHT
+    pub fn to_record(&self, keypair: &NodeKeypair, sequence: u64, expires_at: u64) -> Result<SignedRecord, Error> {
+        let value = bincode::serialize(&self.attestations_by(&keypair.node_id()))
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        Ok(SignedRecord::new(keypair, ATTESTATION_NAMESPACE, value, sequence, expires_at))
+    }
+
+    /// Import the attestations from a verified attestation record
+    pub fn import_record(&mut self, record: &SignedRecord) -> Result<usize, AttestationError> {
+        let attestations: Vec<Attestation> = bincode::deserialize(&record.value)
+            .map_err(|e| AttestationError::Malformed(e.to_string()))?;
+        let publisher = record.publisher_id();
+        let mut added = 0;
+        for attestation in attestations {
+            if attestation.attester_id() != publisher {
+                return Err(AttestationError::PublisherMismatch);
+            }
+            if self.add(attestation)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Fetch and import the attestations `attester` published
+    pub async fn fetch<D: DhtNode>(&mut self, dht: &D, attester: DeviceID) -> Result<usize, Error> {
+        let key = SignedRecord::key_for(ATTESTATION_NAMESPACE, &attester);
+        match dht.get_value(key).await? {
+            Some(record) => Ok(self.import_record(&record)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Recompute global trust with EigenTrust
+    ///
+    /// Local trust c_ij is i's positive satisfaction with j, normalised over
+    /// everyone i attested to. Global trust iterates t = (1 - a) C^T t + a p,
+    /// where p spreads evenly over the local node and the pre-trusted peers.
+    pub fn compute(&mut self) {
+        let mut peers: HashSet<DeviceID> = HashSet::new();
+        peers.insert(self.local);
+        peers.extend(self.config.pre_trusted.iter().copied());
+        for (attester, subjects) in &self.attestations {
+            peers.insert(*attester);
+            peers.extend(subjects.keys().copied());
+        }
+        let mut peers: Vec<DeviceID> = peers.into_iter().collect();
+        peers.sort_by_key(|p| p.0);
+        let index: HashMap<DeviceID, usize> = peers.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+        let n = peers.len();
+
+        let mut pre_trusted = vec![0.0; n];
+        pre_trusted[index[&self.local]] = 1.0;
+        for peer in &self.config.pre_trusted {
+            pre_trusted[index[peer]] = 1.0;
+        }
+        let total: f64 = pre_trusted.iter().sum();
+        pre_trusted.iter_mut().for_each(|p| *p /= total);
+
+        // Sparse rows of the normalised local trust matrix
+        let mut rows: Vec<Vec<(usize, f64)>> = vec![Vec::new(); n];
+        for (attester, subjects) in &self.attestations {
+            let row: Vec<(usize, f64)> = subjects.iter()
+                .map(|(subject, list)| (index[subject], list.iter().map(Attestation::satisfaction).sum::<f64>()))
+                .filter(|(_, s)| *s > 0.0)
+                .collect();
+            let sum: f64 = row.iter().map(|(_, s)| s).sum();
+            rows[index[attester]] = row.into_iter().map(|(j, s)| (j, s / sum)).collect();
+        }
+
+        let alpha = self.config.alpha;
+        let mut trust = pre_trusted.clone();
+        for iteration in 0..self.config.max_iterations {
+            let mut next: Vec<f64> = pre_trusted.iter().map(|p| alpha * p).collect();
+            for (i, row) in rows.iter().enumerate() {
+                if row.is_empty() {
+                    // Peers that trust nobody defer to the pre-trusted peers
+                    for (j, p) in pre_trusted.iter().enumerate() {
+                        next[j] += (1.0 - alpha) * trust[i] * p;
+                    }
+                } else {
+                    for (j, c) in row {
+                        next[*j] += (1.0 - alpha) * trust[i] * c;
+                    }
+                }
+            }
+            let delta: f64 = next.iter().zip(&trust).map(|(a, b)| (a - b).abs()).sum();
+            trust = next;
+            if delta < self.config.epsilon {
+                debug!("EigenTrust converged after {} iterations", iteration + 1);
+                break;
+            }
+        }
+
+        // The most trusted peer scores the maximum; others scale with it
+        let max = trust.iter().copied().fold(0.0, f64::max);
+        self.trust = peers.iter().copied().zip(trust.iter().copied()).collect();
+        self.scores = self.trust.iter()
+            .map(|(peer, t)| {
+                let score = if max > 0.0 { (t / max * ReputationScore::MAX.value() as f64).round() as u64 } else { 0 };
+                (*peer, ReputationScore::new(score))
+            })
+            .collect();
+    }
+
+    /// Global trust of a peer, if it is part of the attestation graph
+    pub fn trust(&self, peer: &DeviceID) -> Option<f64> {
+        self.trust.get(peer).copied()
+    }
+
+    /// Computed reputation; peers nobody attested to get the default score
+    pub fn score(&self, peer: &DeviceID) -> ReputationScore {
+        self.scores.get(peer).copied().unwrap_or(ReputationScore::DEFAULT)
+    }
+
+    /// All computed reputations
+    pub fn scores(&self) -> &HashMap<DeviceID, ReputationScore> {
+        &self.scores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn success() -> SessionOutcome {
+        SessionOutcome { success: true, bytes_relayed: 10 * 1024 * 1024, latency_ms: 50 }
+    }
+
+    fn failure() -> SessionOutcome {
+        SessionOutcome { success: false, bytes_relayed: 0, latency_ms: 0 }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let client = NodeKeypair::generate();
+        let relay = NodeKeypair::generate();
+        let attestation = Attestation::new(&client, relay.node_id(), [1u8; 32], success(), 100);
+        assert_eq!(attestation.verify(), Ok(()));
+        assert!(attestation.satisfaction() > 0.0);
+
+        let mut tampered = attestation.clone();
+        tampered.outcome.success = false;
+        assert_eq!(tampered.verify(), Err(AttestationError::InvalidSignature));
+
+        let own = Attestation::new(&client, client.node_id(), [1u8; 32], success(), 100);
+        assert_eq!(own.verify(), Err(AttestationError::SelfAttestation));
+
+        let slow = Attestation::new(&client, relay.node_id(), [2u8; 32], SessionOutcome { latency_ms: 2_000, ..success() }, 100);
+        assert!(slow.satisfaction() < attestation.satisfaction());
+        assert!(Attestation::new(&client, relay.node_id(), [3u8; 32], failure(), 100).satisfaction() < 0.0);
+    }
+
+    #[test]
+    fn test_eigentrust_weights_attesters() {
+        let local = NodeKeypair::generate();
+        let good = NodeKeypair::generate();
+        let bad = NodeKeypair::generate();
+        let mut ledger = ReputationLedger::new(local.node_id(), TrustConfig::default());
+
+        // We had good sessions with `good` and failed ones with `bad`
+        for i in 0..3u8 {
+            ledger.attest(&local, good.node_id(), [i; 32], success(), 100).unwrap();
+            ledger.attest(&local, bad.node_id(), [i; 32], failure(), 100).unwrap();
+        }
+        // Duplicate sessions are ignored
+        let repeat = Attestation::new(&local, good.node_id(), [0; 32], success(), 100);
+        assert_eq!(ledger.add(repeat), Ok(false));
+        assert_eq!(ledger.attestations_by(&local.node_id()).len(), 6);
+
+        // A Sybil ring vouching for itself gains nothing without trust flowing in
+        let sybils: Vec<_> = (0..5).map(|_| NodeKeypair::generate()).collect();
+        for (i, sybil) in sybils.iter().enumerate() {
+            let next = &sybils[(i + 1) % sybils.len()];
+            ledger.add(Attestation::new(sybil, next.node_id(), [9; 32], success(), 100)).unwrap();
+            ledger.add(Attestation::new(sybil, bad.node_id(), [9; 32], success(), 100)).unwrap();
+        }
+        // `good` vouches for a newcomer
+        let newcomer = NodeKeypair::generate();
+        ledger.add(Attestation::new(&good, newcomer.node_id(), [7; 32], success(), 100)).unwrap();
+
+        ledger.compute();
+        let score = |k: &NodeKeypair| ledger.score(&k.node_id());
+        assert_eq!(score(&local), ReputationScore::MAX);
+        assert!(score(&good) > score(&bad));
+        assert!(score(&newcomer) > score(&bad));
+        assert_eq!(score(&bad).value(), 0);
+        assert!(sybils.iter().all(|s| score(s).value() == 0));
+        assert_eq!(ledger.score(&NodeKeypair::generate().node_id()), ReputationScore::DEFAULT);
+
+        let total: f64 = ledger.scores().keys().filter_map(|p| ledger.trust(p)).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let alice = NodeKeypair::generate();
+        let relay = NodeKeypair::generate();
+        let mut ledger = ReputationLedger::new(alice.node_id(), TrustConfig::default());
+        ledger.attest(&alice, relay.node_id(), [1; 32], success(), 100).unwrap();
+
+        let record = ledger.to_record(&alice, 1, u64::MAX).unwrap();
+        let mut other = ReputationLedger::new(relay.node_id(), TrustConfig::default());
+        assert_eq!(other.import_record(&record), Ok(1));
+        assert_eq!(other.attestations_by(&alice.node_id()).len(), 1);
+
+        // Attestations smuggled into someone else's record are refused
+        let mallory = NodeKeypair::generate();
+        let forged = SignedRecord::new(&mallory, ATTESTATION_NAMESPACE, record.value.clone(), 1, u64::MAX);
+        assert_eq!(other.import_record(&forged), Err(AttestationError::PublisherMismatch));
+    }
+}
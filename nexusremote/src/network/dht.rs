@@ -1,12 +1,16 @@
 //! DHT (Distributed Hash Table) implementation with weighted routing
 
+use crate::core::crypto::pow;
 use crate::core::distance::*;
 use crate::core::types::*;
-use crate::core::state::NodeState;
-use crate::Error;
+use crate::network::lookup::{LookupMode, LookupState};
+use crate::network::record::{select_record, RecordError, SignedRecord};
 use async_trait::async_trait;
 use std::collections::{HashMap, VecDeque};
-use tracing::{info, warn, debug};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{warn, debug};
 
 /// Kademlia bucket configuration
 const K: usize = 20;
@@ -18,11 +22,11 @@ pub trait DhtNode: Send + Sync {
     /// Find peers close to a target ID
     async fn find_peer(&self, target: DeviceID) -> Result<Vec<PeerInfo>, crate::Error>;
     
-    /// Store a value in the DHT
-    async fn put_value(&mut self, key: [u8; 32], value: Vec<u8>) -> Result<(), crate::Error>;
+    /// Store a signed record under its key
+    async fn put_value(&mut self, record: SignedRecord) -> Result<(), crate::Error>;
     
-    /// Retrieve a value from the DHT
-    async fn get_value(&self, key: [u8; 32]) -> Result<Option<Vec<u8>>, crate::Error>;
+    /// Retrieve the highest-sequence valid record stored under a key
+    async fn get_value(&self, key: [u8; 32]) -> Result<Option<SignedRecord>, crate::Error>;
     
     /// Add a peer to the routing table
     async fn add_peer(&mut self, peer: PeerInfo) -> Result<(), crate::Error>;
@@ -31,6 +35,115 @@ pub trait DhtNode: Send + Sync {
     fn local_peer(&self) -> PeerInfo;
 }
 
+/// Failed requests tolerated before a peer is replaced from the replacement cache
+pub const MAX_PEER_FAILURES: u32 = 3;
+
+/// Buckets without a lookup for this long are refreshed (seconds)
+pub const BUCKET_REFRESH_INTERVAL: u64 = 60 * 60;
+
+/// Peers from one IPv4 /24 allowed in a bucket
+pub const MAX_PEERS_PER_SUBNET: usize = 2;
+
+/// Checks a peer must pass before entering the routing table
+#[derive(Debug, Clone)]
+pub struct AdmissionPolicy {
+    /// Difficulty the peer's identity proof must meet; `None` admits peers without one
+    pub identity_difficulty: Option<u32>,
+    /// Peers sharing an IPv4 /24 allowed per bucket; `None` disables the limit
+    pub max_per_subnet: Option<usize>,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self {
+            identity_difficulty: Some(pow::IDENTITY_DIFFICULTY),
+            max_per_subnet: Some(MAX_PEERS_PER_SUBNET),
+        }
+    }
+}
+
+impl AdmissionPolicy {
+    /// Admit every peer
+    pub fn disabled() -> Self {
+        Self {
+            identity_difficulty: None,
+            max_per_subnet: None,
+        }
+    }
+
+    /// Whether the peer proves the work behind its identity
+    pub fn admits_identity(&self, peer: &PeerInfo) -> bool {
+        match self.identity_difficulty {
+            Some(difficulty) => peer.identity_proof.as_ref()
+                .is_some_and(|proof| proof.verify(&peer.device_id, difficulty)),
+            None => true,
+        }
+    }
+}
+
+/// IPv4 /24 prefixes a peer advertises addresses in
+pub fn ipv4_subnets(peer: &PeerInfo) -> Vec<[u8; 3]> {
+    let mut subnets: Vec<_> = peer.addresses.iter()
+        .filter_map(|addr| {
+            // Multiaddrs ("/ip4/1.2.3.4/udp/...") or plain socket addresses
+            let ip = match addr.strip_prefix("/ip4/") {
+                Some(rest) => rest.split('/').next()?.parse::<Ipv4Addr>().ok()?,
+                None => addr.parse::<SocketAddrV4>().map(|a| *a.ip())
+                    .or_else(|_| addr.parse::<Ipv4Addr>())
+                    .ok()?,
+            };
+            let [a, b, c, _] = ip.octets();
+            Some([a, b, c])
+        })
+        .collect();
+    subnets.sort_unstable();
+    subnets.dedup();
+    subnets
+}
+
+/// Liveness check used before evicting a routing table entry
+pub trait PeerPinger {
+    /// Ping `peer`. Pingers that can answer straight away return the result;
+    /// asynchronous ones return `None` and later report it through
+    /// `WeightedRoutingTable::on_ping_result`.
+    fn ping(&mut self, peer: &PeerInfo) -> Option<bool>;
+}
+
+/// Pinger that assumes every known peer is alive
+pub struct AssumeAlive;
+
+impl PeerPinger for AssumeAlive {
+    fn ping(&mut self, _peer: &PeerInfo) -> Option<bool> {
+        Some(true)
+    }
+}
+
+/// What happened to a peer offered to the routing table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// Added to a bucket with room, or in place of a dead peer
+    Inserted,
+    /// Already known; marked as seen
+    Updated,
+    /// Bucket full of live peers; kept in the replacement cache
+    Cached,
+    /// Failed the admission policy
+    Refused,
+    /// The local node itself
+    Ignored,
+}
+
+/// Routing table entry with liveness information
+#[derive(Debug, Clone)]
+pub struct RoutingEntry {
+    /// Peer
+    pub peer: PeerInfo,
+    /// When the peer was last heard from (unix seconds)
+    pub last_seen: u64,
+    /// Consecutive failed requests
+    pub failures: u32,
+}
+
 /// Weighted Kademlia routing table
 #[derive(Clone)]
 pub struct WeightedRoutingTable {
@@ -38,15 +151,25 @@ pub struct WeightedRoutingTable {
     local_peer: PeerInfo,
     /// K-buckets, indexed by prefix length
     buckets: Vec<Bucket>,
-    /// Maximum bucket size
-    k: usize,
+    /// Checks applied to new peers
+    admission: AdmissionPolicy,
+    /// Locally computed reputations; peers' own claims are replaced with these
+    reputations: HashMap<DeviceID, ReputationScore>,
+    /// Ordering used to pick the closest peers
+    policy: Arc<dyn DistancePolicy>,
 }
 
 /// A single K-bucket
 #[derive(Clone)]
 struct Bucket {
-    /// Peers in this bucket, sorted by last seen
-    peers: VecDeque<PeerInfo>,
+    /// Entries in this bucket, most recently seen first
+    entries: VecDeque<RoutingEntry>,
+    /// Peers waiting for a slot, most recently seen first
+    replacements: VecDeque<PeerInfo>,
+    /// Least-recently-seen peer with a ping outstanding
+    pinging: Option<DeviceID>,
+    /// When a lookup last targeted this bucket
+    last_lookup: u64,
     /// Maximum size
     max_size: usize,
 }
@@ -54,39 +177,128 @@ struct Bucket {
 impl Bucket {
     fn new(max_size: usize) -> Self {
         Self {
-            peers: VecDeque::new(),
+            entries: VecDeque::new(),
+            replacements: VecDeque::new(),
+            pinging: None,
+            last_lookup: 0,
             max_size,
         }
     }
     
-    fn add_peer(&mut self, peer: PeerInfo) {
-        // Remove if already present
-        self.peers.retain(|p| p.device_id != peer.device_id);
+    fn position(&self, device_id: &DeviceID) -> Option<usize> {
+        self.entries.iter().position(|e| e.peer.device_id == *device_id)
+    }
+    
+    fn insert(&mut self, peer: PeerInfo, now: u64, pinger: &mut dyn PeerPinger) -> InsertOutcome {
+        if let Some(pos) = self.position(&peer.device_id) {
+            let mut entry = self.entries.remove(pos).unwrap();
+            entry.peer = peer;
+            entry.last_seen = now;
+            entry.failures = 0;
+            self.entries.push_front(entry);
+            return InsertOutcome::Updated;
+        }
         
-        // Add to front (most recent)
-        self.peers.push_front(peer);
+        if self.entries.len() < self.max_size {
+            self.replacements.retain(|p| p.device_id != peer.device_id);
+            self.entries.push_front(RoutingEntry { peer, last_seen: now, failures: 0 });
+            return InsertOutcome::Inserted;
+        }
         
-        // Trim if over capacity
-        while self.peers.len() > self.max_size {
-            self.peers.pop_back();
+        // Full: long-lived peers are kept unless they stop answering
+        let device_id = peer.device_id;
+        self.replacements.retain(|p| p.device_id != device_id);
+        self.replacements.push_front(peer);
+        self.replacements.truncate(self.max_size);
+        
+        if self.pinging.is_some() {
+            return InsertOutcome::Cached;
+        }
+        let oldest = self.entries.back().unwrap().peer.clone();
+        self.pinging = Some(oldest.device_id);
+        match pinger.ping(&oldest) {
+            Some(alive) => {
+                self.on_ping_result(&oldest.device_id, alive, now);
+                if self.position(&device_id).is_some() {
+                    InsertOutcome::Inserted
+                } else {
+                    InsertOutcome::Cached
+                }
+            }
+            None => InsertOutcome::Cached,
+        }
+    }
+    
+    fn on_ping_result(&mut self, device_id: &DeviceID, alive: bool, now: u64) {
+        if self.pinging == Some(*device_id) {
+            self.pinging = None;
+        }
+        if alive {
+            self.mark_seen(device_id, now);
+        } else {
+            self.evict(device_id, now);
         }
     }
     
+    fn mark_seen(&mut self, device_id: &DeviceID, now: u64) {
+        if let Some(pos) = self.position(device_id) {
+            let mut entry = self.entries.remove(pos).unwrap();
+            entry.last_seen = now;
+            entry.failures = 0;
+            self.entries.push_front(entry);
+        }
+    }
+    
+    fn mark_failed(&mut self, device_id: &DeviceID, now: u64) {
+        let Some(pos) = self.position(device_id) else { return };
+        self.entries[pos].failures += 1;
+        // Without a replacement a flaky peer is still better than an empty slot
+        if self.entries[pos].failures >= MAX_PEER_FAILURES && !self.replacements.is_empty() {
+            self.evict(device_id, now);
+        }
+    }
+    
+    /// Remove a peer, promoting the freshest replacement into its slot
+    fn evict(&mut self, device_id: &DeviceID, now: u64) {
+        let Some(pos) = self.position(device_id) else { return };
+        self.entries.remove(pos);
+        if let Some(peer) = self.replacements.pop_front() {
+            self.entries.push_front(RoutingEntry { peer, last_seen: now, failures: 0 });
+        }
+    }
+    
+    /// Whether another peer from one of `subnets` would exceed the per-subnet limit
+    fn subnet_full(&self, subnets: &[[u8; 3]], limit: usize) -> bool {
+        subnets.iter().any(|subnet| {
+            self.entries.iter()
+                .filter(|e| ipv4_subnets(&e.peer).contains(subnet))
+                .count() >= limit
+        })
+    }
+    
     fn get_peers(&self) -> Vec<PeerInfo> {
-        self.peers.iter().cloned().collect()
+        self.entries.iter().map(|e| e.peer.clone()).collect()
     }
     
-    fn find_closest(&self, target: &DeviceID, count: usize) -> Vec<PeerInfo> {
-        let mut peers: Vec<_> = self.peers.iter().cloned().collect();
-        sort_peers_by_distance(&mut peers, target);
-        peers.truncate(count);
-        peers
+    /// Replace the reputation of every peer in the bucket
+    fn rescore(&mut self, reputations: &HashMap<DeviceID, ReputationScore>) {
+        let score = |peer: &mut PeerInfo| {
+            peer.reputation = reputations.get(&peer.device_id).copied().unwrap_or(ReputationScore::DEFAULT);
+        };
+        self.entries.iter_mut().for_each(|e| score(&mut e.peer));
+        self.replacements.iter_mut().for_each(score);
     }
+    
 }
 
 impl WeightedRoutingTable {
-    /// Create a new weighted routing table
+    /// Create a new weighted routing table with the default admission policy
     pub fn new(local_peer: PeerInfo, k: usize) -> Self {
+        Self::with_admission(local_peer, k, AdmissionPolicy::default())
+    }
+    
+    /// Create a routing table with a custom admission policy
+    pub fn with_admission(local_peer: PeerInfo, k: usize, admission: AdmissionPolicy) -> Self {
         let mut buckets = Vec::with_capacity(257);
         for _ in 0..257 {
             buckets.push(Bucket::new(k));
@@ -95,12 +307,24 @@ impl WeightedRoutingTable {
         Self {
             local_peer,
             buckets,
-            k,
+            admission,
+            reputations: HashMap::new(),
+            policy: DistancePolicyKind::default().policy(),
         }
     }
     
+    /// Rank peers with `policy` from now on
+    pub fn set_distance_policy(&mut self, policy: Arc<dyn DistancePolicy>) {
+        self.policy = policy;
+    }
+    
+    /// Ordering used to pick the closest peers
+    pub fn distance_policy(&self) -> Arc<dyn DistancePolicy> {
+        self.policy.clone()
+    }
+    
     /// Calculate the bucket index for a given target
-    fn bucket_index(&self, target: &DeviceID) -> usize {
+    pub fn bucket_index(&self, target: &DeviceID) -> usize {
         let distance = calculate_raw_xor_distance(&self.local_peer.device_id, target);
         
         // Count the number of leading zero bits
@@ -117,10 +341,107 @@ impl WeightedRoutingTable {
         leading_zeros.min(256)
     }
     
-    /// Add a peer to the routing table
+    /// Add a peer, treating the peers already known as alive
     pub fn add_peer(&mut self, peer: PeerInfo) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.insert(peer, now, &mut AssumeAlive);
+    }
+    
+    /// Offer a peer to the routing table
+    ///
+    /// Peers must pass the admission policy. When the bucket is full the
+    /// least-recently-seen peer is pinged and only evicted if it fails to
+    /// answer; until then the newcomer waits in the bucket's replacement cache.
+    pub fn insert(&mut self, mut peer: PeerInfo, now: u64, pinger: &mut dyn PeerPinger) -> InsertOutcome {
+        if peer.device_id == self.local_peer.device_id {
+            return InsertOutcome::Ignored;
+        }
+        peer.reputation = self.reputation_of(&peer.device_id);
+        if !self.admission.admits_identity(&peer) {
+            debug!("Refusing {}: missing or invalid identity proof", peer.device_id);
+            return InsertOutcome::Refused;
+        }
         let bucket_idx = self.bucket_index(&peer.device_id);
-        self.buckets[bucket_idx].add_peer(peer);
+        let bucket = &mut self.buckets[bucket_idx];
+        if let Some(limit) = self.admission.max_per_subnet {
+            if bucket.position(&peer.device_id).is_none() && bucket.subnet_full(&ipv4_subnets(&peer), limit) {
+                debug!("Refusing {}: too many peers from its /24 in bucket {}", peer.device_id, bucket_idx);
+                return InsertOutcome::Refused;
+            }
+        }
+        bucket.insert(peer, now, pinger)
+    }
+    
+    /// Report the outcome of a ping started by `insert`
+    pub fn on_ping_result(&mut self, device_id: &DeviceID, alive: bool, now: u64) {
+        let bucket_idx = self.bucket_index(device_id);
+        self.buckets[bucket_idx].on_ping_result(device_id, alive, now);
+    }
+    
+    /// Record that a peer answered a request
+    pub fn mark_seen(&mut self, device_id: &DeviceID, now: u64) {
+        let bucket_idx = self.bucket_index(device_id);
+        self.buckets[bucket_idx].mark_seen(device_id, now);
+    }
+    
+    /// Record that a request to a peer failed
+    pub fn mark_failed(&mut self, device_id: &DeviceID, now: u64) {
+        let bucket_idx = self.bucket_index(device_id);
+        self.buckets[bucket_idx].mark_failed(device_id, now);
+    }
+    
+    /// Drop a peer that went away, promoting a replacement into its slot
+    pub fn remove(&mut self, device_id: &DeviceID, now: u64) {
+        let bucket_idx = self.bucket_index(device_id);
+        let bucket = &mut self.buckets[bucket_idx];
+        bucket.replacements.retain(|p| p.device_id != *device_id);
+        bucket.evict(device_id, now);
+    }
+    
+    /// Liveness information for a known peer
+    pub fn entry(&self, device_id: &DeviceID) -> Option<&RoutingEntry> {
+        let bucket = &self.buckets[self.bucket_index(device_id)];
+        bucket.position(device_id).map(|pos| &bucket.entries[pos])
+    }
+    
+    /// Peers waiting for a slot in the bucket `device_id` falls into
+    pub fn replacements(&self, device_id: &DeviceID) -> Vec<PeerInfo> {
+        self.buckets[self.bucket_index(device_id)].replacements.iter().cloned().collect()
+    }
+    
+    /// Note that a lookup for `target` ran, which refreshes its bucket
+    pub fn record_lookup(&mut self, target: &DeviceID, now: u64) {
+        let bucket_idx = self.bucket_index(target);
+        self.buckets[bucket_idx].last_lookup = now;
+    }
+    
+    /// Random lookup targets for buckets that have not seen a lookup within `interval`
+    ///
+    /// Only buckets up to the deepest non-empty one are considered; closer
+    /// buckets are too small to ever hold a peer.
+    pub fn buckets_to_refresh(&self, now: u64, interval: u64) -> Vec<DeviceID> {
+        let Some(deepest) = self.buckets.iter().rposition(|b| !b.entries.is_empty()) else {
+            return Vec::new();
+        };
+        let mut rng = rand::thread_rng();
+        (0..=deepest.min(255))
+            .filter(|&i| now >= self.buckets[i].last_lookup + interval)
+            .map(|i| self.random_id_in_bucket(i, &mut rng))
+            .collect()
+    }
+    
+    /// An ID sharing exactly `index` leading bits with the local ID
+    fn random_id_in_bucket(&self, index: usize, rng: &mut impl rand::Rng) -> DeviceID {
+        let local = self.local_peer.device_id.0;
+        let (byte, bit) = (index / 8, index % 8);
+        let mut id: [u8; 32] = rng.gen();
+        // Keep the shared prefix, flip bit `index`, leave the rest random
+        id[..byte].copy_from_slice(&local[..byte]);
+        id[byte] = (local[byte] & !(0xff >> bit)) | (!local[byte] & (0x80 >> bit)) | (id[byte] & (0x7f >> bit));
+        DeviceID::new(id)
     }
     
     /// Find the K closest peers to a target
@@ -143,12 +464,25 @@ impl WeightedRoutingTable {
             i += 1;
         }
         
-        // Sort by weighted distance and take top K
-        sort_peers_by_distance(&mut candidates, &target);
+        // Sort by the table's distance policy and take top K
+        self.policy.sort(&mut candidates, &target);
         candidates.truncate(count);
         candidates
     }
     
+    /// Install locally computed reputations, replacing those of known peers
+    pub fn set_reputations(&mut self, reputations: HashMap<DeviceID, ReputationScore>) {
+        for bucket in &mut self.buckets {
+            bucket.rescore(&reputations);
+        }
+        self.reputations = reputations;
+    }
+    
+    /// Locally computed reputation of a peer
+    pub fn reputation_of(&self, device_id: &DeviceID) -> ReputationScore {
+        self.reputations.get(device_id).copied().unwrap_or(ReputationScore::DEFAULT)
+    }
+    
     /// Get all known peers
     pub fn all_peers(&self) -> Vec<PeerInfo> {
         self.buckets.iter()
@@ -157,57 +491,456 @@ impl WeightedRoutingTable {
     }
 }
 
+/// Outcome of an iterative lookup
+#[derive(Debug, Clone)]
+pub struct LookupResult {
+    /// Closest peers found, nearest first
+    pub peers: Vec<PeerInfo>,
+    /// Longest chain of referrals from a seed to a queried node
+    pub hops: usize,
+    /// Number of nodes queried
+    pub contacted: usize,
+}
+
+/// Record replication settings for `InMemoryDht`
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    /// Number of nodes each record is stored on
+    pub replication_factor: usize,
+    /// Record lifetime (seconds)
+    pub record_ttl: u64,
+    /// How often the originator republishes its records (seconds)
+    pub republish_interval: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            replication_factor: K,
+            record_ttl: 24 * 60 * 60,     // 24 hours
+            republish_interval: 60 * 60,  // 1 hour
+        }
+    }
+}
+
+/// Record held in a node's store
+#[derive(Debug, Clone)]
+struct StoredRecord {
+    record: SignedRecord,
+    expires_at: u64,
+}
+
+/// Record this node originated and keeps republishing
+#[derive(Debug, Clone)]
+struct PublishedRecord {
+    record: SignedRecord,
+    published_at: u64,
+}
+
+type SharedStore = Arc<Mutex<HashMap<[u8; 32], StoredRecord>>>;
+type Links = HashMap<DeviceID, InMemoryDhtHandle>;
+
+/// Pings in-memory nodes by checking whether their link is up
+struct LinkPinger<'a>(&'a Links);
+
+impl PeerPinger for LinkPinger<'_> {
+    fn ping(&mut self, peer: &PeerInfo) -> Option<bool> {
+        Some(self.0.get(&peer.device_id).is_some_and(|h| h.is_online()))
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 /// Simple in-memory DHT implementation for testing
+///
+/// Clones share state, so a clone can be handed to other nodes or the
+/// simulator. Lookups walk the network iteratively through the links each
+/// node holds to the nodes it knows, and records live on the
+/// `replication_factor` nodes closest to their key.
 #[derive(Clone)]
 pub struct InMemoryDht {
     /// Routing table
-    routing_table: WeightedRoutingTable,
+    routing_table: Arc<Mutex<WeightedRoutingTable>>,
     /// Local peer info
     local_peer: PeerInfo,
     /// Value store
-    store: std::sync::Arc<std::sync::Mutex<HashMap<[u8; 32], Vec<u8>>>>,
-    /// Other DHT nodes (for simulation)
-    other_nodes: HashMap<DeviceID, InMemoryDhtHandle>,
+    store: SharedStore,
+    /// Records we originated
+    published: Arc<Mutex<HashMap<[u8; 32], PublishedRecord>>>,
+    /// Reachable nodes (for simulation)
+    links: Arc<Mutex<Links>>,
+    /// Whether other nodes can reach us
+    online: Arc<AtomicBool>,
+    config: ReplicationConfig,
 }
 
 /// Handle to another in-memory DHT node
 #[derive(Clone)]
 struct InMemoryDhtHandle {
-    store: std::sync::Arc<std::sync::Mutex<HashMap<[u8; 32], Vec<u8>>>>,
-    peers: std::sync::Arc<std::sync::Mutex<Vec<PeerInfo>>>,
     peer_info: PeerInfo,
+    routing_table: Arc<Mutex<WeightedRoutingTable>>,
+    store: SharedStore,
+    /// Weak so that linked nodes do not keep each other alive
+    links: Weak<Mutex<Links>>,
+    online: Arc<AtomicBool>,
+    replication_factor: usize,
+}
+
+impl InMemoryDhtHandle {
+    fn is_online(&self) -> bool {
+        self.online.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Answer a FIND_NODE from `requester`, who the node learns about
+    fn find_node(&self, target: &DeviceID, requester: &InMemoryDhtHandle, now: u64) -> Vec<(PeerInfo, Option<InMemoryDhtHandle>)> {
+        let links = self.links.upgrade();
+        let peers = {
+            let mut table = self.routing_table.lock().unwrap();
+            match &links {
+                Some(links) => table.insert(requester.peer_info.clone(), now, &mut LinkPinger(&links.lock().unwrap())),
+                None => table.insert(requester.peer_info.clone(), now, &mut AssumeAlive),
+            };
+            table.find_closest_peers(*target, K)
+        };
+        let Some(links) = links else {
+            return peers.into_iter().map(|p| (p, None)).collect();
+        };
+        let (answer, is_new) = {
+            let mut links = links.lock().unwrap();
+            let is_new = links.insert(requester.peer_info.device_id, requester.clone()).is_none();
+            let answer = peers.into_iter()
+                .map(|p| {
+                    let handle = links.get(&p.device_id).cloned();
+                    (p, handle)
+                })
+                .collect();
+            (answer, is_new)
+        };
+        if is_new {
+            self.offer_records_to(requester, now);
+        }
+        answer
+    }
+
+    fn get_record(&self, key: &[u8; 32], now: u64) -> Option<SignedRecord> {
+        self.store.lock().unwrap()
+            .get(key)
+            .filter(|r| r.expires_at > now)
+            .map(|r| r.record.clone())
+    }
+
+    /// Validate and store a record, keeping the highest sequence
+    fn put_record(&self, key: [u8; 32], record: SignedRecord, expires_at: u64, now: u64) -> Result<(), RecordError> {
+        record.verify(&key, now)?;
+        let mut store = self.store.lock().unwrap();
+        if let Some(current) = store.get(&key).filter(|r| r.expires_at > now) {
+            if current.record == record {
+                // Republished copy: never shorten its lifetime
+                if current.expires_at >= expires_at {
+                    return Ok(());
+                }
+            } else {
+                record.supersedes(&current.record)?;
+            }
+        }
+        store.insert(key, StoredRecord { record, expires_at });
+        Ok(())
+    }
+
+    /// Hand a newly seen node the records it is now among the closest to
+    fn offer_records_to(&self, newcomer: &InMemoryDhtHandle, now: u64) {
+        if !newcomer.is_online() {
+            return;
+        }
+        let records: Vec<_> = self.store.lock().unwrap()
+            .iter()
+            .filter(|(_, r)| r.expires_at > now)
+            .map(|(k, r)| (*k, r.clone()))
+            .collect();
+        for (key, record) in records {
+            let closest = self.routing_table.lock().unwrap()
+                .find_closest_peers(DeviceID::new(key), self.replication_factor);
+            if closest.iter().any(|p| p.device_id == newcomer.peer_info.device_id) {
+                debug!("Replicating {:?} to new close node {}", &key[..8], newcomer.peer_info.device_id);
+                let _ = newcomer.put_record(key, record.record, record.expires_at, now);
+            }
+        }
+    }
+}
+
+/// Result of walking the network towards a target
+struct Walk {
+    result: LookupResult,
+    /// Handles of the closest responsive nodes, nearest first
+    closest: Vec<InMemoryDhtHandle>,
+    /// Copies of the record found, when walking for a key
+    records: Vec<SignedRecord>,
 }
 
 impl InMemoryDht {
     /// Create a new in-memory DHT
     pub fn new(local_peer: PeerInfo) -> Self {
+        Self::with_config(local_peer, ReplicationConfig::default())
+    }
+
+    /// Create an in-memory DHT with custom replication settings
+    ///
+    /// Simulated nodes use arbitrary device IDs, so no admission checks apply.
+    pub fn with_config(local_peer: PeerInfo, config: ReplicationConfig) -> Self {
+        Self::with_admission(local_peer, config, AdmissionPolicy::disabled())
+    }
+
+    /// Create an in-memory DHT whose routing table enforces `admission`
+    pub fn with_admission(local_peer: PeerInfo, config: ReplicationConfig, admission: AdmissionPolicy) -> Self {
         Self {
-            routing_table: WeightedRoutingTable::new(local_peer.clone(), K),
+            routing_table: Arc::new(Mutex::new(WeightedRoutingTable::with_admission(local_peer.clone(), K, admission))),
             local_peer,
-            store: std::sync::Arc::new(std::sync::Mutex::new(HashMap::new())),
-            other_nodes: HashMap::new(),
+            store: Arc::new(Mutex::new(HashMap::new())),
+            published: Arc::new(Mutex::new(HashMap::new())),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            online: Arc::new(AtomicBool::new(true)),
+            config,
         }
     }
-    
-    /// Connect to another node
+
+    fn handle(&self) -> InMemoryDhtHandle {
+        InMemoryDhtHandle {
+            peer_info: self.local_peer.clone(),
+            routing_table: self.routing_table.clone(),
+            store: self.store.clone(),
+            links: Arc::downgrade(&self.links),
+            online: self.online.clone(),
+            replication_factor: self.config.replication_factor,
+        }
+    }
+
+    /// Connect to another node, handing over records it should now hold
     pub fn connect_to(&mut self, other: &mut InMemoryDht) {
-        let other_handle = InMemoryDhtHandle {
-            store: other.store.clone(),
-            peers: std::sync::Arc::new(std::sync::Mutex::new(other.routing_table.all_peers())),
-            peer_info: other.local_peer.clone(),
+        let now = now_secs();
+        self.links.lock().unwrap().insert(other.local_peer.device_id, other.handle());
+        self.insert_peer(other.local_peer.clone(), now);
+
+        other.links.lock().unwrap().insert(self.local_peer.device_id, self.handle());
+        other.insert_peer(self.local_peer.clone(), now);
+
+        self.handle().offer_records_to(&other.handle(), now);
+        other.handle().offer_records_to(&self.handle(), now);
+    }
+
+    /// Offer a peer to our routing table, pinging through our links
+    fn insert_peer(&self, peer: PeerInfo, now: u64) -> InsertOutcome {
+        let mut table = self.routing_table.lock().unwrap();
+        table.insert(peer, now, &mut LinkPinger(&self.links.lock().unwrap()))
+    }
+
+    /// Look up a random ID in every bucket that has not seen a lookup recently
+    pub fn refresh_buckets(&self, now: u64) -> usize {
+        let targets = self.routing_table.lock().unwrap()
+            .buckets_to_refresh(now, BUCKET_REFRESH_INTERVAL);
+        for target in &targets {
+            self.walk(*target, None, now);
+        }
+        targets.len()
+    }
+
+    /// Install locally computed reputations used to rank peers
+    pub fn set_reputations(&self, reputations: HashMap<DeviceID, ReputationScore>) {
+        self.routing_table.lock().unwrap().set_reputations(reputations);
+    }
+    
+    fn reputation_of(&self, device_id: &DeviceID) -> ReputationScore {
+        self.routing_table.lock().unwrap().reputation_of(device_id)
+    }
+    
+    /// Rank peers with `policy` in lookups and replication
+    pub fn set_distance_policy(&self, policy: Arc<dyn DistancePolicy>) {
+        self.routing_table.lock().unwrap().set_distance_policy(policy);
+    }
+    
+    /// Stop answering other nodes (simulates leaving the network)
+    pub fn disconnect(&self) {
+        self.online.store(false, AtomicOrdering::Relaxed);
+    }
+
+    /// Answer other nodes again
+    pub fn reconnect(&self) {
+        self.online.store(true, AtomicOrdering::Relaxed);
+    }
+
+    /// Whether other nodes can reach us
+    pub fn is_online(&self) -> bool {
+        self.online.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Whether this node holds an unexpired copy of `key`
+    pub fn holds(&self, key: &[u8; 32]) -> bool {
+        self.handle().get_record(key, now_secs()).is_some()
+    }
+
+    /// Iterative Kademlia lookup for the peers closest to `target`
+    ///
+    /// Each round queries up to `ALPHA` of the closest unqueried nodes; the
+    /// lookup converges once the closest `K` nodes seen have all answered.
+    pub fn lookup(&self, target: DeviceID) -> LookupResult {
+        self.walk(target, None, now_secs()).result
+    }
+
+    /// Walk towards `target` in rounds of up to `ALPHA` queries
+    ///
+    /// The rounds are sequential by design: in-memory nodes answer as soon
+    /// as they are asked, so there is nothing to overlap, and a round's
+    /// queries run one after another before the next round starts.
+    fn walk(&self, target: DeviceID, find_value: Option<&[u8; 32]>, now: u64) -> Walk {
+        let (seeds, policy) = {
+            let mut table = self.routing_table.lock().unwrap();
+            table.record_lookup(&target, now);
+            (table.find_closest_peers(target, K), table.distance_policy())
         };
-        
-        self.other_nodes.insert(other.local_peer.device_id, other_handle);
-        self.routing_table.add_peer(other.local_peer.clone());
-        
-        let self_handle = InMemoryDhtHandle {
-            store: self.store.clone(),
-            peers: std::sync::Arc::new(std::sync::Mutex::new(self.routing_table.all_peers())),
-            peer_info: self.local_peer.clone(),
+        let mut handles: Links = self.links.lock().unwrap().clone();
+        // Hop at which each candidate was learned; seeds are one hop away
+        let mut depth: HashMap<DeviceID, usize> = seeds.iter().map(|p| (p.device_id, 1)).collect();
+        let mut state = LookupState::new(target, self.local_peer.device_id, seeds, LookupMode::Weighted, ALPHA, K)
+            .with_policy(policy);
+        let me = self.handle();
+        let key = find_value.copied();
+        let mut hops = 0;
+        let mut records = Vec::new();
+
+        loop {
+            let queries = state.next_queries();
+            if queries.is_empty() {
+                break;
+            }
+            for peer in queries {
+                let handle = match handles.get(&peer.device_id) {
+                    Some(handle) if handle.is_online() => handle.clone(),
+                    _ => {
+                        state.on_failure(&peer.device_id);
+                        self.routing_table.lock().unwrap().mark_failed(&peer.device_id, now);
+                        continue;
+                    }
+                };
+                self.routing_table.lock().unwrap().mark_seen(&peer.device_id, now);
+                let (answer, record) = Self::query(&handle, &me, target, key, now);
+                let hop = depth.get(&peer.device_id).copied().unwrap_or(1);
+                hops = hops.max(hop);
+                // Keep walking after a hit so a stale copy cannot hide a newer one
+                records.extend(record);
+                let mut peers = Vec::with_capacity(answer.len());
+                for (mut info, link) in answer {
+                    // Rank by our own view of the peer, not the responder's
+                    info.reputation = self.reputation_of(&info.device_id);
+                    if let Some(link) = link {
+                        handles.entry(info.device_id).or_insert(link);
+                    }
+                    depth.entry(info.device_id).or_insert(hop + 1);
+                    peers.push(info);
+                }
+                state.on_response(&peer.device_id, peers);
+            }
+        }
+
+        debug!("Lookup for {} took {} hops, {} nodes contacted", target, hops, state.contacted());
+        let peers = state.results();
+        let closest = peers.iter()
+            .filter_map(|p| handles.get(&p.device_id).cloned())
+            .collect();
+        Walk {
+            result: LookupResult {
+                peers,
+                hops,
+                contacted: state.contacted(),
+            },
+            closest,
+            records,
+        }
+    }
+
+    /// Ask `handle` for the nodes closest to `target`, and for its copy of `key`
+    fn query(
+        handle: &InMemoryDhtHandle,
+        me: &InMemoryDhtHandle,
+        target: DeviceID,
+        key: Option<[u8; 32]>,
+        now: u64,
+    ) -> (Vec<(PeerInfo, Option<InMemoryDhtHandle>)>, Option<SignedRecord>) {
+        let record = key.and_then(|key| handle.get_record(&key, now));
+        (handle.find_node(&target, me, now), record)
+    }
+
+    /// Store a record on the nodes closest to its key, returning how many copies were written
+    fn replicate(&self, record: &SignedRecord, now: u64) -> usize {
+        let key = record.key();
+        let target = DeviceID::new(key);
+        let walk = self.walk(target, None, now);
+
+        // We hold a copy too if we are among the closest nodes
+        let mut holders = walk.closest;
+        holders.push(self.handle());
+        let rated = |holder: &InMemoryDhtHandle| PeerInfo {
+            reputation: self.reputation_of(&holder.peer_info.device_id),
+            ..holder.peer_info.clone()
         };
-        
-        other.other_nodes.insert(self.local_peer.device_id, self_handle);
-        other.routing_table.add_peer(self.local_peer.clone());
+        let policy = self.routing_table.lock().unwrap().distance_policy();
+        holders.sort_by(|a, b| policy.compare(&rated(a), &rated(b), &target));
+        holders.truncate(self.config.replication_factor);
+
+        // A copy never outlives the record's own expiry
+        let expires_at = (now + self.config.record_ttl).min(record.expires_at);
+        holders.iter()
+            .filter(|holder| match holder.put_record(key, record.clone(), expires_at, now) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("{} refused record: {}", holder.peer_info.device_id, e);
+                    false
+                }
+            })
+            .count()
+    }
+
+    fn store_record(&self, record: SignedRecord, now: u64) -> Result<usize, crate::Error> {
+        let key = record.key();
+        record.verify(&key, now)?;
+        self.published.lock().unwrap().insert(key, PublishedRecord { record: record.clone(), published_at: now });
+        Ok(self.replicate(&record, now))
+    }
+
+    fn find_value(&self, key: [u8; 32], now: u64) -> Option<SignedRecord> {
+        let walk = self.walk(DeviceID::new(key), Some(&key), now);
+        select_record(&key, walk.records.into_iter().chain(self.handle().get_record(&key, now)), now)
+    }
+
+    /// Republish records we originated whose republish interval has passed
+    pub fn republish(&self, now: u64) -> usize {
+        let due: Vec<_> = {
+            let mut published = self.published.lock().unwrap();
+            // Records past their own expiry are not worth keeping alive
+            published.retain(|_, r| r.record.expires_at > now);
+            published.values_mut()
+                .filter(|r| now >= r.published_at + self.config.republish_interval)
+                .map(|r| {
+                    r.published_at = now;
+                    r.record.clone()
+                })
+                .collect()
+        };
+        for record in &due {
+            self.replicate(record, now);
+        }
+        due.len()
+    }
+
+    /// Drop records whose TTL has passed, returning how many were removed
+    pub fn expire_records(&self, now: u64) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let before = store.len();
+        store.retain(|_, r| r.expires_at > now);
+        before - store.len()
     }
 }
 
@@ -215,64 +948,24 @@ impl InMemoryDht {
 impl DhtNode for InMemoryDht {
     async fn find_peer(&self, target: DeviceID) -> Result<Vec<PeerInfo>, crate::Error> {
         debug!("Finding peer close to: {}", target);
-        
-        // Get closest from our routing table
-        let mut results = self.routing_table.find_closest_peers(target, K);
-        
-        // Also query other connected nodes (simulated)
-        for handle in self.other_nodes.values() {
-            let mut peer_list = handle.peers.lock().unwrap();
-            sort_peers_by_distance(&mut peer_list, &target);
-            results.extend(peer_list.iter().take(ALPHA).cloned());
-        }
-        
-        // Deduplicate and sort
-        results.sort_by(|a, b| compare_by_distance(a, b, &target));
-        results.dedup_by(|a, b| a.device_id == b.device_id);
-        results.truncate(K);
-        
-        Ok(results)
+        Ok(self.lookup(target).peers)
     }
     
-    async fn put_value(&mut self, key: [u8; 32], value: Vec<u8>) -> Result<(), crate::Error> {
-        debug!("Putting value with key: {:?}", &key[..8]);
-        
-        // Store locally
-        let mut store = self.store.lock().unwrap();
-        store.insert(key, value.clone());
-        
-        // Also store on connected nodes (simulated)
-        for handle in self.other_nodes.values() {
-            let mut other_store = handle.store.lock().unwrap();
-            other_store.insert(key, value.clone());
-        }
-        
+    async fn put_value(&mut self, record: SignedRecord) -> Result<(), crate::Error> {
+        debug!("Putting record with key: {:?}", &record.key()[..8]);
+        let copies = self.store_record(record, now_secs())?;
+        debug!("Stored {} copies", copies);
         Ok(())
     }
     
-    async fn get_value(&self, key: [u8; 32]) -> Result<Option<Vec<u8>>, crate::Error> {
+    async fn get_value(&self, key: [u8; 32]) -> Result<Option<SignedRecord>, crate::Error> {
         debug!("Getting value with key: {:?}", &key[..8]);
-        
-        // Check local store first
-        let store = self.store.lock().unwrap();
-        if let Some(value) = store.get(&key) {
-            return Ok(Some(value.clone()));
-        }
-        
-        // Check connected nodes
-        for handle in self.other_nodes.values() {
-            let other_store = handle.store.lock().unwrap();
-            if let Some(value) = other_store.get(&key) {
-                return Ok(Some(value.clone()));
-            }
-        }
-        
-        Ok(None)
+        Ok(self.find_value(key, now_secs()))
     }
     
     async fn add_peer(&mut self, peer: PeerInfo) -> Result<(), crate::Error> {
         debug!("Adding peer: {}", peer.device_id);
-        self.routing_table.add_peer(peer);
+        self.insert_peer(peer, now_secs());
         Ok(())
     }
     
@@ -284,6 +977,7 @@ impl DhtNode for InMemoryDht {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::crypto::{IdentityProof, NodeKeypair};
     
     #[test]
     fn test_routing_table_add_and_find() {
@@ -295,9 +989,11 @@ mod tests {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 100_000_000,
+            identity_proof: None,
         };
         
-        let mut table = WeightedRoutingTable::new(local_peer.clone(), K);
+        let admission = AdmissionPolicy { identity_difficulty: Some(8), ..Default::default() };
+        let mut table = WeightedRoutingTable::with_admission(local_peer.clone(), K, admission);
         
         // Add some peers
         for i in 0..30 {
@@ -309,6 +1005,7 @@ mod tests {
                 role: NodeRole::Idle,
                 addresses: vec![],
                 available_bandwidth: 100_000_000,
+                identity_proof: Some(IdentityProof::solve(&keypair, 8)),
             };
             table.add_peer(peer);
         }
@@ -320,9 +1017,124 @@ mod tests {
         assert_eq!(closest.len(), 5);
     }
     
+    /// Answers pings from a fixed set of live peers, or defers every ping
+    struct TestPinger {
+        alive: Option<Vec<DeviceID>>,
+        pinged: Vec<DeviceID>,
+    }
+    
+    impl PeerPinger for TestPinger {
+        fn ping(&mut self, peer: &PeerInfo) -> Option<bool> {
+            self.pinged.push(peer.device_id);
+            self.alive.as_ref().map(|alive| alive.contains(&peer.device_id))
+        }
+    }
+    
+    /// A small table where every peer built with `bucket_peer` lands in bucket 0
+    fn small_table() -> WeightedRoutingTable {
+        WeightedRoutingTable::with_admission(sim_peer(0, DeviceID::new([0u8; 32])), 2, AdmissionPolicy::disabled())
+    }
+    
+    fn bucket_peer(i: u8) -> PeerInfo {
+        let mut id = [0u8; 32];
+        id[0] = 0x80 | i;
+        sim_peer(i as usize, DeviceID::new(id))
+    }
+    
+    #[test]
+    fn test_full_bucket_pings_before_evicting() {
+        let mut table = small_table();
+        let (a, b, c) = (bucket_peer(1), bucket_peer(2), bucket_peer(3));
+        let mut pinger = TestPinger { alive: Some(vec![a.device_id, b.device_id]), pinged: vec![] };
+        
+        assert_eq!(table.insert(a.clone(), 10, &mut pinger), InsertOutcome::Inserted);
+        assert_eq!(table.insert(b.clone(), 20, &mut pinger), InsertOutcome::Inserted);
+        
+        // The least-recently-seen peer answers, so the newcomer waits
+        assert_eq!(table.insert(c.clone(), 30, &mut pinger), InsertOutcome::Cached);
+        assert_eq!(pinger.pinged, vec![a.device_id]);
+        assert_eq!(table.entry(&a.device_id).unwrap().last_seen, 30);
+        assert_eq!(table.replacements(&c.device_id).len(), 1);
+        
+        // Once b stops answering it is replaced by the cached peer
+        pinger.alive = Some(vec![a.device_id]);
+        let d = bucket_peer(4);
+        assert_eq!(table.insert(d.clone(), 40, &mut pinger), InsertOutcome::Inserted);
+        assert!(table.entry(&b.device_id).is_none());
+        assert_eq!(table.entry(&d.device_id).unwrap().last_seen, 40);
+        assert_eq!(table.replacements(&c.device_id)[0].device_id, c.device_id);
+    }
+    
+    #[test]
+    fn test_deferred_ping_and_failures() {
+        let mut table = small_table();
+        let (a, b, c) = (bucket_peer(1), bucket_peer(2), bucket_peer(3));
+        let mut pinger = TestPinger { alive: None, pinged: vec![] };
+        table.insert(a.clone(), 10, &mut pinger);
+        table.insert(b.clone(), 20, &mut pinger);
+        
+        assert_eq!(table.insert(c.clone(), 30, &mut pinger), InsertOutcome::Cached);
+        // Only one ping per bucket is outstanding at a time
+        table.insert(bucket_peer(4), 31, &mut pinger);
+        assert_eq!(pinger.pinged, vec![a.device_id]);
+        
+        table.on_ping_result(&a.device_id, false, 40);
+        assert!(table.entry(&a.device_id).is_none());
+        assert!(table.entry(&bucket_peer(4).device_id).is_some());
+        
+        // Repeated request failures evict a peer only while a replacement waits
+        for _ in 0..MAX_PEER_FAILURES {
+            table.mark_failed(&b.device_id, 50);
+        }
+        assert!(table.entry(&b.device_id).is_none());
+        assert!(table.entry(&c.device_id).is_some());
+        for _ in 0..MAX_PEER_FAILURES {
+            table.mark_failed(&c.device_id, 60);
+        }
+        assert_eq!(table.entry(&c.device_id).unwrap().failures, MAX_PEER_FAILURES);
+        table.mark_seen(&c.device_id, 70);
+        assert_eq!(table.entry(&c.device_id).unwrap().failures, 0);
+    }
+    
+    #[test]
+    fn test_bucket_refresh_targets() {
+        let local = NodeKeypair::generate().node_id();
+        let mut table = WeightedRoutingTable::with_admission(sim_peer(0, local), K, AdmissionPolicy::disabled());
+        let mut far = local.0;
+        far[0] ^= 0x80;
+        let mut near = local.0;
+        near[1] ^= 0x01;
+        table.add_peer(sim_peer(1, DeviceID::new(far)));
+        table.add_peer(sim_peer(2, DeviceID::new(near)));
+        
+        // Buckets 0..=15 are up to the deepest occupied one
+        let targets = table.buckets_to_refresh(BUCKET_REFRESH_INTERVAL, BUCKET_REFRESH_INTERVAL);
+        assert_eq!(targets.len(), 16);
+        for (i, target) in targets.iter().enumerate() {
+            assert_eq!(table.bucket_index(target), i);
+        }
+        
+        table.record_lookup(&targets[3], BUCKET_REFRESH_INTERVAL);
+        assert_eq!(table.buckets_to_refresh(BUCKET_REFRESH_INTERVAL, BUCKET_REFRESH_INTERVAL).len(), 15);
+    }
+    
+    #[test]
+    fn test_in_memory_refresh_and_dead_peers() {
+        let nodes = random_network(30, ReplicationConfig::default());
+        let now = now_secs();
+        assert!(nodes[0].refresh_buckets(now) > 0);
+        assert_eq!(nodes[0].refresh_buckets(now), 0);
+        
+        // Peers that stop answering during lookups accumulate failures
+        let peer = nodes[0].routing_table.lock().unwrap().all_peers()[0].device_id;
+        nodes.iter().find(|n| n.local_peer.device_id == peer).unwrap().disconnect();
+        nodes[0].lookup(peer);
+        assert!(nodes[0].routing_table.lock().unwrap().entry(&peer).unwrap().failures > 0);
+    }
+    
     #[tokio::test]
     async fn test_in_memory_dht() {
-        let keypair1 = crate::core::crypto::NodeKeypair::generate();
+        let keypair1 = NodeKeypair::generate();
         let peer1 = PeerInfo {
             peer_id: PeerID::new("peer1".to_string()),
             device_id: keypair1.node_id(),
@@ -330,9 +1142,10 @@ mod tests {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 100_000_000,
+            identity_proof: None,
         };
         
-        let keypair2 = crate::core::crypto::NodeKeypair::generate();
+        let keypair2 = NodeKeypair::generate();
         let peer2 = PeerInfo {
             peer_id: PeerID::new("peer2".to_string()),
             device_id: keypair2.node_id(),
@@ -340,6 +1153,7 @@ mod tests {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 100_000_000,
+            identity_proof: None,
         };
         
         let mut dht1 = InMemoryDht::new(peer1);
@@ -348,17 +1162,265 @@ mod tests {
         dht1.connect_to(&mut dht2);
         
         // Test put and get
-        let key = [1u8; 32];
-        let value = b"test value".to_vec();
+        let record = SignedRecord::new(&keypair1, "test", b"test value".to_vec(), 1, u64::MAX);
         
         // Put value in dht1
-        dht1.put_value(key, value.clone()).await.unwrap();
+        dht1.put_value(record.clone()).await.unwrap();
         
         // Get value from dht2
-        let retrieved = dht2.get_value(key).await.unwrap();
+        let retrieved = dht2.get_value(record.key()).await.unwrap();
+        
+        // Both nodes are among the closest to the key, so both hold a copy
+        assert_eq!(retrieved, Some(record));
+    }
+    
+    fn sim_peer(i: usize, device_id: DeviceID) -> PeerInfo {
+        PeerInfo {
+            peer_id: PeerID::new(format!("peer{}", i)),
+            device_id,
+            reputation: ReputationScore::new(500),
+            role: NodeRole::Idle,
+            addresses: vec![],
+            available_bandwidth: 100_000_000,
+            identity_proof: None,
+        }
+    }
+    
+    #[test]
+    fn test_iterative_lookup_walks_network() {
+        let mut nodes: Vec<InMemoryDht> = (0..40)
+            .map(|i| InMemoryDht::new(sim_peer(i, NodeKeypair::generate().node_id())))
+            .collect();
+        
+        // Each node knows one node from every non-empty bucket, which is all
+        // a walk needs to reach any target whatever the identities
+        for i in 0..nodes.len() {
+            let mut buckets = HashMap::new();
+            for (j, node) in nodes.iter().enumerate().filter(|(j, _)| *j != i) {
+                let bucket = nodes[i].routing_table.lock().unwrap().bucket_index(&node.local_peer.device_id);
+                buckets.entry(bucket).or_insert(j);
+            }
+            for j in buckets.into_values() {
+                let mut other = nodes[j].clone();
+                nodes[i].connect_to(&mut other);
+            }
+        }
+        
+        let mut max_hops = 0;
+        for from in nodes.iter().take(4) {
+            for to in nodes.iter().filter(|to| to.local_peer.device_id != from.local_peer.device_id) {
+                let target = to.local_peer.device_id;
+                let result = from.lookup(target);
+                assert_eq!(result.peers[0].device_id, target);
+                max_hops = max_hops.max(result.hops);
+            }
+        }
+        assert!(max_hops > 1);
+    }
+    
+    #[test]
+    fn test_iterative_lookup_follows_closer_peers() {
+        // A line of nodes, each one closer to the all-zero target than the last
+        let mut nodes: Vec<InMemoryDht> = (0..10)
+            .map(|i| {
+                let mut id = [0u8; 32];
+                id[0] = 0x90 - 0x10 * i as u8;
+                InMemoryDht::new(sim_peer(i, DeviceID::new(id)))
+            })
+            .collect();
+        for i in 0..nodes.len() - 1 {
+            let mut next = nodes[i + 1].clone();
+            nodes[i].connect_to(&mut next);
+        }
+        
+        let target = DeviceID::new([0u8; 32]);
+        let result = nodes[0].lookup(target);
+        
+        assert_eq!(result.peers[0].device_id, target);
+        assert_eq!(result.hops, 9);
+        assert_eq!(result.contacted, 9);
+    }
+    
+    fn random_network(count: usize, config: ReplicationConfig) -> Vec<InMemoryDht> {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut nodes: Vec<InMemoryDht> = (0..count)
+            .map(|i| InMemoryDht::with_config(sim_peer(i, DeviceID::new(rng.gen())), config.clone()))
+            .collect();
+        for i in 0..count {
+            for _ in 0..4 {
+                let j = rng.gen_range(0..count);
+                if i != j {
+                    let mut other = nodes[j].clone();
+                    nodes[i].connect_to(&mut other);
+                }
+            }
+        }
+        nodes
+    }
+    
+    #[tokio::test]
+    async fn test_records_survive_holder_churn() {
+        let config = ReplicationConfig { replication_factor: 8, ..Default::default() };
+        let mut nodes = random_network(60, config);
+        
+        let record = SignedRecord::new(&NodeKeypair::generate(), "churn", b"still here".to_vec(), 1, u64::MAX);
+        let key = record.key();
+        nodes[0].put_value(record.clone()).await.unwrap();
+        
+        let holders: Vec<_> = nodes.iter().filter(|n| n.holds(&key)).cloned().collect();
+        assert_eq!(holders.len(), 8);
+        
+        // Half of the holders leave the network
+        for holder in holders.iter().take(4) {
+            holder.disconnect();
+        }
+        
+        let reader = nodes.iter().find(|n| n.is_online() && !n.holds(&key)).unwrap();
+        assert_eq!(reader.get_value(key).await.unwrap(), Some(record));
+    }
+    
+    #[test]
+    fn test_record_expiry_and_republish() {
+        let config = ReplicationConfig {
+            replication_factor: 4,
+            record_ttl: 100,
+            republish_interval: 50,
+        };
+        let nodes = random_network(10, config);
+        let record = SignedRecord::new(&NodeKeypair::generate(), "ttl", b"v".to_vec(), 1, 10_000);
+        let key = record.key();
+        
+        assert_eq!(nodes[0].store_record(record, 0).unwrap(), 4);
+        
+        // Republishing at t=60 extends the TTL past t=120
+        assert_eq!(nodes[0].republish(40), 0);
+        assert_eq!(nodes[0].republish(60), 1);
+        let expired: usize = nodes.iter().map(|n| n.expire_records(120)).sum();
+        assert_eq!(expired, 0);
+        assert!(nodes[5].find_value(key, 120).is_some());
+        
+        // The lookup at t=120 walks the whole network, so holders may hand the
+        // record to close nodes it introduced; every copy must still expire
+        let holders = nodes.iter().filter(|n| n.handle().get_record(&key, 120).is_some()).count();
+        assert!(holders >= 4);
+        let expired: usize = nodes.iter().map(|n| n.expire_records(200)).sum();
+        assert_eq!(expired, holders);
+        assert!(nodes[5].find_value(key, 200).is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_replication_to_new_close_node() {
+        let config = ReplicationConfig { replication_factor: 3, ..Default::default() };
+        let mut nodes = random_network(20, config.clone());
+        
+        let record = SignedRecord::new(&NodeKeypair::generate(), "mine", b"mine".to_vec(), 1, u64::MAX);
+        let key = record.key();
+        
+        // The newcomer will be the closest node to the key
+        let newcomer_id = DeviceID::new(key);
+        let mut newcomer = InMemoryDht::with_config(sim_peer(99, newcomer_id), config);
+        nodes[0].put_value(record).await.unwrap();
+        assert!(!newcomer.holds(&key));
+        
+        // Join and bootstrap with a self-lookup
+        newcomer.connect_to(&mut nodes[1]);
+        newcomer.lookup(newcomer_id);
+        
+        assert!(newcomer.holds(&key));
+    }
+    
+    #[tokio::test]
+    async fn test_records_cannot_be_overwritten() {
+        let config = ReplicationConfig { replication_factor: 4, ..Default::default() };
+        let mut nodes = random_network(12, config);
+        let owner = NodeKeypair::generate();
+        let attacker = NodeKeypair::generate();
+        
+        let v1 = SignedRecord::new(&owner, "presence", b"v1".to_vec(), 1, u64::MAX);
+        let key = v1.key();
+        nodes[0].put_value(v1.clone()).await.unwrap();
+        
+        // Another publisher cannot store under the owner's key
+        let mut forged = SignedRecord::new(&attacker, "presence", b"evil".to_vec(), 9, u64::MAX);
+        forged.publisher = owner.public_key().to_bytes();
+        assert!(nodes[3].put_value(forged).await.is_err());
+        
+        // Replaying an older sequence does not roll the record back
+        let v2 = SignedRecord::new(&owner, "presence", b"v2".to_vec(), 2, u64::MAX);
+        nodes[1].put_value(v2.clone()).await.unwrap();
+        assert_eq!(nodes[2].store_record(v1, now_secs()).unwrap(), 0);
+        
+        for node in &nodes {
+            assert_eq!(node.get_value(key).await.unwrap(), Some(v2.clone()));
+        }
+    }
+    
+    fn proven_peer(keypair: &NodeKeypair, address: &str, difficulty: u32) -> PeerInfo {
+        let mut peer = sim_peer(0, keypair.node_id());
+        peer.addresses = vec![address.to_string()];
+        peer.identity_proof = Some(IdentityProof::solve(keypair, difficulty));
+        peer
+    }
+    
+    #[test]
+    fn test_admission_requires_identity_proof() {
+        let admission = AdmissionPolicy { identity_difficulty: Some(8), max_per_subnet: None };
+        let mut table = WeightedRoutingTable::with_admission(sim_peer(0, DeviceID::new([0u8; 32])), K, admission);
+        let keypair = NodeKeypair::generate();
+        
+        let mut unproven = proven_peer(&keypair, "/ip4/10.0.0.1/udp/4001/quic-v1", 8);
+        unproven.identity_proof = None;
+        assert_eq!(table.insert(unproven, 0, &mut AssumeAlive), InsertOutcome::Refused);
+        
+        // A proof mined for another identity does not transfer
+        let mut borrowed = proven_peer(&NodeKeypair::generate(), "/ip4/10.0.0.1/udp/4001/quic-v1", 8);
+        borrowed.device_id = keypair.node_id();
+        assert_eq!(table.insert(borrowed, 0, &mut AssumeAlive), InsertOutcome::Refused);
+        
+        let proven = proven_peer(&keypair, "/ip4/10.0.0.1/udp/4001/quic-v1", 8);
+        assert_eq!(table.insert(proven, 0, &mut AssumeAlive), InsertOutcome::Inserted);
+    }
+    
+    #[test]
+    fn test_subnet_limit_per_bucket() {
+        let admission = AdmissionPolicy { identity_difficulty: None, max_per_subnet: Some(2) };
+        let mut table = small_table();
+        table.admission = admission;
+        
+        let mut peer = |i: u8, address: &str| {
+            let mut peer = bucket_peer(i);
+            peer.addresses = vec![address.to_string()];
+            table.insert(peer, 0, &mut AssumeAlive)
+        };
+        assert_eq!(peer(1, "/ip4/198.51.100.1/udp/4001/quic-v1"), InsertOutcome::Inserted);
+        assert_eq!(peer(2, "198.51.100.2:4001"), InsertOutcome::Inserted);
+        assert_eq!(peer(3, "/ip4/198.51.100.3/tcp/4001"), InsertOutcome::Refused);
+        // Known peers may refresh their entry
+        assert_eq!(peer(1, "/ip4/198.51.100.1/udp/4001/quic-v1"), InsertOutcome::Updated);
+        
+        assert_eq!(ipv4_subnets(&bucket_peer(4)), Vec::<[u8; 3]>::new());
+    }
+    
+    #[test]
+    fn test_distance_policy_is_selectable() {
+        let target = DeviceID::new([0u8; 32]);
+        let mut table = WeightedRoutingTable::with_admission(sim_peer(0, DeviceID::new([0xff; 32])), K, AdmissionPolicy::disabled());
+        let id = |first: u8| {
+            let mut id = [0u8; 32];
+            id[0] = first;
+            DeviceID::new(id)
+        };
+        // The nearer peer is unreliable, the farther one shares its bucket
+        table.insert(sim_peer(1, id(0x40)), 0, &mut AssumeAlive);
+        table.insert(sim_peer(2, id(0x60)), 0, &mut AssumeAlive);
+        table.set_reputations(HashMap::from([(id(0x40), ReputationScore::MIN), (id(0x60), ReputationScore::MAX)]));
+        let closest = |table: &WeightedRoutingTable| table.find_closest_peers(target, 1)[0].device_id;
         
-        // The value should be found because dht1 and dht2 are connected
-        // and dht1's put_value stores in both its own store and dht2's handle
-        assert_eq!(retrieved, Some(value));
+        assert_eq!(closest(&table), id(0x60));
+        table.set_distance_policy(DistancePolicyKind::RawXor.policy());
+        assert_eq!(closest(&table), id(0x40));
+        table.set_distance_policy(DistancePolicyKind::BucketPreserving.policy());
+        assert_eq!(closest(&table), id(0x60));
     }
 }
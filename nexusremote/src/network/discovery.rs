@@ -1,35 +1,173 @@
 //! Peer discovery module
+//!
+//! Peers are found three ways: mDNS announcements on the local network
+//! (handled inside the libp2p swarm), dialing the configured bootstrap
+//! nodes, and a DHT lookup for our own ID, which fills the buckets around
+//! it. Whatever the source, a peer is reported once it enters the routing
+//! table and again when it leaves it.
 
+use crate::core::state::NodeState;
 use crate::core::types::*;
+use crate::network::dht::DhtNode;
+use crate::network::libp2p_integration::{peer_from_multiaddr, Libp2pDht};
 use crate::Error;
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// Change in the set of peers we route through
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    /// Peer entered the routing table
+    Discovered(PeerInfo),
+    /// Peer left the routing table
+    Expired(PeerInfo),
+}
+
+impl DiscoveryEvent {
+    /// The peer concerned
+    pub fn peer(&self) -> &PeerInfo {
+        match self {
+            DiscoveryEvent::Discovered(peer) | DiscoveryEvent::Expired(peer) => peer,
+        }
+    }
+
+    /// Bring the node's known peers in line with this event
+    pub fn apply(&self, state: &mut NodeState) -> Result<(), Error> {
+        match self {
+            DiscoveryEvent::Discovered(peer) => state.add_peer(peer.clone()),
+            DiscoveryEvent::Expired(peer) => {
+                state.remove_peer(&peer.peer_id);
+                Ok(())
+            }
+        }
+    }
+}
 
 /// Peer discovery service
 pub struct PeerDiscovery {
+    dht: Libp2pDht,
     /// Bootstrap nodes
     bootstrap_nodes: Vec<PeerInfo>,
+    events: broadcast::Receiver<DiscoveryEvent>,
 }
 
 impl PeerDiscovery {
-    /// Create a new peer discovery service
-    pub fn new(bootstrap_nodes: Vec<PeerInfo>) -> Self {
-        Self { bootstrap_nodes }
-    }
-    
-    /// Create with default bootstrap nodes
-    pub fn default() -> Self {
-        Self {
-            bootstrap_nodes: vec![],
-        }
+    /// Create a peer discovery service for `dht`
+    ///
+    /// Events are reported from this point on, so create it before the
+    /// DHT dials anyone.
+    pub fn new(dht: Libp2pDht, bootstrap_nodes: Vec<PeerInfo>) -> Self {
+        let events = dht.discovery_events();
+        Self { dht, bootstrap_nodes, events }
     }
-    
+
     /// Add a bootstrap node
     pub fn add_bootstrap_node(&mut self, node: PeerInfo) {
         self.bootstrap_nodes.push(node);
     }
-    
-    /// Discover peers
-    pub async fn discover_peers(&self) -> Result<Vec<PeerInfo>, Error> {
-        // Placeholder - will implement with mDNS and DHT
-        Ok(vec![])
+
+    /// Add a bootstrap node from a multiaddr ending in `/p2p/<peer id>`
+    pub fn add_bootstrap_addr(&mut self, addr: &str) -> Result<(), Error> {
+        self.add_bootstrap_node(peer_from_multiaddr(addr)?);
+        Ok(())
+    }
+
+    /// Bootstrap nodes
+    pub fn bootstrap_nodes(&self) -> &[PeerInfo] {
+        &self.bootstrap_nodes
+    }
+
+    /// Dial the bootstrap nodes and look ourselves up
+    ///
+    /// Returns the peers in the routing table afterwards. Peers found over
+    /// mDNS show up here as well as in the event stream, whenever they
+    /// were announced.
+    pub async fn discover_peers(&mut self) -> Result<Vec<PeerInfo>, Error> {
+        for node in &self.bootstrap_nodes {
+            if let Err(e) = self.dht.add_peer(node.clone()).await {
+                warn!("Skipping bootstrap node {}: {}", node.peer_id, e);
+            }
+        }
+        let local = self.dht.local_peer().device_id;
+        let closest = self.dht.find_peer(local).await?;
+        debug!("Self-lookup returned {} peers", closest.len());
+        self.dht.peers().await
+    }
+
+    /// Wait for the next discovery event; `None` once the DHT has stopped
+    pub async fn next_event(&mut self) -> Option<DiscoveryEvent> {
+        loop {
+            match self.events.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Missed {} discovery events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::network::dht::AdmissionPolicy;
+    use crate::network::libp2p_integration::Libp2pDhtConfig;
+    use std::time::Duration;
+
+    async fn node() -> (NodeKeypair, Libp2pDht) {
+        let keypair = NodeKeypair::generate();
+        let config = Libp2pDhtConfig {
+            admission: AdmissionPolicy { max_per_subnet: None, ..Default::default() },
+            // Loopback is never announced over mDNS; bootstrap nodes do the work here
+            mdns: false,
+            ..Default::default()
+        };
+        let dht = Libp2pDht::new(&keypair, config).unwrap();
+        dht.listen("/ip4/127.0.0.1/udp/0/quic-v1").await.unwrap();
+        (keypair, dht)
+    }
+
+    #[tokio::test]
+    async fn test_nodes_discover_each_other_through_bootstrap() {
+        let (_, bootstrap) = node().await;
+        let local = bootstrap.local_peer();
+        let bootstrap_addr = format!("{}/p2p/{}", local.addresses[0], local.peer_id);
+
+        let mut nodes = Vec::new();
+        for _ in 0..3 {
+            let (keypair, dht) = node().await;
+            let mut discovery = PeerDiscovery::new(dht, Vec::new());
+            discovery.add_bootstrap_addr(&bootstrap_addr).unwrap();
+            assert!(discovery.add_bootstrap_addr(&local.addresses[0]).is_err());
+            nodes.push((NodeState::new(keypair), discovery));
+        }
+
+        // Later nodes learn about earlier ones through the bootstrap node;
+        // earlier ones hear of later ones when those look themselves up
+        for _ in 0..50 {
+            for (_, discovery) in &mut nodes {
+                discovery.discover_peers().await.unwrap();
+            }
+            for (state, discovery) in &mut nodes {
+                while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(10), discovery.next_event()).await {
+                    event.apply(state).unwrap();
+                }
+            }
+            if nodes.iter().all(|(state, _)| state.known_peers.len() == 3) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        for (state, _) in &nodes {
+            assert_eq!(state.known_peers.len(), 3);
+            assert!(state.known_peers.contains_key(&local.peer_id));
+        }
+
+        let peer = nodes[1].0.known_peers[&local.peer_id].clone();
+        let (state, _) = &mut nodes[0];
+        DiscoveryEvent::Expired(peer).apply(state).unwrap();
+        assert!(!state.known_peers.contains_key(&local.peer_id));
     }
 }
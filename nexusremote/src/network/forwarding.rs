@@ -0,0 +1,830 @@
+//! Relay data plane
+//!
+//! `RelayForwarder` moves the bytes of a relay session: it takes the stream
+//! a client opened to us, opens one to the target, and copies both
+//! directions until both sides have finished. The streams carry
+//! `SecureChannel` frames sealed end-to-end, so they are copied as opaque
+//! bytes. Both directions draw from one token bucket sized by
+//! `max_bandwidth_per_session`, and every chunk is counted with
+//! `RelayManager::record_data` once it has been written.
+//!
+//! With a `WfqScheduler`, every chunk also waits for its turn on the
+//! relay's shared bandwidth, so sessions get their share by priority.
+//!
+//! With metering, sessions are paid for through the client's payment
+//! channel: each chunk is charged to a `RelayMeter` before it is written,
+//! and a session the client has fallen behind on waits until it pays. The
+//! requests, payments and the session's receipt travel as `RelayControl`
+//! messages on the client's `RelayLink`; `pay_relay` is the client's side.
+//!
+//! A side closing its write half is passed on to the other side. An I/O
+//! error in either direction, or the session being dropped from the
+//! `RelayManager`, tears down both directions. A session preempted by a
+//! better client is torn down the same way, but still reports the receipt
+//! it was ended with.
+
+use crate::core::crypto::NodeKeypair;
+use crate::core::receipt::ReceiptError;
+use crate::core::types::*;
+use crate::network::relay::{RelayManager, SessionRequest};
+use crate::network::scheduler::WfqScheduler;
+use crate::network::transport::{device_id_from_peer_id, QuicTransport, RelayLink};
+use crate::wallet::channel::{ChannelManager, ChannelUpdate};
+use crate::wallet::metering::{MeteringConfig, PaymentRequest, RelayMeter, RelayPayer};
+use crate::Error;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::sync::{mpsc, Notify};
+use tokio_util::compat::Compat;
+use tracing::{debug, warn};
+
+/// How often a paused session checks that it still exists
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a client waits for the traffic a payment request covers to reach it
+const PAYMENT_WAIT: Duration = Duration::from_secs(5);
+
+/// Forwarding configuration
+#[derive(Debug, Clone)]
+pub struct ForwardingConfig {
+    /// Largest chunk read before it is forwarded (bytes)
+    pub chunk_size: usize,
+    /// Traffic allowed above the rate limit in one go, as time at full rate
+    pub burst: Duration,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 64 * 1024,
+            burst: Duration::from_millis(100),
+        }
+    }
+}
+
+/// First message a client sends on its channel to a relay
+///
+/// Everything after it is forwarded to the target as opaque bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayRequest {
+    /// Peer to relay to, by its libp2p peer id
+    pub target: PeerID,
+    /// Addresses the target can be dialed at
+    pub addresses: Vec<String>,
+    /// Channel the client holds with the relay, which sets its priority
+    #[serde(default)]
+    pub channel_id: Option<[u8; 32]>,
+}
+
+/// Messages a client and its relay exchange beside the relayed stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RelayControl {
+    /// The relay asks to be paid for the traffic so far
+    PaymentRequest(PaymentRequest),
+    /// The client pays for a session with a channel update
+    Payment {
+        /// Session paid for
+        session_id: [u8; 32],
+        /// Update crediting the relay
+        update: ChannelUpdate,
+    },
+    /// The relay countersigned a payment
+    PaymentAccepted(ChannelUpdate),
+    /// Receipt of the finished or preempted session, signed by the relay
+    Receipt(SignedReceipt),
+    /// The receipt co-signed by the client
+    CosignedReceipt(SignedReceipt),
+}
+
+/// Pay for a relayed session from the client's side of `link`
+///
+/// Requests are paid through `channel_id` at the relay's advertised
+/// `tokens_per_mb`, for no more traffic than went through the link. Returns
+/// the session's receipt once we have co-signed it, which we only do for
+/// no more than we paid.
+pub async fn pay_relay(
+    link: &mut RelayLink,
+    channels: &mut ChannelManager,
+    keypair: &NodeKeypair,
+    channel_id: [u8; 32],
+    tokens_per_mb: TokenAmount,
+) -> Result<SignedReceipt, Error> {
+    let mut payer: Option<RelayPayer> = None;
+    let mut recorded = 0;
+    loop {
+        match link.receive().await? {
+            RelayControl::PaymentRequest(request) => {
+                let payer = payer.get_or_insert_with(|| RelayPayer::new(request.session_id, channel_id, tokens_per_mb));
+                // The relay charges a chunk before it reaches us
+                let relayed = tokio::time::timeout(PAYMENT_WAIT, link.wait_relayed(request.data_relayed)).await
+                    .unwrap_or_else(|_| link.relayed());
+                payer.record_sent(relayed - recorded);
+                recorded = relayed;
+                let update = payer.pay(channels, &request)?;
+                link.send(&RelayControl::Payment { session_id: request.session_id, update }).await?;
+            }
+            RelayControl::PaymentAccepted(update) => channels.confirm_update(update)?,
+            RelayControl::Receipt(mut receipt) => {
+                let paid = payer.as_ref().map_or(TokenAmount::ZERO, |payer| payer.total_paid());
+                if receipt.client_public_key != keypair.public_key().to_bytes() {
+                    return Err(Error::Token("Receipt names another client".to_string()));
+                }
+                if receipt.amount > paid {
+                    return Err(ReceiptError::Unfunded { claimed: receipt.amount, available: paid }.into());
+                }
+                receipt.sign_as_client(keypair);
+                link.send(&RelayControl::CosignedReceipt(receipt.clone())).await?;
+                return Ok(receipt);
+            }
+            message => return Err(Error::Network(format!("Unexpected relay message {:?}", message))),
+        }
+    }
+}
+
+/// Opens streams to relay targets
+#[async_trait]
+pub trait TargetConnector: Send + Sync {
+    /// Stream to a target
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin;
+
+    /// Open a stream to `target`, which the client said is at `addresses`
+    async fn open(&self, target: &PeerID, addresses: &[String]) -> Result<Self::Stream, Error>;
+}
+
+/// Reaches relay targets with a QUIC channel of our own
+pub struct QuicConnector {
+    transport: Arc<QuicTransport>,
+}
+
+impl QuicConnector {
+    /// Dial targets through `transport`
+    pub fn new(transport: Arc<QuicTransport>) -> Self {
+        Self { transport }
+    }
+}
+
+#[async_trait]
+impl TargetConnector for QuicConnector {
+    type Stream = Compat<DuplexStream>;
+
+    async fn open(&self, target: &PeerID, addresses: &[String]) -> Result<Self::Stream, Error> {
+        // The target's identity is checked against its peer id when the channel opens
+        let peer_id: libp2p::PeerId = target.0.parse()
+            .map_err(|e| Error::Network(format!("Invalid relay target {}: {}", target, e)))?;
+        let device_id = device_id_from_peer_id(&peer_id)
+            .ok_or_else(|| Error::Crypto(format!("Relay target {} has no Ed25519 identity", target)))?;
+        let peer = PeerInfo {
+            peer_id: target.clone(),
+            device_id,
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Idle,
+            addresses: addresses.to_vec(),
+            available_bandwidth: 0,
+            identity_proof: None,
+        };
+        Ok(self.transport.connect(&peer).await?.into_stream())
+    }
+}
+
+/// Token bucket limiting a session's bandwidth
+///
+/// Tokens are bytes. A chunk larger than the tokens left is let through
+/// and the bucket goes into debt, which the caller waits out before
+/// sending anything else.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    /// Refill rate (bytes per second); zero means unlimited
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    /// Limit to `bits_per_second`, allowing `burst` worth of traffic at once
+    pub fn new(bits_per_second: u64, burst: Duration) -> Self {
+        let rate = bits_per_second as f64 / 8.0;
+        let capacity = rate * burst.as_secs_f64();
+        Self { rate, capacity, tokens: capacity, last_refill: Instant::now() }
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before sending them
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Traffic of a finished or preempted relay session
+#[derive(Debug, Clone)]
+pub struct ForwardReport {
+    /// Relay session
+    pub session_id: [u8; 32],
+    /// Bytes forwarded from client to target
+    pub client_to_target: u64,
+    /// Bytes forwarded from target to client
+    pub target_to_client: u64,
+    /// Time from the target stream opening until both sides finished
+    pub duration: Duration,
+    /// Unsigned receipt from `RelayManager::end_session`, or from the admission that preempted the session
+    pub receipt: SignedReceipt,
+    /// Received through the client's channel, if the session was metered
+    pub paid: TokenAmount,
+    /// Whether a better client took the session's place before it finished
+    pub preempted: bool,
+}
+
+/// Meters of the sessions a forwarder charges for
+struct Metering {
+    config: MeteringConfig,
+    meters: Mutex<HashMap<[u8; 32], RelayMeter>>,
+    /// Payment requests for delivery to the clients
+    requests: mpsc::UnboundedSender<PaymentRequest>,
+    /// Woken whenever a client pays
+    paid: Notify,
+}
+
+impl Metering {
+    /// Charge `bytes` to the session, first waiting until the client has caught up on payments
+    async fn charge(&self, relay: &Mutex<RelayManager>, session_id: &[u8; 32], bytes: u64) -> Result<(), Error> {
+        loop {
+            let paid = self.paid.notified();
+            {
+                let mut meters = self.meters.lock().unwrap();
+                let meter = meters.get_mut(session_id)
+                    .ok_or_else(|| Error::Network("Session not found".to_string()))?;
+                if meter.can_forward() {
+                    if let Some(request) = meter.record(&mut relay.lock().unwrap(), bytes)? {
+                        // A client that cannot be asked will never pay, so the session pauses
+                        let _ = self.requests.send(request);
+                    }
+                    return Ok(());
+                }
+            }
+            if !relay.lock().unwrap().active_sessions().iter().any(|s| &s.session_id == session_id) {
+                return Err(Error::Network("Session not found".to_string()));
+            }
+            let _ = tokio::time::timeout(PAUSE_CHECK_INTERVAL, paid).await;
+        }
+    }
+}
+
+/// Forwards the traffic of relay sessions
+pub struct RelayForwarder<C> {
+    relay: Arc<Mutex<RelayManager>>,
+    connector: C,
+    scheduler: Option<WfqScheduler>,
+    metering: Option<Metering>,
+    /// Receipts of preempted sessions, until their forwarding stops
+    preempted: Mutex<HashMap<[u8; 32], SignedReceipt>>,
+    config: ForwardingConfig,
+}
+
+impl<C: TargetConnector> RelayForwarder<C> {
+    /// Create a forwarder for the sessions of `relay`, reaching targets through `connector`
+    pub fn new(relay: Arc<Mutex<RelayManager>>, connector: C, config: ForwardingConfig) -> Self {
+        Self { relay, connector, scheduler: None, metering: None, preempted: Mutex::new(HashMap::new()), config }
+    }
+
+    /// Share the relay's total bandwidth among sessions through `scheduler`
+    pub fn with_scheduler(mut self, scheduler: WfqScheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Charge sessions through the clients' payment channels
+    ///
+    /// Every session must then name its channel. Payment requests go out
+    /// through `requests` for delivery to the client, and the channel
+    /// updates it answers with are handed to `on_payment`.
+    pub fn with_metering(mut self, config: MeteringConfig, requests: mpsc::UnboundedSender<PaymentRequest>) -> Self {
+        self.metering = Some(Metering {
+            config,
+            meters: Mutex::new(HashMap::new()),
+            requests,
+            paid: Notify::new(),
+        });
+        self
+    }
+
+    /// Countersign a client's channel update paying for `session_id`, resuming the session if it paused
+    pub fn on_payment(&self, channels: &mut ChannelManager, session_id: &[u8; 32], update: ChannelUpdate) -> Result<ChannelUpdate, Error> {
+        let metering = self.metering.as_ref()
+            .ok_or_else(|| Error::Token("Relay sessions are not metered".to_string()))?;
+        let countersigned = metering.meters.lock().unwrap()
+            .get_mut(session_id)
+            .ok_or_else(|| Error::Network("Session not found".to_string()))?
+            .on_payment(channels, update)?;
+        metering.paid.notify_waiters();
+        Ok(countersigned)
+    }
+
+    /// Relay `client_stream` for `request` until both sides are done
+    ///
+    /// The session is admitted by the `RelayManager` before the target is
+    /// dialed. A session that ends with both sides closing returns its
+    /// receipt, and so does one preempted by a later session, marked as
+    /// such; one cut short by an error is dropped.
+    pub async fn relay<S>(&self, client_stream: S, request: SessionRequest) -> Result<ForwardReport, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (target, addresses) = (request.target.clone(), request.target_addresses.clone());
+        let channel_id = request.channel_id;
+        if self.metering.is_some() && channel_id.is_none() {
+            return Err(Error::Token("Relay sessions must be paid through a channel".to_string()));
+        }
+        let session = {
+            // Stashed under the relay lock, so a preempted session always finds its receipt
+            let mut relay = self.relay.lock().unwrap();
+            let admission = relay.admit(request)?;
+            self.preempted.lock().unwrap()
+                .extend(admission.preempted.into_iter().map(|receipt| (receipt.session_id, receipt)));
+            admission.session
+        };
+        let session_id = session.session_id;
+        if let (Some(metering), Some(channel_id)) = (&self.metering, channel_id) {
+            let meter = RelayMeter::new(&session, channel_id, metering.config.clone());
+            metering.meters.lock().unwrap().insert(session_id, meter);
+        }
+        let result = self.forward(client_stream, &target, &addresses, session.session_id, session.priority).await;
+        let paid = self.metering.as_ref()
+            .and_then(|metering| metering.meters.lock().unwrap().remove(&session_id))
+            .map(|meter| meter.total_paid());
+        result.map(|mut report| {
+            if let Some(paid) = paid {
+                // Traffic the client has not paid for yet cannot be claimed from its channel
+                report.receipt.amount = report.receipt.amount.min(paid);
+                report.paid = paid;
+            }
+            report
+        })
+    }
+
+    /// Dial the target of an admitted session and copy both directions
+    async fn forward<S>(
+        &self,
+        client_stream: S,
+        target: &PeerID,
+        addresses: &[String],
+        session_id: [u8; 32],
+        priority: f64,
+    ) -> Result<ForwardReport, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let target_stream = match self.connector.open(target, addresses).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                // The target being unreachable is not the relay's fault
+                let mut relay = self.relay.lock().unwrap();
+                if self.preempted.lock().unwrap().remove(&session_id).is_none() {
+                    let _ = relay.end_session(&session_id);
+                }
+                return Err(e);
+            }
+        };
+
+        let limit = self.relay.lock().unwrap().config().max_bandwidth_per_session;
+        let limiter = Mutex::new(BandwidthLimiter::new(limit, self.config.burst));
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.set_priority(session_id, priority);
+        }
+        let started = Instant::now();
+        let (client_read, client_write) = client_stream.split();
+        let (target_read, target_write) = target_stream.split();
+        let (client_to_target, target_to_client) = (AtomicU64::new(0), AtomicU64::new(0));
+        let result = futures::future::try_join(
+            self.pipe(session_id, client_read, target_write, &limiter, &client_to_target),
+            self.pipe(session_id, target_read, client_write, &limiter, &target_to_client),
+        ).await;
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.remove(session_id);
+        }
+
+        let mut relay = self.relay.lock().unwrap();
+        let (client_to_target, target_to_client) = (client_to_target.into_inner(), target_to_client.into_inner());
+        let preempted = self.preempted.lock().unwrap().remove(&session_id);
+        let was_preempted = preempted.is_some();
+        let receipt = match (result, preempted) {
+            // Whatever stopped forwarding, the session already ended with this receipt
+            (_, Some(receipt)) => {
+                debug!("Relay session {} preempted after {} bytes up, {} down",
+                    hex::encode(&session_id[..8]), client_to_target, target_to_client);
+                receipt
+            }
+            (Ok(_), None) => {
+                debug!("Relay session {} finished: {} bytes up, {} down",
+                    hex::encode(&session_id[..8]), client_to_target, target_to_client);
+                relay.end_session(&session_id)?
+            }
+            (Err(e), None) => {
+                warn!("Relay session {} torn down: {}", hex::encode(&session_id[..8]), e);
+                // Already gone if it was dropped while forwarding
+                let _ = relay.drop_session(&session_id);
+                return Err(e);
+            }
+        };
+        Ok(ForwardReport {
+            session_id,
+            client_to_target,
+            target_to_client,
+            duration: started.elapsed(),
+            receipt,
+            paid: TokenAmount::ZERO,
+            preempted: was_preempted,
+        })
+    }
+
+    /// Copy `from` into `to` until `from` ends, then close `to`
+    ///
+    /// Forwarded bytes are added to `total` as they go, so they are known
+    /// even if forwarding is cut short.
+    async fn pipe<R, W>(
+        &self,
+        session_id: [u8; 32],
+        mut from: R,
+        mut to: W,
+        limiter: &Mutex<BandwidthLimiter>,
+        total: &AtomicU64,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; self.config.chunk_size];
+        loop {
+            let n = from.read(&mut buf).await?;
+            if n == 0 {
+                to.close().await?;
+                return Ok(());
+            }
+            let delay = limiter.lock().unwrap().reserve(n);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(scheduler) = &self.scheduler {
+                scheduler.transmit(session_id, n).await?;
+            }
+            // Metered chunks are charged up front, so both directions see the same unpaid total
+            if let Some(metering) = &self.metering {
+                metering.charge(&self.relay, &session_id, n as u64).await?;
+            }
+            to.write_all(&buf[..n]).await?;
+            if self.metering.is_none() {
+                // Fails once the session was dropped, which stops forwarding
+                self.relay.lock().unwrap().record_data(&session_id, n as u64)?;
+            }
+            total.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::network::relay::RelayConfig;
+    use crate::wallet::metering::RelayPayer;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, DuplexStream};
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    /// Hands out the relay's ends of streams the test holds the target ends of, last first
+    struct Loopback(Mutex<Vec<DuplexStream>>);
+
+    #[async_trait]
+    impl TargetConnector for Loopback {
+        type Stream = Compat<DuplexStream>;
+
+        async fn open(&self, target: &PeerID, _addresses: &[String]) -> Result<Self::Stream, Error> {
+            self.0.lock().unwrap().pop()
+                .map(|stream| stream.compat())
+                .ok_or_else(|| Error::Network(format!("{} is unreachable", target)))
+        }
+    }
+
+    /// Forwarder plus the client's stream, the relay's end of it, and the target's stream
+    fn setup(bandwidth: u64) -> (RelayForwarder<Loopback>, DuplexStream, DuplexStream, DuplexStream) {
+        let (client, relay_client_side) = tokio::io::duplex(256 * 1024);
+        let (relay_target_side, target) = tokio::io::duplex(256 * 1024);
+        let relay = RelayManager::new(RelayConfig { max_bandwidth_per_session: bandwidth, ..Default::default() });
+        let forwarder = RelayForwarder::new(
+            Arc::new(Mutex::new(relay)),
+            Loopback(Mutex::new(vec![relay_target_side])),
+            ForwardingConfig::default(),
+        );
+        (forwarder, client, relay_client_side, target)
+    }
+
+    fn checksum(sum: u64, data: &[u8]) -> u64 {
+        data.iter().fold(sum, |sum, b| sum.wrapping_mul(31).wrapping_add(*b as u64))
+    }
+
+    async fn run(forwarder: RelayForwarder<Loopback>, stream: DuplexStream) -> Result<ForwardReport, Error> {
+        let request = SessionRequest {
+            client: PeerID::new("client".to_string()),
+            client_public_key: [0u8; 32],
+            target: PeerID::new("target".to_string()),
+            target_addresses: Vec::new(),
+            reputation: ReputationScore::new(500),
+            balance: TokenAmount::new(0),
+            channel_id: None,
+        };
+        forwarder.relay(stream.compat(), request).await
+    }
+
+    #[test]
+    fn test_limiter_spreads_traffic_over_time() {
+        // 8 Mbps is 1 MB/s, with 100 KB allowed at once
+        let mut limiter = BandwidthLimiter::new(8_000_000, Duration::from_millis(100));
+        assert_eq!(limiter.reserve(100_000), Duration::ZERO);
+        let wait = limiter.reserve(500_000);
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500), "{:?}", wait);
+
+        let mut unlimited = BandwidthLimiter::new(0, Duration::from_millis(100));
+        assert_eq!(unlimited.reserve(usize::MAX), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_forwards_100mb_within_the_bandwidth_limit() {
+        const UPLOAD: usize = 100 * 1024 * 1024;
+        const REPLY: usize = 1024 * 1024;
+        // 400 Mbps: the upload alone takes just over two seconds
+        const BANDWIDTH: u64 = 400_000_000;
+
+        let (forwarder, mut client, relay_client_side, mut target) = setup(BANDWIDTH);
+        let relay = forwarder.relay.clone();
+        let session = tokio::spawn(run(forwarder, relay_client_side));
+
+        // The target reads everything, then replies and closes
+        let target_task = tokio::spawn(async move {
+            let (mut received, mut sum) = (0, 0);
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = target.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                sum = checksum(sum, &buf[..n]);
+                received += n;
+            }
+            target.write_all(&[7u8; REPLY]).await.unwrap();
+            target.shutdown().await.unwrap();
+            (received, sum)
+        });
+
+        let started = Instant::now();
+        let chunk: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut sum = 0;
+        for _ in 0..UPLOAD / chunk.len() {
+            client.write_all(&chunk).await.unwrap();
+            sum = checksum(sum, &chunk);
+        }
+        client.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(target_task.await.unwrap(), (UPLOAD, sum));
+        assert_eq!(reply, vec![7u8; REPLY]);
+        let report = session.await.unwrap().unwrap();
+        assert_eq!(report.client_to_target, UPLOAD as u64);
+        assert_eq!(report.target_to_client, REPLY as u64);
+        assert_eq!(report.receipt.data_relayed, (UPLOAD + REPLY) as u64);
+
+        // Everything past the initial burst went no faster than the limit
+        let burst = BANDWIDTH as f64 / 8.0 * ForwardingConfig::default().burst.as_secs_f64();
+        let fastest = ((UPLOAD + REPLY) as f64 - burst) * 8.0 / BANDWIDTH as f64;
+        assert!(elapsed.as_secs_f64() >= fastest, "{:?} is faster than {:.2}s", elapsed, fastest);
+        let relay = relay.lock().unwrap();
+        assert!(relay.active_sessions().is_empty());
+        assert_eq!(relay.stats().total_data_relayed, (UPLOAD + REPLY) as u64);
+        assert_eq!(relay.stats().relay_sessions, 1);
+    }
+
+    #[tokio::test]
+    async fn test_teardown_when_a_side_fails() {
+        // The target goes away while the client is still sending
+        let (forwarder, mut client, relay_client_side, target) = setup(0);
+        let relay = forwarder.relay.clone();
+        let session = tokio::spawn(run(forwarder, relay_client_side));
+        client.write_all(&[1u8; 1024]).await.unwrap();
+        drop(target);
+        let _ = client.write_all(&[1u8; 1024]).await;
+        assert!(tokio::time::timeout(Duration::from_secs(5), session).await.unwrap().unwrap().is_err());
+        // The client sees its end closed
+        let mut rest = Vec::new();
+        assert!(matches!(client.read_to_end(&mut rest).await, Ok(0)));
+        {
+            let mut relay = relay.lock().unwrap();
+            assert!(relay.active_sessions().is_empty());
+            assert_eq!(relay.take_reputation_events().len(), 1);
+        }
+
+        // Dropping the session from the manager stops forwarding
+        let (forwarder, mut client, relay_client_side, _target) = setup(0);
+        let relay = forwarder.relay.clone();
+        let session = tokio::spawn(run(forwarder, relay_client_side));
+        client.write_all(&[1u8; 1024]).await.unwrap();
+        let mut session_id = None;
+        while session_id.is_none() {
+            tokio::task::yield_now().await;
+            session_id = relay.lock().unwrap().active_sessions().first().map(|s| s.session_id);
+        }
+        relay.lock().unwrap().drop_session(&session_id.unwrap()).unwrap();
+        client.write_all(&[1u8; 1024]).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(5), session).await.unwrap().unwrap().is_err());
+
+        // An unreachable target ends the session before anything is forwarded
+        let (forwarder, _client, relay_client_side, _target) = setup(0);
+        forwarder.connector.0.lock().unwrap().clear();
+        let relay = forwarder.relay.clone();
+        assert!(run(forwarder, relay_client_side).await.is_err());
+        let mut relay = relay.lock().unwrap();
+        assert!(relay.active_sessions().is_empty());
+        assert!(relay.take_reputation_events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metered_session_pauses_until_the_client_pays() {
+        const MB: usize = 1024 * 1024;
+        const UPLOAD: usize = 6 * MB;
+
+        let relay_keys = NodeKeypair::generate();
+        let client_keys = NodeKeypair::generate();
+        let mut relay_channels = ChannelManager::new(relay_keys.clone());
+        let mut client_channels = ChannelManager::new(client_keys.clone());
+        let open = client_channels.create_channel(
+            PeerID::new("relay".to_string()),
+            relay_keys.public_key().to_bytes(),
+            TokenAmount::new(100),
+        ).unwrap();
+        let channel_id = open.params.channel_id;
+        let initial = relay_channels.accept_channel(PeerID::new("client".to_string()), open).unwrap();
+        client_channels.confirm_update(initial).unwrap();
+
+        // Traffic is priced at the advertised whole token per MB
+        let (client, relay_client_side) = tokio::io::duplex(256 * 1024);
+        let (relay_target_side, mut target) = tokio::io::duplex(256 * 1024);
+        let relay = RelayManager::default();
+        let tokens_per_mb = relay.config().tokens_per_mb;
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        let metering = MeteringConfig { payment_interval: MB as u64, max_unpaid: 2 * MB as u64 };
+        let forwarder = Arc::new(
+            RelayForwarder::new(
+                Arc::new(Mutex::new(relay)),
+                Loopback(Mutex::new(vec![relay_target_side])),
+                ForwardingConfig::default(),
+            ).with_metering(metering, requests_tx),
+        );
+        let request = SessionRequest {
+            client: PeerID::new("client".to_string()),
+            client_public_key: client_keys.public_key().to_bytes(),
+            target: PeerID::new("target".to_string()),
+            target_addresses: Vec::new(),
+            reputation: ReputationScore::new(500),
+            balance: TokenAmount::new(0),
+            channel_id: Some(channel_id),
+        };
+        let session = tokio::spawn({
+            let forwarder = forwarder.clone();
+            async move { forwarder.relay(relay_client_side.compat(), request).await }
+        });
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        tokio::spawn(async move {
+            client_write.write_all(&vec![1u8; UPLOAD]).await.unwrap();
+            client_write.shutdown().await.unwrap();
+        });
+
+        // Without payment, forwarding stops at the unpaid limit
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while let Ok(n) = tokio::time::timeout(Duration::from_millis(300), target.read(&mut buf)).await {
+            let n = n.unwrap();
+            assert!(n > 0);
+            received += n;
+        }
+        assert!(received >= 2 * MB && received < 2 * MB + buf.len(), "{} bytes got through", received);
+        let first = requests.try_recv().unwrap();
+        assert_eq!(first.amount, TokenAmount::new(1));
+        assert!(requests.try_recv().is_err());
+
+        // Paying each request lets the rest through
+        let mut payer = RelayPayer::new(first.session_id, channel_id, tokens_per_mb);
+        payer.record_sent(UPLOAD as u64);
+        let mut pay = |request: PaymentRequest| {
+            let update = payer.pay(&mut client_channels, &request).unwrap();
+            let countersigned = forwarder.on_payment(&mut relay_channels, &request.session_id, update).unwrap();
+            client_channels.confirm_update(countersigned).unwrap();
+        };
+        pay(first);
+        loop {
+            tokio::select! {
+                Some(request) = requests.recv() => pay(request),
+                n = target.read(&mut buf) => match n.unwrap() {
+                    0 => break,
+                    n => received += n,
+                },
+            }
+        }
+        assert_eq!(received, UPLOAD);
+        target.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        client_read.read_to_end(&mut reply).await.unwrap();
+
+        let report = session.await.unwrap().unwrap();
+        assert_eq!(report.receipt.data_relayed, UPLOAD as u64);
+        assert!(report.paid >= TokenAmount::new(4), "paid {}", report.paid);
+        // The receipt claims no more than was paid, so it can be redeemed
+        assert!(report.receipt.amount <= report.paid);
+        assert_eq!(relay_channels.channel(&channel_id).unwrap().our_balance(), report.paid);
+
+        // A metered forwarder does not relay for free
+        let unpaid = SessionRequest {
+            client: PeerID::new("client".to_string()),
+            client_public_key: client_keys.public_key().to_bytes(),
+            target: PeerID::new("target".to_string()),
+            target_addresses: Vec::new(),
+            reputation: ReputationScore::MIN,
+            balance: TokenAmount::new(0),
+            channel_id: None,
+        };
+        let (_, stream) = tokio::io::duplex(1024);
+        assert!(forwarder.relay(stream.compat(), unpaid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preempted_sessions_report_their_receipts() {
+        let (mut low_client, low_relay_side) = tokio::io::duplex(64 * 1024);
+        let (low_relay_target_side, mut low_target) = tokio::io::duplex(64 * 1024);
+        let (mut high_client, high_relay_side) = tokio::io::duplex(64 * 1024);
+        let (high_relay_target_side, mut high_target) = tokio::io::duplex(64 * 1024);
+        let relay = RelayManager::new(RelayConfig { max_sessions: 1, ..Default::default() });
+        let forwarder = Arc::new(RelayForwarder::new(
+            Arc::new(Mutex::new(relay)),
+            Loopback(Mutex::new(vec![high_relay_target_side, low_relay_target_side])),
+            ForwardingConfig::default(),
+        ));
+        let low_key = NodeKeypair::generate().public_key().to_bytes();
+        let request = |name: &str, client_public_key: [u8; 32], reputation: u64, balance: u128| SessionRequest {
+            client: PeerID::new(name.to_string()),
+            client_public_key,
+            target: PeerID::new("target".to_string()),
+            target_addresses: Vec::new(),
+            reputation: ReputationScore::new(reputation),
+            balance: TokenAmount::new(balance),
+            channel_id: None,
+        };
+
+        let low = tokio::spawn({
+            let (forwarder, request) = (forwarder.clone(), request("low", low_key, 200, 0));
+            async move { forwarder.relay(low_relay_side.compat(), request).await }
+        });
+        low_client.write_all(&[1u8; 1024]).await.unwrap();
+        low_target.read_exact(&mut [0u8; 1024]).await.unwrap();
+
+        // A clearly better client takes the only slot
+        let high = tokio::spawn({
+            let (forwarder, request) = (forwarder.clone(), request("high", [9u8; 32], 900, 1000));
+            async move { forwarder.relay(high_relay_side.compat(), request).await }
+        });
+        while forwarder.relay.lock().unwrap().active_sessions().iter().all(|s| s.client.0 != "high") {
+            tokio::task::yield_now().await;
+        }
+
+        // The preempted session stops at its next chunk, reporting what it had relayed
+        let _ = low_client.write_all(&[1u8; 1024]).await;
+        let report = tokio::time::timeout(Duration::from_secs(5), low).await.unwrap().unwrap().unwrap();
+        assert!(report.preempted);
+        assert_eq!(report.client_to_target, 1024);
+        assert_eq!(report.receipt.data_relayed, 1024);
+        assert_eq!(report.receipt.client_public_key, low_key);
+
+        high_client.write_all(&[2u8; 1024]).await.unwrap();
+        high_client.shutdown().await.unwrap();
+        high_target.read_exact(&mut [0u8; 1024]).await.unwrap();
+        high_target.shutdown().await.unwrap();
+        let report = tokio::time::timeout(Duration::from_secs(5), high).await.unwrap().unwrap().unwrap();
+        assert_eq!(report.client_to_target, 1024);
+        assert!(!report.preempted);
+        assert!(forwarder.preempted.lock().unwrap().is_empty());
+        assert!(forwarder.relay.lock().unwrap().take_reputation_events().is_empty());
+    }
+}
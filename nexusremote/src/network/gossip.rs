@@ -0,0 +1,416 @@
+//! Network announcements over gossipsub
+//!
+//! Nodes push short-lived facts to everyone on three topics: relays
+//! advertise their capacity and price, clients share the attestations they
+//! issue, and nodes announce the protocol version they speak. Messages are
+//! signed by their author's libp2p key; `GossipValidator` decides whether
+//! each one is forwarded before gossipsub passes it on.
+
+use crate::core::types::*;
+use crate::network::attestation::{Attestation, AttestationError};
+use crate::network::relay::RelayManager;
+use crate::network::transport::device_id_from_peer_id;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Topic for relay availability adverts
+pub const RELAY_TOPIC: &str = "/nexusremote/relays/1.0.0";
+
+/// Topic for reputation attestations
+pub const ATTESTATION_TOPIC: &str = "/nexusremote/attestations/1.0.0";
+
+/// Topic for protocol version notices
+pub const VERSION_TOPIC: &str = "/nexusremote/version/1.0.0";
+
+/// Every topic a node subscribes to
+pub const TOPICS: [&str; 3] = [RELAY_TOPIC, ATTESTATION_TOPIC, VERSION_TOPIC];
+
+/// Wire protocol version announced on `VERSION_TOPIC`
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Most addresses a relay advert may carry
+const MAX_ADVERT_ADDRESSES: usize = 16;
+
+/// Reasons an announcement is dropped
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum GossipError {
+    /// The message carries no author signature
+    #[error("unsigned message")]
+    Unsigned,
+
+    /// The payload could not be decoded
+    #[error("malformed announcement: {0}")]
+    Malformed(String),
+
+    /// The announcement was published on another type's topic
+    #[error("announcement on the wrong topic")]
+    WrongTopic,
+
+    /// Signed by someone other than the node it speaks for
+    #[error("announcement published by another node")]
+    AuthorMismatch,
+
+    /// Too old, or dated in the future
+    #[error("announcement timestamp outside the accepted window")]
+    Stale,
+
+    /// The author sent more than its share of messages
+    #[error("rate limit exceeded")]
+    RateLimited,
+
+    /// A relay advert with impossible values
+    #[error("invalid relay advert: {0}")]
+    InvalidAdvert(String),
+
+    /// An attestation that does not verify
+    #[error("invalid attestation: {0}")]
+    InvalidAttestation(#[from] AttestationError),
+}
+
+impl GossipError {
+    /// Whether the message is harmless but unwanted, as opposed to a protocol violation
+    ///
+    /// Ignored messages are not forwarded, but do not count against the
+    /// peer's gossipsub score.
+    pub fn is_benign(&self) -> bool {
+        matches!(self, GossipError::Stale | GossipError::RateLimited)
+    }
+}
+
+/// Offer to relay traffic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayAdvert {
+    /// Relay device
+    pub device_id: DeviceID,
+    /// Addresses the relay can be reached at, ending in `/p2p/<peer id>`
+    pub addresses: Vec<String>,
+    /// Sessions the relay can still take
+    pub available_sessions: usize,
+    /// Bandwidth cap per session (bps)
+    pub max_bandwidth_per_session: u64,
+    /// Price per MB relayed
+    pub tokens_per_mb: TokenAmount,
+    /// Reputation a client needs to be served
+    pub min_reputation: ReputationScore,
+    /// When the advert was issued (unix seconds)
+    pub timestamp: u64,
+}
+
+impl RelayAdvert {
+    /// Advertise the spare capacity of `relay`
+    pub fn new(device_id: DeviceID, addresses: Vec<String>, relay: &RelayManager, timestamp: u64) -> Self {
+        let config = relay.config();
+        Self {
+            device_id,
+            addresses,
+            available_sessions: relay.session_capacity().saturating_sub(relay.active_sessions().len()),
+            max_bandwidth_per_session: config.max_bandwidth_per_session,
+            tokens_per_mb: config.tokens_per_mb,
+            min_reputation: config.min_reputation,
+            timestamp,
+        }
+    }
+
+    /// Whether the advert is younger than `ttl` at `now` (time since the unix epoch)
+    pub fn is_current(&self, now: Duration, ttl: Duration) -> bool {
+        Duration::from_secs(self.timestamp) + ttl >= now
+    }
+}
+
+/// Protocol version spoken by a node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionNotice {
+    /// Announcing device
+    pub device_id: DeviceID,
+    /// Wire protocol version
+    pub protocol_version: u32,
+    /// Software release
+    pub software_version: String,
+    /// When the notice was issued (unix seconds)
+    pub timestamp: u64,
+}
+
+impl VersionNotice {
+    /// Notice for this build
+    pub fn current(device_id: DeviceID, timestamp: u64) -> Self {
+        Self {
+            device_id,
+            protocol_version: PROTOCOL_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp,
+        }
+    }
+}
+
+/// Message carried on one of the gossip topics
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Announcement {
+    /// Relay availability
+    Relay(RelayAdvert),
+    /// Reputation attestation
+    Attestation(Attestation),
+    /// Protocol version
+    Version(VersionNotice),
+}
+
+impl Announcement {
+    /// Topic the announcement is published on
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Announcement::Relay(_) => RELAY_TOPIC,
+            Announcement::Attestation(_) => ATTESTATION_TOPIC,
+            Announcement::Version(_) => VERSION_TOPIC,
+        }
+    }
+
+    /// Node the announcement speaks for
+    pub fn author(&self) -> DeviceID {
+        match self {
+            Announcement::Relay(advert) => advert.device_id,
+            Announcement::Attestation(attestation) => attestation.attester_id(),
+            Announcement::Version(notice) => notice.device_id,
+        }
+    }
+
+    /// When the announcement was issued (unix seconds)
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            Announcement::Relay(advert) => advert.timestamp,
+            Announcement::Attestation(attestation) => attestation.timestamp,
+            Announcement::Version(notice) => notice.timestamp,
+        }
+    }
+
+    /// Encode for the wire
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("announcement serialization cannot fail")
+    }
+
+    /// Decode from the wire
+    pub fn decode(bytes: &[u8]) -> Result<Self, GossipError> {
+        bincode::deserialize(bytes).map_err(|e| GossipError::Malformed(e.to_string()))
+    }
+
+    /// Checks that need nothing but the announcement itself
+    fn check_contents(&self) -> Result<(), GossipError> {
+        match self {
+            Announcement::Relay(advert) => {
+                if advert.addresses.is_empty() || advert.addresses.len() > MAX_ADVERT_ADDRESSES {
+                    return Err(GossipError::InvalidAdvert(format!("{} addresses", advert.addresses.len())));
+                }
+                if let Some(addr) = advert.addresses.iter().find(|a| a.parse::<Multiaddr>().is_err()) {
+                    return Err(GossipError::InvalidAdvert(format!("bad address {}", addr)));
+                }
+                if advert.max_bandwidth_per_session == 0 {
+                    return Err(GossipError::InvalidAdvert("no bandwidth".to_string()));
+                }
+                Ok(())
+            }
+            Announcement::Attestation(attestation) => Ok(attestation.verify()?),
+            Announcement::Version(notice) => {
+                if notice.protocol_version == 0 {
+                    return Err(GossipError::Malformed("protocol version 0".to_string()));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Gossip limits
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    /// Messages an author may send in a burst
+    pub burst: u32,
+    /// Messages per second an author may sustain
+    pub rate: f64,
+    /// Oldest announcement accepted, and how far ahead a clock may run
+    pub max_age: Duration,
+    /// How long a relay advert is trusted without a refresh
+    pub advert_ttl: Duration,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            burst: 10,
+            rate: 0.5,
+            max_age: Duration::from_secs(10 * 60),
+            advert_ttl: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Message allowance of one author
+#[derive(Debug, Clone)]
+struct Allowance {
+    tokens: f64,
+    updated: Duration,
+}
+
+/// Decides which gossip messages are accepted and forwarded
+#[derive(Debug)]
+pub struct GossipValidator {
+    config: GossipConfig,
+    allowances: HashMap<PeerId, Allowance>,
+}
+
+impl GossipValidator {
+    /// Create a validator
+    pub fn new(config: GossipConfig) -> Self {
+        Self { config, allowances: HashMap::new() }
+    }
+
+    /// Validate a message published by `source` on `topic`
+    ///
+    /// `now` is the time since the unix epoch.
+    pub fn validate(&mut self, source: Option<PeerId>, topic: &str, data: &[u8], now: Duration) -> Result<Announcement, GossipError> {
+        let source = source.ok_or(GossipError::Unsigned)?;
+        let author = device_id_from_peer_id(&source).ok_or(GossipError::Unsigned)?;
+        self.take_allowance(source, now)?;
+
+        let announcement = Announcement::decode(data)?;
+        if announcement.topic() != topic {
+            return Err(GossipError::WrongTopic);
+        }
+        if announcement.author() != author {
+            return Err(GossipError::AuthorMismatch);
+        }
+        let issued = Duration::from_secs(announcement.timestamp());
+        if issued + self.config.max_age < now || issued > now + self.config.max_age {
+            return Err(GossipError::Stale);
+        }
+        announcement.check_contents()?;
+        Ok(announcement)
+    }
+
+    /// Spend one message from `source`'s allowance
+    fn take_allowance(&mut self, source: PeerId, now: Duration) -> Result<(), GossipError> {
+        let burst = self.config.burst as f64;
+        let allowance = self.allowances.entry(source)
+            .or_insert(Allowance { tokens: burst, updated: now });
+        let elapsed = now.saturating_sub(allowance.updated).as_secs_f64();
+        allowance.tokens = (allowance.tokens + elapsed * self.config.rate).min(burst);
+        allowance.updated = now;
+        if allowance.tokens < 1.0 {
+            return Err(GossipError::RateLimited);
+        }
+        allowance.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Forget authors whose allowance has been full for a while
+    pub fn prune(&mut self, now: Duration) {
+        let refill = Duration::from_secs_f64(self.config.burst as f64 / self.config.rate.max(f64::EPSILON));
+        self.allowances.retain(|_, a| a.updated + refill > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::network::attestation::SessionOutcome;
+    use crate::network::relay::RelayConfig;
+    use crate::network::transport::libp2p_keypair;
+
+    const NOW: Duration = Duration::from_secs(1_700_000_000);
+
+    fn peer_id(keypair: &NodeKeypair) -> PeerId {
+        libp2p_keypair(keypair).public().to_peer_id()
+    }
+
+    fn advert(keypair: &NodeKeypair) -> Announcement {
+        let relay = RelayManager::new(RelayConfig { tokens_per_mb: TokenAmount::new(3), ..Default::default() });
+        let addresses = vec!["/ip4/192.0.2.1/udp/4001/quic-v1".to_string()];
+        Announcement::Relay(RelayAdvert::new(keypair.node_id(), addresses, &relay, NOW.as_secs()))
+    }
+
+    #[test]
+    fn test_accepts_signed_announcements() {
+        let keypair = NodeKeypair::generate();
+        let mut validator = GossipValidator::new(GossipConfig::default());
+
+        let relay = advert(&keypair);
+        let accepted = validator.validate(Some(peer_id(&keypair)), RELAY_TOPIC, &relay.encode(), NOW).unwrap();
+        assert_eq!(accepted, relay);
+        let Announcement::Relay(advert) = accepted else { unreachable!() };
+        assert_eq!(advert.tokens_per_mb, TokenAmount::new(3));
+        assert_eq!(advert.available_sessions, RelayConfig::default().max_sessions);
+        let ttl = GossipConfig::default().advert_ttl;
+        assert!(advert.is_current(NOW + Duration::from_secs(60), ttl));
+        assert!(!advert.is_current(NOW + Duration::from_secs(3600), ttl));
+
+        let subject = NodeKeypair::generate().node_id();
+        let outcome = SessionOutcome { success: true, bytes_relayed: 1024, latency_ms: 30 };
+        let attestation = Announcement::Attestation(Attestation::new(&keypair, subject, [7u8; 32], outcome, NOW.as_secs()));
+        assert!(validator.validate(Some(peer_id(&keypair)), ATTESTATION_TOPIC, &attestation.encode(), NOW).is_ok());
+
+        let version = Announcement::Version(VersionNotice::current(keypair.node_id(), NOW.as_secs()));
+        assert!(validator.validate(Some(peer_id(&keypair)), VERSION_TOPIC, &version.encode(), NOW).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_bad_announcements() {
+        let keypair = NodeKeypair::generate();
+        let source = Some(peer_id(&keypair));
+        let mut validator = GossipValidator::new(GossipConfig { burst: 100, ..Default::default() });
+        let mut check = |source, topic, announcement: &Announcement| {
+            validator.validate(source, topic, &announcement.encode(), NOW).unwrap_err()
+        };
+
+        let relay = advert(&keypair);
+        assert_eq!(check(None, RELAY_TOPIC, &relay), GossipError::Unsigned);
+        assert_eq!(check(source, VERSION_TOPIC, &relay), GossipError::WrongTopic);
+        // Speaking for another node
+        let other = NodeKeypair::generate();
+        assert_eq!(check(Some(peer_id(&other)), RELAY_TOPIC, &relay), GossipError::AuthorMismatch);
+
+        let Announcement::Relay(mut stale) = relay.clone() else { unreachable!() };
+        stale.timestamp -= 3600;
+        assert_eq!(check(source, RELAY_TOPIC, &Announcement::Relay(stale)), GossipError::Stale);
+        let Announcement::Relay(mut unreachable) = relay.clone() else { unreachable!() };
+        unreachable.addresses = vec!["not an address".to_string()];
+        assert!(matches!(check(source, RELAY_TOPIC, &Announcement::Relay(unreachable)), GossipError::InvalidAdvert(_)));
+
+        let outcome = SessionOutcome { success: true, bytes_relayed: 1024, latency_ms: 30 };
+        let mut forged = Attestation::new(&keypair, other.node_id(), [7u8; 32], outcome, NOW.as_secs());
+        forged.outcome.bytes_relayed *= 2;
+        assert_eq!(
+            check(source, ATTESTATION_TOPIC, &Announcement::Attestation(forged)),
+            GossipError::InvalidAttestation(AttestationError::InvalidSignature)
+        );
+
+        assert!(matches!(
+            validator.validate(source, RELAY_TOPIC, b"garbage", NOW),
+            Err(GossipError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_rate_limits_each_author() {
+        let keypair = NodeKeypair::generate();
+        let other = NodeKeypair::generate();
+        let mut validator = GossipValidator::new(GossipConfig { burst: 3, rate: 1.0, ..Default::default() });
+        let relay = advert(&keypair).encode();
+        let other_relay = advert(&other).encode();
+
+        for _ in 0..3 {
+            validator.validate(Some(peer_id(&keypair)), RELAY_TOPIC, &relay, NOW).unwrap();
+        }
+        let limited = validator.validate(Some(peer_id(&keypair)), RELAY_TOPIC, &relay, NOW).unwrap_err();
+        assert_eq!(limited, GossipError::RateLimited);
+        assert!(limited.is_benign());
+        // Other authors have their own allowance
+        assert!(validator.validate(Some(peer_id(&other)), RELAY_TOPIC, &other_relay, NOW).is_ok());
+        // The allowance refills over time
+        let later = NOW + Duration::from_secs(1);
+        assert!(validator.validate(Some(peer_id(&keypair)), RELAY_TOPIC, &relay, later).is_ok());
+        assert!(validator.validate(Some(peer_id(&keypair)), RELAY_TOPIC, &relay, later).is_err());
+
+        validator.prune(NOW + Duration::from_secs(60));
+        assert!(validator.allowances.is_empty());
+    }
+}
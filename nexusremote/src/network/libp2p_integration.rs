@@ -1,166 +1,1650 @@
-//! libp2p 集成模块
-
-use libp2p::{
-    kad::{Kademlia, KademliaConfig, KademliaEvent, Record},
-    noise, tcp, yamux, Multiaddr, PeerId, Transport,
-};
-use libp2p::swarm::{Swarm, SwarmEvent};
-use libp2p::identity::Keypair;
-use libp2p::core::upgrade;
+//! libp2p-backed DHT
+//!
+//! `Libp2pDht` runs a Kademlia swarm over QUIC on a background task and
+//! implements `DhtNode` by sending commands to it, so it can replace
+//! `InMemoryDht` without API changes.
+//!
+//! Kademlia places peers by the hash of their libp2p `PeerId`, not by
+//! `DeviceID`, so it is only used for records. `find_peer` runs our own
+//! iterative `LookupState` over a FIND_NODE request-response protocol keyed
+//! on `DeviceID`, ordering candidates by raw or reputation-weighted distance.
+//! Reputation is computed locally by a `ReputationLedger` from the signed
+//! attestations peers publish in the DHT, never from what a peer claims
+//! about itself.
+//!
+//! Records are `SignedRecord`s. Inbound stores are filtered so a node only
+//! keeps records that verify and supersede what it already holds.
+//!
+//! Peers enter the routing table only with a valid `IdentityProof`, which
+//! they attach to every FIND_NODE request and response.
+//!
+//! Peers announced over mDNS on the local network are dialed like any
+//! other. Every peer entering or leaving the routing table is reported as a
+//! `DiscoveryEvent` to the subscribers of `discovery_events`.
+//!
+//! Relay adverts, attestations and version notices travel over gossipsub.
+//! Only messages `GossipValidator` accepts are forwarded; relay adverts are
+//! kept until they expire and attestations go straight into the ledger.
+//!
+//! Nodes behind NAT stay reachable through circuit relay v2: `reserve_relay`
+//! takes a slot on a relay-capable peer and adds the relayed address to the
+//! ones we advertise. When a peer connects over the relay, DCUtR tries to
+//! punch a direct connection through; if that fails the relayed connection
+//! stays in use. Which path each connection took is counted in
+//! `NetworkStats`.
+
+use crate::core::crypto::{pow, IdentityProof, NodeKeypair};
+use crate::core::state::NetworkStats;
+use crate::core::types::*;
+use crate::network::dht::{AdmissionPolicy, DhtNode, InsertOutcome, PeerPinger, WeightedRoutingTable, BUCKET_REFRESH_INTERVAL};
+use crate::network::attestation::{Attestation, ReputationLedger, SessionOutcome, TrustConfig, ATTESTATION_NAMESPACE};
+use crate::network::discovery::DiscoveryEvent;
+use crate::network::gossip::{Announcement, GossipConfig, GossipValidator, RelayAdvert, PROTOCOL_VERSION, TOPICS};
+use crate::network::lookup::{LookupMode, LookupState};
+use crate::network::record::{select_record, RecordError, SignedRecord};
+use crate::network::transport::{device_id_from_peer_id, libp2p_keypair};
+use crate::Error;
+use async_trait::async_trait;
 use futures::StreamExt;
-use std::error::Error;
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::kad::{self, store::{MemoryStore, RecordStore}, InboundRequest, QueryId, QueryResult, Quorum, Record, RecordKey};
+use libp2p::request_response::{self, OutboundRequestId, ProtocolSupport};
+use libp2p::swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, ConnectionId, DialError, NetworkBehaviour, SwarmEvent};
+use libp2p::{dcutr, gossipsub, identify, mdns, noise, relay, yamux, Multiaddr, PeerId, StreamProtocol, Swarm};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use crate::core::types::*;
-use crate::Error as NexusError;
-
-/// libp2p 网络节点
-pub struct Libp2pNode {
-    swarm: Swarm<Kademlia<libp2p::kad::store::MemoryStore>>,
-    local_peer_id: PeerId,
-}
-
-impl Libp2pNode {
-    /// 创建新的 libp2p 节点
-    pub async fn new() -> Result<Self, Box<dyn Error>> {
-        // 生成密钥对
-        let local_key = Keypair::generate_ed25519();
-        let local_peer_id = PeerId::from(local_key.public());
-        
-        println!("本地节点 ID: {}", local_peer_id);
-        
-        // 创建传输层 (TCP + Noise + Yamux)
-        let transport = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&local_key).expect("签名配置应该成功"))
-            .multiplex(yamux::Config::default())
-            .timeout(Duration::from_secs(20))
-            .boxed();
-        
-        // 创建 Kademlia DHT
-        let store = libp2p::kad::store::MemoryStore::new(local_peer_id);
-        let mut kademlia_config = KademliaConfig::default();
-        kademlia_config.set_query_timeout(Duration::from_secs(60));
-        
-        let mut kademlia = Kademlia::new(local_peer_id, store);
-        kademlia.set_mode(Some(libp2p::kad::Mode::Server));
-        
-        // 创建 Swarm
-        let swarm = Swarm::with_tokio_executor(transport, kademlia, local_peer_id);
-        
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// Kademlia protocol name
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/nexusremote/kad/1.0.0");
+
+/// Identify protocol name
+pub const IDENTIFY_PROTOCOL: &str = "/nexusremote/id/1.0.0";
+
+/// FIND_NODE protocol name
+pub const FIND_NODE_PROTOCOL: StreamProtocol = StreamProtocol::new("/nexusremote/find-node/1.0.0");
+
+/// Events buffered for a slow subscriber before it starts missing them
+const EVENT_BUFFER: usize = 256;
+
+/// libp2p DHT configuration
+#[derive(Debug, Clone)]
+pub struct Libp2pDhtConfig {
+    /// Timeout for a single Kademlia query
+    pub query_timeout: Duration,
+    /// How long idle connections are kept open
+    pub idle_connection_timeout: Duration,
+    /// Distance ordering used by `find_peer`
+    pub lookup_mode: LookupMode,
+    /// Concurrent requests per lookup
+    pub alpha: usize,
+    /// Peers returned per lookup
+    pub k: usize,
+    /// Buckets without a lookup for this long are refreshed
+    pub bucket_refresh_interval: Duration,
+    /// Checks peers must pass to enter the routing table
+    pub admission: AdmissionPolicy,
+    /// Our identity proof, which must meet the admission difficulty; mined at it when absent
+    pub identity_proof: Option<IdentityProof>,
+    /// EigenTrust parameters for computing peer reputation
+    pub trust: TrustConfig,
+    /// Find peers on the local network with mDNS
+    pub mdns: bool,
+    /// Limits on gossip announcements
+    pub gossip: GossipConfig,
+    /// Serve circuit relay reservations for peers behind NAT
+    pub relay_server: bool,
+    /// Relayed connections served at once
+    pub max_circuits: usize,
+}
+
+impl Default for Libp2pDhtConfig {
+    fn default() -> Self {
+        Self {
+            query_timeout: Duration::from_secs(30),
+            idle_connection_timeout: Duration::from_secs(60),
+            lookup_mode: LookupMode::Weighted,
+            alpha: 3,
+            k: 20,
+            bucket_refresh_interval: Duration::from_secs(BUCKET_REFRESH_INTERVAL),
+            admission: AdmissionPolicy::default(),
+            identity_proof: None,
+            trust: TrustConfig::default(),
+            mdns: true,
+            gossip: GossipConfig::default(),
+            relay_server: false,
+            max_circuits: 16,
+        }
+    }
+}
+
+/// FIND_NODE request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FindNodeRequest {
+    /// Lookup target
+    pub target: DeviceID,
+    /// Ordering the requester wants the answer in
+    pub mode: LookupMode,
+    /// Requester's identity proof
+    #[serde(default)]
+    pub proof: Option<IdentityProof>,
+}
+
+/// FIND_NODE response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FindNodeResponse {
+    /// Closest peers known to the responder
+    pub peers: Vec<PeerInfo>,
+    /// Responder's identity proof
+    #[serde(default)]
+    pub proof: Option<IdentityProof>,
+}
+
+/// Lifetime of a published attestation record
+const ATTESTATION_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// DHT key of the attestations a node published
+fn attestation_key(device_id: &DeviceID) -> [u8; 32] {
+    SignedRecord::key_for(ATTESTATION_NAMESPACE, device_id)
+}
+
+fn unix_time() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+}
+
+/// Raw 32-byte key of a Kademlia record
+fn key_bytes(key: &RecordKey) -> Option<[u8; 32]> {
+    key.to_vec().try_into().ok()
+}
+
+/// How we are connected to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionPath {
+    /// At least one connection goes straight to the peer
+    Direct,
+    /// Every connection goes through a circuit relay
+    Relayed,
+}
+
+/// Describe the peer behind a multiaddr ending in `/p2p/<peer id>`
+pub fn peer_from_multiaddr(addr: &str) -> Result<PeerInfo, Error> {
+    let mut dial: Multiaddr = addr.parse()
+        .map_err(|e| Error::Network(format!("Invalid peer address {}: {}", addr, e)))?;
+    let Some(Protocol::P2p(peer_id)) = dial.pop() else {
+        return Err(Error::Network(format!("Peer address {} has no /p2p/ peer id", addr)));
+    };
+    let device_id = device_id_from_peer_id(&peer_id)
+        .ok_or_else(|| Error::Crypto(format!("Peer {} has no Ed25519 identity", peer_id)))?;
+    Ok(PeerInfo {
+        peer_id: PeerID::new(peer_id.to_string()),
+        device_id,
+        reputation: ReputationScore::DEFAULT,
+        role: NodeRole::Idle,
+        addresses: vec![dial.to_string()],
+        available_bandwidth: 0,
+        identity_proof: None,
+    })
+}
+
+/// Combined network behaviour
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    kademlia: kad::Behaviour<MemoryStore>,
+    identify: identify::Behaviour,
+    find_node: request_response::json::Behaviour<FindNodeRequest, FindNodeResponse>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    gossipsub: gossipsub::Behaviour,
+    relay_client: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    dcutr: dcutr::Behaviour,
+}
+
+/// Request sent to the swarm task
+enum Command {
+    Listen {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<Multiaddr, Error>>,
+    },
+    AddPeer {
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+        proof: Option<IdentityProof>,
+    },
+    Connect {
+        peer_id: PeerId,
+        addrs: Vec<Multiaddr>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    FindPeer {
+        target: DeviceID,
+        mode: LookupMode,
+        reply: oneshot::Sender<Result<Vec<PeerInfo>, Error>>,
+    },
+    PutValue {
+        record: SignedRecord,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    GetValue {
+        key: [u8; 32],
+        reply: oneshot::Sender<Result<Option<SignedRecord>, Error>>,
+    },
+    /// Recompute reputations after the ledger changed
+    UpdateReputations,
+    Peers {
+        reply: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    Announce {
+        announcement: Announcement,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+}
+
+/// Caller waiting for a Kademlia query
+enum PendingQuery {
+    PutValue(oneshot::Sender<Result<(), Error>>),
+    GetValue {
+        key: [u8; 32],
+        reply: oneshot::Sender<Result<Option<SignedRecord>, Error>>,
+        /// Copies collected so far; the best is chosen when the query ends
+        found: Vec<SignedRecord>,
+    },
+    /// Background fetch of the attestations a peer published
+    Attestations {
+        attester: DeviceID,
+        found: Vec<SignedRecord>,
+    },
+}
+
+/// Lookup in progress and the caller waiting for it
+struct ActiveLookup {
+    state: LookupState,
+    reply: oneshot::Sender<Result<Vec<PeerInfo>, Error>>,
+}
+
+/// Pings peers with a FIND_NODE for our own ID; the answer arrives as a swarm event
+struct FindNodePinger<'a> {
+    find_node: &'a mut request_response::json::Behaviour<FindNodeRequest, FindNodeResponse>,
+    pings: &'a mut HashMap<OutboundRequestId, DeviceID>,
+    local: DeviceID,
+    proof: Option<IdentityProof>,
+}
+
+impl PeerPinger for FindNodePinger<'_> {
+    fn ping(&mut self, peer: &PeerInfo) -> Option<bool> {
+        let Ok(peer_id) = peer.peer_id.0.parse::<PeerId>() else {
+            return Some(false);
+        };
+        let request = FindNodeRequest { target: self.local, mode: LookupMode::Raw, proof: self.proof.clone() };
+        let request_id = self.find_node.send_request(&peer_id, request);
+        self.pings.insert(request_id, peer.device_id);
+        None
+    }
+}
+
+/// DHT node backed by a libp2p Kademlia swarm
+#[derive(Clone)]
+pub struct Libp2pDht {
+    commands: mpsc::Sender<Command>,
+    keypair: NodeKeypair,
+    local_peer: Arc<Mutex<PeerInfo>>,
+    ledger: Arc<Mutex<ReputationLedger>>,
+    lookup_mode: LookupMode,
+    discovery: broadcast::Sender<DiscoveryEvent>,
+    announcements: broadcast::Sender<Announcement>,
+    relay_offers: Arc<Mutex<HashMap<DeviceID, RelayAdvert>>>,
+    advert_ttl: Duration,
+    stats: Arc<Mutex<NetworkStats>>,
+    paths: Arc<Mutex<HashMap<DeviceID, ConnectionPath>>>,
+}
+
+impl Libp2pDht {
+    /// Build the swarm for `keypair` and start its event loop
+    pub fn new(keypair: &NodeKeypair, config: Libp2pDhtConfig) -> Result<Self, Error> {
+        let local_key = libp2p_keypair(keypair);
+        let local_peer_id = local_key.public().to_peer_id();
+        // Our own proof has to pass the check we hold other peers to
+        let difficulty = config.admission.identity_difficulty.unwrap_or(pow::IDENTITY_DIFFICULTY);
+        let identity_proof = match &config.identity_proof {
+            Some(proof) if proof.verify(&keypair.node_id(), difficulty) => proof.clone(),
+            Some(_) => {
+                return Err(Error::Crypto(format!(
+                    "Identity proof does not match keypair at difficulty {}",
+                    difficulty
+                )));
+            }
+            None => IdentityProof::solve(keypair, difficulty),
+        };
+
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+            .with_tokio()
+            .with_quic()
+            .with_relay_client(noise::Config::new, yamux::Config::default)
+            .map_err(|e| Error::Network(format!("Failed to build relay client: {}", e)))?
+            .with_behaviour(|key, relay_client| {
+                let mut kad_config = kad::Config::default();
+                kad_config.set_protocol_names(vec![KAD_PROTOCOL]);
+                kad_config.set_query_timeout(config.query_timeout);
+                // Inbound records are verified before they reach the store
+                kad_config.set_record_filtering(kad::StoreInserts::FilterBoth);
+
+                let mut kademlia = kad::Behaviour::with_config(
+                    key.public().to_peer_id(),
+                    MemoryStore::new(key.public().to_peer_id()),
+                    kad_config,
+                );
+                // Answer queries without waiting for an external address to be confirmed
+                kademlia.set_mode(Some(kad::Mode::Server));
+
+                let identify = identify::Behaviour::new(identify::Config::new(
+                    IDENTIFY_PROTOCOL.to_string(),
+                    key.public(),
+                ));
+
+                let find_node = request_response::json::Behaviour::new(
+                    [(FIND_NODE_PROTOCOL, ProtocolSupport::Full)],
+                    request_response::Config::default().with_request_timeout(config.query_timeout),
+                );
+
+                let mdns = if config.mdns {
+                    mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
+                        .map_err(|e| warn!("mDNS unavailable, only bootstrap peers will be found: {}", e))
+                        .ok()
+                } else {
+                    None
+                };
+
+                let gossip_config = gossipsub::ConfigBuilder::default()
+                    // Unsigned messages never reach `GossipValidator`
+                    .validation_mode(gossipsub::ValidationMode::Strict)
+                    // Nothing is forwarded until `GossipValidator` accepts it
+                    .validate_messages()
+                    .build()?;
+                let gossipsub = gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), gossip_config)?;
+
+                let relay_server = config.relay_server.then(|| relay::Behaviour::new(
+                    key.public().to_peer_id(),
+                    relay::Config { max_circuits: config.max_circuits, ..Default::default() },
+                ));
+                let dcutr = dcutr::Behaviour::new(key.public().to_peer_id());
+
+                Ok(Behaviour {
+                    kademlia,
+                    identify,
+                    find_node,
+                    mdns: mdns.into(),
+                    gossipsub,
+                    relay_client,
+                    relay_server: relay_server.into(),
+                    dcutr,
+                })
+            })
+            .map_err(|e| Error::Network(format!("Failed to build behaviour: {}", e)))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(config.idle_connection_timeout))
+            .build();
+        for topic in TOPICS {
+            swarm.behaviour_mut().gossipsub.subscribe(&gossipsub::IdentTopic::new(topic))
+                .map_err(|e| Error::Network(format!("Failed to subscribe to {}: {}", topic, e)))?;
+        }
+
+        let local_peer = Arc::new(Mutex::new(PeerInfo {
+            peer_id: PeerID::new(local_peer_id.to_string()),
+            device_id: keypair.node_id(),
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Idle,
+            addresses: vec![],
+            available_bandwidth: 0,
+            identity_proof: Some(identity_proof),
+        }));
+
+        let ledger = Arc::new(Mutex::new(ReputationLedger::new(keypair.node_id(), config.trust.clone())));
+        let lookup_mode = config.lookup_mode;
+        let (commands, rx) = mpsc::channel(64);
+        let (discovery, _) = broadcast::channel(EVENT_BUFFER);
+        let (announcements, _) = broadcast::channel(EVENT_BUFFER);
+        let relay_offers = Arc::new(Mutex::new(HashMap::new()));
+        let advert_ttl = config.gossip.advert_ttl;
+        let stats = Arc::new(Mutex::new(NetworkStats::default()));
+        let paths = Arc::new(Mutex::new(HashMap::new()));
+        let channels = EventChannels {
+            discovery: discovery.clone(),
+            announcements: announcements.clone(),
+            relay_offers: relay_offers.clone(),
+            stats: stats.clone(),
+            paths: paths.clone(),
+        };
+        tokio::spawn(EventLoop::new(swarm, rx, local_peer.clone(), ledger.clone(), channels, config).run());
+
         Ok(Self {
-            swarm,
-            local_peer_id,
+            commands,
+            keypair: keypair.clone(),
+            local_peer,
+            ledger,
+            lookup_mode,
+            discovery,
+            announcements,
+            relay_offers,
+            advert_ttl,
+            stats,
+            paths,
         })
     }
-    
-    /// 监听地址
-    pub async fn listen_on(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
-        self.swarm.listen_on(addr)?;
-        println!("监听地址: {}", addr);
-        Ok(())
+
+    /// Find peers close to `target` using an explicit distance ordering
+    pub async fn find_peer_with_mode(&self, target: DeviceID, mode: LookupMode) -> Result<Vec<PeerInfo>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::FindPeer { target, mode, reply }).await?;
+        Self::recv(rx).await?
     }
-    
-    /// 连接到对等节点
-    pub async fn dial(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
-        match self.swarm.dial(addr) {
-            Ok(_) => {
-                println!("连接到: {}", addr);
-                Ok(())
-            }
-            Err(e) => {
-                println!("连接失败: {}", e);
-                Err(Box::new(e))
-            }
+
+    /// Attest to a relay session with `subject` and republish our attestations
+    pub async fn attest(&mut self, subject: DeviceID, session_id: [u8; 32], outcome: SessionOutcome) -> Result<Attestation, Error> {
+        let now = unix_time();
+        let (attestation, record) = {
+            let mut ledger = self.ledger.lock().unwrap();
+            let attestation = ledger.attest(&self.keypair, subject, session_id, outcome, now.as_secs())?;
+            // Millisecond timestamps keep sequences increasing across restarts
+            let record = ledger.to_record(&self.keypair, now.as_millis() as u64, (now + ATTESTATION_RECORD_TTL).as_secs())?;
+            (attestation, record)
+        };
+        self.send(Command::UpdateReputations).await?;
+        self.put_value(record).await?;
+        // The DHT copy is authoritative; gossip only gets it to peers sooner
+        if let Err(e) = self.announce(Announcement::Attestation(attestation.clone())).await {
+            debug!("Attestation not gossiped: {}", e);
+        }
+        Ok(attestation)
+    }
+
+    /// Peers in the routing table
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Peers { reply }).await?;
+        Self::recv(rx).await
+    }
+
+    /// Peers entering and leaving the routing table from now on
+    pub fn discovery_events(&self) -> broadcast::Receiver<DiscoveryEvent> {
+        self.discovery.subscribe()
+    }
+
+    /// Publish an announcement on its gossip topic
+    pub async fn announce(&self, announcement: Announcement) -> Result<(), Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Announce { announcement, reply }).await?;
+        Self::recv(rx).await?
+    }
+
+    /// Announcements accepted from other nodes from now on
+    pub fn announcements(&self) -> broadcast::Receiver<Announcement> {
+        self.announcements.subscribe()
+    }
+
+    /// Current relay offers heard over gossip, cheapest first
+    pub fn relay_offers(&self) -> Vec<RelayAdvert> {
+        let now = unix_time();
+        let mut offers: Vec<_> = self.relay_offers.lock().unwrap().values()
+            .filter(|advert| advert.is_current(now, self.advert_ttl) && advert.available_sessions > 0)
+            .cloned()
+            .collect();
+        offers.sort_by_key(|advert| (advert.tokens_per_mb, std::cmp::Reverse(advert.available_sessions)));
+        offers
+    }
+
+    /// Reputation of a peer as computed from the attestations we know
+    pub fn reputation(&self, device_id: &DeviceID) -> ReputationScore {
+        self.ledger.lock().unwrap().score(device_id)
+    }
+
+    /// Add a bootstrap peer from a multiaddr ending in `/p2p/<peer id>`
+    /// and wait until it is connected
+    pub async fn add_bootstrap(&mut self, addr: &str) -> Result<(), Error> {
+        let peer = peer_from_multiaddr(addr)?;
+        let peer_id: PeerId = peer.peer_id.0.parse()
+            .map_err(|e| Error::Network(format!("Invalid libp2p peer id {}: {}", peer.peer_id, e)))?;
+        let addrs = peer.addresses.iter().filter_map(|a| a.parse().ok()).collect();
+        self.add_peer(peer).await?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Connect { peer_id, addrs, reply }).await?;
+        Self::recv(rx).await?
+    }
+
+    /// Start listening on a multiaddr, returning the bound address
+    pub async fn listen(&self, addr: &str) -> Result<String, Error> {
+        let addr: Multiaddr = addr.parse()
+            .map_err(|e| Error::Network(format!("Invalid listen address {}: {}", addr, e)))?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Listen { addr, reply }).await?;
+        Ok(Self::recv(rx).await??.to_string())
+    }
+
+    /// Reserve a slot on the relay at `relay_addr`, which ends in `/p2p/<peer id>`
+    ///
+    /// Returns our address through the relay once the reservation is
+    /// accepted. It is advertised with our other addresses and renewed
+    /// for as long as the relay keeps it.
+    pub async fn reserve_relay(&self, relay_addr: &str) -> Result<String, Error> {
+        let addr: Multiaddr = relay_addr.parse()
+            .map_err(|e| Error::Network(format!("Invalid relay address {}: {}", relay_addr, e)))?;
+        if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+            return Err(Error::Network(format!("Relay address {} has no /p2p/ peer id", relay_addr)));
+        }
+        if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            return Err(Error::Network(format!("Relay address {} is itself relayed", relay_addr)));
+        }
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::Listen { addr: addr.with(Protocol::P2pCircuit), reply }).await?;
+        Ok(Self::recv(rx).await??.to_string())
+    }
+
+    /// Addresses we are reachable at through a relay
+    pub fn relayed_addrs(&self) -> Vec<String> {
+        self.local_peer.lock().unwrap().addresses.iter()
+            .filter(|a| a.contains("/p2p-circuit"))
+            .cloned()
+            .collect()
+    }
+
+    /// Connection counters since the node started
+    pub fn stats(&self) -> NetworkStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// How we are currently connected to a peer, if at all
+    pub fn connection_path(&self, device_id: &DeviceID) -> Option<ConnectionPath> {
+        self.paths.lock().unwrap().get(device_id).copied()
+    }
+
+    async fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands.send(command).await
+            .map_err(|_| Error::Network("libp2p event loop stopped".to_string()))
+    }
+
+    async fn recv<T>(rx: oneshot::Receiver<T>) -> Result<T, Error> {
+        rx.await.map_err(|_| Error::Network("libp2p event loop stopped".to_string()))
+    }
+}
+
+#[async_trait]
+impl DhtNode for Libp2pDht {
+    async fn find_peer(&self, target: DeviceID) -> Result<Vec<PeerInfo>, Error> {
+        self.find_peer_with_mode(target, self.lookup_mode).await
+    }
+
+    async fn put_value(&mut self, record: SignedRecord) -> Result<(), Error> {
+        record.verify(&record.key(), unix_time().as_secs())?;
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::PutValue { record, reply }).await?;
+        Self::recv(rx).await?
+    }
+
+    async fn get_value(&self, key: [u8; 32]) -> Result<Option<SignedRecord>, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Command::GetValue { key, reply }).await?;
+        Self::recv(rx).await?
+    }
+
+    async fn add_peer(&mut self, peer: PeerInfo) -> Result<(), Error> {
+        let peer_id: PeerId = peer.peer_id.0.parse()
+            .map_err(|e| Error::Network(format!("Invalid libp2p peer id {}: {}", peer.peer_id, e)))?;
+        if device_id_from_peer_id(&peer_id) != Some(peer.device_id) {
+            return Err(Error::Crypto("Peer ID does not match device ID".to_string()));
+        }
+        let addrs = peer.addresses.iter()
+            .filter_map(|a| a.parse().ok())
+            .collect::<Vec<Multiaddr>>();
+        if addrs.is_empty() {
+            return Err(Error::Network(format!("No usable address for peer {}", peer.peer_id)));
+        }
+        self.send(Command::AddPeer { peer_id, addrs, proof: peer.identity_proof }).await
+    }
+
+    fn local_peer(&self) -> PeerInfo {
+        self.local_peer.lock().unwrap().clone()
+    }
+}
+
+/// Where the swarm task reports what it learns
+struct EventChannels {
+    discovery: broadcast::Sender<DiscoveryEvent>,
+    announcements: broadcast::Sender<Announcement>,
+    relay_offers: Arc<Mutex<HashMap<DeviceID, RelayAdvert>>>,
+    stats: Arc<Mutex<NetworkStats>>,
+    paths: Arc<Mutex<HashMap<DeviceID, ConnectionPath>>>,
+}
+
+/// Swarm task state
+struct EventLoop {
+    swarm: Swarm<Behaviour>,
+    commands: mpsc::Receiver<Command>,
+    local_peer: Arc<Mutex<PeerInfo>>,
+    pending_queries: HashMap<QueryId, PendingQuery>,
+    pending_listens: HashMap<ListenerId, oneshot::Sender<Result<Multiaddr, Error>>>,
+    /// Callers waiting for a connection to a peer
+    pending_dials: HashMap<PeerId, Vec<oneshot::Sender<Result<(), Error>>>>,
+    /// Known dialable addresses, used to fill in `PeerInfo`
+    addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Peers we route through, with liveness tracking
+    table: WeightedRoutingTable,
+    /// Outstanding liveness pings
+    pings: HashMap<OutboundRequestId, DeviceID>,
+    /// Verified identity proofs of remote peers
+    proofs: HashMap<DeviceID, IdentityProof>,
+    /// Outstanding requests for a peer's identity proof
+    proof_requests: HashMap<OutboundRequestId, PeerId>,
+    /// Attestations collected from peers and the reputations computed from them
+    ledger: Arc<Mutex<ReputationLedger>>,
+    /// Peers whose attestations were fetched or are being fetched
+    fetched_attestations: HashSet<DeviceID>,
+    lookups: HashMap<u64, ActiveLookup>,
+    next_lookup_id: u64,
+    /// Outstanding FIND_NODE requests: lookup and queried device
+    find_node_requests: HashMap<OutboundRequestId, (u64, DeviceID)>,
+    /// Subscribers to routing table changes and gossip
+    channels: EventChannels,
+    /// Routing table contents as last reported to `channels.discovery`
+    announced: HashMap<DeviceID, PeerInfo>,
+    gossip_validator: GossipValidator,
+    /// Open connections and the path each one takes
+    connections: HashMap<ConnectionId, (DeviceID, ConnectionPath)>,
+    config: Libp2pDhtConfig,
+}
+
+impl EventLoop {
+    fn new(
+        swarm: Swarm<Behaviour>,
+        commands: mpsc::Receiver<Command>,
+        local_peer: Arc<Mutex<PeerInfo>>,
+        ledger: Arc<Mutex<ReputationLedger>>,
+        channels: EventChannels,
+        config: Libp2pDhtConfig,
+    ) -> Self {
+        let table = WeightedRoutingTable::with_admission(local_peer.lock().unwrap().clone(), config.k, config.admission.clone());
+        Self {
+            swarm,
+            commands,
+            local_peer,
+            pending_queries: HashMap::new(),
+            pending_listens: HashMap::new(),
+            pending_dials: HashMap::new(),
+            addresses: HashMap::new(),
+            table,
+            pings: HashMap::new(),
+            proofs: HashMap::new(),
+            proof_requests: HashMap::new(),
+            ledger,
+            fetched_attestations: HashSet::new(),
+            lookups: HashMap::new(),
+            next_lookup_id: 0,
+            find_node_requests: HashMap::new(),
+            channels,
+            announced: HashMap::new(),
+            gossip_validator: GossipValidator::new(config.gossip.clone()),
+            connections: HashMap::new(),
+            config,
         }
     }
-    
-    /// 启动节点事件循环
-    pub async fn run_event_loop(&mut self) -> Result<(), Box<dyn Error>> {
-        println!("启动事件循环...");
-        
+
+    async fn run(mut self) {
+        let mut refresh = tokio::time::interval(self.config.bucket_refresh_interval.min(Duration::from_secs(60)));
         loop {
-            match self.swarm.select_next_some().await {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    println!("新监听地址: {}", address);
+            tokio::select! {
+                _ = refresh.tick() => self.refresh_buckets(),
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    // Every handle was dropped
+                    None => break,
+                },
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event),
+            }
+            self.announce_table_changes();
+        }
+        debug!("libp2p event loop stopped");
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Listen { addr, reply } => match self.swarm.listen_on(addr) {
+                Ok(id) => {
+                    self.pending_listens.insert(id, reply);
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(Error::Network(format!("Listen failed: {}", e))));
+                }
+            },
+            Command::AddPeer { peer_id, addrs, proof } => {
+                self.record_proof(peer_id, proof);
+                for addr in addrs {
+                    self.add_address(peer_id, addr);
                 }
-                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                    println!("连接建立: {}", peer_id);
+            }
+            Command::Connect { peer_id, addrs, reply } => {
+                if self.swarm.is_connected(&peer_id) {
+                    let _ = reply.send(Ok(()));
+                    return;
+                }
+                let waiting = self.pending_dials.entry(peer_id).or_default();
+                waiting.push(reply);
+                if waiting.len() > 1 {
+                    return;
+                }
+                let opts = DialOpts::peer_id(peer_id).addresses(addrs).build();
+                match self.swarm.dial(opts) {
+                    // Another behaviour is already dialing; its outcome answers us
+                    Ok(()) | Err(DialError::DialPeerConditionFalse(_)) => {}
+                    Err(e) => self.finish_dial(peer_id, Err(format!("Dial to {} failed: {}", peer_id, e))),
                 }
-                SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                    println!("连接关闭: {}", peer_id);
+            }
+            Command::FindPeer { target, mode, reply } => self.start_lookup(target, mode, reply),
+            Command::PutValue { record, reply } => {
+                if let Err(e) = self.check_against_store(&record) {
+                    let _ = reply.send(Err(e.into()));
+                    return;
                 }
-                SwarmEvent::Behaviour(event) => {
-                    self.handle_kademlia_event(event).await?;
+                let record = Record::new(RecordKey::new(&record.key()), record.encode());
+                match self.swarm.behaviour_mut().kademlia.put_record(record, Quorum::One) {
+                    Ok(id) => {
+                        self.pending_queries.insert(id, PendingQuery::PutValue(reply));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(Error::Network(format!("Failed to store record: {:?}", e))));
+                    }
                 }
-                _ => {}
+            }
+            Command::GetValue { key, reply } => {
+                let id = self.swarm.behaviour_mut().kademlia.get_record(RecordKey::new(&key));
+                self.pending_queries.insert(id, PendingQuery::GetValue { key, reply, found: Vec::new() });
+            }
+            Command::UpdateReputations => self.update_reputations(),
+            Command::Peers { reply } => {
+                let _ = reply.send(self.table.all_peers());
+            }
+            Command::Announce { announcement, reply } => {
+                let topic = gossipsub::IdentTopic::new(announcement.topic());
+                let result = self.swarm.behaviour_mut().gossipsub.publish(topic, announcement.encode())
+                    .map(|_| ())
+                    .map_err(|e| Error::Network(format!("Failed to publish on {}: {}", announcement.topic(), e)));
+                let _ = reply.send(result);
             }
         }
     }
-    
-    /// 处理 Kademlia 事件
-    async fn handle_kademlia_event(&mut self, event: KademliaEvent) -> Result<(), Box<dyn Error>> {
+
+    fn handle_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
         match event {
-            KademliaEvent::OutboundQueryCompleted { result, .. } => {
+            SwarmEvent::NewListenAddr { listener_id, address } => {
+                info!("libp2p listening on {}", address);
+                // Relayed addresses end in our own peer id; the others do not
+                let mut address = address;
+                if address.iter().last() == Some(Protocol::P2p(*self.swarm.local_peer_id())) {
+                    address.pop();
+                }
+                if self.config.relay_server && !address.iter().any(|p| p == Protocol::P2pCircuit) {
+                    // Reservations only carry confirmed external addresses, so
+                    // a relay has to be publicly reachable where it listens
+                    self.swarm.add_external_address(address.clone());
+                }
+                self.local_peer.lock().unwrap().addresses.push(address.to_string());
+                if let Some(reply) = self.pending_listens.remove(&listener_id) {
+                    let _ = reply.send(Ok(address));
+                }
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                info!("libp2p no longer listening on {}", address);
+                let mut expired = address;
+                if expired.iter().last() == Some(Protocol::P2p(*self.swarm.local_peer_id())) {
+                    expired.pop();
+                }
+                let expired = expired.to_string();
+                self.local_peer.lock().unwrap().addresses.retain(|a| *a != expired);
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                let path = if endpoint.is_relayed() { ConnectionPath::Relayed } else { ConnectionPath::Direct };
+                debug!("Connected to {} ({:?}) at {}", peer_id, path, endpoint.get_remote_address());
+                {
+                    let mut stats = self.channels.stats.lock().unwrap();
+                    stats.successful_connections += 1;
+                    match path {
+                        ConnectionPath::Direct => stats.direct_connections += 1,
+                        ConnectionPath::Relayed => stats.relayed_connections += 1,
+                    }
+                }
+                if let Some(device_id) = device_id_from_peer_id(&peer_id) {
+                    self.connections.insert(connection_id, (device_id, path));
+                    self.update_path(device_id);
+                }
+                self.finish_dial(peer_id, Ok(()));
+            }
+            SwarmEvent::ConnectionClosed { connection_id, .. } => {
+                if let Some((device_id, _)) = self.connections.remove(&connection_id) {
+                    self.update_path(device_id);
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                debug!("Dialing {:?} failed: {}", peer_id, error);
+                self.channels.stats.lock().unwrap().failed_connections += 1;
+                if let Some(peer_id) = peer_id {
+                    self.finish_dial(peer_id, Err(format!("Dial to {} failed: {}", peer_id, error)));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => match event {
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. } => {
+                    info!("Relay {} accepted our reservation", relay_peer_id);
+                }
+                event => debug!("{:?}", event),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::RelayServer(event)) => debug!("{:?}", event),
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                let mut stats = self.channels.stats.lock().unwrap();
                 match result {
-                    libp2p::kad::QueryResult::GetRecord(Ok(result)) => {
-                        println!("获取记录成功: {:?}", result);
+                    Ok(_) => {
+                        info!("Hole punched to {}; connected directly", remote_peer_id);
+                        stats.hole_punch_successes += 1;
                     }
-                    libp2p::kad::QueryResult::GetRecord(Err(e)) => {
-                        println!("获取记录失败: {:?}", e);
+                    Err(e) => {
+                        info!("Hole punching to {} failed, staying on the relay: {}", remote_peer_id, e);
+                        stats.hole_punch_failures += 1;
+                    }
+                }
+            }
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                if let Some(reply) = self.pending_listens.remove(&listener_id) {
+                    let _ = reply.send(Err(Error::Network(format!("Listener closed: {:?}", reason))));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                for addr in info.listen_addrs {
+                    self.add_address(peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(event)) => self.handle_kad_event(event),
+            SwarmEvent::Behaviour(BehaviourEvent::FindNode(event)) => self.handle_find_node_event(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                for (peer_id, addr) in found {
+                    debug!("mDNS found {} at {}", peer_id, addr);
+                    self.add_address(peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message })) => {
+                let acceptance = match self.gossip_validator.validate(message.source, message.topic.as_str(), &message.data, unix_time()) {
+                    Ok(announcement) => {
+                        self.handle_announcement(announcement);
+                        gossipsub::MessageAcceptance::Accept
                     }
-                    libp2p::kad::QueryResult::PutRecord(Ok(_)) => {
-                        println!("存储记录成功");
+                    Err(e) if e.is_benign() => {
+                        debug!("Ignoring gossip from {:?}: {}", message.source, e);
+                        gossipsub::MessageAcceptance::Ignore
                     }
-                    libp2p::kad::QueryResult::PutRecord(Err(e)) => {
-                        println!("存储记录失败: {:?}", e);
+                    Err(e) => {
+                        debug!("Rejected gossip from {:?} via {}: {}", message.source, propagation_source, e);
+                        gossipsub::MessageAcceptance::Reject
                     }
-                    _ => {}
+                };
+                let _ = self.swarm.behaviour_mut().gossipsub
+                    .report_message_validation_result(&message_id, &propagation_source, acceptance);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(expired))) => {
+                for (peer_id, addr) in expired {
+                    debug!("mDNS record for {} at {} expired", peer_id, addr);
+                    self.remove_address(peer_id, &addr);
                 }
             }
             _ => {}
         }
-        Ok(())
     }
-    
-    /// 获取本地 PeerId
-    pub fn local_peer_id(&self) -> &PeerId {
-        &self.local_peer_id
+
+    /// Answer callers waiting for a connection to `peer_id`
+    fn finish_dial(&mut self, peer_id: PeerId, result: Result<(), String>) {
+        for reply in self.pending_dials.remove(&peer_id).unwrap_or_default() {
+            let _ = reply.send(result.clone().map_err(Error::Network));
+        }
     }
-    
-    /// 转换为 NexusRemote 的 PeerID
-    pub fn to_nexus_peer_id(&self) -> PeerID {
-        PeerID::new(self.local_peer_id.to_string())
+
+    fn handle_kad_event(&mut self, event: kad::Event) {
+        match event {
+            kad::Event::RoutingUpdated { peer, addresses, .. } => {
+                let known = self.addresses.entry(peer).or_default();
+                for addr in addresses.iter() {
+                    if !known.contains(addr) {
+                        known.push(addr.clone());
+                    }
+                }
+                self.learn_peer(peer);
+            }
+            kad::Event::OutboundQueryProgressed { id, result, step, .. } => {
+                self.handle_query_result(id, result, step.last);
+            }
+            kad::Event::InboundRequest { request: InboundRequest::PutRecord { source, record: Some(record), .. } } => {
+                match self.validate_inbound(&record) {
+                    Ok(()) => {
+                        if let Err(e) = self.swarm.behaviour_mut().kademlia.store_mut().put(record) {
+                            warn!("Failed to store record from {}: {:?}", source, e);
+                        }
+                    }
+                    Err(e) => debug!("Rejected record from {}: {}", source, e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_query_result(&mut self, id: QueryId, result: QueryResult, last: bool) {
+        match result {
+            QueryResult::PutRecord(result) => {
+                if let Err(e) = result {
+                    // The record is already in our own store; replication is best effort
+                    warn!("Record replication incomplete: {:?}", e);
+                }
+                if let Some(PendingQuery::PutValue(reply)) = self.pending_queries.remove(&id) {
+                    let _ = reply.send(Ok(()));
+                }
+            }
+            QueryResult::GetRecord(result) => {
+                let Some(mut pending) = self.pending_queries.remove(&id) else { return };
+                let failure = match result {
+                    Ok(kad::GetRecordOk::FoundRecord(found)) => {
+                        // Keep collecting: another peer may hold a newer sequence
+                        match (&mut pending, SignedRecord::decode(&found.record.value)) {
+                            (PendingQuery::GetValue { found, .. } | PendingQuery::Attestations { found, .. }, Ok(record)) => {
+                                found.push(record);
+                            }
+                            (_, Err(e)) => debug!("Ignoring undecodable record: {}", e),
+                            _ => {}
+                        }
+                        None
+                    }
+                    Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. })
+                    | Err(kad::GetRecordError::NotFound { .. }) => None,
+                    Err(e) => Some(e),
+                };
+                if !last {
+                    self.pending_queries.insert(id, pending);
+                    return;
+                }
+                let now = unix_time().as_secs();
+                match pending {
+                    PendingQuery::GetValue { key, reply, found } => {
+                        let best = select_record(&key, found, now);
+                        let _ = match (best, failure) {
+                            (None, Some(e)) => reply.send(Err(Error::Network(format!("Record lookup failed: {:?}", e)))),
+                            (best, _) => reply.send(Ok(best)),
+                        };
+                    }
+                    PendingQuery::Attestations { attester, found } => {
+                        self.handle_attestations(attester, select_record(&attestation_key(&attester), found, now));
+                    }
+                    PendingQuery::PutValue(_) => {}
+                }
+            }
+            _ => {
+                if last {
+                    self.pending_queries.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn handle_attestations(&mut self, attester: DeviceID, record: Option<SignedRecord>) {
+        let Some(record) = record else {
+            // Nothing published yet; try again next time we meet the peer
+            self.fetched_attestations.remove(&attester);
+            return;
+        };
+        let imported = self.ledger.lock().unwrap().import_record(&record);
+        match imported {
+            Ok(0) => {}
+            Ok(added) => {
+                debug!("Imported {} attestations from {}", added, attester);
+                self.update_reputations();
+            }
+            Err(e) => warn!("Ignoring attestations from {}: {}", attester, e),
+        }
+    }
+
+    /// Act on a validated announcement and pass it to subscribers
+    fn handle_announcement(&mut self, announcement: Announcement) {
+        match &announcement {
+            Announcement::Relay(advert) => {
+                let mut offers = self.channels.relay_offers.lock().unwrap();
+                let newer = offers.get(&advert.device_id).is_none_or(|known| known.timestamp < advert.timestamp);
+                if newer {
+                    offers.insert(advert.device_id, advert.clone());
+                }
+            }
+            Announcement::Attestation(attestation) => {
+                let added = self.ledger.lock().unwrap().add(attestation.clone());
+                match added {
+                    Ok(true) => self.update_reputations(),
+                    Ok(false) => {}
+                    Err(e) => debug!("Ignoring gossiped attestation: {}", e),
+                }
+            }
+            Announcement::Version(notice) => {
+                if notice.protocol_version > PROTOCOL_VERSION {
+                    info!(
+                        "{} speaks protocol version {} (we speak {}); consider upgrading from {}",
+                        notice.device_id, notice.protocol_version, PROTOCOL_VERSION, env!("CARGO_PKG_VERSION")
+                    );
+                }
+            }
+        }
+        let _ = self.channels.announcements.send(announcement);
+    }
+
+    /// Recompute reputations and re-rank the routing table
+    fn update_reputations(&mut self) {
+        let scores = {
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.compute();
+            ledger.scores().clone()
+        };
+        self.table.set_reputations(scores);
+    }
+
+    /// Check an inbound Kademlia record before it is stored
+    fn validate_inbound(&mut self, record: &Record) -> Result<(), RecordError> {
+        let key = key_bytes(&record.key)
+            .ok_or_else(|| RecordError::Malformed("record key is not 32 bytes".to_string()))?;
+        let signed = SignedRecord::decode(&record.value)?;
+        signed.verify(&key, unix_time().as_secs())?;
+        self.check_against_store(&signed)
+    }
+
+    /// Refuse records that do not supersede the copy we hold
+    fn check_against_store(&mut self, record: &SignedRecord) -> Result<(), RecordError> {
+        let key = RecordKey::new(&record.key());
+        let current = self.swarm.behaviour_mut().kademlia.store_mut().get(&key)
+            .and_then(|r| SignedRecord::decode(&r.value).ok());
+        match current {
+            // Replicas of the record we already hold are accepted to refresh it
+            Some(current) if current != *record => record.supersedes(&current),
+            _ => Ok(()),
+        }
+    }
+
+    fn fetch_attestations(&mut self, attester: DeviceID) {
+        if !self.fetched_attestations.insert(attester) {
+            return;
+        }
+        let id = self.swarm.behaviour_mut().kademlia.get_record(RecordKey::new(&attestation_key(&attester)));
+        self.pending_queries.insert(id, PendingQuery::Attestations { attester, found: Vec::new() });
+    }
+
+    fn reputation_of(&self, device_id: &DeviceID) -> ReputationScore {
+        self.ledger.lock().unwrap().score(device_id)
+    }
+
+    /// Run lookups for random IDs in buckets that have gone quiet
+    fn refresh_buckets(&mut self) {
+        // Pick up attestations published since we last looked
+        self.fetched_attestations.clear();
+        let now = unix_time();
+        self.gossip_validator.prune(now);
+        let ttl = self.config.gossip.advert_ttl;
+        self.channels.relay_offers.lock().unwrap().retain(|_, advert| advert.is_current(now, ttl));
+        let targets = self.table.buckets_to_refresh(unix_time().as_secs(), self.config.bucket_refresh_interval.as_secs());
+        for target in targets {
+            debug!("Refreshing bucket with a lookup for {}", target);
+            // Nobody waits for the answer; the lookup itself repopulates the table
+            let (reply, _) = oneshot::channel();
+            self.start_lookup(target, self.config.lookup_mode, reply);
+        }
+    }
+
+    fn start_lookup(&mut self, target: DeviceID, mode: LookupMode, reply: oneshot::Sender<Result<Vec<PeerInfo>, Error>>) {
+        let local = self.local_peer.lock().unwrap().device_id;
+        self.table.record_lookup(&target, unix_time().as_secs());
+        // Any dialable peer can seed a lookup; only admitted peers are handed out to others
+        let seeds = self.addresses.keys()
+            .filter_map(|p| self.peer_info(*p))
+            .collect();
+        let id = self.next_lookup_id;
+        self.next_lookup_id += 1;
+        self.lookups.insert(id, ActiveLookup {
+            state: LookupState::new(target, local, seeds, mode, self.config.alpha, self.config.k),
+            reply,
+        });
+        self.drive_lookup(id);
+    }
+
+    /// Send the next FIND_NODE requests, or answer the caller once the lookup converges
+    fn drive_lookup(&mut self, id: u64) {
+        let proof = self.local_proof();
+        let Some(lookup) = self.lookups.get_mut(&id) else { return };
+        let (target, mode) = (lookup.state.target(), lookup.state.mode());
+        loop {
+            let queries = lookup.state.next_queries();
+            if queries.is_empty() {
+                break;
+            }
+            for peer in queries {
+                match peer.peer_id.0.parse::<PeerId>() {
+                    Ok(peer_id) => {
+                        let request_id = self.swarm.behaviour_mut().find_node
+                            .send_request(&peer_id, FindNodeRequest { target, mode, proof: proof.clone() });
+                        self.find_node_requests.insert(request_id, (id, peer.device_id));
+                    }
+                    Err(_) => lookup.state.on_failure(&peer.device_id),
+                }
+            }
+        }
+        if lookup.state.is_finished() {
+            let lookup = self.lookups.remove(&id).unwrap();
+            debug!("Lookup for {} finished after contacting {} peers", lookup.state.target(), lookup.state.contacted());
+            let _ = lookup.reply.send(Ok(lookup.state.results()));
+        }
+    }
+
+    fn handle_find_node_event(&mut self, event: request_response::Event<FindNodeRequest, FindNodeResponse>) {
+        match event {
+            request_response::Event::Message { peer, message } => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    if self.record_proof(peer, request.proof) {
+                        self.learn_peer(peer);
+                    }
+                    if let Some(device_id) = device_id_from_peer_id(&peer) {
+                        self.table.mark_seen(&device_id, unix_time().as_secs());
+                    }
+                    let mut peers: Vec<_> = self.known_peers().into_iter()
+                        .filter(|p| p.peer_id.0 != peer.to_string())
+                        .collect();
+                    request.mode.sort(&mut peers, &request.target);
+                    peers.truncate(self.config.k);
+                    let proof = self.local_proof();
+                    let _ = self.swarm.behaviour_mut().find_node
+                        .send_response(channel, FindNodeResponse { peers, proof });
+                }
+                request_response::Message::Response { request_id, response } => {
+                    let now = unix_time().as_secs();
+                    if self.record_proof(peer, response.proof) {
+                        self.learn_peer(peer);
+                    }
+                    if self.proof_requests.remove(&request_id).is_some() {
+                        return;
+                    }
+                    if let Some(device_id) = self.pings.remove(&request_id) {
+                        self.table.on_ping_result(&device_id, true, now);
+                        return;
+                    }
+                    let Some((lookup_id, from)) = self.find_node_requests.remove(&request_id) else { return };
+                    self.table.mark_seen(&from, now);
+                    self.fetch_attestations(from);
+                    let peers = self.accept_peers(response.peers);
+                    if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                        lookup.state.on_response(&from, peers);
+                    }
+                    self.drive_lookup(lookup_id);
+                }
+            },
+            request_response::Event::OutboundFailure { request_id, error, .. } => {
+                let now = unix_time().as_secs();
+                if let Some(peer_id) = self.proof_requests.remove(&request_id) {
+                    debug!("Identity proof request to {} failed: {}", peer_id, error);
+                    return;
+                }
+                if let Some(device_id) = self.pings.remove(&request_id) {
+                    debug!("Ping to {} failed: {}", device_id, error);
+                    self.table.on_ping_result(&device_id, false, now);
+                    return;
+                }
+                let Some((lookup_id, from)) = self.find_node_requests.remove(&request_id) else { return };
+                debug!("FIND_NODE to {} failed: {}", from, error);
+                self.table.mark_failed(&from, now);
+                if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                    lookup.state.on_failure(&from);
+                }
+                self.drive_lookup(lookup_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Validate peers reported by a remote node and replace their claimed reputation
+    fn accept_peers(&mut self, reported: Vec<PeerInfo>) -> Vec<PeerInfo> {
+        let mut accepted = Vec::new();
+        for mut peer in reported {
+            let Ok(peer_id) = peer.peer_id.0.parse::<PeerId>() else { continue };
+            if device_id_from_peer_id(&peer_id) != Some(peer.device_id) {
+                continue;
+            }
+            if !self.config.admission.admits_identity(&peer) {
+                debug!("Ignoring reported peer {} without a valid identity proof", peer.peer_id);
+                continue;
+            }
+            self.record_proof(peer_id, peer.identity_proof.clone());
+            for addr in peer.addresses.iter().filter_map(|a| a.parse().ok()) {
+                self.add_address(peer_id, addr);
+            }
+            self.fetch_attestations(peer.device_id);
+            peer.reputation = self.reputation_of(&peer.device_id);
+            accepted.push(peer);
+        }
+        accepted
+    }
+
+    /// Peers in the routing table, with current addresses and reputation
+    fn known_peers(&self) -> Vec<PeerInfo> {
+        self.table.all_peers().iter()
+            .filter_map(|p| p.peer_id.0.parse().ok())
+            .filter_map(|p| self.peer_info(p))
+            .collect()
+    }
+
+    /// Offer a peer with a known address to the routing table
+    fn learn_peer(&mut self, peer_id: PeerId) {
+        let Some(peer) = self.peer_info(peer_id) else { return };
+        if peer.addresses.is_empty() {
+            return;
+        }
+        if peer.identity_proof.is_none() && self.config.admission.identity_difficulty.is_some() {
+            self.request_proof(peer_id);
+            return;
+        }
+        let local = self.local_peer.lock().unwrap().clone();
+        let mut pinger = FindNodePinger {
+            find_node: &mut self.swarm.behaviour_mut().find_node,
+            pings: &mut self.pings,
+            local: local.device_id,
+            proof: local.identity_proof,
+        };
+        let device_id = peer.device_id;
+        if self.table.insert(peer, unix_time().as_secs(), &mut pinger) == InsertOutcome::Refused {
+            debug!("Routing table refused peer {}", peer_id);
+            return;
+        }
+        self.fetch_attestations(device_id);
+    }
+
+    /// Ask a peer for its identity proof; it comes back with any FIND_NODE response
+    fn request_proof(&mut self, peer_id: PeerId) {
+        if self.proof_requests.values().any(|p| *p == peer_id) {
+            return;
+        }
+        let local = self.local_peer.lock().unwrap().clone();
+        let request = FindNodeRequest { target: local.device_id, mode: LookupMode::Raw, proof: local.identity_proof };
+        let request_id = self.swarm.behaviour_mut().find_node.send_request(&peer_id, request);
+        self.proof_requests.insert(request_id, peer_id);
+    }
+
+    /// Remember a peer's identity proof if it is new and valid
+    fn record_proof(&mut self, peer_id: PeerId, proof: Option<IdentityProof>) -> bool {
+        let (Some(device_id), Some(proof)) = (device_id_from_peer_id(&peer_id), proof) else { return false };
+        if self.proofs.contains_key(&device_id) {
+            return false;
+        }
+        let difficulty = self.config.admission.identity_difficulty.unwrap_or(0);
+        if !proof.verify(&device_id, difficulty) {
+            debug!("Ignoring invalid identity proof from {}", peer_id);
+            return false;
+        }
+        self.proofs.insert(device_id, proof);
+        true
+    }
+
+    fn local_proof(&self) -> Option<IdentityProof> {
+        self.local_peer.lock().unwrap().identity_proof.clone()
+    }
+
+    fn add_address(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let known = self.addresses.entry(peer_id).or_default();
+        if !known.contains(&addr) {
+            known.push(addr.clone());
+        }
+        self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+        self.learn_peer(peer_id);
     }
-}
 
-/// 启动 libp2p 节点 (示例)
-pub async fn start_libp2p_node() -> Result<(), Box<dyn Error>> {
-    println!("🚀 启动 libp2p 节点...");
-    
-    let mut node = Libp2pNode::new().await?;
-    
-    // 监听本地地址
-    let listen_addr: Multiaddr = "/ip4/0.0.0.0/tcp/0".parse()?;
-    node.listen_on(listen_addr).await?;
-    
-    // 启动事件循环
-    tokio::spawn(async move {
-        if let Err(e) = node.run_event_loop().await {
-            println!("事件循环错误: {}", e);
+    /// Forget an address; a peer left without any is dropped from the routing table
+    fn remove_address(&mut self, peer_id: PeerId, addr: &Multiaddr) {
+        self.swarm.behaviour_mut().kademlia.remove_address(&peer_id, addr);
+        let Some(known) = self.addresses.get_mut(&peer_id) else { return };
+        known.retain(|a| a != addr);
+        if !known.is_empty() {
+            return;
+        }
+        self.addresses.remove(&peer_id);
+        if let Some(device_id) = device_id_from_peer_id(&peer_id) {
+            self.table.remove(&device_id, unix_time().as_secs());
         }
-    });
-    
-    println!("✅ libp2p 节点启动成功");
-    Ok(())
+    }
+
+    /// Publish the best path among our open connections to a device
+    fn update_path(&self, device_id: DeviceID) {
+        let path = self.connections.values()
+            .filter(|(id, _)| *id == device_id)
+            .map(|(_, path)| *path)
+            .min_by_key(|path| *path == ConnectionPath::Relayed);
+        let mut paths = self.channels.paths.lock().unwrap();
+        match path {
+            Some(path) => paths.insert(device_id, path),
+            None => paths.remove(&device_id),
+        };
+    }
+
+    /// Report peers that entered or left the routing table since the last call
+    fn announce_table_changes(&mut self) {
+        let current: HashMap<DeviceID, PeerInfo> = self.table.all_peers().into_iter()
+            .map(|peer| (peer.device_id, peer))
+            .collect();
+        // Sending only fails when nobody is subscribed
+        for (device_id, peer) in &current {
+            if !self.announced.contains_key(device_id) {
+                let _ = self.channels.discovery.send(DiscoveryEvent::Discovered(peer.clone()));
+            }
+        }
+        for (device_id, peer) in &self.announced {
+            if !current.contains_key(device_id) {
+                let _ = self.channels.discovery.send(DiscoveryEvent::Expired(peer.clone()));
+            }
+        }
+        self.announced = current;
+    }
+
+    /// Describe a remote peer; peers without an Ed25519 identity are skipped
+    fn peer_info(&self, peer_id: PeerId) -> Option<PeerInfo> {
+        let device_id = device_id_from_peer_id(&peer_id)?;
+        Some(PeerInfo {
+            peer_id: PeerID::new(peer_id.to_string()),
+            device_id,
+            reputation: self.reputation_of(&device_id),
+            role: NodeRole::Idle,
+            addresses: self.addresses.get(&peer_id)
+                .map(|addrs| addrs.iter().map(|a| a.to_string()).collect())
+                .unwrap_or_default(),
+            available_bandwidth: 0,
+            identity_proof: self.proofs.get(&device_id).cloned(),
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
+    fn record(keypair: &NodeKeypair, value: &[u8], sequence: u64) -> SignedRecord {
+        SignedRecord::new(keypair, "test", value.to_vec(), sequence, u64::MAX)
+    }
+
+    fn test_config() -> Libp2pDhtConfig {
+        // Every test node listens on loopback, so the per-subnet limit would hold them back
+        // mDNS would let concurrently running tests find each other
+        Libp2pDhtConfig {
+            admission: AdmissionPolicy { max_per_subnet: None, ..Default::default() },
+            mdns: false,
+            ..Default::default()
+        }
+    }
+
+    async fn node() -> (NodeKeypair, Libp2pDht) {
+        node_with(test_config()).await
+    }
+
+    async fn node_with(config: Libp2pDhtConfig) -> (NodeKeypair, Libp2pDht) {
+        let keypair = NodeKeypair::generate();
+        let dht = Libp2pDht::new(&keypair, config).unwrap();
+        dht.listen("/ip4/127.0.0.1/udp/0/quic-v1").await.unwrap();
+        (keypair, dht)
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get_across_nodes() {
+        let (_, first) = node().await;
+        let (second_keys, mut second) = node().await;
+        second.add_peer(first.local_peer()).await.unwrap();
+
+        let hello = record(&second_keys, b"hello", 1);
+        second.put_value(hello.clone()).await.unwrap();
+
+        assert_eq!(first.get_value(hello.key()).await.unwrap(), Some(hello.clone()));
+        assert_eq!(first.get_value([1u8; 32]).await.unwrap(), None);
+
+        // Newer sequences replace the record; older ones are refused
+        let update = record(&second_keys, b"updated", 2);
+        second.put_value(update.clone()).await.unwrap();
+        assert!(second.put_value(hello.clone()).await.is_err());
+        assert_eq!(first.get_value(hello.key()).await.unwrap(), Some(update));
+    }
+
+    #[tokio::test]
+    async fn test_find_peer_through_bootstrap() {
+        let (_, bootstrap) = node().await;
+        let (target_keys, mut target) = node().await;
+        let (_, mut searcher) = node().await;
+
+        target.add_peer(bootstrap.local_peer()).await.unwrap();
+        target.find_peer(target_keys.node_id()).await.unwrap();
+
+        searcher.add_peer(bootstrap.local_peer()).await.unwrap();
+        // The bootstrap node learns the target's listen address via identify
+        let mut found = false;
+        for _ in 0..50 {
+            let peers = searcher.find_peer(target_keys.node_id()).await.unwrap();
+            found = peers.iter().any(|p| p.device_id == target_keys.node_id());
+            if found {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(found);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_uses_attested_reputation() {
+        let (bootstrap_keys, mut bootstrap) = node().await;
+        let (trusted_keys, mut trusted) = node().await;
+        let (other_keys, mut other) = node().await;
+
+        trusted.add_peer(bootstrap.local_peer()).await.unwrap();
+        trusted.find_peer(trusted_keys.node_id()).await.unwrap();
+        other.add_peer(bootstrap.local_peer()).await.unwrap();
+        other.find_peer(other_keys.node_id()).await.unwrap();
+
+        // The bootstrap node vouches for `trusted`; `other` only vouches for itself
+        let session = SessionOutcome { success: true, bytes_relayed: 1 << 20, latency_ms: 20 };
+        bootstrap.attest(trusted_keys.node_id(), [1u8; 32], session).await.unwrap();
+        assert!(other.attest(other_keys.node_id(), [2u8; 32], session).await.is_err());
+
+        let mut config = test_config();
+        config.trust.pre_trusted.insert(bootstrap_keys.node_id());
+        let (_, mut searcher) = node_with(config).await;
+        searcher.add_peer(bootstrap.local_peer()).await.unwrap();
+
+        let target = DeviceID::new([0u8; 32]);
+        let mut weighted = Vec::new();
+        for _ in 0..50 {
+            weighted = searcher.find_peer_with_mode(target, LookupMode::Weighted).await.unwrap();
+            let trusted_seen = weighted.iter()
+                .any(|p| p.device_id == trusted_keys.node_id() && p.reputation > ReputationScore::DEFAULT);
+            if trusted_seen && weighted.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let reputation_of = |peers: &[PeerInfo], id: DeviceID| peers.iter().find(|p| p.device_id == id).map(|p| p.reputation);
+        assert!(reputation_of(&weighted, trusted_keys.node_id()).unwrap() > ReputationScore::DEFAULT);
+        assert_eq!(reputation_of(&weighted, other_keys.node_id()), Some(ReputationScore::DEFAULT));
+        assert_eq!(searcher.reputation(&trusted_keys.node_id()), reputation_of(&weighted, trusted_keys.node_id()).unwrap());
+
+        let ids = |peers: &[PeerInfo]| peers.iter().map(|p| p.device_id).collect::<Vec<_>>();
+        let mut expected = weighted.clone();
+        LookupMode::Weighted.sort(&mut expected, &target);
+        assert_eq!(ids(&weighted), ids(&expected));
+
+        let raw = searcher.find_peer_with_mode(target, LookupMode::Raw).await.unwrap();
+        let mut expected = raw.clone();
+        LookupMode::Raw.sort(&mut expected, &target);
+        assert_eq!(ids(&raw), ids(&expected));
+    }
+
+    #[tokio::test]
+    async fn test_add_bootstrap_from_multiaddr() {
+        let (first_keys, first) = node().await;
+        let (_, mut second) = node().await;
+
+        let local = first.local_peer();
+        let addr = format!("{}/p2p/{}", local.addresses[0], local.peer_id);
+        second.add_bootstrap(&addr).await.unwrap();
+        assert!(second.add_bootstrap(&local.addresses[0]).await.is_err());
+
+        let peers = second.find_peer(first_keys.node_id()).await.unwrap();
+        assert_eq!(peers[0].device_id, first_keys.node_id());
+    }
+
+    #[tokio::test]
+    async fn test_gossip_reaches_connected_peers() {
+        use crate::network::gossip::{RelayAdvert, VersionNotice};
+        use crate::network::relay::{RelayConfig, RelayManager};
+
+        let (_, listener) = node().await;
+        let (relay_keys, mut relay) = node().await;
+        relay.add_peer(listener.local_peer()).await.unwrap();
+        relay.find_peer(relay_keys.node_id()).await.unwrap();
+        let mut heard = listener.announcements();
+
+        let manager = RelayManager::new(RelayConfig { tokens_per_mb: TokenAmount::new(2), ..Default::default() });
+        let now = unix_time().as_secs();
+        let advert = RelayAdvert::new(relay_keys.node_id(), relay.local_peer().addresses, &manager, now);
+        // Publishing fails until the listener's subscriptions have arrived
+        let mut published = false;
+        for _ in 0..50 {
+            published = relay.announce(Announcement::Relay(advert.clone())).await.is_ok();
+            if published {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(published);
+        let received = tokio::time::timeout(Duration::from_secs(5), heard.recv()).await.unwrap().unwrap();
+        assert_eq!(received, Announcement::Relay(advert.clone()));
+        assert_eq!(listener.relay_offers(), vec![advert]);
+
+        // A notice claiming to come from someone else is dropped
+        let forged = VersionNotice::current(NodeKeypair::generate().node_id(), now);
+        relay.announce(Announcement::Version(forged)).await.unwrap();
+        let notice = VersionNotice::current(relay_keys.node_id(), now + 1);
+        relay.announce(Announcement::Version(notice.clone())).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), heard.recv()).await.unwrap().unwrap();
+        assert_eq!(received, Announcement::Version(notice));
+
+        // Attestations are gossiped as well as stored
+        let subject = NodeKeypair::generate().node_id();
+        let session = SessionOutcome { success: true, bytes_relayed: 1 << 20, latency_ms: 20 };
+        let attestation = relay.attest(subject, [3u8; 32], session).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), heard.recv()).await.unwrap().unwrap();
+        assert_eq!(received, Announcement::Attestation(attestation));
+    }
+
+    #[tokio::test]
+    async fn test_nated_peer_reachable_through_relay() {
+        let (_, relay) = node_with(Libp2pDhtConfig { relay_server: true, ..test_config() }).await;
+        let relay_peer = relay.local_peer();
+        let relay_addr = format!("{}/p2p/{}", relay_peer.addresses[0], relay_peer.peer_id);
+
+        // Behind its NAT the node can dial out but nobody can dial in, so it
+        // has no address of its own until the relay gives it one
+        let nated_keys = NodeKeypair::generate();
+        let nated = Libp2pDht::new(&nated_keys, test_config()).unwrap();
+        assert!(nated.reserve_relay(&relay_peer.addresses[0]).await.is_err());
+        let circuit = nated.reserve_relay(&relay_addr).await.unwrap();
+        assert!(circuit.starts_with(&relay_addr) && circuit.ends_with("/p2p-circuit"));
+        assert_eq!(nated.local_peer().addresses, vec![circuit.clone()]);
+        assert_eq!(nated.relayed_addrs(), vec![circuit.clone()]);
+
+        let dialer = dial_through_circuit(&nated, &nated_keys, &circuit).await;
+        assert!(dialer.stats().relayed_connections >= 1);
+        assert!(nated.stats().relayed_connections >= 1);
+
+        // With nothing but the circuit to listen on, the node has no socket a
+        // direct connection could arrive at, so hole punching has to fail
+        let stats = hole_punch_outcome(&nated).await;
+        assert_eq!((stats.hole_punch_successes, stats.hole_punch_failures), (0, 1));
+        assert_eq!(wait_for_path(&dialer, &nated_keys, ConnectionPath::Relayed).await, Some(ConnectionPath::Relayed));
+        assert_eq!(dialer.connection_path(&NodeKeypair::generate().node_id()), None);
+    }
+
+    #[tokio::test]
+    async fn test_relayed_connection_upgrades_to_direct() {
+        let (_, relay) = node_with(Libp2pDhtConfig { relay_server: true, ..test_config() }).await;
+        let relay_peer = relay.local_peer();
+        let relay_addr = format!("{}/p2p/{}", relay_peer.addresses[0], relay_peer.peer_id);
+
+        // The node also listens on loopback, which the dialer can reach, so
+        // the relayed connection it is first dialed on must become direct
+        let (keys, peer) = node().await;
+        let circuit = peer.reserve_relay(&relay_addr).await.unwrap();
+        let dialer = dial_through_circuit(&peer, &keys, &circuit).await;
+        assert!(dialer.stats().relayed_connections >= 1);
+        assert_eq!(wait_for_path(&dialer, &keys, ConnectionPath::Direct).await, Some(ConnectionPath::Direct));
+    }
+
+    /// Dial `peer` at `circuit` from a new node and wait for it to join the routing table
+    async fn dial_through_circuit(peer: &Libp2pDht, keys: &NodeKeypair, circuit: &str) -> Libp2pDht {
+        let (_, mut dialer) = node().await;
+        let addr = format!("{}/p2p/{}", circuit, peer.local_peer().peer_id);
+        dialer.add_peer(peer_from_multiaddr(&addr).unwrap()).await.unwrap();
+        for _ in 0..100 {
+            if dialer.peers().await.unwrap().iter().any(|p| p.device_id == keys.node_id()) {
+                return dialer;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{} was not reached through {}", keys.node_id(), circuit);
+    }
+
+    /// Stats of `node` once a hole punching attempt has finished
+    async fn hole_punch_outcome(node: &Libp2pDht) -> NetworkStats {
+        for _ in 0..200 {
+            let stats = node.stats();
+            if stats.hole_punch_successes + stats.hole_punch_failures > 0 {
+                // Give a second outcome the chance to show up
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                return node.stats();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("no hole punching outcome recorded");
+    }
+
+    /// The dialer's path to `keys`, once it is `expected` or after a few seconds
+    async fn wait_for_path(dialer: &Libp2pDht, keys: &NodeKeypair, expected: ConnectionPath) -> Option<ConnectionPath> {
+        let mut path = None;
+        for _ in 0..100 {
+            path = dialer.connection_path(&keys.node_id());
+            if path == Some(expected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn test_add_peer_rejects_mismatched_identity() {
+        let (_, first) = node().await;
+        let (_, mut second) = node().await;
+
+        let mut peer = first.local_peer();
+        peer.device_id = NodeKeypair::generate().node_id();
+        assert!(second.add_peer(peer).await.is_err());
+    }
+
     #[tokio::test]
-    async fn test_libp2p_node_creation() {
-        let node = Libp2pNode::new().await;
-        assert!(node.is_ok());
+    async fn test_configured_proof_meets_admission_difficulty() {
+        let keypair = NodeKeypair::generate();
+        let proof = IdentityProof::solve(&keypair, 8);
+        let config = |identity_difficulty| Libp2pDhtConfig {
+            admission: AdmissionPolicy { identity_difficulty, max_per_subnet: None },
+            identity_proof: Some(proof.clone()),
+            ..test_config()
+        };
+        assert!(Libp2pDht::new(&keypair, config(Some(8))).is_ok());
+        // The proof may happen to beat its difficulty, so look for one it misses
+        let unmet = (9..=256).find(|d| !proof.verify(&keypair.node_id(), *d)).unwrap();
+        assert!(Libp2pDht::new(&keypair, config(Some(unmet))).is_err());
+        assert!(Libp2pDht::new(&NodeKeypair::generate(), config(Some(8))).is_err());
     }
 }
@@ -0,0 +1,253 @@
+//! Iterative Kademlia lookup
+//!
+//! Transport-independent state machine for a FIND_NODE lookup: the caller
+//! asks for the next peers to query, feeds back their answers, and stops once
+//! the closest `k` peers seen have all responded. Peers are ordered either by
+//! raw XOR distance or by reputation-weighted distance, or by any
+//! [`DistancePolicy`].
+
+use crate::core::distance::{DistancePolicy, HyperbolicPolicy, RawXorPolicy};
+use crate::core::types::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// How peers are ordered during a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LookupMode {
+    /// Plain XOR distance
+    Raw,
+    /// XOR distance weighted by reputation
+    Weighted,
+}
+
+impl LookupMode {
+    /// Compare two peers by distance to `target`
+    pub fn compare(self, a: &PeerInfo, b: &PeerInfo, target: &DeviceID) -> Ordering {
+        match self {
+            LookupMode::Raw => RawXorPolicy.compare(a, b, target),
+            LookupMode::Weighted => HyperbolicPolicy::default().compare(a, b, target),
+        }
+    }
+
+    /// Distance policy implementing this mode
+    pub fn policy(self) -> Arc<dyn DistancePolicy> {
+        match self {
+            LookupMode::Raw => Arc::new(RawXorPolicy),
+            LookupMode::Weighted => Arc::new(HyperbolicPolicy::default()),
+        }
+    }
+
+    /// Sort peers by distance to `target`
+    pub fn sort(self, peers: &mut [PeerInfo], target: &DeviceID) {
+        peers.sort_by(|a, b| self.compare(a, b, target));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    NotContacted,
+    Waiting,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    peer: PeerInfo,
+    state: CandidateState,
+}
+
+/// State of one iterative lookup
+#[derive(Debug, Clone)]
+pub struct LookupState {
+    target: DeviceID,
+    local: DeviceID,
+    mode: LookupMode,
+    /// Ordering of the candidates; `mode.policy()` unless overridden
+    policy: Arc<dyn DistancePolicy>,
+    alpha: usize,
+    k: usize,
+    /// Candidates sorted by distance to the target
+    candidates: Vec<Candidate>,
+}
+
+impl LookupState {
+    /// Start a lookup from the given seed peers
+    pub fn new(target: DeviceID, local: DeviceID, seeds: Vec<PeerInfo>, mode: LookupMode, alpha: usize, k: usize) -> Self {
+        let mut lookup = Self {
+            target,
+            local,
+            mode,
+            policy: mode.policy(),
+            alpha,
+            k,
+            candidates: Vec::new(),
+        };
+        lookup.insert(seeds);
+        lookup
+    }
+
+    /// Order candidates by `policy` instead of the mode's own ordering
+    ///
+    /// The mode is still what remote peers are asked to sort by.
+    pub fn with_policy(mut self, policy: Arc<dyn DistancePolicy>) -> Self {
+        let target = self.target;
+        self.candidates.sort_by(|a, b| policy.compare(&a.peer, &b.peer, &target));
+        self.policy = policy;
+        self
+    }
+
+    /// Lookup target
+    pub fn target(&self) -> DeviceID {
+        self.target
+    }
+
+    /// Distance ordering requested from remote peers
+    pub fn mode(&self) -> LookupMode {
+        self.mode
+    }
+
+    /// Peers to query next, keeping at most `alpha` requests in flight
+    pub fn next_queries(&mut self) -> Vec<PeerInfo> {
+        let mut in_flight = self.in_flight();
+        let mut queries = Vec::new();
+        for candidate in self.candidates.iter_mut().filter(|c| c.state != CandidateState::Failed).take(self.k) {
+            if in_flight >= self.alpha {
+                break;
+            }
+            if candidate.state == CandidateState::NotContacted {
+                candidate.state = CandidateState::Waiting;
+                in_flight += 1;
+                queries.push(candidate.peer.clone());
+            }
+        }
+        queries
+    }
+
+    /// Record a peer's answer
+    pub fn on_response(&mut self, from: &DeviceID, peers: Vec<PeerInfo>) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.peer.device_id == *from) {
+            candidate.state = CandidateState::Responded;
+        }
+        self.insert(peers);
+    }
+
+    /// Record that a peer could not be reached
+    pub fn on_failure(&mut self, from: &DeviceID) {
+        if let Some(candidate) = self.candidates.iter_mut().find(|c| c.peer.device_id == *from) {
+            candidate.state = CandidateState::Failed;
+        }
+    }
+
+    /// Whether the closest `k` live peers have all responded
+    pub fn is_finished(&self) -> bool {
+        self.in_flight() == 0
+            && self.live().take(self.k).all(|c| c.state == CandidateState::Responded)
+    }
+
+    /// Closest `k` peers that responded
+    pub fn results(&self) -> Vec<PeerInfo> {
+        self.candidates.iter()
+            .filter(|c| c.state == CandidateState::Responded)
+            .take(self.k)
+            .map(|c| c.peer.clone())
+            .collect()
+    }
+
+    /// Number of peers queried so far
+    pub fn contacted(&self) -> usize {
+        self.candidates.iter().filter(|c| c.state != CandidateState::NotContacted).count()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.candidates.iter().filter(|c| c.state == CandidateState::Waiting).count()
+    }
+
+    fn live(&self) -> impl Iterator<Item = &Candidate> {
+        self.candidates.iter().filter(|c| c.state != CandidateState::Failed)
+    }
+
+    fn insert(&mut self, peers: Vec<PeerInfo>) {
+        for peer in peers {
+            if peer.device_id == self.local || self.candidates.iter().any(|c| c.peer.device_id == peer.device_id) {
+                continue;
+            }
+            let (policy, target) = (&self.policy, self.target);
+            let pos = self.candidates
+                .partition_point(|c| policy.compare(&c.peer, &peer, &target) != Ordering::Greater);
+            self.candidates.insert(pos, Candidate { peer, state: CandidateState::NotContacted });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn peer(id: u8, reputation: u64) -> PeerInfo {
+        let mut device = [0u8; 32];
+        device[0] = id;
+        PeerInfo {
+            peer_id: PeerID::new(format!("peer{}", id)),
+            device_id: DeviceID::new(device),
+            reputation: ReputationScore::new(reputation),
+            role: NodeRole::Idle,
+            addresses: vec![],
+            available_bandwidth: 0,
+            identity_proof: None,
+        }
+    }
+
+    /// Run a lookup against a static network where every peer knows every other
+    fn run(mode: LookupMode, peers: &[PeerInfo], target: DeviceID) -> LookupState {
+        let network: HashMap<DeviceID, Vec<PeerInfo>> = peers.iter()
+            .map(|p| (p.device_id, peers.to_vec()))
+            .collect();
+        let local = DeviceID::new([0xff; 32]);
+        let mut lookup = LookupState::new(target, local, vec![peers[0].clone()], mode, 3, 2);
+        while !lookup.is_finished() {
+            for queried in lookup.next_queries() {
+                lookup.on_response(&queried.device_id, network[&queried.device_id].clone());
+            }
+        }
+        lookup
+    }
+
+    #[test]
+    fn test_raw_vs_weighted_lookup() {
+        let target = DeviceID::new([0u8; 32]);
+        let peers = vec![
+            peer(0x80, 100),
+            peer(0x02, 0),
+            peer(0x03, 0),
+            peer(0x04, 1000),
+            peer(0x05, 1000),
+        ];
+
+        let raw: Vec<_> = run(LookupMode::Raw, &peers, target).results().iter().map(|p| p.device_id.0[0]).collect();
+        assert_eq!(raw, vec![0x02, 0x03]);
+
+        let weighted: Vec<_> = run(LookupMode::Weighted, &peers, target).results().iter().map(|p| p.device_id.0[0]).collect();
+        assert_eq!(weighted, vec![0x04, 0x05]);
+    }
+
+    #[test]
+    fn test_failed_peers_are_skipped() {
+        let target = DeviceID::new([0u8; 32]);
+        let local = DeviceID::new([0xff; 32]);
+        let mut lookup = LookupState::new(target, local, vec![peer(0x01, 0), peer(0x40, 0)], LookupMode::Raw, 1, 1);
+
+        let first = lookup.next_queries();
+        assert_eq!(first.len(), 1);
+        lookup.on_failure(&first[0].device_id);
+        assert!(!lookup.is_finished());
+
+        let second = lookup.next_queries();
+        assert_eq!(second[0].device_id.0[0], 0x40);
+        lookup.on_response(&second[0].device_id, vec![]);
+        assert!(lookup.is_finished());
+        assert_eq!(lookup.contacted(), 2);
+    }
+}
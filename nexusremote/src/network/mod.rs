@@ -1,11 +1,29 @@
 //! P2P network module for NexusRemote
 
 pub mod dht;
+pub mod lookup;
+pub mod record;
+pub mod attestation;
+pub mod presence;
 pub mod transport;
+pub mod secure;
 pub mod relay;
+pub mod forwarding;
+pub mod scheduler;
 pub mod discovery;
+pub mod gossip;
+pub mod libp2p_integration;
 
 pub use dht::*;
+pub use lookup::*;
+pub use record::*;
+pub use attestation::*;
+pub use presence::*;
 pub use transport::*;
+pub use secure::*;
 pub use relay::*;
+pub use forwarding::*;
+pub use scheduler::*;
 pub use discovery::*;
+pub use gossip::*;
+pub use libp2p_integration::*;
@@ -0,0 +1,227 @@
+//! Device presence announcements
+//!
+//! Every node periodically publishes a `SignedRecord` in the "presence"
+//! namespace mapping its `DeviceID` to its current `PeerInfo`. Because the
+//! record key is derived from the publisher's identity, resolving a device
+//! only ever returns information that device signed itself. Announcements
+//! older than `max_age` are treated as stale and ignored.
+
+use crate::core::crypto::NodeKeypair;
+use crate::core::types::*;
+use crate::network::dht::DhtNode;
+use crate::network::record::SignedRecord;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Record namespace for presence announcements
+pub const PRESENCE_NAMESPACE: &str = "presence";
+
+/// Presence configuration
+#[derive(Debug, Clone)]
+pub struct PresenceConfig {
+    /// How often the local node re-announces itself (seconds)
+    pub refresh_interval: u64,
+    /// Age after which an announcement is considered stale (seconds)
+    pub max_age: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: 5 * 60, // 5 minutes
+            max_age: 15 * 60,         // 15 minutes
+        }
+    }
+}
+
+/// Payload of a presence record
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceRecord {
+    /// How to reach the device
+    pub peer: PeerInfo,
+    /// When the announcement was made (unix seconds)
+    pub published_at: u64,
+}
+
+/// DHT key of a device's presence record
+pub fn presence_key(device_id: &DeviceID) -> [u8; 32] {
+    SignedRecord::key_for(PRESENCE_NAMESPACE, device_id)
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Publishes our presence and resolves other devices
+pub struct PresenceService<D: DhtNode> {
+    dht: D,
+    keypair: NodeKeypair,
+    config: PresenceConfig,
+    /// Sequence of the last announcement
+    sequence: u64,
+    last_published: Option<u64>,
+}
+
+impl<D: DhtNode> PresenceService<D> {
+    /// Create a presence service on top of a DHT node
+    pub fn new(dht: D, keypair: NodeKeypair, config: PresenceConfig) -> Self {
+        Self {
+            dht,
+            keypair,
+            config,
+            sequence: 0,
+            last_published: None,
+        }
+    }
+
+    /// Underlying DHT node
+    pub fn dht(&self) -> &D {
+        &self.dht
+    }
+
+    /// Whether the next announcement is due
+    pub fn refresh_due(&self, now: u64) -> bool {
+        self.last_published
+            .is_none_or(|last| now >= last + self.config.refresh_interval)
+    }
+
+    /// Announce our current `PeerInfo`
+    pub async fn publish(&mut self, now: u64) -> Result<SignedRecord, Error> {
+        let peer = self.dht.local_peer();
+        if peer.device_id != self.keypair.node_id() {
+            return Err(Error::Crypto("DHT node identity does not match presence keypair".to_string()));
+        }
+        let payload = PresenceRecord { peer, published_at: now };
+        // Sequences follow the clock but never repeat within a second
+        self.sequence = now.max(self.sequence + 1);
+        let record = SignedRecord::new(
+            &self.keypair,
+            PRESENCE_NAMESPACE,
+            serde_json::to_vec(&payload)?,
+            self.sequence,
+            now + self.config.max_age,
+        );
+        self.dht.put_value(record.clone()).await?;
+        self.last_published = Some(now);
+        debug!("Announced presence with sequence {}", self.sequence);
+        Ok(record)
+    }
+
+    /// Announce our presence if the refresh interval has passed
+    pub async fn refresh(&mut self, now: u64) -> Result<bool, Error> {
+        if !self.refresh_due(now) {
+            return Ok(false);
+        }
+        self.publish(now).await?;
+        Ok(true)
+    }
+
+    /// Keep our presence announcement fresh until the DHT stops
+    pub async fn run(mut self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.refresh_interval.max(1)));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.refresh(now_secs()).await {
+                warn!("Failed to announce presence: {}", e);
+            }
+        }
+    }
+
+    /// Resolve a device to its latest fresh, verified `PeerInfo`
+    pub async fn resolve(&self, device_id: DeviceID, now: u64) -> Result<Option<PeerInfo>, Error> {
+        let Some(record) = self.dht.get_value(presence_key(&device_id)).await? else {
+            return Ok(None);
+        };
+        // The DHT already checked the key and signature; the payload must describe the publisher
+        let payload: PresenceRecord = serde_json::from_slice(&record.value)?;
+        if payload.peer.device_id != device_id || record.publisher_id() != device_id {
+            warn!("Presence record for {} describes another device", device_id);
+            return Ok(None);
+        }
+        if now.saturating_sub(payload.published_at) > self.config.max_age {
+            debug!("Presence record for {} is stale (published at {})", device_id, payload.published_at);
+            return Ok(None);
+        }
+        Ok(Some(payload.peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::dht::InMemoryDht;
+
+    fn dht_for(keypair: &NodeKeypair, name: &str, address: &str) -> InMemoryDht {
+        InMemoryDht::new(PeerInfo {
+            peer_id: PeerID::new(name.to_string()),
+            device_id: keypair.node_id(),
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Relay,
+            addresses: vec![address.to_string()],
+            available_bandwidth: 50_000_000,
+            identity_proof: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_resolve() {
+        let alice_keys = NodeKeypair::generate();
+        let bob_keys = NodeKeypair::generate();
+        let mut alice_dht = dht_for(&alice_keys, "alice", "/ip4/10.0.0.1/udp/4001/quic-v1");
+        let mut bob_dht = dht_for(&bob_keys, "bob", "/ip4/10.0.0.2/udp/4001/quic-v1");
+        alice_dht.connect_to(&mut bob_dht);
+
+        let mut alice = PresenceService::new(alice_dht, alice_keys.clone(), PresenceConfig::default());
+        let bob = PresenceService::new(bob_dht, bob_keys, PresenceConfig::default());
+
+        let now = now_secs();
+        assert!(bob.resolve(alice_keys.node_id(), now).await.unwrap().is_none());
+
+        alice.publish(now).await.unwrap();
+        let peer = bob.resolve(alice_keys.node_id(), now).await.unwrap().unwrap();
+        assert_eq!(peer.peer_id, PeerID::new("alice".to_string()));
+        assert_eq!(peer.addresses, vec!["/ip4/10.0.0.1/udp/4001/quic-v1".to_string()]);
+        assert_eq!(peer.role, NodeRole::Relay);
+        assert_eq!(peer.available_bandwidth, 50_000_000);
+
+        // Without a refresh the announcement goes stale
+        let later = now + PresenceConfig::default().max_age + 1;
+        assert!(bob.resolve(alice_keys.node_id(), later).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_publishes_new_addresses() {
+        let keys = NodeKeypair::generate();
+        let config = PresenceConfig { refresh_interval: 60, max_age: 180 };
+        let mut bob_dht = dht_for(&NodeKeypair::generate(), "bob", "/ip4/10.0.0.2/udp/4001/quic-v1");
+        let mut old_dht = dht_for(&keys, "alice", "/ip4/10.0.0.1/udp/4001/quic-v1");
+        old_dht.connect_to(&mut bob_dht);
+        let mut old = PresenceService::new(old_dht, keys.clone(), config.clone());
+
+        let now = now_secs();
+        assert!(old.refresh(now).await.unwrap());
+        assert!(!old.refresh(now + 30).await.unwrap());
+        assert!(old.refresh_due(now + 60));
+
+        // The device moved to a new address and re-announces itself from there
+        let mut new_dht = dht_for(&keys, "alice", "/ip4/192.168.1.7/udp/4001/quic-v1");
+        new_dht.connect_to(&mut bob_dht);
+        let mut moved = PresenceService::new(new_dht, keys.clone(), config);
+        moved.refresh(now + 60).await.unwrap();
+
+        let peer = old.resolve(keys.node_id(), now + 60).await.unwrap().unwrap();
+        assert_eq!(peer.addresses, vec!["/ip4/192.168.1.7/udp/4001/quic-v1".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_mismatched_keypair() {
+        let dht = dht_for(&NodeKeypair::generate(), "alice", "/ip4/10.0.0.1/udp/4001/quic-v1");
+        let mut presence = PresenceService::new(dht, NodeKeypair::generate(), PresenceConfig::default());
+        assert!(presence.publish(now_secs()).await.is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Signed, self-certifying DHT records
+//!
+//! A record's key is derived from its namespace and the publisher's
+//! `DeviceID`, and the record carries the publisher's public key and
+//! signature. Anyone can check that the record was published by the node
+//! that owns the key, so nodes cannot overwrite each other's data.
+
+use crate::core::crypto::{hash, node_id_from_public_key, verify_signature, NodeKeypair};
+use crate::core::types::*;
+use serde::{Deserialize, Serialize};
+
+/// Domain separator for record signatures
+const RECORD_DOMAIN: &[u8] = b"nexusremote-record-v1";
+
+/// Reasons a DHT record is rejected
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RecordError {
+    /// The record could not be decoded
+    #[error("malformed record: {0}")]
+    Malformed(String),
+
+    /// The record was stored under a key its publisher does not own
+    #[error("record key does not match namespace and publisher")]
+    KeyMismatch,
+
+    /// The signature does not verify
+    #[error("invalid record signature")]
+    InvalidSignature,
+
+    /// The record is past its expiry
+    #[error("record expired at {0}")]
+    Expired(u64),
+
+    /// A record with an equal or higher sequence is already known
+    #[error("stale record: sequence {offered} does not supersede {current}")]
+    Stale {
+        /// Sequence already held
+        current: u64,
+        /// Sequence offered
+        offered: u64,
+    },
+}
+
+/// DHT record envelope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRecord {
+    /// Namespace (e.g. "presence")
+    pub namespace: String,
+    /// Publisher public key
+    pub publisher: [u8; 32],
+    /// Sequence number; higher sequences supersede lower ones
+    pub sequence: u64,
+    /// Expiry (unix seconds)
+    pub expires_at: u64,
+    /// Payload
+    pub value: Vec<u8>,
+    /// Publisher signature over `signing_bytes`
+    pub signature: Vec<u8>,
+}
+
+impl SignedRecord {
+    /// Create and sign a record
+    pub fn new(keypair: &NodeKeypair, namespace: &str, value: Vec<u8>, sequence: u64, expires_at: u64) -> Self {
+        let mut record = Self {
+            namespace: namespace.to_string(),
+            publisher: keypair.public_key().to_bytes(),
+            sequence,
+            expires_at,
+            value,
+            signature: vec![],
+        };
+        record.signature = keypair.sign(&record.signing_bytes());
+        record
+    }
+
+    /// DHT key for a namespace and publisher
+    pub fn key_for(namespace: &str, publisher: &DeviceID) -> [u8; 32] {
+        let mut bytes = Vec::with_capacity(namespace.len() + 1 + 32);
+        bytes.extend_from_slice(namespace.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(publisher.as_bytes());
+        hash::dht_key(&bytes)
+    }
+
+    /// Device that published the record
+    pub fn publisher_id(&self) -> DeviceID {
+        node_id_from_public_key(&self.publisher)
+    }
+
+    /// DHT key this record is stored under
+    pub fn key(&self) -> [u8; 32] {
+        Self::key_for(&self.namespace, &self.publisher_id())
+    }
+
+    /// Canonical bytes covered by the signature
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RECORD_DOMAIN.len() + 4 + self.namespace.len() + 32 + 8 + 8 + 4 + self.value.len());
+        bytes.extend_from_slice(RECORD_DOMAIN);
+        bytes.extend_from_slice(&(self.namespace.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(self.namespace.as_bytes());
+        bytes.extend_from_slice(&self.publisher);
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(&(self.value.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.value);
+        bytes
+    }
+
+    /// Check the record belongs under `key`, is signed by its publisher and has not expired
+    pub fn verify(&self, key: &[u8; 32], now: u64) -> Result<(), RecordError> {
+        if self.key() != *key {
+            return Err(RecordError::KeyMismatch);
+        }
+        if !verify_signature(&self.publisher, &self.signing_bytes(), &self.signature) {
+            return Err(RecordError::InvalidSignature);
+        }
+        if self.expires_at <= now {
+            return Err(RecordError::Expired(self.expires_at));
+        }
+        Ok(())
+    }
+
+    /// Check that this record may replace `current`
+    pub fn supersedes(&self, current: &SignedRecord) -> Result<(), RecordError> {
+        if self.sequence <= current.sequence {
+            return Err(RecordError::Stale { current: current.sequence, offered: self.sequence });
+        }
+        Ok(())
+    }
+
+    /// Encode for the wire
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("record serialization cannot fail")
+    }
+
+    /// Decode from the wire
+    pub fn decode(bytes: &[u8]) -> Result<Self, RecordError> {
+        bincode::deserialize(bytes).map_err(|e| RecordError::Malformed(e.to_string()))
+    }
+}
+
+/// Pick the highest-sequence record that verifies under `key`
+pub fn select_record(key: &[u8; 32], candidates: impl IntoIterator<Item = SignedRecord>, now: u64) -> Option<SignedRecord> {
+    candidates.into_iter()
+        .filter(|r| r.verify(key, now).is_ok())
+        .max_by_key(|r| r.sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keypair = NodeKeypair::generate();
+        let record = SignedRecord::new(&keypair, "presence", b"hello".to_vec(), 1, 1_000);
+        let key = SignedRecord::key_for("presence", &keypair.node_id());
+
+        assert_eq!(record.key(), key);
+        assert_eq!(record.verify(&key, 500), Ok(()));
+        assert_eq!(record.verify(&key, 1_000), Err(RecordError::Expired(1_000)));
+        assert_eq!(SignedRecord::decode(&record.encode()), Ok(record.clone()));
+
+        let mut tampered = record.clone();
+        tampered.value = b"evil".to_vec();
+        assert_eq!(tampered.verify(&key, 500), Err(RecordError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_cannot_publish_under_another_key() {
+        let victim = NodeKeypair::generate();
+        let attacker = NodeKeypair::generate();
+        let victim_key = SignedRecord::key_for("presence", &victim.node_id());
+
+        // Validly signed, but by the wrong publisher for this key
+        let forged = SignedRecord::new(&attacker, "presence", b"spoofed".to_vec(), 99, 1_000);
+        assert_eq!(forged.verify(&victim_key, 0), Err(RecordError::KeyMismatch));
+
+        // Claiming the victim's key without their signature
+        let mut forged = forged;
+        forged.publisher = victim.public_key().to_bytes();
+        assert_eq!(forged.verify(&victim_key, 0), Err(RecordError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_highest_sequence_wins() {
+        let keypair = NodeKeypair::generate();
+        let old = SignedRecord::new(&keypair, "presence", b"old".to_vec(), 1, 1_000);
+        let new = SignedRecord::new(&keypair, "presence", b"new".to_vec(), 2, 1_000);
+        let mut bad = SignedRecord::new(&keypair, "presence", b"bad".to_vec(), 3, 1_000);
+        bad.signature[0] ^= 1;
+
+        assert_eq!(select_record(&old.key(), vec![old.clone(), bad, new.clone()], 0), Some(new.clone()));
+        assert!(new.supersedes(&old).is_ok());
+        assert_eq!(old.supersedes(&new), Err(RecordError::Stale { current: 2, offered: 1 }));
+    }
+}
@@ -1,12 +1,18 @@
 //! Relay node functionality
 
 use crate::core::types::*;
+use crate::core::receipt::receipt_amount;
+use crate::core::reputation::ReputationEvent;
 use crate::core::state::NetworkStats;
+use crate::network::scheduler::{session_priority, AdmissionConfig, AdmissionController, LoadMeter};
 use crate::Error;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use tracing::debug;
 
 /// Relay configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RelayConfig {
     /// Maximum concurrent relay sessions
     pub max_sessions: usize,
@@ -15,7 +21,31 @@ pub struct RelayConfig {
     /// Minimum reputation for relaying
     pub min_reputation: ReputationScore,
     /// Token rate per MB
+    #[serde(with = "tokens_as_u64")]
     pub tokens_per_mb: TokenAmount,
+    /// Bandwidth shared by all sessions (bps)
+    pub total_bandwidth: u64,
+    /// Least bandwidth worth giving a session (bps)
+    pub min_session_bandwidth: u64,
+    /// Admission tuning
+    pub admission: AdmissionConfig,
+}
+
+/// Relay prices are written as `u64` so they fit config formats without
+/// 128-bit integers, such as TOML
+mod tokens_as_u64 {
+    use crate::core::types::TokenAmount;
+    use serde::{ser::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(amount: &TokenAmount, serializer: S) -> Result<S::Ok, S::Error> {
+        u64::try_from(amount.value())
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TokenAmount, D::Error> {
+        Ok(TokenAmount::new(u64::deserialize(deserializer)? as u128))
+    }
 }
 
 impl Default for RelayConfig {
@@ -25,17 +55,23 @@ impl Default for RelayConfig {
             max_bandwidth_per_session: 100_000_000, // 100 Mbps
             min_reputation: ReputationScore::new(100),
             tokens_per_mb: TokenAmount::new(1), // 1 NEXUS per MB
+            total_bandwidth: 1_000_000_000,     // 1 Gbps
+            min_session_bandwidth: 10_000_000,  // 10 Mbps
+            admission: AdmissionConfig::default(),
         }
     }
 }
 
 /// Relay session
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelaySession {
     /// Session ID
     pub session_id: [u8; 32],
     /// Client peer
     pub client: PeerID,
+    /// Client's public key; receipts for the session must be co-signed with it
+    #[serde(default)]
+    pub client_public_key: [u8; 32],
     /// Target peer
     pub target: PeerID,
     /// Start time
@@ -46,50 +82,124 @@ pub struct RelaySession {
     pub current_bandwidth: u64,
     /// Token rate for this session
     pub token_rate: TokenAmount,
+    /// Scheduling priority from the client's reputation and balance, 0 to 1
+    #[serde(default)]
+    pub priority: f64,
+}
+
+/// Client asking for a relay session
+#[derive(Debug, Clone)]
+pub struct SessionRequest {
+    /// Client peer
+    pub client: PeerID,
+    /// Client's public key, as authenticated by its connection
+    pub client_public_key: [u8; 32],
+    /// Target peer
+    pub target: PeerID,
+    /// Addresses the client gave for the target
+    pub target_addresses: Vec<String>,
+    /// Client's reputation
+    pub reputation: ReputationScore,
+    /// Client's wallet balance
+    pub balance: TokenAmount,
+    /// Channel the client pays for the session through, if it is metered
+    pub channel_id: Option<[u8; 32]>,
+}
+
+/// Session admitted by `RelayManager::admit`
+#[derive(Debug, Clone)]
+pub struct Admission {
+    /// The new session
+    pub session: RelaySession,
+    /// Receipts of the sessions preempted to make room for it
+    pub preempted: Vec<SignedReceipt>,
 }
 
 /// Relay manager
+///
+/// Relayed streams carry `SecureChannel` frames that are sealed end-to-end
+/// between client and target, so the relay only accounts for ciphertext.
 pub struct RelayManager {
     config: RelayConfig,
+    admission: AdmissionController,
     sessions: HashMap<[u8; 32], RelaySession>,
+    load: LoadMeter,
     stats: NetworkStats,
+    /// Events for the node's reputation model, with their time
+    reputation_events: Vec<(u64, ReputationEvent)>,
+    /// Whether new sessions are accepted
+    accepting: bool,
+}
+
+impl Default for RelayManager {
+    fn default() -> Self {
+        Self::new(RelayConfig::default())
+    }
 }
 
 impl RelayManager {
     /// Create a new relay manager
     pub fn new(config: RelayConfig) -> Self {
         Self {
+            admission: AdmissionController::new(config.admission.clone()),
             config,
             sessions: HashMap::new(),
+            load: LoadMeter::new(),
             stats: NetworkStats::default(),
+            reputation_events: Vec::new(),
+            accepting: true,
         }
     }
     
-    /// Create a relay manager with default config
-    pub fn default() -> Self {
-        Self::new(RelayConfig::default())
-    }
-    
-    /// Start a new relay session
+    /// Start a new relay session for a client whose balance is unknown
     pub fn start_session(
         &mut self,
         client: PeerID,
+        client_public_key: [u8; 32],
         target: PeerID,
         reputation: ReputationScore,
     ) -> Result<RelaySession, Error> {
-        if self.sessions.len() >= self.config.max_sessions {
-            return Err(Error::Network("Max relay sessions reached".to_string()));
+        let request = SessionRequest {
+            client,
+            client_public_key,
+            target,
+            target_addresses: Vec::new(),
+            reputation,
+            balance: TokenAmount::new(0),
+            channel_id: None,
+        };
+        Ok(self.admit(request)?.session)
+    }
+    
+    /// Start a new relay session if admission control lets the client in
+    ///
+    /// A full relay preempts its lowest-priority session for a client that
+    /// clearly outranks it; preempted sessions end with a receipt for what
+    /// they relayed.
+    pub fn admit(&mut self, request: SessionRequest) -> Result<Admission, Error> {
+        if !self.accepting {
+            return Err(Error::Network("Relay is not accepting sessions".to_string()));
         }
         
-        if reputation < self.config.min_reputation {
+        if request.reputation < self.config.min_reputation {
             return Err(Error::Token("Insufficient reputation for relay".to_string()));
         }
         
+        let priority = session_priority(request.reputation, request.balance);
+        let active: Vec<&RelaySession> = self.sessions.values().collect();
+        let victims = self.admission.admit(&self.config, &active, priority, self.load.bps())?;
+        let mut preempted = Vec::with_capacity(victims.len());
+        for session_id in &victims {
+            debug!("Preempting relay session {} for a priority {:.2} client", hex::encode(&session_id[..8]), priority);
+            preempted.push(self.end_session(session_id)?);
+        }
+        
         let session_id: [u8; 32] = rand::random();
         let session = RelaySession {
             session_id,
-            client,
-            target,
+            client: request.client,
+            client_public_key: request.client_public_key,
+            target: request.target,
             start_time: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -97,19 +207,24 @@ impl RelayManager {
             data_relayed: 0,
             current_bandwidth: 0,
             token_rate: self.config.tokens_per_mb,
+            priority,
         };
         
         self.sessions.insert(session_id, session.clone());
         
-        Ok(session)
+        Ok(Admission { session, preempted })
     }
     
     /// Record data relayed for a session
+    ///
+    /// Returns the tokens earned for this chunk; `RelayMeter` collects them
+    /// from the client through a payment channel while the session runs.
     pub fn record_data(&mut self, session_id: &[u8; 32], bytes: u64) -> Result<TokenAmount, Error> {
         let session = self.sessions.get_mut(session_id)
             .ok_or_else(|| Error::Network("Session not found".to_string()))?;
         
         session.data_relayed += bytes;
+        self.load.record(bytes);
         self.stats.bytes_sent += bytes;
         self.stats.bytes_received += bytes;
         
@@ -121,6 +236,11 @@ impl RelayManager {
     }
     
     /// End a relay session and get final receipt
+    ///
+    /// The receipt names the client key recorded at admission and is
+    /// returned unsigned; the relay signs it with
+    /// `SignedReceipt::sign_as_relay` and the client co-signs it with
+    /// `SignedReceipt::sign_as_client` before it can be redeemed.
     pub fn end_session(&mut self, session_id: &[u8; 32]) -> Result<SignedReceipt, Error> {
         let session = self.sessions.remove(session_id)
             .ok_or_else(|| Error::Network("Session not found".to_string()))?;
@@ -133,26 +253,75 @@ impl RelayManager {
         let duration = now - session.start_time;
         
         // Calculate final amount
-        let mb_relayed = (session.data_relayed as f64) / (1024.0 * 1024.0);
-        let amount = TokenAmount::new((mb_relayed * session.token_rate.value() as f64) as u128);
+        let amount = receipt_amount(session.data_relayed, session.token_rate);
         
         // Update stats
         self.stats.relay_sessions += 1;
         self.stats.total_relay_duration += duration;
         self.stats.total_data_relayed += session.data_relayed;
         
-        // Create receipt (signatures will be added by the actual implementation)
         Ok(SignedReceipt {
             session_id: *session_id,
             data_relayed: session.data_relayed,
             duration,
             amount,
+            relay_public_key: [0u8; 32],
+            client_public_key: session.client_public_key,
             relay_signature: vec![],
             client_signature: vec![],
             timestamp: now,
         })
     }
     
+    /// Abandon a session without a receipt
+    pub fn drop_session(&mut self, session_id: &[u8; 32]) -> Result<RelaySession, Error> {
+        let session = self.sessions.remove(session_id)
+            .ok_or_else(|| Error::Network("Session not found".to_string()))?;
+        
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.reputation_events.push((now, ReputationEvent::SessionDropped { session_id: *session_id }));
+        
+        Ok(session)
+    }
+    
+    /// Take the reputation events since the last call
+    pub fn take_reputation_events(&mut self) -> Vec<(u64, ReputationEvent)> {
+        std::mem::take(&mut self.reputation_events)
+    }
+    
+    /// Refuse new sessions while the active ones drain
+    pub fn stop_accepting(&mut self) {
+        self.accepting = false;
+    }
+    
+    /// Accept new sessions again
+    pub fn start_accepting(&mut self) {
+        self.accepting = true;
+    }
+    
+    /// Whether new sessions are accepted
+    pub fn is_accepting(&self) -> bool {
+        self.accepting
+    }
+    
+    /// Relay configuration
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+    
+    /// Sessions the relay can run at once, given its bandwidth
+    pub fn session_capacity(&self) -> usize {
+        self.admission.capacity(&self.config)
+    }
+    
+    /// Relayed traffic over the last second (bps)
+    pub fn load(&self) -> u64 {
+        self.load.bps()
+    }
+    
     /// Get active sessions
     pub fn active_sessions(&self) -> Vec<&RelaySession> {
         self.sessions.values().collect()
@@ -163,3 +332,150 @@ impl RelayManager {
         &self.stats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::network::secure::SessionConfig;
+    use crate::network::transport::{read_frame, write_frame, SecureChannel};
+    use std::sync::{Arc, Mutex};
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    async fn forward<R, W>(
+        read: R,
+        write: W,
+        session_id: [u8; 32],
+        manager: Arc<Mutex<RelayManager>>,
+        observed: Arc<Mutex<Vec<u8>>>,
+    ) where
+        R: tokio::io::AsyncRead + Unpin,
+        W: tokio::io::AsyncWrite + Unpin,
+    {
+        let mut read = read.compat();
+        let mut write = write.compat_write();
+        while let Ok(frame) = read_frame(&mut read).await {
+            observed.lock().unwrap().extend_from_slice(&frame);
+            manager.lock().unwrap().record_data(&session_id, frame.len() as u64).unwrap();
+            if write_frame(&mut write, &frame).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_only_sees_ciphertext() {
+        let client_keys = NodeKeypair::generate();
+        let target_keys = NodeKeypair::generate();
+
+        let (client_io, relay_client_side) = tokio::io::duplex(64 * 1024);
+        let (relay_target_side, target_io) = tokio::io::duplex(64 * 1024);
+
+        let mut manager = RelayManager::default();
+        let session = manager.start_session(
+            PeerID::new("client".to_string()),
+            client_keys.public_key().to_bytes(),
+            PeerID::new("target".to_string()),
+            ReputationScore::new(500),
+        ).unwrap();
+        let manager = Arc::new(Mutex::new(manager));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+
+        // Forward frames in both directions, recording what the relay sees
+        let (client_read, client_write) = tokio::io::split(relay_client_side);
+        let (target_read, target_write) = tokio::io::split(relay_target_side);
+        tokio::spawn(forward(client_read, target_write, session.session_id, manager.clone(), observed.clone()));
+        tokio::spawn(forward(target_read, client_write, session.session_id, manager.clone(), observed.clone()));
+
+        let (client, target) = tokio::join!(
+            SecureChannel::initiate(
+                client_io.compat(),
+                PeerID::new("target".to_string()),
+                target_keys.node_id(),
+                &client_keys,
+                SessionConfig::default(),
+            ),
+            SecureChannel::respond(
+                target_io.compat(),
+                PeerID::new("client".to_string()),
+                &target_keys,
+                SessionConfig::default(),
+            ),
+        );
+        let client = client.unwrap();
+        let target = target.unwrap();
+        assert_eq!(target.device_id, client_keys.node_id());
+
+        let secret = b"remote desktop keystrokes: hunter2";
+        client.send(secret).await.unwrap();
+        assert_eq!(target.receive().await.unwrap(), secret);
+        target.send(secret).await.unwrap();
+        assert_eq!(client.receive().await.unwrap(), secret);
+
+        let observed = observed.lock().unwrap();
+        assert!(!observed.windows(secret.len()).any(|w| w == secret));
+        assert_eq!(
+            manager.lock().unwrap().active_sessions()[0].data_relayed,
+            observed.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_full_relay_preempts_lowest_priority_session() {
+        let mut manager = RelayManager::new(RelayConfig { max_sessions: 2, ..Default::default() });
+        let request = |name: &str, reputation: u64, balance: u128| SessionRequest {
+            client: PeerID::new(name.to_string()),
+            client_public_key: [0u8; 32],
+            target: PeerID::new("target".to_string()),
+            target_addresses: Vec::new(),
+            reputation: ReputationScore::new(reputation),
+            balance: TokenAmount::new(balance),
+            channel_id: None,
+        };
+
+        let low = manager.admit(request("low", 200, 0)).unwrap().session;
+        let mid = manager.admit(request("mid", 500, 0)).unwrap().session;
+        assert!(low.priority < mid.priority);
+        manager.record_data(&low.session_id, 1024 * 1024).unwrap();
+
+        // A peer of the weakest session cannot push it out
+        assert!(manager.admit(request("peer", 250, 0)).is_err());
+        let high = manager.admit(request("high", 900, 1000)).unwrap();
+        assert_eq!(high.preempted.len(), 1);
+        assert_eq!(high.preempted[0].session_id, low.session_id);
+        assert_eq!(high.preempted[0].data_relayed, 1024 * 1024);
+
+        let mut active: Vec<[u8; 32]> = manager.active_sessions().iter().map(|s| s.session_id).collect();
+        active.sort();
+        let mut expected = vec![mid.session_id, high.session.session_id];
+        expected.sort();
+        assert_eq!(active, expected);
+        // Preemption is the relay's policy, not a dropped session
+        assert!(manager.take_reputation_events().is_empty());
+        assert!(manager.record_data(&low.session_id, 1).is_err());
+    }
+
+    #[test]
+    fn test_dropped_sessions_cost_reputation() {
+        let mut manager = RelayManager::default();
+        let mut node = crate::core::state::NodeState::new(NodeKeypair::generate());
+        let start = |manager: &mut RelayManager| manager.start_session(
+            PeerID::new("client".to_string()),
+            [0u8; 32],
+            PeerID::new("target".to_string()),
+            ReputationScore::DEFAULT,
+        ).unwrap();
+
+        let completed = start(&mut manager);
+        manager.end_session(&completed.session_id).unwrap();
+        let dropped = start(&mut manager);
+        manager.drop_session(&dropped.session_id).unwrap();
+        assert!(manager.drop_session(&dropped.session_id).is_err());
+
+        let events = manager.take_reputation_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(node.apply_reputation_events(events), ReputationScore::new(90));
+        assert_eq!(node.reputation_model.history().count(), 1);
+        assert!(manager.take_reputation_events().is_empty());
+    }
+}
@@ -0,0 +1,433 @@
+//! Relay admission control and bandwidth scheduling
+//!
+//! Each relay session gets a priority from the client's reputation and
+//! wallet balance (`calculate_priority_score`). `AdmissionController`
+//! uses it to decide whether a new session fits: the relay's total
+//! bandwidth has to leave every session a useful minimum, and as measured
+//! load nears capacity only clients of higher priority are let in. A full
+//! relay makes room by preempting its lowest-priority session, but only
+//! for a client that clearly outranks it.
+//!
+//! `WfqScheduler` then shares the relay's bandwidth among the admitted
+//! sessions with self-clocked weighted fair queueing: each chunk gets a
+//! virtual finish time that grows by its size over the session's weight,
+//! and chunks go out in finish-time order at the relay's total rate.
+
+use crate::core::types::*;
+use crate::network::forwarding::BandwidthLimiter;
+use crate::network::relay::{RelayConfig, RelaySession};
+use crate::wallet::token::calculate_priority_score;
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// Window over which relay load is measured
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// Smallest scheduling weight, so no session is starved outright
+const MIN_WEIGHT: f64 = 0.01;
+
+/// Admission tuning
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    /// Share of total bandwidth in use above which low-priority clients are turned away
+    pub busy_load: f64,
+    /// How much a client's priority must exceed a session's for it to be preempted
+    pub preemption_margin: f64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            busy_load: 0.8,
+            preemption_margin: 0.1,
+        }
+    }
+}
+
+/// Priority of a client asking for a relay session, from 0 to 1
+pub fn session_priority(reputation: ReputationScore, balance: TokenAmount) -> f64 {
+    calculate_priority_score(reputation, balance).clamp(0.0, 1.0)
+}
+
+/// Decides which relay sessions to take
+#[derive(Debug, Clone, Default)]
+pub struct AdmissionController {
+    config: AdmissionConfig,
+}
+
+impl AdmissionController {
+    /// Create an admission controller
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self { config }
+    }
+
+    /// Sessions a relay with `config` can run at once
+    pub fn capacity(&self, config: &RelayConfig) -> usize {
+        let by_bandwidth = config.total_bandwidth / config.min_session_bandwidth.max(1);
+        config.max_sessions.min(by_bandwidth.try_into().unwrap_or(usize::MAX))
+    }
+
+    /// Lowest priority admitted while the relay carries `load` bps
+    pub fn required_priority(&self, config: &RelayConfig, load: u64) -> f64 {
+        let utilisation = load as f64 / config.total_bandwidth.max(1) as f64;
+        if utilisation <= self.config.busy_load {
+            return 0.0;
+        }
+        ((utilisation - self.config.busy_load) / (1.0 - self.config.busy_load).max(f64::EPSILON)).min(1.0)
+    }
+
+    /// Decide on a client of `priority` given the `active` sessions and current `load`
+    ///
+    /// Returns the sessions to preempt to make room, which is empty when
+    /// the relay has a free slot.
+    pub fn admit(
+        &self,
+        config: &RelayConfig,
+        active: &[&RelaySession],
+        priority: f64,
+        load: u64,
+    ) -> Result<Vec<[u8; 32]>, Error> {
+        if priority < self.required_priority(config, load) {
+            return Err(Error::Network("Relay is too busy for this client".to_string()));
+        }
+        if active.len() < self.capacity(config) {
+            return Ok(Vec::new());
+        }
+        let victim = active.iter()
+            .min_by(|a, b| a.priority.total_cmp(&b.priority))
+            .filter(|victim| victim.priority + self.config.preemption_margin < priority);
+        match victim {
+            Some(victim) => Ok(vec![victim.session_id]),
+            None => Err(Error::Network("Max relay sessions reached".to_string())),
+        }
+    }
+}
+
+/// Rate of relayed traffic over the last complete window
+#[derive(Debug)]
+pub struct LoadMeter {
+    window_start: Instant,
+    bytes: u64,
+    /// Rate measured over the last complete window (bps)
+    rate: u64,
+}
+
+impl LoadMeter {
+    /// Start measuring
+    pub fn new() -> Self {
+        Self { window_start: Instant::now(), bytes: 0, rate: 0 }
+    }
+
+    /// Count `bytes` relayed now
+    pub fn record(&mut self, bytes: u64) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= LOAD_WINDOW {
+            self.rate = (self.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            self.bytes = 0;
+            self.window_start = Instant::now();
+        }
+        self.bytes += bytes;
+    }
+
+    /// Current load (bps); a relay that went quiet reads as idle
+    pub fn bps(&self) -> u64 {
+        if self.window_start.elapsed() >= 2 * LOAD_WINDOW {
+            0
+        } else {
+            self.rate
+        }
+    }
+}
+
+impl Default for LoadMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Chunk waiting for its turn
+struct Queued<T> {
+    finish: f64,
+    /// Arrival order, to break ties
+    seq: u64,
+    session_id: [u8; 32],
+    bytes: usize,
+    item: T,
+}
+
+impl<T> PartialEq for Queued<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Queued<T> {}
+
+impl<T> PartialOrd for Queued<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Queued<T> {
+    /// Earliest finish first out of a max-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.finish.total_cmp(&self.finish).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// Weighted fair queue of chunks from relay sessions
+pub struct FairQueue<T> {
+    virtual_time: f64,
+    /// Weight and last finish time of each session
+    sessions: HashMap<[u8; 32], (f64, f64)>,
+    queue: BinaryHeap<Queued<T>>,
+    next_seq: u64,
+}
+
+impl<T> FairQueue<T> {
+    /// Create an empty queue
+    pub fn new() -> Self {
+        Self { virtual_time: 0.0, sessions: HashMap::new(), queue: BinaryHeap::new(), next_seq: 0 }
+    }
+
+    /// Give a session its share, by priority
+    pub fn set_priority(&mut self, session_id: [u8; 32], priority: f64) {
+        let weight = priority.max(MIN_WEIGHT);
+        self.sessions.entry(session_id).or_insert((weight, self.virtual_time)).0 = weight;
+    }
+
+    /// Forget a session; its queued chunks still go out
+    pub fn remove(&mut self, session_id: &[u8; 32]) {
+        self.sessions.remove(session_id);
+    }
+
+    /// Queue `bytes` for a session; unknown sessions get the smallest share
+    pub fn push(&mut self, session_id: [u8; 32], bytes: usize, item: T) {
+        let virtual_time = self.virtual_time;
+        let (weight, last_finish) = self.sessions.entry(session_id).or_insert((MIN_WEIGHT, virtual_time));
+        let finish = last_finish.max(virtual_time) + bytes as f64 / *weight;
+        *last_finish = finish;
+        self.queue.push(Queued { finish, seq: self.next_seq, session_id, bytes, item });
+        self.next_seq += 1;
+    }
+
+    /// Take the next chunk to send: its session, size and item
+    pub fn pop(&mut self) -> Option<([u8; 32], usize, T)> {
+        let next = self.queue.pop()?;
+        self.virtual_time = next.finish;
+        Some((next.session_id, next.bytes, next.item))
+    }
+
+    /// Whether nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Request to the scheduler task
+enum Command {
+    SetPriority([u8; 32], f64),
+    Remove([u8; 32]),
+    Transmit {
+        session_id: [u8; 32],
+        bytes: usize,
+        grant: oneshot::Sender<()>,
+    },
+}
+
+/// Shares the relay's total bandwidth among sessions by priority
+///
+/// Senders ask for permission with `transmit` before writing a chunk and
+/// are let through in weighted-fair order at `total_bandwidth`. The task
+/// behind it stops once every handle is dropped.
+#[derive(Clone)]
+pub struct WfqScheduler {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl WfqScheduler {
+    /// Start a scheduler for a link of `total_bandwidth` bps
+    pub fn new(total_bandwidth: u64, burst: Duration) -> Self {
+        let (commands, rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::run(rx, BandwidthLimiter::new(total_bandwidth, burst)));
+        Self { commands }
+    }
+
+    /// Set a session's priority
+    pub fn set_priority(&self, session_id: [u8; 32], priority: f64) {
+        let _ = self.commands.send(Command::SetPriority(session_id, priority));
+    }
+
+    /// Stop scheduling a session
+    pub fn remove(&self, session_id: [u8; 32]) {
+        let _ = self.commands.send(Command::Remove(session_id));
+    }
+
+    /// Wait until `bytes` of a session may be sent
+    pub async fn transmit(&self, session_id: [u8; 32], bytes: usize) -> Result<(), Error> {
+        let (grant, granted) = oneshot::channel();
+        self.commands.send(Command::Transmit { session_id, bytes, grant })
+            .map_err(|_| Error::Network("Relay scheduler stopped".to_string()))?;
+        granted.await.map_err(|_| Error::Network("Relay scheduler stopped".to_string()))
+    }
+
+    async fn run(mut commands: mpsc::UnboundedReceiver<Command>, mut link: BandwidthLimiter) {
+        fn apply(queue: &mut FairQueue<oneshot::Sender<()>>, command: Command) {
+            match command {
+                Command::SetPriority(session_id, priority) => queue.set_priority(session_id, priority),
+                Command::Remove(session_id) => queue.remove(&session_id),
+                Command::Transmit { session_id, bytes, grant } => queue.push(session_id, bytes, grant),
+            }
+        }
+        let mut queue = FairQueue::new();
+        loop {
+            // Everything that arrived while the last chunk went out competes for the next turn
+            while let Ok(command) = commands.try_recv() {
+                apply(&mut queue, command);
+            }
+            let Some((_, bytes, grant)) = queue.pop() else {
+                match commands.recv().await {
+                    Some(command) => apply(&mut queue, command),
+                    None => break,
+                }
+                continue;
+            };
+            let delay = link.reserve(bytes);
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let _ = grant.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+    use std::sync::Arc;
+
+    fn session(id: u8, priority: f64) -> RelaySession {
+        RelaySession {
+            session_id: [id; 32],
+            client: PeerID::new(format!("client-{}", id)),
+            client_public_key: [id; 32],
+            target: PeerID::new("target".to_string()),
+            start_time: 0,
+            data_relayed: 0,
+            current_bandwidth: 0,
+            token_rate: TokenAmount::new(1),
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_admission_weighs_capacity_priority_and_load() {
+        let controller = AdmissionController::default();
+        // Ten sessions by count, but the bandwidth only leaves room for four
+        let config = RelayConfig {
+            max_sessions: 10,
+            total_bandwidth: 400_000_000,
+            min_session_bandwidth: 100_000_000,
+            ..Default::default()
+        };
+        assert_eq!(controller.capacity(&config), 4);
+
+        let sessions: Vec<RelaySession> = (0..4).map(|i| session(i, 0.2 + 0.1 * i as f64)).collect();
+        let active: Vec<&RelaySession> = sessions.iter().collect();
+        assert_eq!(controller.admit(&config, &active[..3], 0.1, 0).unwrap(), Vec::<[u8; 32]>::new());
+
+        // Full: only a client well above the weakest session gets in, at its expense
+        assert!(controller.admit(&config, &active, 0.25, 0).is_err());
+        assert_eq!(controller.admit(&config, &active, 0.9, 0).unwrap(), vec![[0u8; 32]]);
+
+        // Under load the bar rises with utilisation
+        assert_eq!(controller.required_priority(&config, 300_000_000), 0.0);
+        assert!((controller.required_priority(&config, 360_000_000) - 0.5).abs() < 1e-9);
+        assert_eq!(controller.required_priority(&config, 800_000_000), 1.0);
+        assert!(controller.admit(&config, &active[..1], 0.4, 360_000_000).is_err());
+        assert!(controller.admit(&config, &active[..1], 0.6, 360_000_000).is_ok());
+
+        // Reputation counts for more than balance
+        let reputable = session_priority(ReputationScore::new(900), TokenAmount::new(0));
+        let wealthy = session_priority(ReputationScore::new(100), TokenAmount::new(1_000_000));
+        assert!(reputable > wealthy);
+    }
+
+    #[test]
+    fn test_fair_queue_shares_by_weight() {
+        let mut queue = FairQueue::new();
+        queue.set_priority([1; 32], 0.75);
+        queue.set_priority([2; 32], 0.25);
+        for _ in 0..100 {
+            queue.push([1; 32], 1000, ());
+            queue.push([2; 32], 1000, ());
+        }
+        let mut sent = HashMap::new();
+        for _ in 0..80 {
+            let (session_id, bytes, _) = queue.pop().unwrap();
+            *sent.entry(session_id).or_insert(0) += bytes;
+        }
+        assert_eq!(sent[&[1; 32]], 60_000);
+        assert_eq!(sent[&[2; 32]], 20_000);
+
+        // A session joining late competes from now on rather than from the start
+        queue.set_priority([3; 32], 0.75);
+        queue.push([3; 32], 1000, ());
+        queue.push([3; 32], 1000, ());
+        let next: Vec<[u8; 32]> = (0..4).map(|_| queue.pop().unwrap().0).collect();
+        assert_eq!(next, vec![[1; 32], [3; 32], [1; 32], [3; 32]]);
+    }
+
+    #[tokio::test]
+    async fn test_scheduler_divides_bandwidth_by_priority() {
+        // 80 Mbps is 10 MB/s shared between two saturating sessions
+        let scheduler = WfqScheduler::new(80_000_000, Duration::from_millis(10));
+        let counters: Vec<Arc<AtomicU64>> = (0..2).map(|_| Arc::new(AtomicU64::new(0))).collect();
+        let mut senders = Vec::new();
+        for (id, priority) in [(1u8, 0.9), (2u8, 0.3)] {
+            scheduler.set_priority([id; 32], priority);
+            let scheduler = scheduler.clone();
+            let sent = counters[id as usize - 1].clone();
+            senders.push(tokio::spawn(async move {
+                // Several chunks in flight keep the session backlogged
+                let chunks = (0..4).map(|_| {
+                    let scheduler = scheduler.clone();
+                    let sent = sent.clone();
+                    tokio::spawn(async move {
+                        while scheduler.transmit([id; 32], 16 * 1024).await.is_ok() {
+                            sent.fetch_add(16 * 1024, AtomicOrdering::Relaxed);
+                        }
+                    })
+                }).collect::<Vec<_>>();
+                futures::future::join_all(chunks).await;
+            }));
+        }
+
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let elapsed = started.elapsed().as_secs_f64();
+        let high = counters[0].load(AtomicOrdering::Relaxed) as f64;
+        let low = counters[1].load(AtomicOrdering::Relaxed) as f64;
+        for sender in senders {
+            sender.abort();
+        }
+
+        let ratio = high / low;
+        assert!((2.5..=3.5).contains(&ratio), "high {} low {} ratio {:.2}", high, low, ratio);
+        // Together they stay within the link, allowing for the burst
+        assert!((high + low) * 8.0 <= 80_000_000.0 * elapsed + 1_000_000.0, "{} bytes in {:.2}s", high + low, elapsed);
+    }
+}
@@ -0,0 +1,674 @@
+//! End-to-end session encryption for secure channels
+//!
+//! Two peers run a three-message handshake over an established stream:
+//!
+//! 1. initiator -> responder: ephemeral X25519 key + Ed25519 identity key
+//! 2. responder -> initiator: ephemeral X25519 key + identity key + signature over the transcript
+//! 3. initiator -> responder: signature over the transcript
+//!
+//! Both sides then derive one ChaCha20-Poly1305 key per direction from the
+//! X25519 shared secret. Because the keys only exist at the two endpoints, any
+//! relay in between forwards ciphertext it cannot read or modify.
+//!
+//! Each rekey mixes a fresh X25519 exchange into the next key: the sender
+//! generates a new ratchet key, combines it with the peer's latest ratchet
+//! public key, and carries both public keys in the first frame of the new
+//! epoch. Someone who learns one epoch's key therefore cannot follow the
+//! session into later epochs without also holding a ratchet secret.
+//!
+//! A sender keeps the ratchet secrets the peer may still combine with until
+//! the peer's own rekey shows which one it has seen. Once
+//! `MAX_RATCHET_SECRETS` are outstanding, rekeying waits for that
+//! acknowledgement rather than dropping a secret the peer still needs, so a
+//! one-way stream stays in its current epoch until the peer rekeys.
+
+use crate::core::crypto::{hash, node_id_from_public_key, verify_signature, NodeKeypair};
+use crate::core::types::*;
+use crate::Error;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use x25519_dalek::{PublicKey as X25519PublicKey, ReusableSecret};
+
+/// Handshake protocol version
+const HANDSHAKE_VERSION: u8 = 1;
+
+/// Domain separator for the handshake transcript
+const TRANSCRIPT_DOMAIN: &[u8] = b"nexusremote-handshake-v1";
+
+/// Size of the frame header (epoch + counter)
+const HEADER_LEN: usize = 12;
+
+/// Ratchet keys carried by the first frame of each epoch after the first
+/// (sender's new public key + the recipient public key it was combined with)
+const RATCHET_LEN: usize = 64;
+
+/// Local ratchet secrets kept for peers that have not yet seen the newest one
+const MAX_RATCHET_SECRETS: usize = 4;
+
+/// Session configuration
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Rekey after this many messages in one direction
+    pub rekey_after_messages: u64,
+    /// Rekey after this much time has elapsed
+    pub rekey_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            rekey_after_messages: 1 << 20,
+            rekey_interval: Duration::from_secs(600),
+        }
+    }
+}
+
+/// First handshake message (initiator -> responder)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HandshakeInit {
+    version: u8,
+    ephemeral: [u8; 32],
+    identity: [u8; 32],
+}
+
+/// Second handshake message (responder -> initiator)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HandshakeResponse {
+    ephemeral: [u8; 32],
+    identity: [u8; 32],
+    signature: Vec<u8>,
+}
+
+/// Third handshake message (initiator -> responder)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HandshakeFinish {
+    signature: Vec<u8>,
+}
+
+/// Handshake role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    /// The side that opened the stream
+    Initiator,
+    /// The side that accepted the stream
+    Responder,
+}
+
+/// In-progress session handshake
+pub struct Handshake {
+    role: HandshakeRole,
+    keypair: NodeKeypair,
+    config: SessionConfig,
+    ephemeral: Option<ReusableSecret>,
+    local_ephemeral: [u8; 32],
+    init: Option<HandshakeInit>,
+    pending: Option<(Session, [u8; 32])>,
+}
+
+impl Handshake {
+    /// Start a handshake as initiator, returning the first message to send
+    pub fn initiate(keypair: &NodeKeypair, config: SessionConfig) -> (Self, Vec<u8>) {
+        let ephemeral = ReusableSecret::random_from_rng(OsRng);
+        let local_ephemeral = X25519PublicKey::from(&ephemeral).to_bytes();
+        let init = HandshakeInit {
+            version: HANDSHAKE_VERSION,
+            ephemeral: local_ephemeral,
+            identity: keypair.public_key().to_bytes(),
+        };
+        let message = bincode::serialize(&init).expect("handshake message serializes");
+
+        let handshake = Self {
+            role: HandshakeRole::Initiator,
+            keypair: keypair.clone(),
+            config,
+            ephemeral: Some(ephemeral),
+            local_ephemeral,
+            init: Some(init),
+            pending: None,
+        };
+        (handshake, message)
+    }
+
+    /// Start a handshake as responder
+    pub fn respond(keypair: &NodeKeypair, config: SessionConfig) -> Self {
+        let ephemeral = ReusableSecret::random_from_rng(OsRng);
+        let local_ephemeral = X25519PublicKey::from(&ephemeral).to_bytes();
+        Self {
+            role: HandshakeRole::Responder,
+            keypair: keypair.clone(),
+            config,
+            ephemeral: Some(ephemeral),
+            local_ephemeral,
+            init: None,
+            pending: None,
+        }
+    }
+
+    /// Responder: process the initiator's first message and produce the response
+    pub fn read_init(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        self.expect_role(HandshakeRole::Responder)?;
+        let init: HandshakeInit = bincode::deserialize(message)?;
+        if init.version != HANDSHAKE_VERSION {
+            return Err(Error::Crypto(format!("Unsupported handshake version {}", init.version)));
+        }
+
+        let identity = self.keypair.public_key().to_bytes();
+        let th = transcript_hash(&init.ephemeral, &init.identity, &self.local_ephemeral, &identity);
+        let signature = self.keypair.sign(&signed_payload(&th, HandshakeRole::Responder));
+
+        let session = self.derive_session(&init.ephemeral, init.identity, &th)?;
+        self.pending = Some((session, th));
+
+        let response = HandshakeResponse {
+            ephemeral: self.local_ephemeral,
+            identity,
+            signature,
+        };
+        Ok(bincode::serialize(&response)?)
+    }
+
+    /// Initiator: verify the response, returning the final message and the session
+    pub fn read_response(mut self, message: &[u8]) -> Result<(Vec<u8>, Session), Error> {
+        self.expect_role(HandshakeRole::Initiator)?;
+        let response: HandshakeResponse = bincode::deserialize(message)?;
+        let init = self.init.take()
+            .ok_or_else(|| Error::Crypto("Handshake already completed".to_string()))?;
+
+        let th = transcript_hash(&init.ephemeral, &init.identity, &response.ephemeral, &response.identity);
+        if !verify_signature(&response.identity, &signed_payload(&th, HandshakeRole::Responder), &response.signature) {
+            return Err(Error::Crypto("Invalid responder handshake signature".to_string()));
+        }
+
+        let session = self.derive_session(&response.ephemeral, response.identity, &th)?;
+        let finish = HandshakeFinish {
+            signature: self.keypair.sign(&signed_payload(&th, HandshakeRole::Initiator)),
+        };
+        Ok((bincode::serialize(&finish)?, session))
+    }
+
+    /// Responder: verify the initiator's signature and return the session
+    pub fn read_finish(mut self, message: &[u8]) -> Result<Session, Error> {
+        self.expect_role(HandshakeRole::Responder)?;
+        let finish: HandshakeFinish = bincode::deserialize(message)?;
+        let (session, th) = self.pending.take()
+            .ok_or_else(|| Error::Crypto("Handshake response not sent".to_string()))?;
+
+        if !verify_signature(&session.remote_public_key, &signed_payload(&th, HandshakeRole::Initiator), &finish.signature) {
+            return Err(Error::Crypto("Invalid initiator handshake signature".to_string()));
+        }
+        Ok(session)
+    }
+
+    fn expect_role(&self, role: HandshakeRole) -> Result<(), Error> {
+        if self.role != role {
+            return Err(Error::Crypto("Unexpected handshake message for role".to_string()));
+        }
+        Ok(())
+    }
+
+    fn derive_session(
+        &mut self,
+        remote_ephemeral: &[u8; 32],
+        remote_identity: [u8; 32],
+        th: &[u8; 32],
+    ) -> Result<Session, Error> {
+        let ephemeral = self.ephemeral.take()
+            .ok_or_else(|| Error::Crypto("Ephemeral key already used".to_string()))?;
+        let shared = ephemeral.diffie_hellman(&X25519PublicKey::from(*remote_ephemeral));
+        if !shared.was_contributory() {
+            return Err(Error::Crypto("Non-contributory key exchange".to_string()));
+        }
+
+        let hk = Hkdf::<Sha256>::new(Some(th), shared.as_bytes());
+        // The handshake ephemerals seed the rekey ratchet on both sides
+        let ratchet = Arc::new(Mutex::new(Ratchet::new(ephemeral, X25519PublicKey::from(*remote_ephemeral))));
+        let mut i2r = [0u8; 32];
+        let mut r2i = [0u8; 32];
+        hk.expand(b"nexusremote initiator->responder", &mut i2r)
+            .map_err(|_| Error::Crypto("Key derivation failed".to_string()))?;
+        hk.expand(b"nexusremote responder->initiator", &mut r2i)
+            .map_err(|_| Error::Crypto("Key derivation failed".to_string()))?;
+
+        let (send_key, recv_key) = match self.role {
+            HandshakeRole::Initiator => (i2r, r2i),
+            HandshakeRole::Responder => (r2i, i2r),
+        };
+
+        Ok(Session {
+            remote_public_key: remote_identity,
+            remote_device: node_id_from_public_key(&remote_identity),
+            send: SendCipher::new(send_key, ratchet.clone(), self.config.clone()),
+            recv: RecvCipher::new(recv_key, ratchet),
+        })
+    }
+}
+
+fn transcript_hash(
+    initiator_ephemeral: &[u8; 32],
+    initiator_identity: &[u8; 32],
+    responder_ephemeral: &[u8; 32],
+    responder_identity: &[u8; 32],
+) -> [u8; 32] {
+    let mut transcript = Vec::with_capacity(TRANSCRIPT_DOMAIN.len() + 128);
+    transcript.extend_from_slice(TRANSCRIPT_DOMAIN);
+    transcript.extend_from_slice(initiator_ephemeral);
+    transcript.extend_from_slice(initiator_identity);
+    transcript.extend_from_slice(responder_ephemeral);
+    transcript.extend_from_slice(responder_identity);
+    hash::sha256(&transcript)
+}
+
+fn signed_payload(th: &[u8; 32], role: HandshakeRole) -> Vec<u8> {
+    let mut payload = th.to_vec();
+    payload.extend_from_slice(match role {
+        HandshakeRole::Initiator => b"initiator",
+        HandshakeRole::Responder => b"responder",
+    });
+    payload
+}
+
+/// Derive the next epoch key from the current one and a fresh X25519 secret
+fn next_epoch_key(key: &[u8; 32], shared: &[u8; 32]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(Some(key), shared);
+    let mut next = [0u8; 32];
+    hk.expand(b"nexusremote rekey", &mut next)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    next
+}
+
+fn nonce_for(epoch: u32, counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&epoch.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// X25519 ratchet state shared by the two directions of a session
+struct Ratchet {
+    /// Our ratchet secrets, newest last; older ones stay until the peer uses a newer one
+    local: Vec<ReusableSecret>,
+    /// The peer's newest ratchet public key
+    remote: X25519PublicKey,
+}
+
+impl Ratchet {
+    fn new(local: ReusableSecret, remote: X25519PublicKey) -> Self {
+        Self { local: vec![local], remote }
+    }
+
+    /// Start a new sending epoch: returns the shared secret and the header keys
+    ///
+    /// Returns `None` while the peer has not acknowledged any of our last
+    /// `MAX_RATCHET_SECRETS - 1` ratchet keys; evicting the oldest secret
+    /// would leave the peer's next epoch undecryptable.
+    fn step(&mut self) -> Option<([u8; 32], [u8; RATCHET_LEN])> {
+        if self.local.len() >= MAX_RATCHET_SECRETS {
+            return None;
+        }
+        let secret = ReusableSecret::random_from_rng(OsRng);
+        let shared = secret.diffie_hellman(&self.remote);
+        let mut keys = [0u8; RATCHET_LEN];
+        keys[..32].copy_from_slice(X25519PublicKey::from(&secret).as_bytes());
+        keys[32..].copy_from_slice(self.remote.as_bytes());
+        self.local.push(secret);
+        Some((*shared.as_bytes(), keys))
+    }
+
+    /// Shared secret for a peer's new epoch, and which local secret produced it
+    fn receive(&self, keys: &[u8]) -> Result<([u8; 32], usize), Error> {
+        let sender = X25519PublicKey::from(<[u8; 32]>::try_from(&keys[..32]).unwrap());
+        let index = self.local.iter()
+            .rposition(|secret| X25519PublicKey::from(secret).as_bytes() == &keys[32..])
+            .ok_or_else(|| Error::Crypto("Frame uses an unknown ratchet key".to_string()))?;
+        let shared = self.local[index].diffie_hellman(&sender);
+        if !shared.was_contributory() {
+            return Err(Error::Crypto("Non-contributory ratchet exchange".to_string()));
+        }
+        Ok((*shared.as_bytes(), index))
+    }
+
+    /// Adopt the peer's new ratchet key once its frame authenticated
+    fn accept(&mut self, keys: &[u8], index: usize) {
+        self.remote = X25519PublicKey::from(<[u8; 32]>::try_from(&keys[..32]).unwrap());
+        // The peer has seen this secret, so it will never use an older one
+        self.local.drain(..index);
+    }
+}
+
+/// Established session keys
+pub struct Session {
+    /// Remote Ed25519 identity key
+    pub remote_public_key: [u8; 32],
+    /// Remote device ID (derived from the identity key)
+    pub remote_device: DeviceID,
+    /// Outbound cipher
+    pub send: SendCipher,
+    /// Inbound cipher
+    pub recv: RecvCipher,
+}
+
+/// Outbound direction of a session
+pub struct SendCipher {
+    key: [u8; 32],
+    epoch: u32,
+    counter: u64,
+    rekeyed_at: Instant,
+    ratchet: Arc<Mutex<Ratchet>>,
+    /// Ratchet keys for the first frame of the current epoch
+    announce: Option<[u8; RATCHET_LEN]>,
+    config: SessionConfig,
+}
+
+impl SendCipher {
+    fn new(key: [u8; 32], ratchet: Arc<Mutex<Ratchet>>, config: SessionConfig) -> Self {
+        Self {
+            key,
+            epoch: 0,
+            counter: 0,
+            rekeyed_at: Instant::now(),
+            ratchet,
+            announce: None,
+            config,
+        }
+    }
+
+    /// Current key epoch
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Encrypt a message into a frame payload (header || ciphertext)
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.counter >= self.config.rekey_after_messages
+            || self.rekeyed_at.elapsed() >= self.config.rekey_interval
+        {
+            self.rekey();
+        }
+
+        let mut header = Vec::with_capacity(HEADER_LEN + RATCHET_LEN);
+        header.extend_from_slice(&self.epoch.to_be_bytes());
+        header.extend_from_slice(&self.counter.to_be_bytes());
+        header.extend(self.announce.take().iter().flatten());
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let nonce = nonce_for(self.epoch, self.counter);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &header })
+            .map_err(|_| Error::Crypto("Encryption failed".to_string()))?;
+
+        self.counter += 1;
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
+    }
+
+    /// Move to the next epoch, unless the peer has yet to acknowledge enough
+    /// of our ratchet keys; the next frame tries again
+    fn rekey(&mut self) {
+        let Some((shared, keys)) = self.ratchet.lock().unwrap().step() else {
+            return;
+        };
+        self.key = next_epoch_key(&self.key, &shared);
+        self.announce = Some(keys);
+        self.epoch += 1;
+        self.counter = 0;
+        self.rekeyed_at = Instant::now();
+    }
+}
+
+/// Inbound direction of a session
+pub struct RecvCipher {
+    key: [u8; 32],
+    epoch: u32,
+    /// Highest counter accepted in the current epoch
+    last_counter: Option<u64>,
+    ratchet: Arc<Mutex<Ratchet>>,
+}
+
+impl RecvCipher {
+    fn new(key: [u8; 32], ratchet: Arc<Mutex<Ratchet>>) -> Self {
+        Self {
+            key,
+            epoch: 0,
+            last_counter: None,
+            ratchet,
+        }
+    }
+
+    /// Current key epoch
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Authenticate and decrypt a frame payload
+    ///
+    /// Frames must arrive with strictly increasing counters; anything at or
+    /// below the last accepted counter, or from a previous epoch, is rejected
+    /// as a replay.
+    pub fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, Error> {
+        if frame.len() < HEADER_LEN {
+            return Err(Error::Crypto("Truncated encrypted frame".to_string()));
+        }
+        let epoch = u32::from_be_bytes(frame[..4].try_into().unwrap());
+        let counter = u64::from_be_bytes(frame[4..HEADER_LEN].try_into().unwrap());
+
+        let (key, advance) = if epoch == self.epoch {
+            if self.last_counter.is_some_and(|last| counter <= last) {
+                return Err(Error::Crypto("Replayed frame rejected".to_string()));
+            }
+            (self.key, None)
+        } else if epoch == self.epoch.wrapping_add(1) {
+            // Only the first frame of an epoch carries its ratchet keys
+            if counter != 0 || frame.len() < HEADER_LEN + RATCHET_LEN {
+                return Err(Error::Crypto("Frame skipped the start of its key epoch".to_string()));
+            }
+            let keys = &frame[HEADER_LEN..HEADER_LEN + RATCHET_LEN];
+            let (shared, index) = self.ratchet.lock().unwrap().receive(keys)?;
+            (next_epoch_key(&self.key, &shared), Some((keys, index)))
+        } else if epoch < self.epoch {
+            return Err(Error::Crypto("Frame from expired key epoch rejected".to_string()));
+        } else {
+            return Err(Error::Crypto("Frame from unknown key epoch".to_string()));
+        };
+
+        let header_len = HEADER_LEN + if advance.is_some() { RATCHET_LEN } else { 0 };
+        let (header, ciphertext) = frame.split_at(header_len);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let nonce = nonce_for(epoch, counter);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| Error::Crypto("Frame authentication failed".to_string()))?;
+
+        if let Some((keys, index)) = advance {
+            self.ratchet.lock().unwrap().accept(keys, index);
+            self.key = key;
+            self.epoch = epoch;
+        }
+        self.last_counter = Some(counter);
+
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake_pair(config: SessionConfig) -> (Session, Session, NodeKeypair, NodeKeypair) {
+        let alice = NodeKeypair::generate();
+        let bob = NodeKeypair::generate();
+
+        let (initiator, msg1) = Handshake::initiate(&alice, config.clone());
+        let mut responder = Handshake::respond(&bob, config);
+        let msg2 = responder.read_init(&msg1).unwrap();
+        let (msg3, alice_session) = initiator.read_response(&msg2).unwrap();
+        let bob_session = responder.read_finish(&msg3).unwrap();
+
+        (alice_session, bob_session, alice, bob)
+    }
+
+    #[test]
+    fn test_handshake_and_roundtrip() {
+        let (mut alice, mut bob, alice_keys, bob_keys) = handshake_pair(SessionConfig::default());
+
+        assert_eq!(alice.remote_device, bob_keys.node_id());
+        assert_eq!(bob.remote_device, alice_keys.node_id());
+
+        let frame = alice.send.encrypt(b"hello bob").unwrap();
+        assert_eq!(bob.recv.decrypt(&frame).unwrap(), b"hello bob");
+
+        let frame = bob.send.encrypt(b"hello alice").unwrap();
+        assert_eq!(alice.recv.decrypt(&frame).unwrap(), b"hello alice");
+    }
+
+    #[test]
+    fn test_replay_and_tamper_rejected() {
+        let (mut alice, mut bob, _, _) = handshake_pair(SessionConfig::default());
+
+        let first = alice.send.encrypt(b"one").unwrap();
+        let second = alice.send.encrypt(b"two").unwrap();
+        bob.recv.decrypt(&first).unwrap();
+        bob.recv.decrypt(&second).unwrap();
+
+        // Replaying an old frame fails
+        assert!(bob.recv.decrypt(&first).is_err());
+
+        // Flipping a ciphertext bit fails authentication
+        let mut tampered = alice.send.encrypt(b"three").unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert!(bob.recv.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_directions_use_distinct_keys() {
+        let (mut alice, _, _, _) = handshake_pair(SessionConfig::default());
+
+        // A frame cannot be reflected back to its sender
+        let frame = alice.send.encrypt(b"echo").unwrap();
+        assert!(alice.recv.decrypt(&frame).is_err());
+    }
+
+    #[test]
+    fn test_rekeying() {
+        let config = SessionConfig {
+            rekey_after_messages: 3,
+            ..SessionConfig::default()
+        };
+        let (mut alice, mut bob, _, _) = handshake_pair(config);
+
+        let mut old_epoch_frame = None;
+        for i in 0..10u8 {
+            let frame = alice.send.encrypt(&[i]).unwrap();
+            if i == 0 {
+                old_epoch_frame = Some(frame.clone());
+            }
+            assert_eq!(bob.recv.decrypt(&frame).unwrap(), vec![i]);
+        }
+
+        assert_eq!(alice.send.epoch(), 3);
+        assert_eq!(bob.recv.epoch(), 3);
+        assert!(bob.recv.decrypt(&old_epoch_frame.unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_rekeying_in_both_directions() {
+        let config = SessionConfig {
+            rekey_after_messages: 2,
+            ..SessionConfig::default()
+        };
+        let (mut alice, mut bob, _, _) = handshake_pair(config);
+
+        // Both sides rekey before seeing the other's new ratchet key
+        for round in 0..4u8 {
+            let to_bob: Vec<_> = (0..3u8).map(|i| alice.send.encrypt(&[round, i]).unwrap()).collect();
+            let to_alice: Vec<_> = (0..3u8).map(|i| bob.send.encrypt(&[round, i]).unwrap()).collect();
+            for (i, frame) in to_bob.iter().enumerate() {
+                assert_eq!(bob.recv.decrypt(frame).unwrap(), vec![round, i as u8]);
+            }
+            for (i, frame) in to_alice.iter().enumerate() {
+                assert_eq!(alice.recv.decrypt(frame).unwrap(), vec![round, i as u8]);
+            }
+        }
+        assert!(alice.send.epoch() > 1);
+        assert_eq!(alice.recv.epoch(), bob.send.epoch());
+    }
+
+    #[test]
+    fn test_unacknowledged_rekeys_wait_for_the_peer() {
+        let config = SessionConfig {
+            rekey_after_messages: 1,
+            ..SessionConfig::default()
+        };
+        let (mut alice, mut bob, _, _) = handshake_pair(config);
+
+        // Bob rekeys against Alice's handshake key before hearing from her
+        let from_bob: Vec<_> = (0..2u8).map(|i| bob.send.encrypt(&[i]).unwrap()).collect();
+
+        // Alice attempts six rekeys that Bob has not acknowledged
+        let to_bob: Vec<_> = (0..7u8).map(|i| alice.send.encrypt(&[i]).unwrap()).collect();
+        assert_eq!(alice.send.epoch() as usize, MAX_RATCHET_SECRETS - 1);
+        for (i, frame) in to_bob.iter().enumerate() {
+            assert_eq!(bob.recv.decrypt(frame).unwrap(), vec![i as u8]);
+        }
+
+        // The secret Bob combined with is still there
+        for (i, frame) in from_bob.iter().enumerate() {
+            assert_eq!(alice.recv.decrypt(frame).unwrap(), vec![i as u8]);
+        }
+
+        // Bob's next epoch uses Alice's newest key, which frees her to rekey again
+        let frame = bob.send.encrypt(b"ack").unwrap();
+        assert_eq!(alice.recv.decrypt(&frame).unwrap(), b"ack");
+        let frame = alice.send.encrypt(b"next").unwrap();
+        assert_eq!(alice.send.epoch() as usize, MAX_RATCHET_SECRETS);
+        assert_eq!(bob.recv.decrypt(&frame).unwrap(), b"next");
+    }
+
+    #[test]
+    fn test_epoch_key_alone_does_not_follow_a_rekey() {
+        let config = SessionConfig {
+            rekey_after_messages: 1,
+            ..SessionConfig::default()
+        };
+        let (mut alice, mut bob, _, _) = handshake_pair(config);
+        bob.recv.decrypt(&alice.send.encrypt(b"epoch 0").unwrap()).unwrap();
+
+        // An eavesdropper holding the epoch 0 key but none of Bob's ratchet secrets
+        let remote = bob.recv.ratchet.lock().unwrap().remote;
+        let stolen = Ratchet::new(ReusableSecret::random_from_rng(OsRng), remote);
+        let mut eavesdropper = RecvCipher::new(bob.recv.key, Arc::new(Mutex::new(stolen)));
+        eavesdropper.epoch = bob.recv.epoch;
+        eavesdropper.last_counter = bob.recv.last_counter;
+
+        let frame = alice.send.encrypt(b"epoch 1").unwrap();
+        assert!(eavesdropper.decrypt(&frame).is_err());
+        assert_eq!(bob.recv.decrypt(&frame).unwrap(), b"epoch 1");
+    }
+
+    #[test]
+    fn test_forged_responder_rejected() {
+        let alice = NodeKeypair::generate();
+        let bob = NodeKeypair::generate();
+        let mallory = NodeKeypair::generate();
+
+        let (initiator, msg1) = Handshake::initiate(&alice, SessionConfig::default());
+        let mut responder = Handshake::respond(&bob, SessionConfig::default());
+        let msg2 = responder.read_init(&msg1).unwrap();
+
+        // Mallory swaps in her identity but cannot produce Bob's signature
+        let mut response: HandshakeResponse = bincode::deserialize(&msg2).unwrap();
+        response.identity = mallory.public_key().to_bytes();
+        let forged = bincode::serialize(&response).unwrap();
+
+        assert!(initiator.read_response(&forged).is_err());
+    }
+}
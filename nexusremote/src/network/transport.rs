@@ -1,10 +1,48 @@
 //! Network transport layer using QUIC
 
+use crate::core::crypto::{node_id_from_public_key, NodeKeypair};
 use crate::core::types::*;
+use crate::network::forwarding::{RelayControl, RelayRequest};
+use crate::network::secure::{Handshake, HandshakeRole, RecvCipher, SendCipher, SessionConfig};
 use crate::Error;
+use futures::future::poll_fn;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::core::muxing::StreamMuxerExt;
+use libp2p::core::transport::{ListenerId, Transport, TransportEvent};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::{mpsc, oneshot, watch, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+use tracing::{debug, warn};
+
+/// Protocol identifier sent as the first frame of every channel
+const PROTOCOL_ID: &[u8] = b"/nexusremote/quic/1.0.0";
+
+/// Maximum size of a single frame (16 MiB)
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Number of accepted channels buffered before the listener applies backpressure
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Largest message a channel carrying a byte stream sends at once
+const STREAM_CHUNK: usize = 64 * 1024;
+
+/// Stream bytes a relay link buffers for a reader that has fallen behind
+const LINK_BACKLOG: usize = 16 * 1024 * 1024;
+
+/// Tags of the messages on a relay link
+const LINK_DATA: u8 = 0;
+const LINK_END: u8 = 1;
+const LINK_CONTROL: u8 = 2;
 
 /// Transport configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TransportConfig {
     /// Enable IPv6优先
     pub prefer_ipv6: bool,
@@ -14,6 +52,20 @@ pub struct TransportConfig {
     pub connection_timeout: u64,
     /// Keepalive interval (ms)
     pub keepalive_interval: u64,
+    /// Session rekey interval (ms)
+    pub rekey_interval: u64,
+    /// Rekey after this many messages in one direction
+    pub rekey_after_messages: u64,
+}
+
+impl TransportConfig {
+    /// Session settings for channels opened by this transport
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            rekey_after_messages: self.rekey_after_messages,
+            rekey_interval: Duration::from_millis(self.rekey_interval),
+        }
+    }
 }
 
 impl Default for TransportConfig {
@@ -23,59 +75,739 @@ impl Default for TransportConfig {
             use_bbr: true,
             connection_timeout: 5000,
             keepalive_interval: 30000,
+            rekey_interval: 600_000,
+            rekey_after_messages: 1 << 20,
         }
     }
 }
 
-/// QUIC transport manager (placeholder - will integrate with libp2p-quic)
+/// QUIC transport manager built on libp2p-quic
+///
+/// The QUIC TLS handshake is performed with a libp2p identity derived from the
+/// node's `NodeKeypair`, so the remote end of every channel is authenticated
+/// against the `DeviceID` it claims.
 pub struct QuicTransport {
     config: TransportConfig,
+    keypair: NodeKeypair,
+    /// Transport used for outbound connections
+    dialer: std::sync::Mutex<libp2p::quic::tokio::Transport>,
+    /// Channels accepted by the listener task
+    incoming: AsyncMutex<Option<mpsc::Receiver<SecureChannel>>>,
 }
 
 impl QuicTransport {
     /// Create a new QUIC transport
-    pub fn new(config: TransportConfig) -> Self {
-        Self { config }
+    pub fn new(config: TransportConfig, keypair: NodeKeypair) -> Self {
+        let dialer = libp2p::quic::tokio::Transport::new(quic_config(&config, &keypair));
+        Self {
+            config,
+            keypair,
+            dialer: std::sync::Mutex::new(dialer),
+            incoming: AsyncMutex::new(None),
+        }
     }
-    
+
     /// Create a new transport with default config
-    pub fn default() -> Self {
-        Self::new(TransportConfig::default())
+    pub fn with_default_config(keypair: NodeKeypair) -> Self {
+        Self::new(TransportConfig::default(), keypair)
+    }
+
+    /// Get the transport configuration
+    pub fn config(&self) -> &TransportConfig {
+        &self.config
     }
-    
+
     /// Connect to a peer
+    ///
+    /// Each QUIC address in `peer.addresses` is tried in turn (IPv6 first when
+    /// `prefer_ipv6` is set) until one completes the handshake within
+    /// `connection_timeout`.
     pub async fn connect(&self, peer: &PeerInfo) -> Result<SecureChannel, Error> {
-        // Placeholder - will implement with libp2p-quic
-        Err(Error::Network("QUIC transport not implemented yet".to_string()))
+        let candidates = self.dial_candidates(peer);
+        if candidates.is_empty() {
+            return Err(Error::Network(format!(
+                "No dialable QUIC address for {}",
+                peer.device_id
+            )));
+        }
+
+        let timeout = Duration::from_millis(self.config.connection_timeout);
+        let mut last_error = None;
+
+        for addr in candidates {
+            debug!("Dialing {} at {}", peer.device_id, addr);
+            match tokio::time::timeout(timeout, self.dial_addr(addr.clone(), peer)).await {
+                Ok(Ok(channel)) => return Ok(channel),
+                Ok(Err(e)) => {
+                    warn!("Failed to connect to {}: {}", addr, e);
+                    last_error = Some(e);
+                }
+                Err(_) => {
+                    warn!("Connection to {} timed out", addr);
+                    last_error = Some(Error::Network(format!("Connection to {} timed out", addr)));
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| Error::Network("Connection failed".to_string())))
     }
-    
+
+    /// Open an end-to-end channel to `target` through the relay at `relay`
+    ///
+    /// The relay forwards the frames of the inner channel, which is sealed
+    /// between us and the target, so it only sees ciphertext. Naming the
+    /// payment channel we hold with the relay, if any, ranks the session by
+    /// what we have locked in it. The relay asks for payments and hands
+    /// over the session's receipt through the returned link.
+    pub async fn connect_relayed(
+        &self,
+        relay: &PeerInfo,
+        target: &PeerInfo,
+        channel_id: Option<[u8; 32]>,
+    ) -> Result<(SecureChannel, RelayLink), Error> {
+        let channel = self.connect(relay).await?;
+        let request = RelayRequest {
+            target: target.peer_id.clone(),
+            addresses: target.addresses.clone(),
+            channel_id,
+        };
+        channel.send(&serde_json::to_vec(&request)?).await?;
+        let (stream, link) = channel.into_relay_link();
+        let channel = SecureChannel::initiate(
+            stream,
+            target.peer_id.clone(),
+            target.device_id,
+            &self.keypair,
+            self.config.session_config(),
+        ).await?;
+        Ok((channel, link))
+    }
+
     /// Listen for incoming connections
-    pub async fn listen(&self, addrs: Vec<String>) -> Result<(), Error> {
-        // Placeholder - will implement with libp2p-quic
-        Err(Error::Network("QUIC transport not implemented yet".to_string()))
+    ///
+    /// Returns the bound addresses (with ephemeral ports resolved). Accepted
+    /// channels are delivered through [`QuicTransport::accept`].
+    pub async fn listen(&self, addrs: Vec<String>) -> Result<Vec<String>, Error> {
+        let mut incoming = self.incoming.lock().await;
+        if incoming.is_some() {
+            return Err(Error::Network("Transport is already listening".to_string()));
+        }
+
+        let mut transport = libp2p::quic::tokio::Transport::new(quic_config(&self.config, &self.keypair));
+        for addr in &addrs {
+            let multiaddr: Multiaddr = addr.parse()
+                .map_err(|e| Error::Network(format!("Invalid listen address {}: {}", addr, e)))?;
+            transport.listen_on(ListenerId::next(), multiaddr)
+                .map_err(|e| Error::Network(format!("Failed to listen on {}: {}", addr, e)))?;
+        }
+
+        // Wait until every listener has reported its bound address
+        let mut bound = Vec::with_capacity(addrs.len());
+        let timeout = Duration::from_millis(self.config.connection_timeout);
+        while bound.len() < addrs.len() {
+            let event = tokio::time::timeout(timeout, poll_fn(|cx| Pin::new(&mut transport).poll(cx)))
+                .await
+                .map_err(|_| Error::Network("Timed out waiting for listen address".to_string()))?;
+            match event {
+                TransportEvent::NewAddress { listen_addr, .. } => {
+                    debug!("Listening on {}", listen_addr);
+                    bound.push(listen_addr.to_string());
+                }
+                TransportEvent::ListenerError { error, .. } => {
+                    return Err(Error::Network(format!("Listener error: {}", error)));
+                }
+                _ => {}
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_loop(transport, tx, self.keypair.clone(), self.config.clone()));
+        *incoming = Some(rx);
+
+        Ok(bound)
+    }
+
+    /// Wait for the next inbound channel
+    pub async fn accept(&self) -> Result<SecureChannel, Error> {
+        let mut incoming = self.incoming.lock().await;
+        let rx = incoming.as_mut()
+            .ok_or_else(|| Error::Network("Transport is not listening".to_string()))?;
+        rx.recv().await
+            .ok_or_else(|| Error::Network("Listener closed".to_string()))
+    }
+
+    /// Order the peer's QUIC addresses by preference
+    fn dial_candidates(&self, peer: &PeerInfo) -> Vec<Multiaddr> {
+        let mut candidates: Vec<Multiaddr> = peer.addresses.iter()
+            .filter_map(|a| a.parse::<Multiaddr>().ok())
+            .filter(is_quic_addr)
+            .collect();
+
+        let prefer_ipv6 = self.config.prefer_ipv6;
+        candidates.sort_by_key(|addr| {
+            let is_ipv6 = matches!(addr.iter().next(), Some(Protocol::Ip6(_)));
+            is_ipv6 != prefer_ipv6
+        });
+        candidates
+    }
+
+    /// Dial a single address and open the channel stream
+    async fn dial_addr(&self, addr: Multiaddr, peer: &PeerInfo) -> Result<SecureChannel, Error> {
+        let dial = self.dialer.lock().unwrap()
+            .dial(addr.clone())
+            .map_err(|e| Error::Network(format!("Cannot dial {}: {}", addr, e)))?;
+        let (remote, mut connection) = dial.await
+            .map_err(|e| Error::Network(format!("QUIC handshake with {} failed: {}", addr, e)))?;
+
+        let device_id = device_id_from_peer_id(&remote)
+            .ok_or_else(|| Error::Crypto(format!("Peer {} has no Ed25519 identity", remote)))?;
+        if device_id != peer.device_id {
+            return Err(Error::Crypto(format!(
+                "Peer identity mismatch: expected {}, got {}",
+                peer.device_id, device_id
+            )));
+        }
+
+        let stream = poll_fn(|cx| connection.poll_outbound_unpin(cx)).await
+            .map_err(|e| Error::Network(format!("Failed to open stream: {}", e)))?;
+
+        let channel = SecureChannel::from_stream(PeerID::new(remote.to_string()), device_id, stream)
+            .with_connection(connection);
+        channel.send(PROTOCOL_ID).await?;
+
+        channel.handshake(&self.keypair, HandshakeRole::Initiator, self.config.session_config()).await
     }
 }
 
+/// Drive the listening transport and hand accepted channels to `tx`
+async fn accept_loop(
+    mut transport: libp2p::quic::tokio::Transport,
+    tx: mpsc::Sender<SecureChannel>,
+    keypair: NodeKeypair,
+    config: TransportConfig,
+) {
+    loop {
+        let event = tokio::select! {
+            event = poll_fn(|cx| Pin::new(&mut transport).poll(cx)) => event,
+            _ = tx.closed() => break,
+        };
+
+        match event {
+            TransportEvent::Incoming { upgrade, send_back_addr, .. } => {
+                let tx = tx.clone();
+                let keypair = keypair.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let timeout = Duration::from_millis(config.connection_timeout);
+                    match tokio::time::timeout(timeout, accept_channel(upgrade, &keypair, &config)).await {
+                        Ok(Ok(channel)) => {
+                            let _ = tx.send(channel).await;
+                        }
+                        Ok(Err(e)) => warn!("Rejected connection from {}: {}", send_back_addr, e),
+                        Err(_) => warn!("Handshake with {} timed out", send_back_addr),
+                    }
+                });
+            }
+            TransportEvent::ListenerClosed { .. } => break,
+            TransportEvent::ListenerError { error, .. } => warn!("Listener error: {}", error),
+            _ => {}
+        }
+    }
+    debug!("QUIC listener stopped");
+}
+
+/// Complete an inbound connection and wait for the dialer's first stream
+async fn accept_channel(
+    upgrade: libp2p::quic::Connecting,
+    keypair: &NodeKeypair,
+    config: &TransportConfig,
+) -> Result<SecureChannel, Error> {
+    let (remote, mut connection) = upgrade.await
+        .map_err(|e| Error::Network(format!("QUIC handshake failed: {}", e)))?;
+    let device_id = device_id_from_peer_id(&remote)
+        .ok_or_else(|| Error::Crypto(format!("Peer {} has no Ed25519 identity", remote)))?;
+
+    let stream = poll_fn(|cx| connection.poll_inbound_unpin(cx)).await
+        .map_err(|e| Error::Network(format!("Failed to accept stream: {}", e)))?;
+
+    let channel = SecureChannel::from_stream(PeerID::new(remote.to_string()), device_id, stream)
+        .with_connection(connection);
+    if channel.receive().await? != PROTOCOL_ID {
+        return Err(Error::Network("Unsupported protocol".to_string()));
+    }
+
+    let channel = channel.handshake(keypair, HandshakeRole::Responder, config.session_config()).await?;
+    // The session identity must match the one authenticated by QUIC TLS
+    if channel.device_id != device_id {
+        return Err(Error::Crypto("Session identity does not match connection identity".to_string()));
+    }
+
+    Ok(channel)
+}
+
+/// Build the libp2p-quic configuration from our transport settings
+fn quic_config(config: &TransportConfig, keypair: &NodeKeypair) -> libp2p::quic::Config {
+    let mut quic = libp2p::quic::Config::new(&libp2p_keypair(keypair));
+    quic.handshake_timeout = Duration::from_millis(config.connection_timeout);
+    quic.keep_alive_interval = Duration::from_millis(config.keepalive_interval);
+    // Drop the connection after three missed keepalives
+    quic.max_idle_timeout = config.keepalive_interval.saturating_mul(3).min(u32::MAX as u64) as u32;
+    // BBR is not exposed by libp2p-quic; `use_bbr` is currently advisory
+    quic
+}
+
+fn is_quic_addr(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::QuicV1))
+}
+
+/// Derive the libp2p identity for a node keypair
+pub(crate) fn libp2p_keypair(keypair: &NodeKeypair) -> libp2p::identity::Keypair {
+    libp2p::identity::Keypair::ed25519_from_bytes(*keypair.secret_key().as_bytes())
+        .expect("32-byte Ed25519 secret is always valid")
+}
+
+/// Recover the DeviceID of a peer from its (Ed25519) libp2p PeerId
+pub(crate) fn device_id_from_peer_id(peer_id: &PeerId) -> Option<DeviceID> {
+    let multihash: &libp2p::multihash::Multihash<64> = peer_id.as_ref();
+    // Ed25519 keys are small enough to be inlined with the identity hash
+    if multihash.code() != 0x00 {
+        return None;
+    }
+    let public_key = libp2p::identity::PublicKey::try_decode_protobuf(multihash.digest()).ok()?;
+    let ed25519 = public_key.try_into_ed25519().ok()?;
+    Some(node_id_from_public_key(&ed25519.to_bytes()))
+}
+
+type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+struct ReadHalf {
+    io: BoxedReader,
+    cipher: Option<RecvCipher>,
+}
+
+struct WriteHalf {
+    io: BoxedWriter,
+    cipher: Option<SendCipher>,
+}
+
 /// Secure channel for encrypted communication
+///
+/// Messages are carried as length-prefixed frames over an underlying byte
+/// stream (a QUIC stream when created by [`QuicTransport`]). Once the session
+/// handshake has run, every frame is sealed end-to-end with ChaCha20-Poly1305,
+/// so the channel stays confidential even when the stream is relayed.
 pub struct SecureChannel {
     /// Channel ID
     pub channel_id: [u8; 32],
     /// Remote peer
     pub peer_id: PeerID,
+    /// Remote device
+    pub device_id: DeviceID,
+    /// Remote identity key, once the handshake has run
+    pub remote_public_key: [u8; 32],
     /// Channel is encrypted
     pub is_encrypted: bool,
+    reader: AsyncMutex<ReadHalf>,
+    writer: AsyncMutex<WriteHalf>,
+    /// Underlying QUIC connection, kept alive for the channel's lifetime
+    connection: Option<std::sync::Mutex<libp2p::quic::Connection>>,
 }
 
 impl SecureChannel {
+    /// Create an unencrypted framed channel over an arbitrary byte stream
+    pub fn from_stream<S>(peer_id: PeerID, device_id: DeviceID, stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = stream.split();
+        Self {
+            channel_id: rand::random(),
+            peer_id,
+            device_id,
+            remote_public_key: [0u8; 32],
+            is_encrypted: false,
+            reader: AsyncMutex::new(ReadHalf { io: Box::new(reader), cipher: None }),
+            writer: AsyncMutex::new(WriteHalf { io: Box::new(writer), cipher: None }),
+            connection: None,
+        }
+    }
+
+    /// Open an encrypted channel to `expected` over an arbitrary byte stream
+    ///
+    /// Fails if the responder's identity does not hash to `expected`.
+    pub async fn initiate<S>(
+        stream: S,
+        peer_id: PeerID,
+        expected: DeviceID,
+        keypair: &NodeKeypair,
+        config: SessionConfig,
+    ) -> Result<Self, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::from_stream(peer_id, expected, stream)
+            .handshake(keypair, HandshakeRole::Initiator, config)
+            .await
+    }
+
+    /// Accept an encrypted channel over an arbitrary byte stream
+    ///
+    /// The remote `device_id` is learned from the handshake.
+    pub async fn respond<S>(
+        stream: S,
+        peer_id: PeerID,
+        keypair: &NodeKeypair,
+        config: SessionConfig,
+    ) -> Result<Self, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::from_stream(peer_id, DeviceID::new([0u8; 32]), stream)
+            .handshake(keypair, HandshakeRole::Responder, config)
+            .await
+    }
+
+    /// Run the session handshake and switch the channel to encrypted frames
+    async fn handshake(
+        mut self,
+        keypair: &NodeKeypair,
+        role: HandshakeRole,
+        config: SessionConfig,
+    ) -> Result<Self, Error> {
+        let session = match role {
+            HandshakeRole::Initiator => {
+                let (handshake, init) = Handshake::initiate(keypair, config);
+                self.send(&init).await?;
+                let response = self.receive().await?;
+                let (finish, session) = handshake.read_response(&response)?;
+                self.send(&finish).await?;
+                session
+            }
+            HandshakeRole::Responder => {
+                let mut handshake = Handshake::respond(keypair, config);
+                let init = self.receive().await?;
+                let response = handshake.read_init(&init)?;
+                self.send(&response).await?;
+                let finish = self.receive().await?;
+                handshake.read_finish(&finish)?
+            }
+        };
+
+        match role {
+            HandshakeRole::Initiator if session.remote_device != self.device_id => {
+                return Err(Error::Crypto(format!(
+                    "Session peer mismatch: expected {}, got {}",
+                    self.device_id, session.remote_device
+                )));
+            }
+            _ => self.device_id = session.remote_device,
+        }
+
+        self.remote_public_key = session.remote_public_key;
+        self.reader.get_mut().cipher = Some(session.recv);
+        self.writer.get_mut().cipher = Some(session.send);
+        self.is_encrypted = true;
+        Ok(self)
+    }
+
+    fn with_connection(mut self, connection: libp2p::quic::Connection) -> Self {
+        self.connection = Some(std::sync::Mutex::new(connection));
+        self
+    }
+
     /// Send data over the channel
     pub async fn send(&self, data: &[u8]) -> Result<(), Error> {
-        // Placeholder
-        Ok(())
+        let mut writer = self.writer.lock().await;
+        let WriteHalf { io, cipher } = &mut *writer;
+        match cipher {
+            Some(cipher) => {
+                let sealed = cipher.encrypt(data)?;
+                write_frame(io, &sealed).await
+            }
+            None => write_frame(io, data).await,
+        }
     }
-    
+
     /// Receive data from the channel
     pub async fn receive(&self) -> Result<Vec<u8>, Error> {
-        // Placeholder
-        Ok(vec![])
+        let mut reader = self.reader.lock().await;
+        let ReadHalf { io, cipher } = &mut *reader;
+        let frame = read_frame(io).await?;
+        match cipher {
+            Some(cipher) => cipher.decrypt(&frame),
+            None => Ok(frame),
+        }
+    }
+
+    /// Close the sending side of the channel
+    pub async fn close(&self) -> Result<(), Error> {
+        self.writer.lock().await.io.close().await?;
+        Ok(())
+    }
+
+    /// Whether the channel is backed by a QUIC connection
+    pub fn is_quic(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Carry a byte stream over the channel's messages
+    ///
+    /// Bytes written to the returned stream are sent in messages, and the
+    /// messages received are read back from it in order. Closing its write
+    /// half closes our sending side, and the peer closing ends it. Relays
+    /// use this to forward the frames of a channel nested inside this one.
+    pub fn into_stream(self) -> Compat<DuplexStream> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let (stream, bridge) = tokio::io::duplex(STREAM_CHUNK);
+        let (mut outbound, mut inbound) = tokio::io::split(bridge);
+        let channel = Arc::new(self);
+        let sender = channel.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; STREAM_CHUNK];
+            while let Ok(n) = outbound.read(&mut buf).await {
+                if n == 0 {
+                    let _ = sender.close().await;
+                    break;
+                }
+                if sender.send(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok(data) = channel.receive().await {
+                if inbound.write_all(&data).await.is_err() {
+                    return;
+                }
+            }
+            let _ = inbound.shutdown().await;
+        });
+        stream.compat()
+    }
+
+    /// Carry a byte stream over the channel's messages, beside relay control messages
+    ///
+    /// Like [`SecureChannel::into_stream`], except that the stream ending
+    /// is a message of its own, and the channel stays open for control
+    /// messages until the returned link is dropped as well. Received stream
+    /// bytes are buffered up to `LINK_BACKLOG`, so control messages are not
+    /// held up behind a reader that has paused. Clients and relays use this
+    /// to settle a relay session on the channel it runs over.
+    pub fn into_relay_link(self) -> (Compat<DuplexStream>, RelayLink) {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let (stream, bridge) = tokio::io::duplex(STREAM_CHUNK);
+        let (mut outbound, mut inbound) = tokio::io::split(bridge);
+        let channel = Arc::new(self);
+        let control = channel.clone();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (relayed_tx, relayed) = watch::channel(0u64);
+        let relayed_tx = Arc::new(relayed_tx);
+        let (closing, closed) = oneshot::channel::<()>();
+
+        let (sender, sent) = (channel.clone(), relayed_tx.clone());
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; STREAM_CHUNK];
+            while let Ok(n) = outbound.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                let mut message = Vec::with_capacity(n + 1);
+                message.push(LINK_DATA);
+                message.extend_from_slice(&buf[..n]);
+                if sender.send(&message).await.is_err() {
+                    return;
+                }
+                sent.send_modify(|relayed| *relayed += n as u64);
+            }
+            if sender.send(&[LINK_END]).await.is_ok() {
+                // Waits for the link to be dropped
+                let _ = closed.await;
+                let _ = sender.close().await;
+            }
+        });
+
+        let (data_tx, mut data_rx) = mpsc::unbounded_channel::<(Vec<u8>, OwnedSemaphorePermit)>();
+        tokio::spawn(async move {
+            while let Some((data, _permit)) = data_rx.recv().await {
+                if inbound.write_all(&data).await.is_err() {
+                    return;
+                }
+            }
+            let _ = inbound.shutdown().await;
+        });
+        tokio::spawn(async move {
+            let backlog = Arc::new(Semaphore::new(LINK_BACKLOG));
+            let mut data_tx = Some(data_tx);
+            while let Ok(message) = channel.receive().await {
+                match message.split_first() {
+                    Some((&LINK_DATA, data)) => {
+                        let Some(data_tx) = &data_tx else { continue };
+                        relayed_tx.send_modify(|relayed| *relayed += data.len() as u64);
+                        let permits = data.len().min(LINK_BACKLOG) as u32;
+                        let Ok(permit) = backlog.clone().acquire_many_owned(permits).await else { break };
+                        let _ = data_tx.send((data.to_vec(), permit));
+                    }
+                    Some((&LINK_END, _)) => data_tx = None,
+                    Some((&LINK_CONTROL, control)) => {
+                        let _ = incoming_tx.send(control.to_vec());
+                    }
+                    _ => debug!("Ignoring a malformed relay link message from {}", channel.peer_id),
+                }
+            }
+        });
+
+        let link = RelayLink { channel: control, incoming, relayed, _closing: closing };
+        (stream.compat(), link)
+    }
+}
+
+/// Control side of a channel between a client and its relay
+///
+/// Created by [`SecureChannel::into_relay_link`]. Control messages keep
+/// flowing after the relayed stream has ended, until the link is dropped.
+pub struct RelayLink {
+    channel: Arc<SecureChannel>,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Stream bytes sent and received so far
+    relayed: watch::Receiver<u64>,
+    /// Dropped with the link, which lets the channel close once the stream has ended
+    _closing: oneshot::Sender<()>,
+}
+
+impl RelayLink {
+    /// Remote peer
+    pub fn peer_id(&self) -> &PeerID {
+        &self.channel.peer_id
+    }
+
+    /// Send a control message
+    pub async fn send(&self, message: &RelayControl) -> Result<(), Error> {
+        let mut frame = vec![LINK_CONTROL];
+        frame.extend(serde_json::to_vec(message)?);
+        self.channel.send(&frame).await
+    }
+
+    /// Receive the next control message
+    pub async fn receive(&mut self) -> Result<RelayControl, Error> {
+        let message = self.incoming.recv().await
+            .ok_or_else(|| Error::Network("Channel closed".to_string()))?;
+        Ok(serde_json::from_slice(&message)?)
+    }
+
+    /// Stream bytes sent and received so far
+    pub fn relayed(&self) -> u64 {
+        *self.relayed.borrow()
+    }
+
+    /// Wait until `bytes` of the stream have been sent and received, returning how many have
+    ///
+    /// Returns early if the channel closes first.
+    pub async fn wait_relayed(&mut self, bytes: u64) -> u64 {
+        let _ = self.relayed.wait_for(|relayed| *relayed >= bytes).await;
+        self.relayed()
+    }
+}
+
+/// Write a length-prefixed frame
+pub async fn write_frame<W: AsyncWrite + Unpin + ?Sized>(writer: &mut W, data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(Error::Network(format!("Frame too large: {} bytes", data.len())));
+    }
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a length-prefixed frame
+pub async fn read_frame<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::Network("Channel closed".to_string()),
+        _ => Error::Io(e),
+    })?;
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Network(format!("Frame too large: {} bytes", len)));
+    }
+
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_for(keypair: &NodeKeypair, addresses: Vec<String>) -> PeerInfo {
+        PeerInfo {
+            peer_id: PeerID::new(keypair.node_id().to_hex()),
+            device_id: keypair.node_id(),
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Idle,
+            addresses,
+            available_bandwidth: 0,
+            identity_proof: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quic_loopback_exchange() {
+        let server_keys = NodeKeypair::generate();
+        let client_keys = NodeKeypair::generate();
+
+        let server = QuicTransport::with_default_config(server_keys.clone());
+        let addrs = server.listen(vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()]).await.unwrap();
+        assert_eq!(addrs.len(), 1);
+
+        let client = QuicTransport::with_default_config(client_keys.clone());
+        let server_peer = peer_for(&server_keys, addrs);
+
+        let (outbound, inbound) = tokio::join!(client.connect(&server_peer), server.accept());
+        let outbound = outbound.unwrap();
+        let inbound = inbound.unwrap();
+
+        assert_eq!(outbound.device_id, server_keys.node_id());
+        assert_eq!(inbound.device_id, client_keys.node_id());
+        assert!(outbound.is_encrypted && inbound.is_encrypted);
+
+        outbound.send(b"ping").await.unwrap();
+        assert_eq!(inbound.receive().await.unwrap(), b"ping");
+
+        inbound.send(b"pong").await.unwrap();
+        assert_eq!(outbound.receive().await.unwrap(), b"pong");
+
+        // Empty and multi-frame payloads keep their boundaries
+        outbound.send(b"").await.unwrap();
+        outbound.send(&[7u8; 100_000]).await.unwrap();
+        assert_eq!(inbound.receive().await.unwrap(), Vec::<u8>::new());
+        assert_eq!(inbound.receive().await.unwrap(), vec![7u8; 100_000]);
+    }
+
+    #[tokio::test]
+    async fn test_quic_rejects_wrong_identity() {
+        let server_keys = NodeKeypair::generate();
+        let server = QuicTransport::with_default_config(server_keys);
+        let addrs = server.listen(vec!["/ip4/127.0.0.1/udp/0/quic-v1".to_string()]).await.unwrap();
+
+        // Claim the server's address under somebody else's identity
+        let impostor = peer_for(&NodeKeypair::generate(), addrs);
+        let client = QuicTransport::with_default_config(NodeKeypair::generate());
+
+        let result = client.connect(&impostor).await;
+        assert!(matches!(result, Err(Error::Crypto(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_without_quic_address() {
+        let client = QuicTransport::with_default_config(NodeKeypair::generate());
+        let peer = peer_for(&NodeKeypair::generate(), vec!["/ip4/127.0.0.1/tcp/4001".to_string()]);
+
+        assert!(client.connect(&peer).await.is_err());
     }
 }
@@ -1,17 +1,22 @@
 //! Network simulator for testing NexusRemote
 
-use crate::core::crypto::NodeKeypair;
+use crate::core::crypto::{IdentityProof, NodeKeypair};
 use crate::core::distance::*;
 use crate::core::types::*;
-use crate::network::dht::{InMemoryDht, WeightedRoutingTable};
+use crate::network::attestation::{Attestation, ReputationLedger, SessionOutcome, TrustConfig};
+use crate::network::dht::{AdmissionPolicy, AssumeAlive, InMemoryDht, InsertOutcome, PeerPinger, WeightedRoutingTable, MAX_PEERS_PER_SUBNET};
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use tracing::{info, debug};
 
 /// Simulated network node
 #[derive(Clone)]
 pub struct SimulatedNode {
-    /// Peer info
+    /// Node identity
+    pub keypair: NodeKeypair,
+    /// Peer info; `reputation` is the node's true reliability, which others
+    /// only learn through attestations
     pub info: PeerInfo,
     /// DHT node
     pub dht: InMemoryDht,
@@ -34,13 +39,15 @@ impl SimulatedNode {
             role: NodeRole::Idle,
             addresses: vec![],
             available_bandwidth: 100_000_000,
+            identity_proof: None,
         };
         
         let dht = InMemoryDht::new(peer_info.clone());
-        let wallet = crate::wallet::wallet::InMemoryWallet::with_initial_balance(keypair, initial_balance);
-        let routing_table = WeightedRoutingTable::new(peer_info.clone(), 20);
+        let wallet = crate::wallet::wallet::InMemoryWallet::with_initial_balance(keypair.clone(), initial_balance);
+        let routing_table = WeightedRoutingTable::with_admission(peer_info.clone(), 20, AdmissionPolicy::disabled());
         
         Self {
+            keypair,
             info: peer_info,
             dht,
             wallet,
@@ -49,9 +56,22 @@ impl SimulatedNode {
     }
 }
 
+/// Pings simulated nodes by checking whether they are online
+struct SimulatedPinger<'a> {
+    online: &'a HashSet<DeviceID>,
+}
+
+impl PeerPinger for SimulatedPinger<'_> {
+    fn ping(&mut self, peer: &PeerInfo) -> Option<bool> {
+        Some(self.online.contains(&peer.device_id))
+    }
+}
+
 /// Network simulation results
 #[derive(Debug, Clone)]
 pub struct SimulationResults {
+    /// Distance policy the nodes routed with
+    pub policy: &'static str,
     /// Total nodes
     pub total_nodes: usize,
     /// High reputation nodes count
@@ -64,6 +84,56 @@ pub struct SimulationResults {
     pub high_rep_selection_rate: f64,
     /// Average path length
     pub average_path_length: f64,
+    /// Jain's fairness index of the routing distribution over all nodes
+    /// (1.0 when every node is selected equally often)
+    pub fairness: f64,
+    /// Share of lookups that found the online node closest to the target by XOR
+    pub lookup_success_rate: f64,
+}
+
+/// Sybil attack scenario
+#[derive(Debug, Clone)]
+pub struct SybilConfig {
+    /// Honest peers, each on its own /24
+    pub honest_nodes: usize,
+    /// Identities the attacker tries to insert, all from one /24
+    pub sybil_identities: usize,
+    /// Whether the attacker mines an identity proof for every identity
+    pub attacker_mines_proofs: bool,
+    /// Identity proof difficulty for this scenario
+    pub difficulty: u32,
+    /// Whether the victim's routing table enforces admission checks
+    pub enforce_admission: bool,
+}
+
+impl Default for SybilConfig {
+    fn default() -> Self {
+        Self {
+            honest_nodes: 100,
+            sybil_identities: 500,
+            attacker_mines_proofs: true,
+            // Low enough to keep simulations fast
+            difficulty: 8,
+            enforce_admission: true,
+        }
+    }
+}
+
+/// Sybil attack results
+#[derive(Debug, Clone)]
+pub struct SybilResults {
+    /// Sybil identities the attacker tried to insert
+    pub attempted: usize,
+    /// Sybil identities that made it into the victim's routing table
+    pub admitted_sybils: usize,
+    /// Honest peers in the victim's routing table
+    pub admitted_honest: usize,
+    /// Insertions the routing table refused
+    pub refused: usize,
+    /// Most sybils found in a single bucket
+    pub max_sybils_per_bucket: usize,
+    /// Share of the routing table held by the attacker
+    pub sybil_fraction: f64,
 }
 
 /// Network simulator
@@ -72,6 +142,8 @@ pub struct NetworkSimulator {
     nodes: HashMap<DeviceID, SimulatedNode>,
     /// Random number generator
     rng: rand::rngs::StdRng,
+    /// Distance policy every node routes with
+    policy: Arc<dyn DistancePolicy>,
 }
 
 impl NetworkSimulator {
@@ -80,17 +152,40 @@ impl NetworkSimulator {
         Self {
             nodes: HashMap::new(),
             rng: rand::SeedableRng::seed_from_u64(42),
+            policy: DistancePolicyKind::default().policy(),
         }
     }
     
     /// Add a node to the simulation
-    pub fn add_node(&mut self, node: SimulatedNode) {
+    pub fn add_node(&mut self, mut node: SimulatedNode) {
+        node.dht.set_distance_policy(self.policy.clone());
+        node.routing_table.set_distance_policy(self.policy.clone());
         self.nodes.insert(node.info.device_id, node);
     }
     
+    /// Make every node, current and future, route with `policy`
+    pub fn set_distance_policy(&mut self, policy: Arc<dyn DistancePolicy>) {
+        for node in self.nodes.values_mut() {
+            node.dht.set_distance_policy(policy.clone());
+            node.routing_table.set_distance_policy(policy.clone());
+        }
+        self.policy = policy;
+    }
+    
+    /// Take a node offline or bring it back
+    pub fn set_online(&mut self, device_id: &DeviceID, online: bool) {
+        if let Some(node) = self.nodes.get(device_id) {
+            if online {
+                node.dht.reconnect();
+            } else {
+                node.dht.disconnect();
+            }
+        }
+    }
+    
     /// Create N random nodes
     pub fn create_random_nodes(&mut self, count: usize) {
-        for i in 0..count {
+        for _ in 0..count {
             // 30% high reputation, 70% low/medium
             let reputation = if self.rng.gen::<f64>() < 0.3 {
                 ReputationScore::new(self.rng.gen_range(700..=1000))
@@ -166,14 +261,68 @@ impl NetworkSimulator {
             connections_to_add.push((*node_id, targets));
         }
         
-        // Now apply the connections
+        // Now apply the connections; full buckets ping their oldest entry before evicting it
+        let online: HashSet<DeviceID> = self.nodes.values()
+            .filter(|n| n.dht.is_online())
+            .map(|n| n.info.device_id)
+            .collect();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         for (node_id, targets) in connections_to_add {
-            let node = self.nodes.get_mut(&node_id).unwrap();
-            
             for peer_info in targets {
-                node.routing_table.add_peer(peer_info);
+                // DHT clones share state, so linking a clone links the node itself
+                let mut other_dht = self.nodes.get(&peer_info.device_id).unwrap().dht.clone();
+                let node = self.nodes.get_mut(&node_id).unwrap();
+                node.dht.connect_to(&mut other_dht);
+                node.routing_table.insert(peer_info, now, &mut SimulatedPinger { online: &online });
+            }
+        }
+    }
+    
+    /// Run relay sessions between random nodes, then let every node rank the
+    /// others by EigenTrust over the resulting attestations
+    ///
+    /// A session through a relay succeeds with probability `reputation / 1000`.
+    pub fn simulate_relay_sessions(&mut self, sessions: usize) {
+        let node_ids: Vec<_> = self.nodes.keys().cloned().collect();
+        if node_ids.len() < 2 {
+            return;
+        }
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        
+        // Attestations are public, so one verified set serves every node
+        let mut ledger = ReputationLedger::new(node_ids[0], TrustConfig::default());
+        for i in 0..sessions {
+            let client = node_ids[self.rng.gen_range(0..node_ids.len())];
+            let mut relay = client;
+            while relay == client {
+                relay = node_ids[self.rng.gen_range(0..node_ids.len())];
             }
+            let reliability = self.nodes[&relay].info.reputation.value();
+            let success = self.rng.gen_range(0..1000) < reliability;
+            let outcome = SessionOutcome {
+                success,
+                bytes_relayed: if success { self.rng.gen_range(1..=50) << 20 } else { 0 },
+                latency_ms: self.rng.gen_range(20..200),
+            };
+            let mut session_id = [0u8; 32];
+            session_id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            let attestation = Attestation::new(&self.nodes[&client].keypair, relay, session_id, outcome, now);
+            ledger.add(attestation).expect("simulated attestations are valid");
+        }
+        
+        for node in self.nodes.values_mut() {
+            let mut view = ledger.for_node(node.info.device_id);
+            view.compute();
+            node.dht.set_reputations(view.scores().clone());
+            node.routing_table.set_reputations(view.scores().clone());
         }
+        debug!("Simulated {} relay sessions", sessions);
     }
     
     /// Run routing simulation
@@ -183,6 +332,8 @@ impl NetworkSimulator {
         let mut routing_distribution = HashMap::new();
         let mut total_high_rep_selected = 0;
         let mut total_queries = 0;
+        let mut total_hops = 0;
+        let mut successful_lookups = 0;
         
         let node_ids: Vec<_> = self.nodes.keys().cloned().collect();
         
@@ -195,10 +346,20 @@ impl NetworkSimulator {
             // Pick a random target
             let target = DeviceID::new(self.rng.gen());
             
-            // Find closest peers using weighted routing
-            let closest = source.routing_table.find_closest_peers(target, 5);
+            // Find closest peers with an iterative weighted lookup
+            let lookup = source.dht.lookup(target);
+            total_hops += lookup.hops;
             
-            for peer in &closest {
+            let nearest = self.nodes.values()
+                .filter(|n| n.info.device_id != source_id && n.dht.is_online())
+                .map(|n| n.info.device_id)
+                .min_by(|a, b| compare_raw_distances(&calculate_raw_xor_distance(a, &target), &calculate_raw_xor_distance(b, &target)));
+            if nearest.is_some_and(|nearest| lookup.peers.iter().any(|p| p.device_id == nearest)) {
+                successful_lookups += 1;
+            }
+            let closest = lookup.peers.iter().take(5);
+            
+            for peer in closest {
                 *routing_distribution.entry(peer.device_id).or_insert(0) += 1;
                 
                 // Check if this is a high reputation node
@@ -224,23 +385,141 @@ impl NetworkSimulator {
             0.0
         };
         
-        info!("Simulation complete!");
+        let average_path_length = if num_lookups > 0 {
+            total_hops as f64 / num_lookups as f64
+        } else {
+            0.0
+        };
+        
+        let (sum, sum_of_squares) = routing_distribution.values()
+            .fold((0.0, 0.0), |(sum, squares), &count| (sum + count as f64, squares + (count * count) as f64));
+        let fairness = if sum_of_squares > 0.0 {
+            sum * sum / (total_nodes as f64 * sum_of_squares)
+        } else {
+            0.0
+        };
+        
+        let lookup_success_rate = if num_lookups > 0 {
+            successful_lookups as f64 / num_lookups as f64
+        } else {
+            0.0
+        };
+        
+        info!("Simulation complete ({} policy)!", self.policy.name());
         info!("High reputation selection rate: {:.2}%", high_rep_selection_rate * 100.0);
+        info!("Average path length: {:.2} hops", average_path_length);
+        info!("Routing fairness: {:.3}", fairness);
+        info!("Lookup success rate: {:.2}%", lookup_success_rate * 100.0);
         
         SimulationResults {
+            policy: self.policy.name(),
             total_nodes,
             high_rep_nodes,
             low_rep_nodes,
             routing_distribution,
             high_rep_selection_rate,
-            average_path_length: 0.0,
+            average_path_length,
+            fairness,
+            lookup_success_rate,
         }
     }
     
+    /// Run the same lookups under each policy in turn
+    ///
+    /// The simulator keeps the last policy afterwards.
+    pub fn compare_distance_policies(&mut self, policies: &[Arc<dyn DistancePolicy>], num_lookups: usize) -> Vec<SimulationResults> {
+        let rng = self.rng.clone();
+        policies.iter()
+            .map(|policy| {
+                self.rng = rng.clone();
+                self.set_distance_policy(policy.clone());
+                self.run_routing_simulation(num_lookups)
+            })
+            .collect()
+    }
+    
+    /// Insert an attacker's Sybil identities into a victim's routing table,
+    /// followed by honest peers, and count who ends up in it
+    pub fn run_sybil_simulation(&mut self, config: &SybilConfig) -> SybilResults {
+        info!("Starting Sybil simulation with {} identities...", config.sybil_identities);
+        
+        let victim = SimulatedNode::new(ReputationScore::DEFAULT, TokenAmount::ZERO).info;
+        let admission = if config.enforce_admission {
+            AdmissionPolicy {
+                identity_difficulty: Some(config.difficulty),
+                max_per_subnet: Some(MAX_PEERS_PER_SUBNET),
+            }
+        } else {
+            AdmissionPolicy::disabled()
+        };
+        let mut table = WeightedRoutingTable::with_admission(victim, 20, admission);
+        
+        let peer = |keypair: &NodeKeypair, address: String, proof: Option<IdentityProof>| PeerInfo {
+            peer_id: PeerID::new(keypair.node_id().to_hex()),
+            device_id: keypair.node_id(),
+            reputation: ReputationScore::DEFAULT,
+            role: NodeRole::Idle,
+            addresses: vec![address],
+            available_bandwidth: 100_000_000,
+            identity_proof: proof,
+        };
+        
+        // The attacker controls a single /24 and gets in first
+        let mut sybils = HashSet::new();
+        let mut arrivals = Vec::new();
+        for i in 0..config.sybil_identities {
+            let keypair = NodeKeypair::generate();
+            let proof = config.attacker_mines_proofs.then(|| IdentityProof::solve(&keypair, config.difficulty));
+            sybils.insert(keypair.node_id());
+            arrivals.push(peer(&keypair, format!("/ip4/203.0.113.{}/udp/4001/quic-v1", i % 256), proof));
+        }
+        for i in 0..config.honest_nodes {
+            let keypair = NodeKeypair::generate();
+            let proof = Some(IdentityProof::solve(&keypair, config.difficulty));
+            arrivals.push(peer(&keypair, format!("/ip4/10.{}.{}.1/udp/4001/quic-v1", i / 256, i % 256), proof));
+        }
+        
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut refused = 0;
+        for peer in arrivals {
+            if table.insert(peer, now, &mut AssumeAlive) == InsertOutcome::Refused {
+                refused += 1;
+            }
+        }
+        
+        let mut per_bucket: HashMap<usize, usize> = HashMap::new();
+        let mut admitted_honest = 0;
+        for peer in table.all_peers() {
+            if sybils.contains(&peer.device_id) {
+                *per_bucket.entry(table.bucket_index(&peer.device_id)).or_insert(0) += 1;
+            } else {
+                admitted_honest += 1;
+            }
+        }
+        let admitted_sybils: usize = per_bucket.values().sum();
+        let total = admitted_sybils + admitted_honest;
+        
+        let results = SybilResults {
+            attempted: config.sybil_identities,
+            admitted_sybils,
+            admitted_honest,
+            refused,
+            max_sybils_per_bucket: per_bucket.values().copied().max().unwrap_or(0),
+            sybil_fraction: if total > 0 { admitted_sybils as f64 / total as f64 } else { 0.0 },
+        };
+        info!("Admitted {} of {} Sybil identities ({:.2}% of the table)",
+            results.admitted_sybils, results.attempted, results.sybil_fraction * 100.0);
+        results
+    }
+    
     /// Demonstrate that high reputation nodes are preferred
     pub fn demonstrate_weighted_routing(&mut self) -> bool {
         self.create_random_nodes(100);
         self.connect_mesh(10);
+        self.simulate_relay_sessions(1000);
         
         let results = self.run_routing_simulation(1000);
         
@@ -299,5 +578,68 @@ mod tests {
         
         assert_eq!(results.total_nodes, 50);
         assert!(!results.routing_distribution.is_empty());
+        assert!(results.average_path_length >= 1.0);
+    }
+    
+    #[test]
+    fn test_attested_reputation_tracks_reliability() {
+        let mut sim = NetworkSimulator::new();
+        sim.create_random_nodes(30);
+        sim.connect_mesh(5);
+        sim.simulate_relay_sessions(600);
+        
+        // Every node sees reliable relays ranked above unreliable ones on average
+        let nodes = sim.nodes();
+        let observer = nodes.values().next().unwrap();
+        let average = |high: bool| {
+            let scores: Vec<u64> = nodes.values()
+                .filter(|n| n.info.device_id != observer.info.device_id)
+                .filter(|n| (n.info.reputation.value() >= 700) == high)
+                .map(|n| observer.routing_table.reputation_of(&n.info.device_id).value())
+                .collect();
+            scores.iter().sum::<u64>() as f64 / scores.len().max(1) as f64
+        };
+        assert!(average(true) > average(false));
+    }
+    
+    #[test]
+    fn test_sybil_admission() {
+        let mut sim = NetworkSimulator::new();
+        let config = SybilConfig { honest_nodes: 20, sybil_identities: 200, ..Default::default() };
+        
+        // Without checks the attacker's identities fill the table
+        let open = sim.run_sybil_simulation(&SybilConfig { enforce_admission: false, attacker_mines_proofs: false, ..config.clone() });
+        assert_eq!(open.refused, 0);
+        assert!(open.admitted_sybils > open.admitted_honest);
+        
+        // Identities without a proof are refused outright
+        let unproven = sim.run_sybil_simulation(&SybilConfig { attacker_mines_proofs: false, ..config.clone() });
+        assert_eq!(unproven.admitted_sybils, 0);
+        assert_eq!(unproven.admitted_honest, 20);
+        
+        // Mined identities still share one /24, so each bucket holds at most two
+        let mined = sim.run_sybil_simulation(&config);
+        assert!(mined.max_sybils_per_bucket <= MAX_PEERS_PER_SUBNET);
+        assert!(mined.admitted_sybils < open.admitted_sybils);
+        assert_eq!(mined.admitted_honest, 20);
+    }
+    
+    #[test]
+    fn test_compare_distance_policies() {
+        let mut sim = NetworkSimulator::new();
+        sim.create_random_nodes(60);
+        sim.connect_mesh(8);
+        sim.simulate_relay_sessions(600);
+        
+        let policies: Vec<_> = DistancePolicyKind::ALL.iter().map(|kind| kind.policy()).collect();
+        let results = sim.compare_distance_policies(&policies, 200);
+        let names: Vec<_> = results.iter().map(|r| r.policy).collect();
+        assert_eq!(names, vec!["raw-xor", "hyperbolic", "bucket-preserving", "bandwidth-aware"]);
+        
+        // Weighting trades fairness for reliable relays without losing the target
+        let (raw, hyperbolic) = (&results[0], &results[1]);
+        assert!(hyperbolic.high_rep_selection_rate > raw.high_rep_selection_rate);
+        assert!(hyperbolic.fairness < raw.fairness);
+        assert!(results.iter().all(|r| r.lookup_success_rate > 0.9));
     }
 }
@@ -1,60 +1,872 @@
 //! Payment channel implementation
+//!
+//! Channels are funded by an opener and updated off-chain: every balance
+//! change is a `ChannelUpdate` with a strictly increasing `sequence` that both
+//! parties sign. Either party can settle with the latest co-signed update; a
+//! unilateral close opens a dispute window during which a higher-sequence
+//! update replaces a stale one.
 
+use crate::core::crypto::{hash, verify_signature, NodeKeypair};
 use crate::core::types::*;
+use crate::wallet::wallet::{ChannelStatus, PaymentChannel};
 use crate::Error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Domain separator for channel update signatures
+const CHANNEL_DOMAIN: &[u8] = b"nexusremote-channel-v1";
+
+/// Default dispute window (seconds)
+pub const DEFAULT_DISPUTE_WINDOW: u64 = 24 * 60 * 60;
 
 /// Payment channel update
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChannelUpdate {
     /// Channel ID
     pub channel_id: [u8; 32],
-    /// New balance (our side)
-    pub our_balance: TokenAmount,
-    /// New balance (their side)
-    pub their_balance: TokenAmount,
+    /// Balance of the party that opened the channel
+    pub opener_balance: TokenAmount,
+    /// Balance of the counterparty
+    pub acceptor_balance: TokenAmount,
     /// Sequence number
     pub sequence: u64,
-    /// Our signature
-    pub our_signature: Vec<u8>,
-    /// Their signature
-    pub their_signature: Option<Vec<u8>>,
+    /// Whether this update cooperatively closes the channel
+    pub is_final: bool,
+    /// Opener's signature
+    pub opener_signature: Option<Vec<u8>>,
+    /// Acceptor's signature
+    pub acceptor_signature: Option<Vec<u8>>,
+}
+
+impl ChannelUpdate {
+    /// Canonical bytes covered by both signatures
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CHANNEL_DOMAIN.len() + 32 + 16 + 16 + 8 + 1);
+        bytes.extend_from_slice(CHANNEL_DOMAIN);
+        bytes.extend_from_slice(&self.channel_id);
+        bytes.extend_from_slice(&self.opener_balance.value().to_be_bytes());
+        bytes.extend_from_slice(&self.acceptor_balance.value().to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.push(self.is_final as u8);
+        bytes
+    }
+
+    /// Whether both parties have signed
+    pub fn is_co_signed(&self) -> bool {
+        self.opener_signature.is_some() && self.acceptor_signature.is_some()
+    }
+
+    /// Verify both signatures against the channel parameters
+    pub fn verify(&self, params: &ChannelParams) -> Result<(), Error> {
+        self.verify_party(params, ChannelRole::Opener)?;
+        self.verify_party(params, ChannelRole::Acceptor)
+    }
+
+    fn verify_party(&self, params: &ChannelParams, role: ChannelRole) -> Result<(), Error> {
+        if self.channel_id != params.channel_id {
+            return Err(Error::Token("Update belongs to a different channel".to_string()));
+        }
+        let (key, signature) = match role {
+            ChannelRole::Opener => (&params.opener, &self.opener_signature),
+            ChannelRole::Acceptor => (&params.acceptor, &self.acceptor_signature),
+        };
+        let signature = signature.as_ref()
+            .ok_or_else(|| Error::Token(format!("Update is missing the {:?} signature", role)))?;
+        if !verify_signature(key, &self.signing_bytes(), signature) {
+            return Err(Error::Token(format!("Invalid {:?} signature on channel update", role)));
+        }
+        Ok(())
+    }
+
+    fn sign(&mut self, keypair: &NodeKeypair, role: ChannelRole) {
+        let signature = Some(keypair.sign(&self.signing_bytes()));
+        match role {
+            ChannelRole::Opener => self.opener_signature = signature,
+            ChannelRole::Acceptor => self.acceptor_signature = signature,
+        }
+    }
+
+    /// Balance held by one side of the channel
+    pub fn balance_of(&self, role: ChannelRole) -> TokenAmount {
+        match role {
+            ChannelRole::Opener => self.opener_balance,
+            ChannelRole::Acceptor => self.acceptor_balance,
+        }
+    }
+}
+
+/// Which side of a channel a party is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChannelRole {
+    /// Funded the channel
+    Opener,
+    /// Accepted the channel
+    Acceptor,
+}
+
+impl ChannelRole {
+    fn other(self) -> Self {
+        match self {
+            ChannelRole::Opener => ChannelRole::Acceptor,
+            ChannelRole::Acceptor => ChannelRole::Opener,
+        }
+    }
+}
+
+/// Fixed parameters agreed when a channel is opened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelParams {
+    /// Channel ID (hash of the other parameters)
+    pub channel_id: [u8; 32],
+    /// Opener public key
+    pub opener: [u8; 32],
+    /// Acceptor public key
+    pub acceptor: [u8; 32],
+    /// Total capacity
+    pub capacity: TokenAmount,
+    /// Dispute window for unilateral closes (seconds)
+    pub dispute_window: u64,
+    /// Random nonce making the channel ID unique
+    pub nonce: [u8; 32],
+}
+
+impl ChannelParams {
+    fn derive_id(opener: &[u8; 32], acceptor: &[u8; 32], capacity: TokenAmount, dispute_window: u64, nonce: &[u8; 32]) -> [u8; 32] {
+        let mut bytes = Vec::with_capacity(CHANNEL_DOMAIN.len() + 32 * 3 + 16 + 8);
+        bytes.extend_from_slice(CHANNEL_DOMAIN);
+        bytes.extend_from_slice(opener);
+        bytes.extend_from_slice(acceptor);
+        bytes.extend_from_slice(&capacity.value().to_be_bytes());
+        bytes.extend_from_slice(&dispute_window.to_be_bytes());
+        bytes.extend_from_slice(nonce);
+        hash::sha256(&bytes)
+    }
+
+    fn is_consistent(&self) -> bool {
+        self.channel_id == Self::derive_id(&self.opener, &self.acceptor, self.capacity, self.dispute_window, &self.nonce)
+    }
+}
+
+/// Channel open proposal sent by the opener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelOpen {
+    /// Channel parameters
+    pub params: ChannelParams,
+    /// Initial state (sequence 0), signed by the opener
+    pub initial: ChannelUpdate,
+}
+
+/// Locally tracked channel state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelState {
+    /// Channel parameters
+    pub params: ChannelParams,
+    /// Our side of the channel
+    pub role: ChannelRole,
+    /// Remote peer
+    pub peer_id: PeerID,
+    /// Latest update signed by both parties (only the initial state while opening)
+    pub latest: ChannelUpdate,
+    /// Update we signed and are waiting for the peer to countersign
+    pub pending: Option<ChannelUpdate>,
+    /// Channel status
+    pub status: ChannelStatus,
+    /// Last update timestamp
+    pub last_update: u64,
+    /// Tokens the peer paid us that relay receipts have already paid out
+    #[serde(default)]
+    pub claimed: TokenAmount,
+}
+
+impl ChannelState {
+    /// Our balance in the latest co-signed state
+    pub fn our_balance(&self) -> TokenAmount {
+        self.latest.balance_of(self.role)
+    }
+
+    /// Their balance in the latest co-signed state
+    pub fn their_balance(&self) -> TokenAmount {
+        self.latest.balance_of(self.role.other())
+    }
+
+    /// The counterparty's public key
+    pub fn their_key(&self) -> [u8; 32] {
+        match self.role {
+            ChannelRole::Opener => self.params.acceptor,
+            ChannelRole::Acceptor => self.params.opener,
+        }
+    }
+
+    /// What we put into the channel when it opened
+    pub fn funded(&self) -> TokenAmount {
+        match self.role {
+            ChannelRole::Opener => self.params.capacity,
+            ChannelRole::Acceptor => TokenAmount::ZERO,
+        }
+    }
+
+    /// Tokens the peer has paid us that no receipt has claimed yet
+    pub fn unclaimed(&self) -> TokenAmount {
+        self.our_balance().sub(self.funded())
+            .and_then(|received| received.sub(self.claimed))
+            .unwrap_or(TokenAmount::ZERO)
+    }
+
+    /// Summary view used by the wallet
+    pub fn to_payment_channel(&self) -> PaymentChannel {
+        PaymentChannel {
+            channel_id: self.params.channel_id,
+            peer_id: self.peer_id.clone(),
+            capacity: self.params.capacity,
+            our_balance: self.our_balance(),
+            their_balance: self.their_balance(),
+            last_update: self.last_update,
+            status: self.status,
+        }
+    }
+}
+
+/// Outcome of settling a channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settlement {
+    /// Channel ID
+    pub channel_id: [u8; 32],
+    /// Final opener balance
+    pub opener_balance: TokenAmount,
+    /// Final acceptor balance
+    pub acceptor_balance: TokenAmount,
+    /// Sequence of the settled update
+    pub sequence: u64,
+}
+
+impl Settlement {
+    fn from_update(update: &ChannelUpdate) -> Self {
+        Self {
+            channel_id: update.channel_id,
+            opener_balance: update.opener_balance,
+            acceptor_balance: update.acceptor_balance,
+            sequence: update.sequence,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 /// Payment channel manager
+#[derive(Clone)]
 pub struct ChannelManager {
-    // Placeholder
+    keypair: NodeKeypair,
+    channels: HashMap<[u8; 32], ChannelState>,
+    /// File the latest co-signed states are persisted to
+    storage: Option<PathBuf>,
 }
 
 impl ChannelManager {
     /// Create a new channel manager
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(keypair: NodeKeypair) -> Self {
+        Self {
+            keypair,
+            channels: HashMap::new(),
+            storage: None,
+        }
+    }
+
+    /// Create a channel manager persisted to `path`, loading existing state
+    pub fn with_storage(keypair: NodeKeypair, path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let channels = if path.exists() {
+            let states: Vec<ChannelState> = serde_json::from_slice(&std::fs::read(&path)?)?;
+            states.into_iter().map(|s| (s.params.channel_id, s)).collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            keypair,
+            channels,
+            storage: Some(path),
+        })
+    }
+
+    /// Look up a channel
+    pub fn channel(&self, channel_id: &[u8; 32]) -> Option<&ChannelState> {
+        self.channels.get(channel_id)
     }
-    
-    /// Create a payment channel
-    pub async fn create_channel(
-        &self,
+
+    /// All channels
+    pub fn channels(&self) -> impl Iterator<Item = &ChannelState> {
+        self.channels.values()
+    }
+
+    /// Create a payment channel, returning the proposal for the counterparty
+    pub fn create_channel(
+        &mut self,
         peer_id: PeerID,
+        counterparty: [u8; 32],
         capacity: TokenAmount,
-    ) -> Result<[u8; 32], Error> {
-        // Placeholder
-        Ok(rand::random())
+    ) -> Result<ChannelOpen, Error> {
+        let opener = self.keypair.public_key().to_bytes();
+        if counterparty == opener {
+            return Err(Error::Token("Cannot open a channel with ourselves".to_string()));
+        }
+
+        let nonce: [u8; 32] = rand::random();
+        let params = ChannelParams {
+            channel_id: ChannelParams::derive_id(&opener, &counterparty, capacity, DEFAULT_DISPUTE_WINDOW, &nonce),
+            opener,
+            acceptor: counterparty,
+            capacity,
+            dispute_window: DEFAULT_DISPUTE_WINDOW,
+            nonce,
+        };
+
+        let mut initial = ChannelUpdate {
+            channel_id: params.channel_id,
+            opener_balance: capacity,
+            acceptor_balance: TokenAmount::ZERO,
+            sequence: 0,
+            is_final: false,
+            opener_signature: None,
+            acceptor_signature: None,
+        };
+        initial.sign(&self.keypair, ChannelRole::Opener);
+
+        self.channels.insert(params.channel_id, ChannelState {
+            params: params.clone(),
+            role: ChannelRole::Opener,
+            peer_id,
+            latest: initial.clone(),
+            pending: Some(initial.clone()),
+            status: ChannelStatus::Opening,
+            last_update: now_secs(),
+            claimed: TokenAmount::ZERO,
+        });
+        self.persist()?;
+
+        debug!("Proposed channel with capacity {}", capacity);
+        Ok(ChannelOpen { params, initial })
+    }
+
+    /// Accept a channel proposal, returning the co-signed initial state
+    pub fn accept_channel(&mut self, peer_id: PeerID, open: ChannelOpen) -> Result<ChannelUpdate, Error> {
+        let ChannelOpen { params, mut initial } = open;
+
+        if params.acceptor != self.keypair.public_key().to_bytes() {
+            return Err(Error::Token("Channel proposal is addressed to another node".to_string()));
+        }
+        if !params.is_consistent() {
+            return Err(Error::Token("Channel ID does not match its parameters".to_string()));
+        }
+        if self.channels.contains_key(&params.channel_id) {
+            return Err(Error::Token("Channel already exists".to_string()));
+        }
+        if initial.sequence != 0
+            || initial.is_final
+            || initial.opener_balance != params.capacity
+            || initial.acceptor_balance != TokenAmount::ZERO
+        {
+            return Err(Error::Token("Invalid initial channel state".to_string()));
+        }
+        initial.verify_party(&params, ChannelRole::Opener)?;
+
+        initial.sign(&self.keypair, ChannelRole::Acceptor);
+        self.channels.insert(params.channel_id, ChannelState {
+            params,
+            role: ChannelRole::Acceptor,
+            peer_id,
+            latest: initial.clone(),
+            pending: None,
+            status: ChannelStatus::Open,
+            last_update: now_secs(),
+            claimed: TokenAmount::ZERO,
+        });
+        self.persist()?;
+
+        Ok(initial)
+    }
+
+    /// Send tokens to the counterparty, returning the update for them to countersign
+    pub fn update_channel(&mut self, channel_id: [u8; 32], amount: TokenAmount) -> Result<ChannelUpdate, Error> {
+        let keypair = self.keypair.clone();
+        let state = self.open_channel_mut(&channel_id)?;
+        if state.pending.is_some() {
+            return Err(Error::Token("Previous channel update not yet acknowledged".to_string()));
+        }
+
+        let ours = state.our_balance().sub(amount)
+            .ok_or_else(|| Error::Token("Insufficient channel balance".to_string()))?;
+        let theirs = state.their_balance().add(amount);
+
+        let mut update = state.latest.clone();
+        update.sequence += 1;
+        update.opener_signature = None;
+        update.acceptor_signature = None;
+        match state.role {
+            ChannelRole::Opener => {
+                update.opener_balance = ours;
+                update.acceptor_balance = theirs;
+            }
+            ChannelRole::Acceptor => {
+                update.acceptor_balance = ours;
+                update.opener_balance = theirs;
+            }
+        }
+        update.sign(&keypair, state.role);
+
+        state.pending = Some(update.clone());
+        self.persist()?;
+        Ok(update)
+    }
+
+    /// Propose a cooperative close at the current balances
+    pub fn close_channel(&mut self, channel_id: [u8; 32]) -> Result<ChannelUpdate, Error> {
+        let keypair = self.keypair.clone();
+        let state = self.open_channel_mut(&channel_id)?;
+        if state.pending.is_some() {
+            return Err(Error::Token("Previous channel update not yet acknowledged".to_string()));
+        }
+
+        let mut update = state.latest.clone();
+        update.sequence += 1;
+        update.is_final = true;
+        update.opener_signature = None;
+        update.acceptor_signature = None;
+        update.sign(&keypair, state.role);
+
+        state.pending = Some(update.clone());
+        state.status = ChannelStatus::Closing;
+        self.persist()?;
+        Ok(update)
+    }
+
+    /// Validate and countersign an update proposed by the counterparty
+    ///
+    /// Only the next sequence number is accepted, the capacity must be
+    /// conserved, and a proposal may never reduce our balance.
+    ///
+    /// If both sides proposed the same sequence at once, the update whose
+    /// signing bytes hash lower wins on both ends: the peer's proposal is
+    /// rejected if ours wins, otherwise ours is withdrawn.
+    pub fn receive_update(&mut self, update: ChannelUpdate) -> Result<ChannelUpdate, Error> {
+        let keypair = self.keypair.clone();
+        let state = self.channels.get_mut(&update.channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let closing_ourselves = state.status == ChannelStatus::Closing
+            && state.pending.as_ref().is_some_and(|pending| pending.is_final);
+        if state.status != ChannelStatus::Open && !closing_ourselves {
+            return Err(Error::Token(format!("Channel is {:?}", state.status)));
+        }
+
+        update.verify_party(&state.params, state.role.other())?;
+        if update.sequence != state.latest.sequence + 1 {
+            return Err(Error::Token(format!(
+                "Stale or out-of-order update: sequence {} after {}",
+                update.sequence, state.latest.sequence
+            )));
+        }
+        if update.opener_balance.value().checked_add(update.acceptor_balance.value()) != Some(state.params.capacity.value()) {
+            return Err(Error::Token("Update does not conserve channel capacity".to_string()));
+        }
+        if update.balance_of(state.role) < state.our_balance() {
+            return Err(Error::Token("Update reduces our balance".to_string()));
+        }
+        if update.is_final && update.balance_of(state.role) != state.our_balance() {
+            return Err(Error::Token("Closing update must keep the current balances".to_string()));
+        }
+        if let Some(pending) = &state.pending {
+            if hash::sha256(&pending.signing_bytes()) < hash::sha256(&update.signing_bytes()) {
+                return Err(Error::Token(format!(
+                    "Conflicting update at sequence {}; our proposal takes precedence",
+                    update.sequence
+                )));
+            }
+            warn!("Withdrawing our update at sequence {} in favour of the peer's", update.sequence);
+            state.pending = None;
+            state.status = ChannelStatus::Open;
+        }
+
+        let mut countersigned = update;
+        countersigned.sign(&keypair, state.role);
+        state.latest = countersigned.clone();
+        state.last_update = now_secs();
+        if countersigned.is_final {
+            state.status = ChannelStatus::Closed;
+        }
+        self.persist()?;
+
+        Ok(countersigned)
+    }
+
+    /// Mark `amount` of the peer's payments as paid out by a relay receipt
+    pub fn claim(&mut self, channel_id: [u8; 32], amount: TokenAmount) -> Result<(), Error> {
+        let state = self.open_channel_mut(&channel_id)?;
+        if amount > state.unclaimed() {
+            return Err(Error::Token("Claim exceeds the peer's channel payments".to_string()));
+        }
+        state.claimed = state.claimed.add(amount);
+        self.persist()
+    }
+
+    /// Record the counterparty's signature on our pending update
+    pub fn confirm_update(&mut self, update: ChannelUpdate) -> Result<(), Error> {
+        let state = self.channels.get_mut(&update.channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let pending = state.pending.as_ref()
+            .ok_or_else(|| Error::Token("No pending update to confirm".to_string()))?;
+
+        if pending.signing_bytes() != update.signing_bytes() {
+            return Err(Error::Token("Countersigned update differs from proposal".to_string()));
+        }
+        update.verify(&state.params)?;
+
+        state.pending = None;
+        state.status = if update.is_final { ChannelStatus::Closed } else { ChannelStatus::Open };
+        state.latest = update;
+        state.last_update = now_secs();
+        self.persist()?;
+        Ok(())
+    }
+
+    /// Settlement for a channel closed cooperatively
+    pub fn settlement(&self, channel_id: &[u8; 32]) -> Result<Settlement, Error> {
+        let state = self.channels.get(channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        if state.status != ChannelStatus::Closed || !state.latest.is_final {
+            return Err(Error::Token("Channel has not been closed cooperatively".to_string()));
+        }
+        Ok(Settlement::from_update(&state.latest))
+    }
+
+    /// Close unilaterally by submitting our latest co-signed state
+    pub fn force_close(&mut self, channel_id: [u8; 32], adjudicator: &mut ChannelAdjudicator, now: u64) -> Result<u64, Error> {
+        let state = self.channels.get_mut(&channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let closes_at = adjudicator.start_close(&state.params, state.latest.clone(), now)?;
+        state.status = ChannelStatus::Closing;
+        self.persist()?;
+        Ok(closes_at)
+    }
+
+    /// Challenge an open dispute if the counterparty submitted a stale state
+    ///
+    /// Returns `true` if a newer update was submitted.
+    pub fn respond_to_dispute(&mut self, channel_id: [u8; 32], adjudicator: &mut ChannelAdjudicator, now: u64) -> Result<bool, Error> {
+        let state = self.channels.get_mut(&channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let submitted = match adjudicator.dispute(&channel_id) {
+            Some(dispute) => dispute.update.sequence,
+            None => return Ok(false),
+        };
+        state.status = ChannelStatus::Closing;
+
+        if state.latest.sequence > submitted {
+            info!("Challenging stale close (sequence {} < {})", submitted, state.latest.sequence);
+            adjudicator.challenge(state.latest.clone(), now)?;
+            self.persist()?;
+            return Ok(true);
+        }
+        self.persist()?;
+        Ok(false)
+    }
+
+    fn open_channel_mut(&mut self, channel_id: &[u8; 32]) -> Result<&mut ChannelState, Error> {
+        let state = self.channels.get_mut(channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        if state.status != ChannelStatus::Open {
+            return Err(Error::Token(format!("Channel is {:?}", state.status)));
+        }
+        Ok(state)
     }
-    
-    /// Update a channel
-    pub async fn update_channel(
-        &self,
-        channel_id: [u8; 32],
-        amount: TokenAmount,
-    ) -> Result<ChannelUpdate, Error> {
-        // Placeholder
-        Err(Error::Token("Payment channels not implemented yet".to_string()))
+
+    fn persist(&self) -> Result<(), Error> {
+        if let Some(path) = &self.storage {
+            save_states(path, self.channels.values())?;
+        }
+        Ok(())
+    }
+}
+
+fn save_states<'a>(path: &Path, states: impl Iterator<Item = &'a ChannelState>) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
     }
+    let states: Vec<_> = states.collect();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&states)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
 }
 
-impl Default for ChannelManager {
-    fn default() -> Self {
-        Self::new()
+/// A channel close waiting for its dispute window to expire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dispute {
+    /// Channel parameters
+    pub params: ChannelParams,
+    /// Highest-sequence update submitted so far
+    pub update: ChannelUpdate,
+    /// When the dispute window ends
+    pub closes_at: u64,
+}
+
+/// Settlement authority for unilateral closes
+///
+/// Stands in for the ledger that holds channel funds: it only accepts
+/// co-signed updates and always settles on the highest sequence seen before
+/// the dispute window ends.
+#[derive(Debug, Default)]
+pub struct ChannelAdjudicator {
+    disputes: HashMap<[u8; 32], Dispute>,
+}
+
+impl ChannelAdjudicator {
+    /// Create an empty adjudicator
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a dispute with a co-signed update, returning when it can be finalized
+    pub fn start_close(&mut self, params: &ChannelParams, update: ChannelUpdate, now: u64) -> Result<u64, Error> {
+        if !params.is_consistent() {
+            return Err(Error::Token("Channel ID does not match its parameters".to_string()));
+        }
+        if self.disputes.contains_key(&params.channel_id) {
+            return Err(Error::Token("Channel is already closing".to_string()));
+        }
+        update.verify(params)?;
+
+        let closes_at = if update.is_final { now } else { now + params.dispute_window };
+        self.disputes.insert(params.channel_id, Dispute {
+            params: params.clone(),
+            update,
+            closes_at,
+        });
+        Ok(closes_at)
+    }
+
+    /// Replace the disputed state with a higher-sequence co-signed update
+    pub fn challenge(&mut self, update: ChannelUpdate, now: u64) -> Result<(), Error> {
+        let dispute = self.disputes.get_mut(&update.channel_id)
+            .ok_or_else(|| Error::Token("No dispute for channel".to_string()))?;
+        if now >= dispute.closes_at {
+            return Err(Error::Token("Dispute window has ended".to_string()));
+        }
+        if update.sequence <= dispute.update.sequence {
+            return Err(Error::Token("Challenge must have a higher sequence".to_string()));
+        }
+        update.verify(&dispute.params)?;
+
+        dispute.update = update;
+        Ok(())
+    }
+
+    /// Current dispute for a channel
+    pub fn dispute(&self, channel_id: &[u8; 32]) -> Option<&Dispute> {
+        self.disputes.get(channel_id)
+    }
+
+    /// Settle a channel once its dispute window has passed
+    pub fn finalize(&mut self, channel_id: &[u8; 32], now: u64) -> Result<Settlement, Error> {
+        let dispute = self.disputes.get(channel_id)
+            .ok_or_else(|| Error::Token("No dispute for channel".to_string()))?;
+        if now < dispute.closes_at {
+            return Err(Error::Token("Dispute window still open".to_string()));
+        }
+        let dispute = self.disputes.remove(channel_id).unwrap();
+        Ok(Settlement::from_update(&dispute.update))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_pair(capacity: u128) -> (ChannelManager, ChannelManager, [u8; 32]) {
+        let alice_keys = NodeKeypair::generate();
+        let bob_keys = NodeKeypair::generate();
+        let mut alice = ChannelManager::new(alice_keys.clone());
+        let mut bob = ChannelManager::new(bob_keys.clone());
+
+        let open = alice.create_channel(
+            PeerID::new("bob".to_string()),
+            bob_keys.public_key().to_bytes(),
+            TokenAmount::new(capacity),
+        ).unwrap();
+        let channel_id = open.params.channel_id;
+        let initial = bob.accept_channel(PeerID::new("alice".to_string()), open).unwrap();
+        alice.confirm_update(initial).unwrap();
+
+        (alice, bob, channel_id)
+    }
+
+    fn pay(from: &mut ChannelManager, to: &mut ChannelManager, channel_id: [u8; 32], amount: u128) -> ChannelUpdate {
+        let update = from.update_channel(channel_id, TokenAmount::new(amount)).unwrap();
+        let countersigned = to.receive_update(update).unwrap();
+        from.confirm_update(countersigned.clone()).unwrap();
+        countersigned
+    }
+
+    #[test]
+    fn test_open_and_pay() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        assert_eq!(alice.channel(&id).unwrap().status, ChannelStatus::Open);
+
+        pay(&mut alice, &mut bob, id, 30);
+        pay(&mut bob, &mut alice, id, 5);
+
+        let a = alice.channel(&id).unwrap();
+        assert_eq!(a.our_balance().value(), 75);
+        assert_eq!(a.their_balance().value(), 25);
+        assert_eq!(a.latest.sequence, 2);
+        assert_eq!(bob.channel(&id).unwrap().latest, a.latest);
+
+        assert!(alice.update_channel(id, TokenAmount::new(1_000)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_updates() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        let first = pay(&mut alice, &mut bob, id, 10);
+
+        // Replaying an already-applied update
+        assert!(bob.receive_update(first).is_err());
+
+        // Peer trying to take money from us
+        let mut theft = bob.channel(&id).unwrap().latest.clone();
+        theft.sequence += 1;
+        theft.opener_balance = TokenAmount::new(100);
+        theft.acceptor_balance = TokenAmount::ZERO;
+        theft.acceptor_signature = None;
+        theft.opener_signature = None;
+        theft.sign(&alice.keypair, ChannelRole::Opener);
+        assert!(bob.receive_update(theft).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_proposals_agree_on_one_state() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        pay(&mut alice, &mut bob, id, 40);
+
+        // Both propose sequence 2 before seeing the other's update
+        let from_alice = alice.update_channel(id, TokenAmount::new(5)).unwrap();
+        let from_bob = bob.close_channel(id).unwrap();
+        assert_eq!(from_alice.sequence, from_bob.sequence);
+        let at_alice = alice.receive_update(from_bob.clone());
+        let at_bob = bob.receive_update(from_alice.clone());
+
+        // Exactly one proposal is countersigned, and both sides settle on it
+        assert_ne!(at_alice.is_ok(), at_bob.is_ok());
+        let (winner, countersigned) = match (at_alice, at_bob) {
+            (Ok(countersigned), Err(_)) => (from_bob, countersigned),
+            (Err(_), Ok(countersigned)) => (from_alice, countersigned),
+            _ => unreachable!(),
+        };
+        assert_eq!(countersigned.signing_bytes(), winner.signing_bytes());
+        if winner.is_final {
+            bob.confirm_update(countersigned).unwrap();
+        } else {
+            alice.confirm_update(countersigned).unwrap();
+        }
+        let (a, b) = (alice.channel(&id).unwrap(), bob.channel(&id).unwrap());
+        assert_eq!(a.latest, b.latest);
+        assert!(a.latest.is_co_signed());
+        assert_eq!(a.status, b.status);
+        assert!(a.pending.is_none() && b.pending.is_none());
+
+        // The channel keeps working after the conflict
+        if a.status == ChannelStatus::Open {
+            pay(&mut bob, &mut alice, id, 1);
+        }
+    }
+
+    #[test]
+    fn test_cooperative_close() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        pay(&mut alice, &mut bob, id, 40);
+
+        let close = alice.close_channel(id).unwrap();
+        let countersigned = bob.receive_update(close).unwrap();
+        alice.confirm_update(countersigned.clone()).unwrap();
+
+        let settlement = alice.settlement(&id).unwrap();
+        assert_eq!(settlement.opener_balance.value(), 60);
+        assert_eq!(settlement.acceptor_balance.value(), 40);
+        assert_eq!(bob.settlement(&id).unwrap(), settlement);
+
+        // A final update settles immediately
+        let mut adjudicator = ChannelAdjudicator::new();
+        let params = alice.channel(&id).unwrap().params.clone();
+        adjudicator.start_close(&params, countersigned, 1_000).unwrap();
+        assert_eq!(adjudicator.finalize(&id, 1_000).unwrap(), settlement);
+    }
+
+    #[test]
+    fn test_cheating_peer_stale_close_is_challenged() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        let stale = pay(&mut alice, &mut bob, id, 10);
+        pay(&mut alice, &mut bob, id, 50);
+
+        // Alice tries to settle on the old state where she still had 90
+        let mut adjudicator = ChannelAdjudicator::new();
+        let params = alice.channel(&id).unwrap().params.clone();
+        let closes_at = adjudicator.start_close(&params, stale, 1_000).unwrap();
+        assert_eq!(closes_at, 1_000 + DEFAULT_DISPUTE_WINDOW);
+
+        // Too early to finalize
+        assert!(adjudicator.finalize(&id, 1_500).is_err());
+
+        // Bob notices and submits the newer state
+        assert!(bob.respond_to_dispute(id, &mut adjudicator, 1_500).unwrap());
+        assert!(!bob.respond_to_dispute(id, &mut adjudicator, 1_600).unwrap());
+
+        let settlement = adjudicator.finalize(&id, closes_at).unwrap();
+        assert_eq!(settlement.sequence, 2);
+        assert_eq!(settlement.opener_balance.value(), 40);
+        assert_eq!(settlement.acceptor_balance.value(), 60);
+    }
+
+    #[test]
+    fn test_late_challenge_rejected() {
+        let (mut alice, mut bob, id) = open_pair(100);
+        let stale = pay(&mut alice, &mut bob, id, 10);
+        let newer = pay(&mut alice, &mut bob, id, 10);
+
+        let mut adjudicator = ChannelAdjudicator::new();
+        let params = alice.channel(&id).unwrap().params.clone();
+        let closes_at = adjudicator.start_close(&params, stale, 0).unwrap();
+
+        assert!(adjudicator.challenge(newer, closes_at).is_err());
+        assert_eq!(adjudicator.finalize(&id, closes_at).unwrap().sequence, 1);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir()
+            .join(format!("nexusremote-channels-{}", hex::encode(rand::random::<[u8; 8]>())))
+            .join("channels.json");
+        let alice_keys = NodeKeypair::generate();
+        let bob_keys = NodeKeypair::generate();
+
+        let mut alice = ChannelManager::with_storage(alice_keys.clone(), &path).unwrap();
+        let mut bob = ChannelManager::new(bob_keys.clone());
+        let open = alice.create_channel(PeerID::new("bob".to_string()), bob_keys.public_key().to_bytes(), TokenAmount::new(50)).unwrap();
+        let id = open.params.channel_id;
+        alice.confirm_update(bob.accept_channel(PeerID::new("alice".to_string()), open).unwrap()).unwrap();
+        pay(&mut alice, &mut bob, id, 20);
+        let latest = alice.channel(&id).unwrap().latest.clone();
+        drop(alice);
+
+        let restored = ChannelManager::with_storage(alice_keys, &path).unwrap();
+        let state = restored.channel(&id).unwrap();
+        assert_eq!(state.latest, latest);
+        assert!(state.latest.is_co_signed());
+        assert_eq!(state.to_payment_channel().our_balance.value(), 30);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
     }
 }
@@ -0,0 +1,345 @@
+//! Streaming relay payments over payment channels
+//!
+//! The relay meters bytes forwarded for a session and periodically asks the
+//! client for a channel update covering the traffic so far. Pricing is always
+//! computed on the cumulative byte count so per-interval rounding never adds
+//! up, and at the session's `token_rate` with `receipt_amount`, so payments
+//! match the advertised price and the receipt the session ends with. If the
+//! client falls too far behind, forwarding pauses until it pays.
+
+use crate::core::receipt::receipt_amount;
+use crate::core::types::*;
+use crate::network::relay::{RelayManager, RelaySession};
+use crate::wallet::channel::{ChannelManager, ChannelUpdate};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// Metering configuration
+#[derive(Debug, Clone)]
+pub struct MeteringConfig {
+    /// Bytes relayed between payment requests
+    pub payment_interval: u64,
+    /// Unpaid bytes tolerated before forwarding pauses
+    pub max_unpaid: u64,
+}
+
+impl Default for MeteringConfig {
+    fn default() -> Self {
+        Self {
+            payment_interval: 1024 * 1024, // 1 MB
+            max_unpaid: 4 * 1024 * 1024,   // 4 MB
+        }
+    }
+}
+
+/// Request for an incremental channel payment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentRequest {
+    /// Relay session
+    pub session_id: [u8; 32],
+    /// Channel to pay through
+    pub channel_id: [u8; 32],
+    /// Total bytes relayed in the session so far
+    pub data_relayed: u64,
+    /// Amount due for this request
+    pub amount: TokenAmount,
+}
+
+/// Relay-side meter tying a relay session to a payment channel
+#[derive(Debug, Clone)]
+pub struct RelayMeter {
+    session_id: [u8; 32],
+    channel_id: [u8; 32],
+    /// Price agreed when the session was admitted (tokens per MB)
+    token_rate: TokenAmount,
+    config: MeteringConfig,
+    /// Bytes relayed so far
+    metered: u64,
+    /// Bytes covered by the last payment request
+    billed: u64,
+    /// Bytes covered by payments received
+    paid_bytes: u64,
+    /// Total received through the channel for this session
+    paid: TokenAmount,
+    outstanding: Option<PaymentRequest>,
+}
+
+impl RelayMeter {
+    /// Start metering a relay session paid through `channel_id`
+    pub fn new(session: &RelaySession, channel_id: [u8; 32], config: MeteringConfig) -> Self {
+        Self {
+            session_id: session.session_id,
+            channel_id,
+            token_rate: session.token_rate,
+            config,
+            metered: 0,
+            billed: 0,
+            paid_bytes: 0,
+            paid: TokenAmount::ZERO,
+            outstanding: None,
+        }
+    }
+
+    /// Whether the relay should keep forwarding traffic
+    pub fn can_forward(&self) -> bool {
+        self.unpaid_bytes() < self.config.max_unpaid
+    }
+
+    /// Bytes relayed that have not been paid for
+    pub fn unpaid_bytes(&self) -> u64 {
+        self.metered - self.paid_bytes
+    }
+
+    /// Total received for this session
+    pub fn total_paid(&self) -> TokenAmount {
+        self.paid
+    }
+
+    /// Payment request the client has not answered yet
+    pub fn outstanding_request(&self) -> Option<&PaymentRequest> {
+        self.outstanding.as_ref()
+    }
+
+    /// Record forwarded bytes, returning a payment request when one is due
+    pub fn record(&mut self, relay: &mut RelayManager, bytes: u64) -> Result<Option<PaymentRequest>, Error> {
+        if !self.can_forward() {
+            return Err(Error::Token("Relay session paused until the client pays".to_string()));
+        }
+        relay.record_data(&self.session_id, bytes)?;
+        self.metered += bytes;
+
+        if self.outstanding.is_some() || self.metered - self.billed < self.config.payment_interval {
+            return Ok(None);
+        }
+
+        let due = receipt_amount(self.metered, self.token_rate);
+        self.billed = self.metered;
+        match due.sub(self.paid) {
+            Some(amount) if amount > TokenAmount::ZERO => {
+                let request = PaymentRequest {
+                    session_id: self.session_id,
+                    channel_id: self.channel_id,
+                    data_relayed: self.metered,
+                    amount,
+                };
+                debug!("Requesting {} for {} bytes relayed", amount, self.metered);
+                self.outstanding = Some(request.clone());
+                Ok(Some(request))
+            }
+            // Nothing owed yet at whole-token granularity
+            _ => {
+                self.paid_bytes = self.metered;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Countersign a channel update paying the outstanding request
+    pub fn on_payment(&mut self, channels: &mut ChannelManager, update: ChannelUpdate) -> Result<ChannelUpdate, Error> {
+        if update.channel_id != self.channel_id {
+            return Err(Error::Token("Payment made on a different channel".to_string()));
+        }
+        let request = self.outstanding.clone()
+            .ok_or_else(|| Error::Token("No payment requested".to_string()))?;
+        let state = channels.channel(&self.channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let credited = update.balance_of(state.role).sub(state.our_balance())
+            .ok_or_else(|| Error::Token("Update reduces our balance".to_string()))?;
+        if credited < request.amount {
+            warn!("Client paid {} of {} requested", credited, request.amount);
+            return Err(Error::Token("Payment below requested amount".to_string()));
+        }
+
+        let countersigned = channels.receive_update(update)?;
+        self.paid = self.paid.add(credited);
+        self.paid_bytes = request.data_relayed;
+        self.outstanding = None;
+        Ok(countersigned)
+    }
+}
+
+/// Client-side counterpart that pays relay requests
+#[derive(Debug, Clone)]
+pub struct RelayPayer {
+    session_id: [u8; 32],
+    channel_id: [u8; 32],
+    /// Price the relay advertised (tokens per MB)
+    tokens_per_mb: TokenAmount,
+    /// Bytes we sent through the relay
+    sent: u64,
+    paid: TokenAmount,
+}
+
+impl RelayPayer {
+    /// Pay for a relay session through `channel_id` at the relay's advertised price
+    pub fn new(session_id: [u8; 32], channel_id: [u8; 32], tokens_per_mb: TokenAmount) -> Self {
+        Self {
+            session_id,
+            channel_id,
+            tokens_per_mb,
+            sent: 0,
+            paid: TokenAmount::ZERO,
+        }
+    }
+
+    /// Record bytes sent through the relay
+    pub fn record_sent(&mut self, bytes: u64) {
+        self.sent += bytes;
+    }
+
+    /// Total paid for this session
+    pub fn total_paid(&self) -> TokenAmount {
+        self.paid
+    }
+
+    /// Sign a channel update for a request, refusing to pay for traffic we did not send
+    pub fn pay(&mut self, channels: &mut ChannelManager, request: &PaymentRequest) -> Result<ChannelUpdate, Error> {
+        if request.session_id != self.session_id || request.channel_id != self.channel_id {
+            return Err(Error::Token("Payment request for a different session".to_string()));
+        }
+        if request.data_relayed > self.sent {
+            return Err(Error::Token(format!(
+                "Relay claims {} bytes but only {} were sent",
+                request.data_relayed, self.sent
+            )));
+        }
+        let due = receipt_amount(request.data_relayed, self.tokens_per_mb);
+        if self.paid.add(request.amount) > due {
+            return Err(Error::Token(format!("Relay requested more than the {} due", due)));
+        }
+
+        let update = channels.update_channel(self.channel_id, request.amount)?;
+        self.paid = self.paid.add(request.amount);
+        Ok(update)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::NodeKeypair;
+    use crate::wallet::wallet::{InMemoryWallet, WalletEngine};
+
+    const MB: u64 = 1024 * 1024;
+
+    struct Setup {
+        relay_keys: NodeKeypair,
+        client_keys: NodeKeypair,
+        relay: RelayManager,
+        relay_channels: ChannelManager,
+        client_channels: ChannelManager,
+        meter: RelayMeter,
+        payer: RelayPayer,
+    }
+
+    fn setup() -> Setup {
+        let relay_keys = NodeKeypair::generate();
+        let mut relay_channels = ChannelManager::new(relay_keys.clone());
+        let client_keys = NodeKeypair::generate();
+        let mut client_channels = ChannelManager::new(client_keys.clone());
+
+        let open = client_channels.create_channel(
+            PeerID::new("relay".to_string()),
+            relay_keys.public_key().to_bytes(),
+            TokenAmount::new(100),
+        ).unwrap();
+        let channel_id = open.params.channel_id;
+        let initial = relay_channels.accept_channel(PeerID::new("client".to_string()), open).unwrap();
+        client_channels.confirm_update(initial).unwrap();
+
+        let mut relay = RelayManager::default();
+        let session = relay.start_session(
+            PeerID::new("client".to_string()),
+            client_keys.public_key().to_bytes(),
+            PeerID::new("target".to_string()),
+            ReputationScore::new(500),
+        ).unwrap();
+
+        // Both sides price at the rate the relay advertises
+        let meter = RelayMeter::new(&session, channel_id, MeteringConfig::default());
+        let payer = RelayPayer::new(session.session_id, channel_id, relay.config().tokens_per_mb);
+
+        Setup { relay_keys, client_keys, relay, relay_channels, client_channels, meter, payer }
+    }
+
+    #[test]
+    fn test_streaming_payments() {
+        let mut s = setup();
+
+        for _ in 0..5 {
+            s.payer.record_sent(MB);
+            let request = s.meter.record(&mut s.relay, MB).unwrap().expect("payment due every MB");
+            let update = s.payer.pay(&mut s.client_channels, &request).unwrap();
+            let countersigned = s.meter.on_payment(&mut s.relay_channels, update).unwrap();
+            s.client_channels.confirm_update(countersigned).unwrap();
+        }
+
+        assert_eq!(s.meter.total_paid().value(), 5);
+        assert_eq!(s.meter.unpaid_bytes(), 0);
+        let channel = s.meter.channel_id;
+        assert_eq!(s.relay_channels.channel(&channel).unwrap().our_balance().value(), 5);
+        assert_eq!(s.client_channels.channel(&channel).unwrap().our_balance().value(), 95);
+    }
+
+    #[tokio::test]
+    async fn test_metered_receipt_is_funded() {
+        let mut s = setup();
+        for _ in 0..3 {
+            s.payer.record_sent(MB);
+            let request = s.meter.record(&mut s.relay, MB).unwrap().unwrap();
+            assert_eq!(request.amount, s.relay.config().tokens_per_mb);
+            let update = s.payer.pay(&mut s.client_channels, &request).unwrap();
+            let countersigned = s.meter.on_payment(&mut s.relay_channels, update).unwrap();
+            s.client_channels.confirm_update(countersigned).unwrap();
+        }
+
+        // The receipt claims exactly what the meter collected
+        let mut receipt = s.relay.end_session(&s.meter.session_id).unwrap();
+        assert_eq!(receipt.amount, s.meter.total_paid());
+        receipt.sign_as_relay(&s.relay_keys);
+        receipt.sign_as_client(&s.client_keys);
+        let mut wallet = InMemoryWallet::new(s.relay_keys.clone())
+            .with_channels(s.relay_channels.clone())
+            .with_relay_rate(s.relay.config().tokens_per_mb);
+        wallet.submit_proof_of_relay(receipt).await.unwrap();
+        assert_eq!(wallet.balance(), s.meter.total_paid());
+    }
+
+    #[test]
+    fn test_pauses_when_client_stops_paying() {
+        let mut s = setup();
+
+        let first = s.meter.record(&mut s.relay, MB).unwrap().unwrap();
+        for _ in 0..3 {
+            assert!(s.meter.record(&mut s.relay, MB).unwrap().is_none());
+        }
+        assert!(!s.meter.can_forward());
+        assert!(s.meter.record(&mut s.relay, MB).is_err());
+
+        // Paying the outstanding request resumes forwarding
+        s.payer.record_sent(4 * MB);
+        let update = s.payer.pay(&mut s.client_channels, &first).unwrap();
+        let countersigned = s.meter.on_payment(&mut s.relay_channels, update).unwrap();
+        s.client_channels.confirm_update(countersigned).unwrap();
+        assert!(s.meter.can_forward());
+
+        let next = s.meter.record(&mut s.relay, MB).unwrap().unwrap();
+        assert_eq!(next.data_relayed, 5 * MB);
+        assert_eq!(next.amount.value(), 4);
+    }
+
+    #[test]
+    fn test_payer_rejects_overcharge() {
+        let mut s = setup();
+        s.payer.record_sent(MB);
+        let mut request = s.meter.record(&mut s.relay, MB).unwrap().unwrap();
+
+        let mut inflated = request.clone();
+        inflated.amount = TokenAmount::new(10);
+        assert!(s.payer.pay(&mut s.client_channels, &inflated).is_err());
+
+        request.data_relayed = 2 * MB;
+        assert!(s.payer.pay(&mut s.client_channels, &request).is_err());
+    }
+}
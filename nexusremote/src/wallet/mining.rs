@@ -3,7 +3,8 @@
 use crate::core::crypto::pow;
 use crate::core::types::*;
 use crate::Error;
-use tracing::{info, debug};
+use serde::{Deserialize, Serialize};
+use tracing::info;
 
 /// Mining configuration
 #[derive(Debug, Clone)]
@@ -30,7 +31,7 @@ impl Default for MiningConfig {
 }
 
 /// Mining result
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MiningResult {
     /// Whether mining succeeded
     pub success: bool,
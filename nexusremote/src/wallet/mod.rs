@@ -1,11 +1,14 @@
 //! Wallet and token economics module
 
+#[allow(clippy::module_inception)]
 pub mod wallet;
 pub mod token;
 pub mod channel;
 pub mod mining;
+pub mod metering;
 
 pub use wallet::*;
 pub use token::*;
 pub use channel::*;
 pub use mining::*;
+pub use metering::*;
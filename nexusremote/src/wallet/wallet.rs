@@ -1,10 +1,15 @@
 //! Wallet implementation for NexusRemote
 
 use crate::core::crypto::NodeKeypair;
+use crate::core::receipt::ReceiptError;
+use crate::core::reputation::ReputationEvent;
 use crate::core::types::*;
+use crate::network::relay::RelayConfig;
+use crate::wallet::channel::{ChannelManager, ChannelOpen, ChannelRole, ChannelState, ChannelUpdate};
 use crate::Error;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use tracing::{info, debug};
 
 /// Wallet state
@@ -20,10 +25,15 @@ pub struct WalletState {
     pub total_earned: TokenAmount,
     /// Total tokens spent
     pub total_spent: TokenAmount,
-    /// Open payment channels
+    /// Payment channels that have not closed, by peer; filled in from the
+    /// channel manager by `WalletEngine::get_status`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub channels: HashMap<PeerID, PaymentChannel>,
     /// Transaction history
     pub transactions: Vec<Transaction>,
+    /// Relay sessions whose receipts have been redeemed
+    #[serde(default)]
+    pub redeemed_receipts: HashSet<[u8; 32]>,
 }
 
 impl WalletState {
@@ -37,6 +47,7 @@ impl WalletState {
             total_spent: TokenAmount::ZERO,
             channels: HashMap::new(),
             transactions: Vec::new(),
+            redeemed_receipts: HashSet::new(),
         }
     }
 }
@@ -101,6 +112,8 @@ pub struct PaymentChannel {
 /// Channel status
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChannelStatus {
+    /// Channel proposed, waiting for the counterparty to accept
+    Opening,
     /// Channel is open and active
     Open,
     /// Channel is being closed
@@ -130,13 +143,25 @@ pub trait WalletEngine: Send + Sync {
     /// Spend tokens from wallet
     fn spend_tokens(&mut self, amount: TokenAmount, description: &str, counterparty: Option<&PeerID>) -> Result<(), Error>;
     
-    /// Open a payment channel
-    async fn open_channel(&mut self, peer_id: PeerID, capacity: TokenAmount) -> Result<PaymentChannel, Error>;
+    /// Propose a payment channel to `counterparty`, locking `capacity` from the balance
+    ///
+    /// Returns the proposal for the counterparty to accept.
+    async fn open_channel(&mut self, peer_id: PeerID, counterparty: [u8; 32], capacity: TokenAmount) -> Result<ChannelOpen, Error>;
     
-    /// Close a payment channel
-    async fn close_channel(&mut self, peer_id: &PeerID) -> Result<(), Error>;
+    /// Accept a channel proposed by `peer_id`, returning the co-signed initial state
+    async fn accept_channel(&mut self, peer_id: PeerID, open: ChannelOpen) -> Result<ChannelUpdate, Error>;
     
-    /// Submit a relay proof for earnings
+    /// Apply a channel update from the counterparty
+    ///
+    /// A countersignature of our own pending update is recorded and returns
+    /// `None`; a proposal from the peer is countersigned and returned. A
+    /// channel that ends up closed is settled into the balance.
+    async fn apply_channel_update(&mut self, update: ChannelUpdate) -> Result<Option<ChannelUpdate>, Error>;
+    
+    /// Propose a cooperative close of the channel with `peer_id`
+    async fn close_channel(&mut self, peer_id: &PeerID) -> Result<ChannelUpdate, Error>;
+    
+    /// Redeem a relay receipt, paying it out of the client's channel payments
     async fn submit_proof_of_relay(&mut self, receipt: SignedReceipt) -> Result<(), Error>;
     
     /// Calculate dynamic overdraft limit based on reputation
@@ -148,6 +173,12 @@ pub trait WalletEngine: Send + Sync {
 pub struct InMemoryWallet {
     state: WalletState,
     keypair: NodeKeypair,
+    /// Co-signed channel states; the channel balances live here, not in `state`
+    channels: ChannelManager,
+    /// Relay price used to bound receipt amounts
+    relay_rate: TokenAmount,
+    /// Events for the node's reputation model, with their time
+    reputation_events: Vec<(u64, ReputationEvent)>,
 }
 
 impl InMemoryWallet {
@@ -155,7 +186,10 @@ impl InMemoryWallet {
     pub fn new(keypair: NodeKeypair) -> Self {
         Self {
             state: WalletState::new(),
+            channels: ChannelManager::new(keypair.clone()),
             keypair,
+            relay_rate: RelayConfig::default().tokens_per_mb,
+            reputation_events: Vec::new(),
         }
     }
     
@@ -165,12 +199,152 @@ impl InMemoryWallet {
         wallet.state.balance = initial_balance;
         wallet
     }
+    
+    /// Load the wallet saved at `path`, or start an empty one
+    ///
+    /// Channel states are kept apart, see `with_channels`.
+    pub fn load_or_new(path: &Path, keypair: NodeKeypair) -> Result<Self, Error> {
+        let mut wallet = Self::new(keypair);
+        if path.exists() {
+            wallet.state = serde_json::from_slice(&std::fs::read(path)?)?;
+        }
+        Ok(wallet)
+    }
+    
+    /// Write the wallet state to `path`, replacing it atomically
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.state)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+    
+    /// Set the relay price (tokens per MB) receipts are checked against
+    pub fn with_relay_rate(mut self, tokens_per_mb: TokenAmount) -> Self {
+        self.relay_rate = tokens_per_mb;
+        self
+    }
+    
+    /// Keep channel states in `channels`, e.g. one loaded with `ChannelManager::with_storage`
+    pub fn with_channels(mut self, channels: ChannelManager) -> Self {
+        self.channels = channels;
+        self
+    }
+    
+    /// Payment channels
+    pub fn channels(&self) -> &ChannelManager {
+        &self.channels
+    }
+    
+    /// Payment channels, for channel updates that pay for relay sessions
+    pub fn channels_mut(&mut self) -> &mut ChannelManager {
+        &mut self.channels
+    }
+    
+    /// Take the reputation events since the last call
+    pub fn take_reputation_events(&mut self) -> Vec<(u64, ReputationEvent)> {
+        std::mem::take(&mut self.reputation_events)
+    }
+    
+    /// Adopt the reputation computed by the node's reputation model
+    pub fn set_reputation(&mut self, reputation: ReputationScore) {
+        self.state.reputation = reputation;
+    }
+    
+    /// The open channel with the node holding `public_key`
+    fn channel_with_key(&self, public_key: &[u8; 32]) -> Option<&ChannelState> {
+        self.channels.channels()
+            .find(|state| &state.their_key() == public_key && state.status == ChannelStatus::Open)
+    }
+    
+    /// The channel with `peer_id` that has not closed yet
+    fn live_channel(&self, peer_id: &PeerID) -> Option<&ChannelState> {
+        self.channels.channels()
+            .find(|state| &state.peer_id == peer_id && state.status != ChannelStatus::Closed)
+    }
+    
+    fn record_transaction(&mut self, tx_type: TransactionType, amount: TokenAmount, counterparty: Option<&PeerID>, description: &str) {
+        self.state.transactions.push(Transaction {
+            id: rand::random(),
+            tx_type,
+            amount,
+            counterparty: counterparty.cloned(),
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            description: description.to_string(),
+        });
+    }
+    
+    /// Pay out our final balance of a channel that has just closed
+    ///
+    /// Only the difference to what we funded counts as earned or spent; the
+    /// rest is the locked capacity coming back. Payments that receipts have
+    /// already paid out are not credited again.
+    fn settle(&mut self, channel_id: &[u8; 32]) -> Result<(), Error> {
+        let settlement = self.channels.settlement(channel_id)?;
+        let state = self.channels.channel(channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let (peer_id, funded) = (state.peer_id.clone(), state.funded());
+        let ours = match state.role {
+            ChannelRole::Opener => settlement.opener_balance,
+            ChannelRole::Acceptor => settlement.acceptor_balance,
+        };
+        let ours = ours.sub(state.claimed).unwrap_or(TokenAmount::ZERO);
+        
+        self.state.balance = self.state.balance.add(ours);
+        if let Some(received) = ours.sub(funded).filter(|a| *a > TokenAmount::ZERO) {
+            self.state.total_earned = self.state.total_earned.add(received);
+            self.record_transaction(TransactionType::Transfer, received, Some(&peer_id), "Channel settlement");
+        } else if let Some(paid) = funded.sub(ours).filter(|a| *a > TokenAmount::ZERO) {
+            self.state.total_spent = self.state.total_spent.add(paid);
+            self.record_transaction(TransactionType::Transfer, paid, Some(&peer_id), "Channel settlement");
+        }
+        debug!("Settled channel with {}, returned: {}", peer_id, ours);
+        Ok(())
+    }
+    
+    /// Check that a receipt is co-signed, addressed to us, fresh, correctly
+    /// priced and covered by the client's channel payments
+    ///
+    /// Returns the channel the receipt is paid from.
+    fn verify_receipt(&self, receipt: &SignedReceipt) -> Result<[u8; 32], ReceiptError> {
+        if !receipt.is_signed() {
+            return Err(ReceiptError::Unsigned);
+        }
+        if receipt.relay_public_key != self.keypair.public_key().to_bytes() {
+            return Err(ReceiptError::WrongRelay);
+        }
+        receipt.verify_signatures()?;
+        if self.state.redeemed_receipts.contains(&receipt.session_id) {
+            return Err(ReceiptError::Replayed(hex::encode(receipt.session_id)));
+        }
+        receipt.verify_amount(self.relay_rate)?;
+        
+        // A receipt pays out tokens the client already sent us; a relay
+        // co-signing with a throwaway key has no channel to draw on
+        let channel = self.channel_with_key(&receipt.client_public_key)
+            .ok_or(ReceiptError::NoChannel)?;
+        if receipt.amount > channel.unclaimed() {
+            return Err(ReceiptError::Unfunded { claimed: receipt.amount, available: channel.unclaimed() });
+        }
+        Ok(channel.params.channel_id)
+    }
 }
 
 #[async_trait::async_trait]
 impl WalletEngine for InMemoryWallet {
     fn get_status(&self) -> WalletState {
-        self.state.clone()
+        let mut state = self.state.clone();
+        state.channels = self.channels.channels()
+            .filter(|channel| channel.status != ChannelStatus::Closed)
+            .map(|channel| (channel.peer_id.clone(), channel.to_payment_channel()))
+            .collect();
+        state
     }
     
     fn balance(&self) -> TokenAmount {
@@ -189,21 +363,9 @@ impl WalletEngine for InMemoryWallet {
     }
     
     fn add_tokens(&mut self, amount: TokenAmount, description: &str, tx_type: TransactionType) {
-        let tx = Transaction {
-            id: rand::random(),
-            tx_type,
-            amount,
-            counterparty: None,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            description: description.to_string(),
-        };
-        
         self.state.balance = self.state.balance.add(amount);
         self.state.total_earned = self.state.total_earned.add(amount);
-        self.state.transactions.push(tx);
+        self.record_transaction(tx_type, amount, None, description);
         
         info!("Added {}: {} - {}", amount, tx_type as u8, description);
     }
@@ -213,69 +375,82 @@ impl WalletEngine for InMemoryWallet {
             return Err(Error::Token("Insufficient funds".to_string()));
         }
         
-        let tx = Transaction {
-            id: rand::random(),
-            tx_type: TransactionType::Transfer,
-            amount,
-            counterparty: counterparty.cloned(),
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            description: description.to_string(),
-        };
-        
         self.state.balance = self.state.balance.sub(amount).unwrap_or(TokenAmount::ZERO);
         self.state.total_spent = self.state.total_spent.add(amount);
-        self.state.transactions.push(tx);
+        self.record_transaction(TransactionType::Transfer, amount, counterparty, description);
         
         info!("Spent {} - {}", amount, description);
         
         Ok(())
     }
     
-    async fn open_channel(&mut self, peer_id: PeerID, capacity: TokenAmount) -> Result<PaymentChannel, Error> {
-        if !self.can_pay(capacity) {
-            return Err(Error::Token("Insufficient funds for channel capacity".to_string()));
+    async fn open_channel(&mut self, peer_id: PeerID, counterparty: [u8; 32], capacity: TokenAmount) -> Result<ChannelOpen, Error> {
+        if self.live_channel(&peer_id).is_some() {
+            return Err(Error::Token(format!("A channel with {} is already open", peer_id)));
         }
+        // Capacity is locked from real tokens; the overdraft cannot fund a channel
+        let remaining = self.state.balance.sub(capacity)
+            .ok_or_else(|| Error::Token("Insufficient funds for channel capacity".to_string()))?;
         
-        // Lock the capacity
-        self.state.balance = self.state.balance.sub(capacity).unwrap();
-        
-        let channel = PaymentChannel {
-            channel_id: rand::random(),
-            peer_id: peer_id.clone(),
-            capacity,
-            our_balance: capacity,
-            their_balance: TokenAmount::ZERO,
-            last_update: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            status: ChannelStatus::Open,
-        };
-        
-        self.state.channels.insert(peer_id, channel.clone());
+        let open = self.channels.create_channel(peer_id, counterparty, capacity)?;
+        self.state.balance = remaining;
         
-        debug!("Opened payment channel with capacity: {}", capacity);
+        debug!("Proposed payment channel with capacity: {}", capacity);
         
-        Ok(channel)
+        Ok(open)
     }
     
-    async fn close_channel(&mut self, peer_id: &PeerID) -> Result<(), Error> {
-        if let Some(channel) = self.state.channels.remove(peer_id) {
-            // Return remaining balance
-            self.state.balance = self.state.balance.add(channel.our_balance);
-            debug!("Closed payment channel, returned: {}", channel.our_balance);
+    async fn accept_channel(&mut self, peer_id: PeerID, open: ChannelOpen) -> Result<ChannelUpdate, Error> {
+        if self.live_channel(&peer_id).is_some() {
+            return Err(Error::Token(format!("A channel with {} is already open", peer_id)));
         }
-        Ok(())
+        self.channels.accept_channel(peer_id, open)
+    }
+    
+    async fn apply_channel_update(&mut self, update: ChannelUpdate) -> Result<Option<ChannelUpdate>, Error> {
+        let channel_id = update.channel_id;
+        let state = self.channels.channel(&channel_id)
+            .ok_or_else(|| Error::Token("Unknown channel".to_string()))?;
+        let confirms_ours = state.pending.as_ref()
+            .is_some_and(|pending| pending.signing_bytes() == update.signing_bytes());
+        
+        let reply = if confirms_ours {
+            self.channels.confirm_update(update)?;
+            None
+        } else {
+            Some(self.channels.receive_update(update)?)
+        };
+        if self.channels.channel(&channel_id).is_some_and(|state| state.status == ChannelStatus::Closed) {
+            self.settle(&channel_id)?;
+        }
+        Ok(reply)
+    }
+    
+    async fn close_channel(&mut self, peer_id: &PeerID) -> Result<ChannelUpdate, Error> {
+        let channel_id = self.live_channel(peer_id)
+            .map(|state| state.params.channel_id)
+            .ok_or_else(|| Error::Token(format!("No open channel with {}", peer_id)))?;
+        self.channels.close_channel(channel_id)
     }
     
     async fn submit_proof_of_relay(&mut self, receipt: SignedReceipt) -> Result<(), Error> {
-        // In a real implementation, we would verify the signatures
-        // For now, just add the earnings
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let session_id = receipt.session_id;
+        let channel_id = match self.verify_receipt(&receipt) {
+            Ok(channel_id) => channel_id,
+            Err(e) => {
+                self.reputation_events.push((now, ReputationEvent::ReceiptRejected { session_id }));
+                return Err(e.into());
+            }
+        };
+        
+        self.channels.claim(channel_id, receipt.amount)?;
+        self.state.redeemed_receipts.insert(session_id);
         self.add_tokens(receipt.amount, "Relay earnings", TransactionType::RelayEarnings);
-        self.state.reputation.increase(1);
+        self.reputation_events.push((now, ReputationEvent::RelayCompleted { session_id }));
         Ok(())
     }
     
@@ -328,4 +503,175 @@ mod tests {
         
         assert_eq!(wallet.balance().value(), 50);
     }
+    
+    #[tokio::test]
+    async fn test_channel_capacity_needs_real_balance() {
+        let keypair = crate::core::crypto::NodeKeypair::generate();
+        let peer = crate::core::crypto::NodeKeypair::generate().public_key().to_bytes();
+        let mut wallet = InMemoryWallet::with_initial_balance(keypair, TokenAmount::new(10));
+        
+        // The overdraft covers 40 but cannot be locked into a channel
+        assert!(wallet.can_pay(TokenAmount::new(40)));
+        assert!(wallet.open_channel(PeerID::new("peer".to_string()), peer, TokenAmount::new(40)).await.is_err());
+        assert_eq!(wallet.balance().value(), 10);
+        assert!(wallet.get_status().channels.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_channel_lifecycle_settles_balances() {
+        let alice_key = crate::core::crypto::NodeKeypair::generate();
+        let bob_key = crate::core::crypto::NodeKeypair::generate();
+        let mut alice = InMemoryWallet::with_initial_balance(alice_key.clone(), TokenAmount::new(100));
+        let mut bob = InMemoryWallet::new(bob_key.clone());
+        let (alice_id, bob_id) = (PeerID::new("alice".to_string()), PeerID::new("bob".to_string()));
+        
+        let open = alice.open_channel(bob_id.clone(), bob_key.public_key().to_bytes(), TokenAmount::new(30)).await.unwrap();
+        assert_eq!(alice.balance().value(), 70);
+        assert!(alice.open_channel(bob_id.clone(), bob_key.public_key().to_bytes(), TokenAmount::new(10)).await.is_err());
+        let accepted = bob.accept_channel(alice_id.clone(), open).await.unwrap();
+        assert!(alice.apply_channel_update(accepted).await.unwrap().is_none());
+        assert_eq!(alice.get_status().channels[&bob_id].status, ChannelStatus::Open);
+        
+        let channel_id = alice.channels().channels().next().unwrap().params.channel_id;
+        let payment = alice.channels.update_channel(channel_id, TokenAmount::new(12)).unwrap();
+        let countersigned = bob.apply_channel_update(payment).await.unwrap().unwrap();
+        assert!(alice.apply_channel_update(countersigned).await.unwrap().is_none());
+        
+        let close = bob.close_channel(&alice_id).await.unwrap();
+        let closed = alice.apply_channel_update(close).await.unwrap().unwrap();
+        assert!(bob.apply_channel_update(closed).await.unwrap().is_none());
+        
+        assert_eq!(alice.balance().value(), 88);
+        assert_eq!(alice.get_status().total_spent.value(), 12);
+        assert_eq!(bob.balance().value(), 12);
+        assert_eq!(bob.get_status().total_earned.value(), 12);
+        assert!(alice.get_status().channels.is_empty());
+        assert!(bob.close_channel(&alice_id).await.is_err());
+    }
+    
+    /// Open a channel from `client` to the relay's wallet and pay `paid` through it
+    async fn fund_channel(wallet: &mut InMemoryWallet, relay: &NodeKeypair, client: &NodeKeypair, paid: u128) -> ChannelManager {
+        let mut client_channels = ChannelManager::new(client.clone());
+        let open = client_channels.create_channel(
+            PeerID::new("relay".to_string()),
+            relay.public_key().to_bytes(),
+            TokenAmount::new(100),
+        ).unwrap();
+        let channel_id = open.params.channel_id;
+        let initial = wallet.accept_channel(PeerID::new("client".to_string()), open).await.unwrap();
+        client_channels.confirm_update(initial).unwrap();
+        
+        let payment = client_channels.update_channel(channel_id, TokenAmount::new(paid)).unwrap();
+        let countersigned = wallet.apply_channel_update(payment).await.unwrap().unwrap();
+        client_channels.confirm_update(countersigned).unwrap();
+        client_channels
+    }
+    
+    fn relay_receipt(relay: &NodeKeypair, client: &NodeKeypair, megabytes: u64) -> SignedReceipt {
+        let mut manager = crate::network::relay::RelayManager::default();
+        let session = manager.start_session(
+            PeerID::new("client".to_string()),
+            client.public_key().to_bytes(),
+            PeerID::new("target".to_string()),
+            ReputationScore::new(500),
+        ).unwrap();
+        manager.record_data(&session.session_id, megabytes * 1024 * 1024).unwrap();
+        
+        let mut receipt = manager.end_session(&session.session_id).unwrap();
+        receipt.sign_as_relay(relay);
+        receipt.sign_as_client(client);
+        receipt
+    }
+    
+    #[tokio::test]
+    async fn test_submit_valid_receipt() {
+        let relay = crate::core::crypto::NodeKeypair::generate();
+        let client = crate::core::crypto::NodeKeypair::generate();
+        let mut wallet = InMemoryWallet::new(relay.clone());
+        let mut client_channels = fund_channel(&mut wallet, &relay, &client, 5).await;
+        
+        wallet.submit_proof_of_relay(relay_receipt(&relay, &client, 3)).await.unwrap();
+        
+        assert_eq!(wallet.balance().value(), 3);
+        
+        // Closing pays out only what the receipt did not already claim
+        let channel_id = client_channels.channels().next().unwrap().params.channel_id;
+        let close = client_channels.close_channel(channel_id).unwrap();
+        let closed = wallet.apply_channel_update(close).await.unwrap().unwrap();
+        client_channels.confirm_update(closed).unwrap();
+        assert_eq!(wallet.balance().value(), 5);
+        assert_eq!(wallet.get_status().total_earned.value(), 5);
+        
+        // The node's reputation model turns the redemption into a gain
+        let mut node = crate::core::state::NodeState::new(relay);
+        let events = wallet.take_reputation_events();
+        assert!(matches!(events[..], [(_, ReputationEvent::RelayCompleted { .. })]));
+        wallet.set_reputation(node.apply_reputation_events(events));
+        assert_eq!(wallet.get_status().reputation.value(), ReputationScore::DEFAULT.value() + 1);
+    }
+    
+    #[tokio::test]
+    async fn test_submit_rejects_bad_receipts() {
+        let relay = crate::core::crypto::NodeKeypair::generate();
+        let client = crate::core::crypto::NodeKeypair::generate();
+        let mut wallet = InMemoryWallet::new(relay.clone());
+        fund_channel(&mut wallet, &relay, &client, 3).await;
+        
+        let mut unsigned = relay_receipt(&relay, &client, 1);
+        unsigned.client_signature.clear();
+        assert!(matches!(
+            wallet.submit_proof_of_relay(unsigned).await,
+            Err(Error::Receipt(ReceiptError::Unsigned))
+        ));
+        
+        let mut mis_signed = relay_receipt(&relay, &client, 1);
+        mis_signed.client_signature = relay.sign(&mis_signed.signing_bytes());
+        assert!(matches!(
+            wallet.submit_proof_of_relay(mis_signed).await,
+            Err(Error::Receipt(ReceiptError::InvalidClientSignature))
+        ));
+        
+        let someone_else = crate::core::crypto::NodeKeypair::generate();
+        assert!(matches!(
+            wallet.submit_proof_of_relay(relay_receipt(&someone_else, &client, 1)).await,
+            Err(Error::Receipt(ReceiptError::WrongRelay))
+        ));
+        
+        // Both parties signing an inflated amount is still rejected
+        let mut inflated = relay_receipt(&relay, &client, 1);
+        inflated.amount = TokenAmount::new(1_000);
+        inflated.sign_as_relay(&relay);
+        inflated.sign_as_client(&client);
+        assert!(matches!(
+            wallet.submit_proof_of_relay(inflated).await,
+            Err(Error::Receipt(ReceiptError::AmountInflated { .. }))
+        ));
+        
+        // The relay co-signing with a throwaway client key mints nothing
+        let throwaway = crate::core::crypto::NodeKeypair::generate();
+        assert!(matches!(
+            wallet.submit_proof_of_relay(relay_receipt(&relay, &throwaway, 1)).await,
+            Err(Error::Receipt(ReceiptError::NoChannel))
+        ));
+        
+        let receipt = relay_receipt(&relay, &client, 2);
+        wallet.submit_proof_of_relay(receipt.clone()).await.unwrap();
+        assert!(matches!(
+            wallet.submit_proof_of_relay(receipt).await,
+            Err(Error::Receipt(ReceiptError::Replayed(_)))
+        ));
+        
+        // The client has paid 3, of which 2 are claimed
+        assert!(matches!(
+            wallet.submit_proof_of_relay(relay_receipt(&relay, &client, 2)).await,
+            Err(Error::Receipt(ReceiptError::Unfunded { .. }))
+        ));
+        
+        let rejected = wallet.take_reputation_events().iter()
+            .filter(|(_, e)| matches!(e, ReputationEvent::ReceiptRejected { .. }))
+            .count();
+        assert_eq!(rejected, 7);
+        
+        assert_eq!(wallet.balance().value(), 2);
+    }
 }